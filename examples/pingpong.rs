@@ -1,16 +1,13 @@
 #![feature(async_closure)]
 
 use anyhow::Error;
-use async_std::sync::Mutex;
 use clap::Parser;
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
 use tracing::info;
 
-use veilid_core::tools::*;
 use veilid_core::*;
 
-use veilid_duplex::veilid::{AppLogic, AppMessage, VeilidDuplex, VeilidDuplexRoutes};
+use veilid_duplex::veilid::{AppLogic, AppMessage, DuplexSender, HandlerError, VeilidDuplex};
 
 #[derive(Parser, Debug)]
 struct Args {
@@ -29,60 +26,33 @@ struct ChatMessage {
 
 #[derive(Clone)]
 struct ChatAppLogic {
-    api: VeilidAPI,
-    our_dht_key: CryptoTyped<CryptoKey>,
-    routes: Arc<Mutex<VeilidDuplexRoutes>>,
-    routing_context: RoutingContext,
+    sender: DuplexSender,
 }
 
 impl ChatAppLogic {
-    pub fn new(app: VeilidDuplex) -> Self {
+    pub fn new(app: &VeilidDuplex) -> Self {
         info!("Starting network loop");
-        let our_dht_key = app.our_dht_key;
-
-        println!("Our DHT key: {}", our_dht_key);
-
-        let api = app.api.clone();
-        let routing_context = app.routing_context.clone();
-        let routes = app.routes.clone();
+        println!("Our DHT key: {}", app.our_dht_key);
 
         Self {
-            api,
-            our_dht_key,
-            routes,
-            routing_context,
+            sender: app.sender(),
         }
     }
 }
 
 impl AppLogic<ChatMessage> for ChatAppLogic {
-    async fn on_message(&mut self, message: AppMessage<ChatMessage>) {
+    async fn on_message(&mut self, message: AppMessage<ChatMessage>) -> Result<(), HandlerError> {
         println!("on_remote_call\treceived: {:?}\t", message.data);
-        let mut message = message.clone();
-
-        message.data.count += 1;
-
-        let remote_dht_record = message.dht_record;
-        message.dht_record = self.our_dht_key;
-
-        loop {
-            let mut routes = self.routes.lock().await;
-            let target = routes
-                .get_route(
-                    remote_dht_record,
-                    self.api.clone(),
-                    self.routing_context.clone(),
-                )
-                .await
-                .unwrap();
-
-            let result = message.send(&self.routing_context, target).await;
-            if result.is_ok() {
-                return;
-            }
-            info!("Failed to send message, sleeping 1 second");
-            sleep(1000).await;
-        }
+
+        let data = ChatMessage {
+            count: message.data.count + 1,
+        };
+        message
+            .reply(&self.sender, data)
+            .await
+            .map_err(|e| HandlerError::new(e.to_string()))?;
+
+        Ok(())
     }
 }
 
@@ -99,12 +69,15 @@ async fn main() -> Result<(), Error> {
             data: ChatMessage { count: 0 },
             dht_record: app.our_dht_key,
             uuid: "".to_string(),
+            reply_to: None,
+            timestamp: 0,
+            topic: None,
         };
 
         app.send_message(app_message, service_dht_key).await?;
     }
 
-    let app_logic = ChatAppLogic::new(app.clone());
+    let app_logic = ChatAppLogic::new(&app);
 
     app.network_loop(app_logic).await?;
     app.api.shutdown().await;
@@ -0,0 +1,160 @@
+use std::fmt;
+use std::future::{poll_fn, Future};
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Poll, Waker};
+
+use futures_util::future::{select, Either};
+
+#[derive(Default)]
+struct CancelState {
+    cancelled: AtomicBool,
+    wakers: Mutex<Vec<Waker>>,
+    // Tokens cancelled along with this one
+    children: Mutex<Vec<CancellationToken>>,
+}
+
+/// Tells a network loop to stop, see `VeilidDuplex::network_loop_until`.
+/// Clones share the signal; once cancelled it stays cancelled.
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    state: Arc<CancelState>,
+}
+
+impl fmt::Debug for CancellationToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CancellationToken")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.state.cancelled.store(true, Ordering::SeqCst);
+        for waker in self.state.wakers.lock().unwrap().drain(..) {
+            waker.wake();
+        }
+        let children = std::mem::take(&mut *self.state.children.lock().unwrap());
+        for child in children {
+            child.cancel();
+        }
+    }
+
+    /// A token cancelled when this one is, that can also be cancelled on its
+    /// own without affecting this one
+    pub fn child_token(&self) -> Self {
+        let child = Self::new();
+        self.add_child(child.clone());
+        child
+    }
+
+    /// Cancel `child` along with this token, on top of whatever else does
+    pub(crate) fn add_child(&self, child: CancellationToken) {
+        let mut children = self.state.children.lock().unwrap();
+        children.retain(|existing| !existing.is_cancelled());
+        children.push(child.clone());
+        drop(children);
+
+        // cancel() may have taken the children before ours went in
+        if self.is_cancelled() {
+            child.cancel();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once the token is cancelled
+    pub async fn cancelled(&self) {
+        poll_fn(|cx| {
+            if self.is_cancelled() {
+                return Poll::Ready(());
+            }
+
+            let mut wakers = self.state.wakers.lock().unwrap();
+            // Polling again from the same task replaces its waker
+            wakers.retain(|waker| !waker.will_wake(cx.waker()));
+            wakers.push(cx.waker().clone());
+            drop(wakers);
+
+            // cancel() may have drained the wakers before ours went in
+            match self.is_cancelled() {
+                true => Poll::Ready(()),
+                false => Poll::Pending,
+            }
+        })
+        .await
+    }
+
+    /// Run `future` unless the token is cancelled first, in which case it's
+    /// dropped and `None` returned
+    pub async fn run_until<F: Future>(&self, future: F) -> Option<F::Output> {
+        match select(pin!(future), pin!(self.cancelled())).await {
+            Either::Left((output, _)) => Some(output),
+            Either::Right(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_cancel_wakes_waiters() {
+        let token = CancellationToken::new();
+        let waiters: Vec<_> = (0..3)
+            .map(|_| {
+                let token = token.clone();
+                tokio::spawn(async move { token.cancelled().await })
+            })
+            .collect();
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        token.cancel();
+        for waiter in waiters {
+            tokio::time::timeout(Duration::from_secs(1), waiter)
+                .await
+                .expect("waiter wasn't woken")
+                .unwrap();
+        }
+        assert!(token.is_cancelled());
+    }
+
+    #[test]
+    fn test_child_follows_parent_only() {
+        let parent = CancellationToken::new();
+        let first = parent.child_token();
+        let second = parent.child_token();
+
+        first.cancel();
+        assert!(!parent.is_cancelled());
+        assert!(!second.is_cancelled());
+
+        parent.cancel();
+        assert!(second.is_cancelled());
+        assert!(parent.child_token().is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn test_run_until_drops_future_on_cancel() {
+        let token = CancellationToken::new();
+        assert_eq!(token.run_until(async { 7 }).await, Some(7));
+
+        let canceller = token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            canceller.cancel();
+        });
+        let never = futures_util::future::pending::<()>();
+        assert_eq!(token.run_until(never).await, None);
+    }
+}
@@ -0,0 +1,246 @@
+use std::time::Duration;
+
+use tracing::info;
+use uuid::Uuid;
+
+use veilid_core::tools::*;
+
+use crate::error::VeilidDuplexError;
+
+/// Largest payload veilid accepts in a single `app_call`
+pub const MAX_FRAME_SIZE: usize = 32 * 1024;
+/// Upper bound on chunks per message, which caps a message at roughly 32mb
+pub const MAX_CHUNKS: u32 = 1024;
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Chunk frames start with a magic that can't begin a serialized AppMessage,
+// so unchunked messages keep their existing wire format
+const CHUNK_MAGIC: &[u8; 4] = b"\0VDC";
+const CHUNK_HEADER_SIZE: usize = CHUNK_MAGIC.len() + 16 + 4 + 4;
+pub const CHUNK_PAYLOAD_SIZE: usize = MAX_FRAME_SIZE - CHUNK_HEADER_SIZE;
+
+/// One frame of a message too large for a single `app_call`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub message_id: Uuid,
+    pub index: u32,
+    pub total: u32,
+    pub data: Vec<u8>,
+}
+
+impl Chunk {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(CHUNK_HEADER_SIZE + self.data.len());
+        frame.extend_from_slice(CHUNK_MAGIC);
+        frame.extend_from_slice(self.message_id.as_bytes());
+        frame.extend_from_slice(&self.index.to_be_bytes());
+        frame.extend_from_slice(&self.total.to_be_bytes());
+        frame.extend_from_slice(&self.data);
+        frame
+    }
+
+    /// Parse a chunk frame, or `None` if `frame` isn't one
+    pub fn from_bytes(frame: &[u8]) -> Option<Self> {
+        if frame.len() < CHUNK_HEADER_SIZE || !frame.starts_with(CHUNK_MAGIC) {
+            return None;
+        }
+
+        let header = &frame[CHUNK_MAGIC.len()..CHUNK_HEADER_SIZE];
+        let message_id = Uuid::from_slice(&header[..16]).ok()?;
+        let index = u32::from_be_bytes(header[16..20].try_into().ok()?);
+        let total = u32::from_be_bytes(header[20..24].try_into().ok()?);
+
+        Some(Self {
+            message_id,
+            index,
+            total,
+            data: frame[CHUNK_HEADER_SIZE..].to_vec(),
+        })
+    }
+}
+
+/// Split `blob` into frames for `app_call`. Blobs that fit in one frame are sent as is.
+pub fn split_into_frames(blob: Vec<u8>) -> Result<Vec<Vec<u8>>, VeilidDuplexError> {
+    if blob.len() <= MAX_FRAME_SIZE {
+        return Ok(vec![blob]);
+    }
+
+    let total = blob.len().div_ceil(CHUNK_PAYLOAD_SIZE);
+    if total > MAX_CHUNKS as usize {
+        return Err(VeilidDuplexError::MessageTooLarge {
+            size: blob.len(),
+            limit: MAX_CHUNKS as usize * CHUNK_PAYLOAD_SIZE,
+        });
+    }
+
+    let message_id = Uuid::new_v4();
+    let frames = blob
+        .chunks(CHUNK_PAYLOAD_SIZE)
+        .enumerate()
+        .map(|(index, data)| {
+            Chunk {
+                message_id,
+                index: index as u32,
+                total: total as u32,
+                data: data.to_vec(),
+            }
+            .to_bytes()
+        })
+        .collect();
+
+    Ok(frames)
+}
+
+struct PartialMessage {
+    chunks: Vec<Option<Vec<u8>>>,
+    received: u32,
+    started_at: u64,
+}
+
+/// Buffers chunks by message id until every chunk of a message has arrived
+pub struct Reassembler {
+    partial: HashMap<Uuid, PartialMessage>,
+    timeout: Duration,
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Self::new(REASSEMBLY_TIMEOUT)
+    }
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            partial: HashMap::new(),
+            timeout,
+        }
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Add a chunk, returning the whole message once its last chunk arrives.
+    /// Malformed chunks are dropped.
+    pub fn insert(&mut self, chunk: Chunk, now: u64) -> Option<Vec<u8>> {
+        if chunk.total == 0 || chunk.total > MAX_CHUNKS || chunk.index >= chunk.total {
+            info!(
+                "Dropping malformed chunk {}/{} of {}",
+                chunk.index, chunk.total, chunk.message_id
+            );
+            return None;
+        }
+
+        let partial = self
+            .partial
+            .entry(chunk.message_id)
+            .or_insert_with(|| PartialMessage {
+                chunks: vec![None; chunk.total as usize],
+                received: 0,
+                started_at: now,
+            });
+
+        if partial.chunks.len() != chunk.total as usize {
+            info!(
+                "Dropping chunk with inconsistent total for {}",
+                chunk.message_id
+            );
+            return None;
+        }
+
+        let slot = &mut partial.chunks[chunk.index as usize];
+        if slot.is_none() {
+            *slot = Some(chunk.data);
+            partial.received += 1;
+        }
+
+        if partial.received < chunk.total {
+            return None;
+        }
+
+        let partial = self.partial.remove(&chunk.message_id)?;
+        Some(partial.chunks.into_iter().flatten().flatten().collect())
+    }
+
+    /// Drop messages that didn't complete within the timeout. Returns how many were dropped.
+    pub fn prune(&mut self, now: u64) -> usize {
+        let timeout = self.timeout.as_micros() as u64;
+        let before = self.partial.len();
+        self.partial
+            .retain(|_, partial| now.saturating_sub(partial.started_at) < timeout);
+
+        let dropped = before - self.partial.len();
+        if dropped > 0 {
+            info!("Dropped {} incomplete chunked message(s)", dropped);
+        }
+        dropped
+    }
+
+    pub fn pending(&self) -> usize {
+        self.partial.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_small_blob_is_not_chunked() {
+        let blob = b"{\"data\":1}".to_vec();
+        let frames = split_into_frames(blob.clone()).unwrap();
+
+        assert_eq!(frames, vec![blob]);
+        assert!(Chunk::from_bytes(&frames[0]).is_none());
+    }
+
+    #[test]
+    fn test_round_trip_200kb() {
+        let blob: Vec<u8> = (0..200 * 1024).map(|i| (i % 251) as u8).collect();
+        let mut frames = split_into_frames(blob.clone()).unwrap();
+        assert!(frames.len() > 1);
+        assert!(frames.iter().all(|frame| frame.len() <= MAX_FRAME_SIZE));
+
+        // Delivery order doesn't matter
+        frames.reverse();
+
+        let mut reassembler = Reassembler::default();
+        let mut result = None;
+        for frame in frames {
+            assert!(result.is_none());
+            result = reassembler.insert(Chunk::from_bytes(&frame).unwrap(), 0);
+        }
+
+        assert_eq!(result, Some(blob));
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    fn test_incomplete_message_is_pruned() {
+        let blob = vec![7u8; MAX_FRAME_SIZE * 2];
+        let frames = split_into_frames(blob).unwrap();
+
+        let mut reassembler = Reassembler::new(Duration::from_secs(1));
+        assert!(reassembler
+            .insert(Chunk::from_bytes(&frames[0]).unwrap(), 0)
+            .is_none());
+        assert_eq!(reassembler.prune(500_000), 0);
+        assert_eq!(reassembler.prune(1_000_000), 1);
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    fn test_malformed_chunk_is_dropped() {
+        let mut reassembler = Reassembler::default();
+        let chunk = Chunk {
+            message_id: Uuid::new_v4(),
+            index: 3,
+            total: 2,
+            data: vec![1, 2, 3],
+        };
+
+        assert!(reassembler.insert(chunk, 0).is_none());
+        assert_eq!(reassembler.pending(), 0);
+    }
+}
@@ -0,0 +1,108 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use uuid::Uuid;
+use veilid_core::tools::get_timestamp;
+
+/// Time as `VeilidDuplex` sees it when stamping and checking messages, in
+/// veilid microsecond timestamps (see `get_timestamp`)
+pub trait Clock: Send + Sync {
+    fn now(&self) -> u64;
+}
+
+/// Uuids given to outgoing messages
+pub trait UuidSource: Send + Sync {
+    fn next_uuid(&self) -> Uuid;
+}
+
+/// Lets a test keep a handle on a clock it gave away, to move it
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> u64 {
+        (**self).now()
+    }
+}
+
+/// The system clock, the default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        get_timestamp()
+    }
+}
+
+/// Random v4 uuids, the default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RandomUuids;
+
+impl UuidSource for RandomUuids {
+    fn next_uuid(&self) -> Uuid {
+        Uuid::new_v4()
+    }
+}
+
+/// Clock that only moves when told to, for tests
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    now: AtomicU64,
+}
+
+impl ManualClock {
+    pub fn new(now: u64) -> Self {
+        Self {
+            now: AtomicU64::new(now),
+        }
+    }
+
+    pub fn set(&self, now: u64) {
+        self.now.store(now, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.now.fetch_add(by.as_micros() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
+
+/// Uuids 1, 2, 3... as u128s, for tests
+#[derive(Debug, Default)]
+pub struct SequentialUuids {
+    last: AtomicU64,
+}
+
+impl SequentialUuids {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl UuidSource for SequentialUuids {
+    fn next_uuid(&self) -> Uuid {
+        Uuid::from_u128(self.last.fetch_add(1, Ordering::SeqCst) as u128 + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manual_clock_and_sequential_uuids() {
+        let clock = ManualClock::new(1_000);
+        clock.advance(Duration::from_millis(2));
+        assert_eq!(clock.now(), 3_000);
+        clock.set(10);
+        assert_eq!(clock.now(), 10);
+
+        let uuids = SequentialUuids::new();
+        assert_eq!(uuids.next_uuid(), Uuid::from_u128(1));
+        assert_eq!(uuids.next_uuid(), Uuid::from_u128(2));
+    }
+}
@@ -0,0 +1,137 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::error::VeilidDuplexError;
+
+// Binary-encoded messages start with a magic and a codec id. JSON messages go
+// out bare, so peers that only speak JSON keep working.
+const CODEC_MAGIC: &[u8; 4] = b"\0VDF";
+
+pub trait Codec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, VeilidDuplexError>;
+    fn decode<T: DeserializeOwned>(&self, blob: &[u8]) -> Result<T, VeilidDuplexError>;
+}
+
+/// Serialization format of `AppMessage`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MessageCodec {
+    #[default]
+    Json,
+    Bincode,
+    Cbor,
+    MessagePack,
+}
+
+impl MessageCodec {
+    fn id(&self) -> u8 {
+        match self {
+            MessageCodec::Json => 0,
+            MessageCodec::Bincode => 1,
+            MessageCodec::Cbor => 2,
+            MessageCodec::MessagePack => 3,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(MessageCodec::Json),
+            1 => Some(MessageCodec::Bincode),
+            2 => Some(MessageCodec::Cbor),
+            3 => Some(MessageCodec::MessagePack),
+            _ => None,
+        }
+    }
+}
+
+impl Codec for MessageCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, VeilidDuplexError> {
+        let blob = match self {
+            MessageCodec::Json => return Ok(serde_json::to_vec(value)?),
+            MessageCodec::Bincode => bincode::serialize(value).map_err(codec_error)?,
+            MessageCodec::Cbor => {
+                let mut blob = Vec::new();
+                ciborium::into_writer(value, &mut blob).map_err(codec_error)?;
+                blob
+            }
+            MessageCodec::MessagePack => rmp_serde::to_vec_named(value).map_err(codec_error)?,
+        };
+
+        let mut framed = Vec::with_capacity(CODEC_MAGIC.len() + 1 + blob.len());
+        framed.extend_from_slice(CODEC_MAGIC);
+        framed.push(self.id());
+        framed.extend_from_slice(&blob);
+        Ok(framed)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, blob: &[u8]) -> Result<T, VeilidDuplexError> {
+        match self {
+            MessageCodec::Json => Ok(serde_json::from_slice(blob)?),
+            MessageCodec::Bincode => bincode::deserialize(blob).map_err(codec_error),
+            MessageCodec::Cbor => ciborium::from_reader(blob).map_err(codec_error),
+            MessageCodec::MessagePack => rmp_serde::from_slice(blob).map_err(codec_error),
+        }
+    }
+}
+
+/// Decode a blob produced by `MessageCodec::encode` with whichever codec the
+/// sender picked
+pub fn decode_any<T: DeserializeOwned>(blob: &[u8]) -> Result<T, VeilidDuplexError> {
+    if !blob.starts_with(CODEC_MAGIC) {
+        return MessageCodec::Json.decode(blob);
+    }
+
+    let id = blob.get(CODEC_MAGIC.len()).copied().unwrap_or_default();
+    let codec = MessageCodec::from_id(id)
+        .ok_or_else(|| VeilidDuplexError::Codec(format!("Unknown codec id {}", id).into()))?;
+    codec.decode(&blob[CODEC_MAGIC.len() + 1..])
+}
+
+fn codec_error(e: impl std::error::Error + Send + Sync + 'static) -> VeilidDuplexError {
+    VeilidDuplexError::Codec(Box::new(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Sample {
+        count: u64,
+        name: String,
+        tags: Vec<String>,
+        reply_to: Option<String>,
+    }
+
+    #[test]
+    fn test_codecs_round_trip() {
+        let sample = Sample {
+            count: 42,
+            name: "pingpong".to_string(),
+            tags: vec!["a".to_string(), "b".to_string()],
+            reply_to: None,
+        };
+
+        for codec in [
+            MessageCodec::Json,
+            MessageCodec::Bincode,
+            MessageCodec::Cbor,
+            MessageCodec::MessagePack,
+        ] {
+            let blob = codec.encode(&sample).unwrap();
+            assert_eq!(decode_any::<Sample>(&blob).unwrap(), sample, "{:?}", codec);
+        }
+    }
+
+    #[test]
+    fn test_json_stays_bare() {
+        let blob = MessageCodec::Json.encode(&42u64).unwrap();
+        assert_eq!(blob, b"42");
+    }
+
+    #[test]
+    fn test_unknown_codec_is_an_error() {
+        let mut blob = CODEC_MAGIC.to_vec();
+        blob.extend_from_slice(&[0xff, 1, 2, 3]);
+        assert!(decode_any::<u64>(&blob).is_err());
+    }
+}
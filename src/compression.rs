@@ -0,0 +1,132 @@
+use std::io::{Read, Write};
+
+use anyhow::{Error, Ok};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+
+use crate::error::VeilidDuplexError;
+
+// Compressed blobs start with a magic that can't begin a serialized AppMessage,
+// so receivers can tell them apart and uncompressed senders keep working. The
+// last byte names the codec.
+//
+// Codecs aren't negotiated per peer: deflate is the only one, and every
+// version that compresses also inflates, so the header alone says how to read
+// a blob. A codec added later gets its own last byte, and receivers that don't
+// know it refuse the message with a NACK rather than misread it.
+const CODEC_PREFIX: &[u8; 3] = b"\0VD";
+const DEFLATE: u8 = b'Z';
+const COMPRESSION_MAGIC: &[u8; 4] = b"\0VDZ";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    /// Blobs smaller than this are sent uncompressed
+    pub threshold: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            threshold: 1024,
+        }
+    }
+}
+
+/// Deflate `blob` if compression is enabled, the blob is over the threshold and
+/// compressing actually makes it smaller. Otherwise the blob is returned as is.
+pub fn compress(blob: Vec<u8>, config: &CompressionConfig) -> Result<Vec<u8>, Error> {
+    if !config.enabled || blob.len() < config.threshold {
+        return Ok(blob);
+    }
+
+    let mut encoder =
+        DeflateEncoder::new(COMPRESSION_MAGIC.to_vec(), flate2::Compression::default());
+    encoder.write_all(&blob)?;
+    let compressed = encoder.finish()?;
+
+    if compressed.len() >= blob.len() {
+        return Ok(blob);
+    }
+    Ok(compressed)
+}
+
+/// Inflate a blob produced by `compress`; uncompressed blobs pass through.
+/// Refuses to inflate past `max_size` bytes.
+pub fn decompress(blob: Vec<u8>, max_size: usize) -> Result<Vec<u8>, Error> {
+    if !blob.starts_with(CODEC_PREFIX) {
+        return Ok(blob);
+    }
+    match blob.get(CODEC_PREFIX.len()) {
+        Some(&DEFLATE) => (),
+        codec => anyhow::bail!("Unsupported compression codec {:?}", codec),
+    }
+
+    let mut decompressed = Vec::new();
+    DeflateDecoder::new(&blob[COMPRESSION_MAGIC.len()..])
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut decompressed)?;
+
+    if decompressed.len() > max_size {
+        return Err(VeilidDuplexError::MessageTooLarge {
+            size: decompressed.len(),
+            limit: max_size,
+        }
+        .into());
+    }
+
+    Ok(decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enabled() -> CompressionConfig {
+        CompressionConfig {
+            enabled: true,
+            threshold: 16,
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let blob = br#"{"data":"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"}"#.repeat(100);
+        let compressed = compress(blob.clone(), &enabled()).unwrap();
+
+        assert!(compressed.starts_with(COMPRESSION_MAGIC));
+        assert!(compressed.len() < blob.len());
+        assert_eq!(decompress(compressed, blob.len()).unwrap(), blob);
+    }
+
+    #[test]
+    fn test_small_or_disabled_is_untouched() {
+        let small = b"{}".to_vec();
+        assert_eq!(compress(small.clone(), &enabled()).unwrap(), small);
+
+        let blob = vec![b'a'; 4096];
+        assert_eq!(
+            compress(blob.clone(), &CompressionConfig::default()).unwrap(),
+            blob
+        );
+        assert_eq!(decompress(blob.clone(), blob.len()).unwrap(), blob);
+    }
+
+    #[test]
+    fn test_unknown_codec_is_refused() {
+        let mut blob = compress(vec![b'a'; 4096], &enabled()).unwrap();
+        blob[CODEC_PREFIX.len()] = b'S';
+
+        assert!(decompress(blob, 4096).is_err());
+        assert!(decompress(CODEC_PREFIX.to_vec(), 4096).is_err());
+    }
+
+    #[test]
+    fn test_decompress_is_bounded() {
+        let blob = vec![b'a'; 64 * 1024];
+        let compressed = compress(blob, &enabled()).unwrap();
+
+        assert!(decompress(compressed, 1024).is_err());
+    }
+}
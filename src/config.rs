@@ -1,166 +1,738 @@
+use std::any::Any;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::error::VeilidDuplexError;
+use crate::utils::ServiceKeys;
+
 use veilid_core::{
-    best_crypto_kind, ConfigCallbackReturn, CryptoTyped, FourCC, KeyPair, TypedKeyGroup,
-    TypedSecretGroup, VeilidAPIError,
+    ConfigCallbackReturn, CryptoKind, CryptoTyped, FourCC, KeyPair, Sequencing, Stability,
+    TypedKeyGroup, TypedSecretGroup, VeilidAPIError, CRYPTO_KIND_VLD0, VALID_CRYPTO_KINDS,
 };
 
-#[cfg(not(target_arch = "wasm32"))]
-pub fn config_callback(
-    veilid_storage_dir: std::path::PathBuf,
-    key_pair: CryptoTyped<KeyPair>,
-    key: String,
-) -> ConfigCallbackReturn {
-    match key.as_str() {
-        "program_name" => Ok(Box::new(String::from("towel"))),
-        "namespace" => Ok(Box::<String>::default()),
-        "capabilities.disable" => Ok(Box::<Vec<FourCC>>::default()),
-        "table_store.directory" => Ok(Box::new(
-            veilid_storage_dir
-                .join("table")
-                .to_str()
-                .as_ref()
-                .unwrap()
-                .to_string(),
-        )),
-        "table_store.delete" => Ok(Box::new(false)),
-        // "block_store.directory" => Ok(Box::new(get_block_store_path())),
-        "block_store.directory" => Ok(Box::new(
-            veilid_storage_dir
-                .join("block")
-                .to_str()
-                .as_ref()
-                .unwrap()
-                .to_string(),
-        )),
-        "block_store.delete" => Ok(Box::new(false)),
-        "protected_store.allow_insecure_fallback" => Ok(Box::new(true)),
-        "protected_store.always_use_insecure_storage" => Ok(Box::new(true)),
-        // "protected_store.directory" => Ok(Box::new(get_protected_store_path())),
-        "protected_store.directory" => Ok(Box::new(
-            veilid_storage_dir
-                .join("protected")
-                .to_str()
-                .as_ref()
-                .unwrap()
-                .to_string(),
-        )),
-        "protected_store.delete" => Ok(Box::new(false)),
-        "protected_store.device_encryption_key_password" => Ok(Box::new("".to_owned())),
-        "protected_store.new_device_encryption_key_password" => {
-            Ok(Box::new(Option::<String>::None))
-        }
-        "network.connection_initial_timeout_ms" => Ok(Box::new(2_000u32)),
-        "network.connection_inactivity_timeout_ms" => Ok(Box::new(60_000u32)),
-        "network.max_connections_per_ip4" => Ok(Box::new(8u32)),
-        "network.max_connections_per_ip6_prefix" => Ok(Box::new(8u32)),
-        "network.max_connections_per_ip6_prefix_size" => Ok(Box::new(56u32)),
-        "network.max_connection_frequency_per_min" => Ok(Box::new(8u32)),
-        "network.client_whitelist_timeout_ms" => Ok(Box::new(300_000u32)),
-        "network.reverse_connection_receipt_time_ms" => Ok(Box::new(5_000u32)),
-        "network.hole_punch_receipt_time_ms" => Ok(Box::new(5_000u32)),
-        "network.network_key_password" => Ok(Box::new(Option::<String>::None)),
-        "network.routing_table.node_id" => {
-            let mut group = TypedKeyGroup::new();
-            group.add(veilid_core::CryptoTyped::new(
-                best_crypto_kind(),
-                key_pair.value.key,
+/// Most hops veilid allows in a private route, `network.rpc.max_route_hop_count`
+pub const MAX_ROUTE_HOP_COUNT: u8 = 4;
+
+/// Most routes a service publishes, see `VeilidDuplexConfig::route_count`
+pub const MAX_ROUTE_COUNT: u16 = 8;
+
+/// How often a startup phase is polled, and how long for before giving up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StartupWait {
+    /// `None` waits forever
+    pub timeout: Option<Duration>,
+    pub poll_interval: Duration,
+}
+
+impl StartupWait {
+    const fn polling(poll_interval: Duration) -> Self {
+        Self {
+            timeout: None,
+            poll_interval,
+        }
+    }
+}
+
+/// Phases `VeilidDuplex::new` waits through after attaching, in order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StartupWaits {
+    pub network_start: StartupWait,
+    pub attached: StartupWait,
+    pub public_internet_ready: StartupWait,
+}
+
+impl StartupWaits {
+    /// Give up on each phase after `timeout`
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.network_start.timeout = Some(timeout);
+        self.attached.timeout = Some(timeout);
+        self.public_internet_ready.timeout = Some(timeout);
+        self
+    }
+}
+
+impl Default for StartupWaits {
+    fn default() -> Self {
+        Self {
+            network_start: StartupWait::polling(Duration::from_millis(100)),
+            attached: StartupWait::polling(Duration::from_millis(1000)),
+            public_internet_ready: StartupWait::polling(Duration::from_millis(5000)),
+        }
+    }
+}
+
+/// Retries with doubling backoff, for steps that tend to fail while the
+/// network is still settling, like creating our private route
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Tries, including the first
+    pub attempts: u16,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(8),
+        }
+    }
+}
+
+/// How peers reach us, as published in our DHT record
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Addressing {
+    /// Through our private route, hiding which node we are
+    #[default]
+    PrivateRoute,
+    /// Straight to our node id. Faster, but anyone reading our DHT record
+    /// learns our node, so only for LAN or trusted deployments.
+    NodeId,
+}
+
+/// Node settings that differ between deployments. The defaults connect to the
+/// public Veilid network.
+#[derive(Debug, Clone)]
+pub struct VeilidDuplexConfig {
+    /// Bootstrap nodes, `network.routing_table.bootstrap`
+    pub bootstrap: Vec<String>,
+    /// Key of a private network, `network.network_key_password`
+    pub network_key_password: Option<String>,
+    /// Root of `table_store.directory`, `block_store.directory`,
+    /// `protected_store.directory` and the `network.tls` paths. A fresh
+    /// temporary directory when unset, created if missing. Ignored on wasm.
+    pub storage_dir: Option<PathBuf>,
+    /// Kind of `network.routing_table.node_id`, our private route and our
+    /// DHT record
+    pub crypto_kind: CryptoKind,
+    /// Sequencing of the routing context and our private route
+    pub sequencing: Sequencing,
+    /// Stability of our private route
+    pub stability: Stability,
+    /// Hops in our private route, `network.rpc.default_route_hop_count`. More
+    /// hops hide us better but add latency.
+    pub route_hop_count: u8,
+    /// Private routes we publish, each in its own subkey of our DHT record.
+    /// Peers fall back to the next one when a route doesn't import. A DHT
+    /// record reused through `service_keys` keeps the subkeys it was created
+    /// with. Must be 1 with `Addressing::NodeId`.
+    pub route_count: u16,
+    /// File holding the node keypair (`network.routing_table.node_id` and
+    /// `node_id_secret`), created on first start. Without it the node gets a new
+    /// id every start. Ignored on wasm.
+    pub identity_path: Option<PathBuf>,
+    /// DHT record to publish our route in instead of creating a new one
+    pub service_keys: Option<ServiceKeys>,
+    /// File the service keys are loaded from, or saved to once the first start
+    /// has created the record. Used when `service_keys` is unset.
+    pub service_keys_path: Option<PathBuf>,
+    /// Polling and timeouts of the waits for the node to come online. Without
+    /// timeouts a node that can't reach bootstrap never finishes starting.
+    pub startup_waits: StartupWaits,
+    /// Retries of route creation and publishing during startup
+    pub startup_retry: RetryPolicy,
+    /// What we publish for peers to send to
+    pub addressing: Addressing,
+    /// Everything else passed to veilid
+    pub veilid_options: VeilidConfigOptions,
+}
+
+impl VeilidDuplexConfig {
+    pub(crate) fn validate(&self) -> Result<(), VeilidDuplexError> {
+        if !VALID_CRYPTO_KINDS.contains(&self.crypto_kind) {
+            return Err(VeilidDuplexError::InvalidConfig(format!(
+                "crypto kind {} isn't supported",
+                self.crypto_kind
+            )));
+        }
+        if !(1..=MAX_ROUTE_HOP_COUNT).contains(&self.route_hop_count) {
+            return Err(VeilidDuplexError::InvalidConfig(format!(
+                "route hop count {} is outside 1..={}",
+                self.route_hop_count, MAX_ROUTE_HOP_COUNT
+            )));
+        }
+
+        if !(1..=MAX_ROUTE_COUNT).contains(&self.route_count) {
+            return Err(VeilidDuplexError::InvalidConfig(format!(
+                "route count {} is outside 1..={}",
+                self.route_count, MAX_ROUTE_COUNT
+            )));
+        }
+        // Backups would be private routes published next to our node id,
+        // revealing the node they're meant to hide
+        if self.addressing == Addressing::NodeId && self.route_count > 1 {
+            return Err(VeilidDuplexError::InvalidConfig(
+                "node id addressing publishes a single route, route count must be 1".to_string(),
             ));
-            Ok(Box::new(group))
         }
-        "network.routing_table.node_id_secret" => {
-            let mut group = TypedSecretGroup::new();
-            group.add(veilid_core::CryptoTyped::new(
-                best_crypto_kind(),
-                key_pair.value.secret,
+
+        // Veilid would read an empty key as no key, joining the public network
+        if self.network_key_password.as_deref() == Some("") {
+            return Err(VeilidDuplexError::InvalidConfig(
+                "network key is empty".to_string(),
             ));
-            Ok(Box::new(group))
-        }
-        // "network.routing_table.bootstrap" => Ok(Box::new(Vec::<String>::new())),
-        "network.routing_table.bootstrap" => Ok(Box::new(vec!["bootstrap.veilid.net".to_string()])),
-        "network.routing_table.limit_over_attached" => Ok(Box::new(64u32)),
-        "network.routing_table.limit_fully_attached" => Ok(Box::new(32u32)),
-        "network.routing_table.limit_attached_strong" => Ok(Box::new(16u32)),
-        "network.routing_table.limit_attached_good" => Ok(Box::new(8u32)),
-        "network.routing_table.limit_attached_weak" => Ok(Box::new(4u32)),
-        "network.rpc.concurrency" => Ok(Box::new(2u32)),
-        "network.rpc.queue_size" => Ok(Box::new(1024u32)),
-        "network.rpc.max_timestamp_behind_ms" => Ok(Box::new(Some(10_000u32))),
-        "network.rpc.max_timestamp_ahead_ms" => Ok(Box::new(Some(10_000u32))),
-        "network.rpc.timeout_ms" => Ok(Box::new(5_000u32)),
-        "network.rpc.max_route_hop_count" => Ok(Box::new(4u8)),
-        "network.rpc.default_route_hop_count" => Ok(Box::new(1u8)),
-        "network.dht.max_find_node_count" => Ok(Box::new(20u32)),
-        "network.dht.resolve_node_timeout_ms" => Ok(Box::new(10_000u32)),
-        "network.dht.resolve_node_count" => Ok(Box::new(1u32)),
-        "network.dht.resolve_node_fanout" => Ok(Box::new(4u32)),
-        "network.dht.get_value_timeout_ms" => Ok(Box::new(10_000u32)),
-        "network.dht.get_value_count" => Ok(Box::new(3u32)),
-        "network.dht.get_value_fanout" => Ok(Box::new(4u32)),
-        "network.dht.set_value_timeout_ms" => Ok(Box::new(10_000u32)),
-        "network.dht.set_value_count" => Ok(Box::new(5u32)),
-        "network.dht.set_value_fanout" => Ok(Box::new(4u32)),
-        "network.dht.min_peer_count" => Ok(Box::new(20u32)),
-        "network.dht.min_peer_refresh_time_ms" => Ok(Box::new(60_000u32)),
-        "network.dht.validate_dial_info_receipt_time_ms" => Ok(Box::new(5_000u32)),
-        "network.dht.local_subkey_cache_size" => Ok(Box::new(128u32)),
-        "network.dht.local_max_subkey_cache_memory_mb" => Ok(Box::new(256u32)),
-        "network.dht.remote_subkey_cache_size" => Ok(Box::new(1024u32)),
-        "network.dht.remote_max_records" => Ok(Box::new(4096u32)),
-        "network.dht.remote_max_subkey_cache_memory_mb" => Ok(Box::new(64u32)),
-        "network.dht.remote_max_storage_space_mb" => Ok(Box::new(64u32)),
-        "network.upnp" => Ok(Box::new(true)),
-        "network.detect_address_changes" => Ok(Box::new(true)),
-        "network.restricted_nat_retries" => Ok(Box::new(3u32)),
-        "network.tls.certificate_path" => Ok(Box::new(
-            veilid_storage_dir
-                .join("cert")
-                .to_str()
-                .as_ref()
-                .unwrap()
-                .to_string(),
-        )),
-        "network.tls.private_key_path" => Ok(Box::new(
-            veilid_storage_dir
-                .join("key")
-                .to_str()
+        }
+
+        self.veilid_options
+            .protocols
+            .check(cfg!(target_arch = "wasm32"))?;
+
+        if self.startup_retry.attempts == 0 {
+            return Err(VeilidDuplexError::InvalidConfig(
+                "startup retry attempts must be at least 1".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Where veilid's stores live across restarts: `storage_dir`, or next to
+    /// the identity file when only that is set
+    pub(crate) fn persistent_storage_dir(&self) -> Option<PathBuf> {
+        self.storage_dir.clone().or_else(|| {
+            self.identity_path
                 .as_ref()
-                .unwrap()
-                .to_string(),
-        )),
-        "network.tls.connection_initial_timeout_ms" => Ok(Box::new(2_000u32)),
-        "network.application.https.enabled" => Ok(Box::new(false)),
-        "network.application.https.listen_address" => Ok(Box::new("".to_owned())),
-        "network.application.https.path" => Ok(Box::new(String::from("app"))),
-        "network.application.https.url" => Ok(Box::new(Option::<String>::None)),
-        "network.application.http.enabled" => Ok(Box::new(false)),
-        "network.application.http.listen_address" => Ok(Box::new("".to_owned())),
-        "network.application.http.path" => Ok(Box::new(String::from("app"))),
-        "network.application.http.url" => Ok(Box::new(Option::<String>::None)),
-        "network.protocol.udp.enabled" => Ok(Box::new(true)),
-        "network.protocol.udp.socket_pool_size" => Ok(Box::new(16u32)),
-        "network.protocol.udp.listen_address" => Ok(Box::new("".to_owned())),
-        "network.protocol.udp.public_address" => Ok(Box::new(Option::<String>::None)),
-        "network.protocol.tcp.connect" => Ok(Box::new(true)),
-        "network.protocol.tcp.listen" => Ok(Box::new(true)),
-        "network.protocol.tcp.max_connections" => Ok(Box::new(32u32)),
-        "network.protocol.tcp.listen_address" => Ok(Box::new("".to_owned())),
-        "network.protocol.tcp.public_address" => Ok(Box::new(Option::<String>::None)),
-        "network.protocol.ws.connect" => Ok(Box::new(false)),
-        "network.protocol.ws.listen" => Ok(Box::new(false)),
-        "network.protocol.ws.max_connections" => Ok(Box::new(16u32)),
-        "network.protocol.ws.listen_address" => Ok(Box::new("".to_owned())),
-        "network.protocol.ws.path" => Ok(Box::new(String::from("ws"))),
-        "network.protocol.ws.url" => Ok(Box::new(Option::<String>::None)),
-        "network.protocol.wss.connect" => Ok(Box::new(false)),
-        "network.protocol.wss.listen" => Ok(Box::new(false)),
-        "network.protocol.wss.max_connections" => Ok(Box::new(16u32)),
-        "network.protocol.wss.listen_address" => Ok(Box::new("".to_owned())),
-        "network.protocol.wss.path" => Ok(Box::new(String::from("ws"))),
-        "network.protocol.wss.url" => Ok(Box::new(Option::<String>::None)),
-        "network.client_allowlist_timeout_ms" => Ok(Box::new(300_000u32)),
-        "network.dht.public_watch_limit" => Ok(Box::new(32u32)),
-        "network.dht.member_watch_limit" => Ok(Box::new(8u32)),
-        "network.dht.max_watch_expiration_ms" => Ok(Box::new(600_000u32)),
-        _ => {
+                .map(|identity_path| identity_path.with_extension("storage"))
+        })
+    }
+}
+
+impl Default for VeilidDuplexConfig {
+    fn default() -> Self {
+        #[cfg(not(target_arch = "wasm32"))]
+        let bootstrap = vec!["bootstrap.veilid.net".to_string()];
+        #[cfg(target_arch = "wasm32")]
+        let bootstrap = vec!["ws://bootstrap.veilid.net:5150/ws".to_string()];
+
+        Self {
+            bootstrap,
+            network_key_password: None,
+            storage_dir: None,
+            crypto_kind: CRYPTO_KIND_VLD0,
+            sequencing: Sequencing::PreferOrdered,
+            stability: Stability::Reliable,
+            route_hop_count: 1,
+            route_count: 1,
+            identity_path: None,
+            service_keys: None,
+            service_keys_path: None,
+            startup_waits: StartupWaits::default(),
+            startup_retry: RetryPolicy::default(),
+            addressing: Addressing::default(),
+            veilid_options: VeilidConfigOptions::default(),
+        }
+    }
+}
+
+/// Transports the node connects over. Browsers only open outbound
+/// websockets, so on wasm UDP and TCP can't be on; that is also the default
+/// there, while native nodes default to UDP and TCP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NetworkProtocols {
+    pub udp: bool,
+    /// Outbound and inbound TCP
+    pub tcp: bool,
+    /// Websockets, inbound too on native
+    pub ws: bool,
+    /// Secure websockets, outbound only
+    pub wss: bool,
+}
+
+impl NetworkProtocols {
+    /// Whether a node can run with these protocols, on wasm if `wasm`
+    pub(crate) fn check(&self, wasm: bool) -> Result<(), VeilidDuplexError> {
+        if wasm && (self.udp || self.tcp) {
+            return Err(VeilidDuplexError::InvalidConfig(
+                "UDP and TCP aren't available on wasm".to_string(),
+            ));
+        }
+        if !(self.udp || self.tcp || self.ws || self.wss) {
+            return Err(VeilidDuplexError::InvalidConfig(
+                "no network protocol is enabled".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for NetworkProtocols {
+    fn default() -> Self {
+        let native = cfg!(not(target_arch = "wasm32"));
+        Self {
+            udp: native,
+            tcp: native,
+            ws: !native,
+            wss: !native,
+        }
+    }
+}
+
+/// Tunables of the veilid node that `VeilidDuplexConfig` doesn't cover. Both
+/// the native config callback and the wasm JSON config are generated from them,
+/// see `VeilidDuplexConfig::veilid_value`. Defaults differ between platforms
+/// only where a browser can't do the same: the protocols, UPnP and NAT
+/// traversal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VeilidConfigOptions {
+    pub program_name: String,
+    pub namespace: String,
+    pub capabilities_disable: Vec<FourCC>,
+    pub connection_initial_timeout_ms: u32,
+    pub connection_inactivity_timeout_ms: u32,
+    pub max_connections_per_ip4: u32,
+    pub max_connections_per_ip6_prefix: u32,
+    pub max_connections_per_ip6_prefix_size: u32,
+    pub max_connection_frequency_per_min: u32,
+    pub client_allowlist_timeout_ms: u32,
+    pub reverse_connection_receipt_time_ms: u32,
+    pub hole_punch_receipt_time_ms: u32,
+    pub limit_over_attached: u32,
+    pub limit_fully_attached: u32,
+    pub limit_attached_strong: u32,
+    pub limit_attached_good: u32,
+    pub limit_attached_weak: u32,
+    pub rpc_concurrency: u32,
+    pub rpc_queue_size: u32,
+    pub rpc_max_timestamp_behind_ms: Option<u32>,
+    pub rpc_max_timestamp_ahead_ms: Option<u32>,
+    pub rpc_timeout_ms: u32,
+    pub dht_max_find_node_count: u32,
+    pub dht_resolve_node_timeout_ms: u32,
+    pub dht_resolve_node_count: u32,
+    pub dht_resolve_node_fanout: u32,
+    pub dht_get_value_timeout_ms: u32,
+    pub dht_get_value_count: u32,
+    pub dht_get_value_fanout: u32,
+    pub dht_set_value_timeout_ms: u32,
+    pub dht_set_value_count: u32,
+    pub dht_set_value_fanout: u32,
+    pub dht_min_peer_count: u32,
+    pub dht_min_peer_refresh_time_ms: u32,
+    pub dht_validate_dial_info_receipt_time_ms: u32,
+    pub dht_local_subkey_cache_size: u32,
+    pub dht_local_max_subkey_cache_memory_mb: u32,
+    pub dht_remote_subkey_cache_size: u32,
+    pub dht_remote_max_records: u32,
+    pub dht_remote_max_subkey_cache_memory_mb: u32,
+    pub dht_remote_max_storage_space_mb: u32,
+    pub dht_public_watch_limit: u32,
+    pub dht_member_watch_limit: u32,
+    pub dht_max_watch_expiration_ms: u32,
+    pub upnp: bool,
+    pub detect_address_changes: bool,
+    pub restricted_nat_retries: u32,
+    pub protocols: NetworkProtocols,
+    pub udp_socket_pool_size: u32,
+    pub tcp_max_connections: u32,
+    pub ws_max_connections: u32,
+    pub wss_max_connections: u32,
+}
+
+impl Default for VeilidConfigOptions {
+    fn default() -> Self {
+        let native = cfg!(not(target_arch = "wasm32"));
+
+        Self {
+            program_name: String::from("towel"),
+            namespace: String::new(),
+            capabilities_disable: Vec::new(),
+            connection_initial_timeout_ms: 2_000,
+            connection_inactivity_timeout_ms: 60_000,
+            max_connections_per_ip4: 8,
+            max_connections_per_ip6_prefix: 8,
+            max_connections_per_ip6_prefix_size: 56,
+            max_connection_frequency_per_min: 8,
+            client_allowlist_timeout_ms: 300_000,
+            reverse_connection_receipt_time_ms: 5_000,
+            hole_punch_receipt_time_ms: 5_000,
+            limit_over_attached: 64,
+            limit_fully_attached: 32,
+            limit_attached_strong: 16,
+            limit_attached_good: 8,
+            limit_attached_weak: 4,
+            rpc_concurrency: 2,
+            rpc_queue_size: 1024,
+            rpc_max_timestamp_behind_ms: Some(10_000),
+            rpc_max_timestamp_ahead_ms: Some(10_000),
+            rpc_timeout_ms: 5_000,
+            dht_max_find_node_count: 20,
+            dht_resolve_node_timeout_ms: 10_000,
+            dht_resolve_node_count: 1,
+            dht_resolve_node_fanout: 4,
+            dht_get_value_timeout_ms: 10_000,
+            dht_get_value_count: 3,
+            dht_get_value_fanout: 4,
+            dht_set_value_timeout_ms: 10_000,
+            dht_set_value_count: 5,
+            dht_set_value_fanout: 4,
+            dht_min_peer_count: 20,
+            dht_min_peer_refresh_time_ms: 60_000,
+            dht_validate_dial_info_receipt_time_ms: 5_000,
+            dht_local_subkey_cache_size: 128,
+            dht_local_max_subkey_cache_memory_mb: 256,
+            dht_remote_subkey_cache_size: 1024,
+            dht_remote_max_records: 4096,
+            dht_remote_max_subkey_cache_memory_mb: 64,
+            dht_remote_max_storage_space_mb: 64,
+            dht_public_watch_limit: 32,
+            dht_member_watch_limit: 8,
+            dht_max_watch_expiration_ms: 600_000,
+            upnp: native,
+            detect_address_changes: true,
+            // A browser can't hole punch, so retrying through a NAT is futile
+            restricted_nat_retries: if native { 3 } else { 0 },
+            protocols: NetworkProtocols::default(),
+            udp_socket_pool_size: 16,
+            tcp_max_connections: 32,
+            ws_max_connections: 16,
+            wss_max_connections: 16,
+        }
+    }
+}
+
+/// Value of a veilid config key, in the type veilid reads it as
+#[derive(Debug, Clone)]
+pub enum ConfigValue {
+    Bool(bool),
+    U8(u8),
+    U32(u32),
+    OptionalU32(Option<u32>),
+    Text(String),
+    OptionalText(Option<String>),
+    TextList(Vec<String>),
+    Capabilities(Vec<FourCC>),
+    NodeIds(TypedKeyGroup),
+    NodeSecrets(TypedSecretGroup),
+}
+
+impl ConfigValue {
+    /// As returned from a veilid config callback
+    pub fn into_any(self) -> Box<dyn Any + Send> {
+        match self {
+            ConfigValue::Bool(value) => Box::new(value),
+            ConfigValue::U8(value) => Box::new(value),
+            ConfigValue::U32(value) => Box::new(value),
+            ConfigValue::OptionalU32(value) => Box::new(value),
+            ConfigValue::Text(value) => Box::new(value),
+            ConfigValue::OptionalText(value) => Box::new(value),
+            ConfigValue::TextList(value) => Box::new(value),
+            ConfigValue::Capabilities(value) => Box::new(value),
+            ConfigValue::NodeIds(value) => Box::new(value),
+            ConfigValue::NodeSecrets(value) => Box::new(value),
+        }
+    }
+
+    /// As written in a veilid JSON config
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            ConfigValue::Bool(value) => (*value).into(),
+            ConfigValue::U8(value) => (*value).into(),
+            ConfigValue::U32(value) => (*value).into(),
+            ConfigValue::OptionalU32(value) => (*value).into(),
+            ConfigValue::Text(value) => value.clone().into(),
+            ConfigValue::OptionalText(value) => value.clone().into(),
+            ConfigValue::TextList(value) => value.clone().into(),
+            ConfigValue::Capabilities(value) => serde_json::to_value(value).unwrap_or_default(),
+            ConfigValue::NodeIds(value) => serde_json::to_value(value).unwrap_or_default(),
+            ConfigValue::NodeSecrets(value) => serde_json::to_value(value).unwrap_or_default(),
+        }
+    }
+}
+
+/// Every key `VeilidDuplexConfig::veilid_value` answers
+pub const VEILID_CONFIG_KEYS: &[&str] = &[
+    "program_name",
+    "namespace",
+    "capabilities.disable",
+    "table_store.directory",
+    "table_store.delete",
+    "block_store.directory",
+    "block_store.delete",
+    "protected_store.allow_insecure_fallback",
+    "protected_store.always_use_insecure_storage",
+    "protected_store.directory",
+    "protected_store.delete",
+    "protected_store.device_encryption_key_password",
+    "protected_store.new_device_encryption_key_password",
+    "network.connection_initial_timeout_ms",
+    "network.connection_inactivity_timeout_ms",
+    "network.max_connections_per_ip4",
+    "network.max_connections_per_ip6_prefix",
+    "network.max_connections_per_ip6_prefix_size",
+    "network.max_connection_frequency_per_min",
+    "network.client_whitelist_timeout_ms",
+    "network.reverse_connection_receipt_time_ms",
+    "network.hole_punch_receipt_time_ms",
+    "network.network_key_password",
+    "network.routing_table.node_id",
+    "network.routing_table.node_id_secret",
+    "network.routing_table.bootstrap",
+    "network.routing_table.limit_over_attached",
+    "network.routing_table.limit_fully_attached",
+    "network.routing_table.limit_attached_strong",
+    "network.routing_table.limit_attached_good",
+    "network.routing_table.limit_attached_weak",
+    "network.rpc.concurrency",
+    "network.rpc.queue_size",
+    "network.rpc.max_timestamp_behind_ms",
+    "network.rpc.max_timestamp_ahead_ms",
+    "network.rpc.timeout_ms",
+    "network.rpc.max_route_hop_count",
+    "network.rpc.default_route_hop_count",
+    "network.dht.max_find_node_count",
+    "network.dht.resolve_node_timeout_ms",
+    "network.dht.resolve_node_count",
+    "network.dht.resolve_node_fanout",
+    "network.dht.get_value_timeout_ms",
+    "network.dht.get_value_count",
+    "network.dht.get_value_fanout",
+    "network.dht.set_value_timeout_ms",
+    "network.dht.set_value_count",
+    "network.dht.set_value_fanout",
+    "network.dht.min_peer_count",
+    "network.dht.min_peer_refresh_time_ms",
+    "network.dht.validate_dial_info_receipt_time_ms",
+    "network.dht.local_subkey_cache_size",
+    "network.dht.local_max_subkey_cache_memory_mb",
+    "network.dht.remote_subkey_cache_size",
+    "network.dht.remote_max_records",
+    "network.dht.remote_max_subkey_cache_memory_mb",
+    "network.dht.remote_max_storage_space_mb",
+    "network.upnp",
+    "network.detect_address_changes",
+    "network.restricted_nat_retries",
+    "network.tls.certificate_path",
+    "network.tls.private_key_path",
+    "network.tls.connection_initial_timeout_ms",
+    "network.application.https.enabled",
+    "network.application.https.listen_address",
+    "network.application.https.path",
+    "network.application.https.url",
+    "network.application.http.enabled",
+    "network.application.http.listen_address",
+    "network.application.http.path",
+    "network.application.http.url",
+    "network.protocol.udp.enabled",
+    "network.protocol.udp.socket_pool_size",
+    "network.protocol.udp.listen_address",
+    "network.protocol.udp.public_address",
+    "network.protocol.tcp.connect",
+    "network.protocol.tcp.listen",
+    "network.protocol.tcp.max_connections",
+    "network.protocol.tcp.listen_address",
+    "network.protocol.tcp.public_address",
+    "network.protocol.ws.connect",
+    "network.protocol.ws.listen",
+    "network.protocol.ws.max_connections",
+    "network.protocol.ws.listen_address",
+    "network.protocol.ws.path",
+    "network.protocol.ws.url",
+    "network.protocol.wss.connect",
+    "network.protocol.wss.listen",
+    "network.protocol.wss.max_connections",
+    "network.protocol.wss.listen_address",
+    "network.protocol.wss.path",
+    "network.protocol.wss.url",
+    "network.client_allowlist_timeout_ms",
+    "network.dht.public_watch_limit",
+    "network.dht.member_watch_limit",
+    "network.dht.max_watch_expiration_ms",
+];
+
+impl VeilidDuplexConfig {
+    /// Value of a veilid config key, `None` for keys veilid doesn't have.
+    /// Store paths are under `storage_dir`, and empty without one as on wasm.
+    /// Without `key_pair` veilid picks the node id.
+    pub fn veilid_value(
+        &self,
+        storage_dir: Option<&Path>,
+        key_pair: Option<CryptoTyped<KeyPair>>,
+        key: &str,
+    ) -> Option<ConfigValue> {
+        use ConfigValue::*;
+
+        let options = &self.veilid_options;
+        let path = |name: &str| {
+            Text(
+                storage_dir
+                    .map(|dir| dir.join(name).to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+            )
+        };
+
+        let value = match key {
+            "program_name" => Text(options.program_name.clone()),
+            "namespace" => Text(options.namespace.clone()),
+            "capabilities.disable" => Capabilities(options.capabilities_disable.clone()),
+            "table_store.directory" => path("table"),
+            "table_store.delete" => Bool(false),
+            "block_store.directory" => path("block"),
+            "block_store.delete" => Bool(false),
+            "protected_store.allow_insecure_fallback" => Bool(true),
+            "protected_store.always_use_insecure_storage" => Bool(true),
+            "protected_store.directory" => path("protected"),
+            "protected_store.delete" => Bool(false),
+            "protected_store.device_encryption_key_password" => Text("".to_owned()),
+            "protected_store.new_device_encryption_key_password" => OptionalText(None),
+            "network.connection_initial_timeout_ms" => U32(options.connection_initial_timeout_ms),
+            "network.connection_inactivity_timeout_ms" => {
+                U32(options.connection_inactivity_timeout_ms)
+            }
+            "network.max_connections_per_ip4" => U32(options.max_connections_per_ip4),
+            "network.max_connections_per_ip6_prefix" => U32(options.max_connections_per_ip6_prefix),
+            "network.max_connections_per_ip6_prefix_size" => {
+                U32(options.max_connections_per_ip6_prefix_size)
+            }
+            "network.max_connection_frequency_per_min" => {
+                U32(options.max_connection_frequency_per_min)
+            }
+            // Older veilid reads the whitelist name
+            "network.client_whitelist_timeout_ms" | "network.client_allowlist_timeout_ms" => {
+                U32(options.client_allowlist_timeout_ms)
+            }
+            "network.reverse_connection_receipt_time_ms" => {
+                U32(options.reverse_connection_receipt_time_ms)
+            }
+            "network.hole_punch_receipt_time_ms" => U32(options.hole_punch_receipt_time_ms),
+            "network.network_key_password" => OptionalText(self.network_key_password.clone()),
+            "network.routing_table.node_id" => {
+                let mut group = TypedKeyGroup::new();
+                if let Some(key_pair) = key_pair {
+                    group.add(CryptoTyped::new(key_pair.kind, key_pair.value.key));
+                }
+                NodeIds(group)
+            }
+            "network.routing_table.node_id_secret" => {
+                let mut group = TypedSecretGroup::new();
+                if let Some(key_pair) = key_pair {
+                    group.add(CryptoTyped::new(key_pair.kind, key_pair.value.secret));
+                }
+                NodeSecrets(group)
+            }
+            "network.routing_table.bootstrap" => TextList(self.bootstrap.clone()),
+            "network.routing_table.limit_over_attached" => U32(options.limit_over_attached),
+            "network.routing_table.limit_fully_attached" => U32(options.limit_fully_attached),
+            "network.routing_table.limit_attached_strong" => U32(options.limit_attached_strong),
+            "network.routing_table.limit_attached_good" => U32(options.limit_attached_good),
+            "network.routing_table.limit_attached_weak" => U32(options.limit_attached_weak),
+            "network.rpc.concurrency" => U32(options.rpc_concurrency),
+            "network.rpc.queue_size" => U32(options.rpc_queue_size),
+            "network.rpc.max_timestamp_behind_ms" => {
+                OptionalU32(options.rpc_max_timestamp_behind_ms)
+            }
+            "network.rpc.max_timestamp_ahead_ms" => OptionalU32(options.rpc_max_timestamp_ahead_ms),
+            "network.rpc.timeout_ms" => U32(options.rpc_timeout_ms),
+            "network.rpc.max_route_hop_count" => U8(MAX_ROUTE_HOP_COUNT),
+            "network.rpc.default_route_hop_count" => U8(self.route_hop_count),
+            "network.dht.max_find_node_count" => U32(options.dht_max_find_node_count),
+            "network.dht.resolve_node_timeout_ms" => U32(options.dht_resolve_node_timeout_ms),
+            "network.dht.resolve_node_count" => U32(options.dht_resolve_node_count),
+            "network.dht.resolve_node_fanout" => U32(options.dht_resolve_node_fanout),
+            "network.dht.get_value_timeout_ms" => U32(options.dht_get_value_timeout_ms),
+            "network.dht.get_value_count" => U32(options.dht_get_value_count),
+            "network.dht.get_value_fanout" => U32(options.dht_get_value_fanout),
+            "network.dht.set_value_timeout_ms" => U32(options.dht_set_value_timeout_ms),
+            "network.dht.set_value_count" => U32(options.dht_set_value_count),
+            "network.dht.set_value_fanout" => U32(options.dht_set_value_fanout),
+            "network.dht.min_peer_count" => U32(options.dht_min_peer_count),
+            "network.dht.min_peer_refresh_time_ms" => U32(options.dht_min_peer_refresh_time_ms),
+            "network.dht.validate_dial_info_receipt_time_ms" => {
+                U32(options.dht_validate_dial_info_receipt_time_ms)
+            }
+            "network.dht.local_subkey_cache_size" => U32(options.dht_local_subkey_cache_size),
+            "network.dht.local_max_subkey_cache_memory_mb" => {
+                U32(options.dht_local_max_subkey_cache_memory_mb)
+            }
+            "network.dht.remote_subkey_cache_size" => U32(options.dht_remote_subkey_cache_size),
+            "network.dht.remote_max_records" => U32(options.dht_remote_max_records),
+            "network.dht.remote_max_subkey_cache_memory_mb" => {
+                U32(options.dht_remote_max_subkey_cache_memory_mb)
+            }
+            "network.dht.remote_max_storage_space_mb" => {
+                U32(options.dht_remote_max_storage_space_mb)
+            }
+            "network.dht.public_watch_limit" => U32(options.dht_public_watch_limit),
+            "network.dht.member_watch_limit" => U32(options.dht_member_watch_limit),
+            "network.dht.max_watch_expiration_ms" => U32(options.dht_max_watch_expiration_ms),
+            "network.upnp" => Bool(options.upnp),
+            "network.detect_address_changes" => Bool(options.detect_address_changes),
+            "network.restricted_nat_retries" => U32(options.restricted_nat_retries),
+            "network.tls.certificate_path" => path("cert"),
+            "network.tls.private_key_path" => path("key"),
+            "network.tls.connection_initial_timeout_ms" => {
+                U32(options.connection_initial_timeout_ms)
+            }
+            "network.application.https.enabled" | "network.application.http.enabled" => Bool(false),
+            "network.application.https.listen_address"
+            | "network.application.http.listen_address" => Text("".to_owned()),
+            "network.application.https.path" | "network.application.http.path" => {
+                Text("app".to_owned())
+            }
+            "network.application.https.url" | "network.application.http.url" => OptionalText(None),
+            "network.protocol.udp.enabled" => Bool(options.protocols.udp),
+            "network.protocol.udp.socket_pool_size" => U32(options.udp_socket_pool_size),
+            "network.protocol.tcp.connect" | "network.protocol.tcp.listen" => {
+                Bool(options.protocols.tcp)
+            }
+            "network.protocol.tcp.max_connections" => U32(options.tcp_max_connections),
+            "network.protocol.ws.connect" => Bool(options.protocols.ws),
+            "network.protocol.ws.listen" => {
+                Bool(options.protocols.ws && cfg!(not(target_arch = "wasm32")))
+            }
+            "network.protocol.ws.max_connections" => U32(options.ws_max_connections),
+            "network.protocol.wss.connect" => Bool(options.protocols.wss),
+            "network.protocol.wss.listen" => Bool(false),
+            "network.protocol.wss.max_connections" => U32(options.wss_max_connections),
+            "network.protocol.udp.listen_address"
+            | "network.protocol.tcp.listen_address"
+            | "network.protocol.ws.listen_address"
+            | "network.protocol.wss.listen_address" => Text("".to_owned()),
+            "network.protocol.udp.public_address"
+            | "network.protocol.tcp.public_address"
+            | "network.protocol.ws.url"
+            | "network.protocol.wss.url" => OptionalText(None),
+            "network.protocol.ws.path" | "network.protocol.wss.path" => Text("ws".to_owned()),
+            _ => return None,
+        };
+
+        Some(value)
+    }
+
+    /// The whole veilid config as JSON, for `api_startup_json`. Holds the same
+    /// values as `veilid_value` gives the config callback.
+    pub fn veilid_json(
+        &self,
+        storage_dir: Option<&Path>,
+        key_pair: Option<CryptoTyped<KeyPair>>,
+    ) -> serde_json::Value {
+        let mut json = serde_json::Value::Null;
+        for key in VEILID_CONFIG_KEYS {
+            let mut slot = &mut json;
+            for part in key.split('.') {
+                slot = &mut slot[part];
+            }
+            if let Some(value) = self.veilid_value(storage_dir, key_pair, key) {
+                *slot = value.to_json();
+            }
+        }
+
+        json
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn config_callback(
+    veilid_storage_dir: PathBuf,
+    key_pair: CryptoTyped<KeyPair>,
+    config: &VeilidDuplexConfig,
+    key: String,
+) -> ConfigCallbackReturn {
+    match config.veilid_value(Some(&veilid_storage_dir), Some(key_pair), &key) {
+        Some(value) => Ok(value.into_any()),
+        None => {
             let err = format!("config key '{}' doesn't exist", key);
             // debug!("{}", err);
             eprintln!("{}", err);
@@ -168,3 +740,222 @@ pub fn config_callback(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crypto_kind_is_validated() {
+        let mut config = VeilidDuplexConfig {
+            crypto_kind: CRYPTO_KIND_VLD0,
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+
+        config.crypto_kind = FourCC(*b"NOPE");
+        assert!(matches!(
+            config.validate(),
+            Err(VeilidDuplexError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_route_hop_count_is_validated() {
+        let mut config = VeilidDuplexConfig::default();
+        assert!(config.validate().is_ok());
+
+        for route_hop_count in [0, MAX_ROUTE_HOP_COUNT + 1] {
+            config.route_hop_count = route_hop_count;
+            assert!(matches!(
+                config.validate(),
+                Err(VeilidDuplexError::InvalidConfig(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_node_id_addressing_takes_one_route() {
+        let mut config = VeilidDuplexConfig {
+            addressing: Addressing::NodeId,
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+
+        config.route_count = 2;
+        assert!(matches!(
+            config.validate(),
+            Err(VeilidDuplexError::InvalidConfig(_))
+        ));
+        config.addressing = Addressing::PrivateRoute;
+        assert!(config.validate().is_ok());
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn test_route_hop_count_reaches_veilid_config() {
+        let config = VeilidDuplexConfig {
+            route_hop_count: 3,
+            ..Default::default()
+        };
+        let key_pair = veilid_core::Crypto::generate_keypair(CRYPTO_KIND_VLD0).unwrap();
+
+        let value = config_callback(
+            PathBuf::from("/tmp"),
+            key_pair,
+            &config,
+            "network.rpc.default_route_hop_count".to_string(),
+        )
+        .unwrap();
+        assert_eq!(*value.downcast::<u8>().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_protocol_defaults_per_target() {
+        let options = VeilidConfigOptions::default();
+        assert!(options
+            .protocols
+            .check(cfg!(target_arch = "wasm32"))
+            .is_ok());
+
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            assert!(options.protocols.udp && options.protocols.tcp);
+            assert!(!options.protocols.ws && !options.protocols.wss);
+            assert_eq!(options.restricted_nat_retries, 3);
+            assert!(options.upnp);
+        }
+        #[cfg(target_arch = "wasm32")]
+        {
+            assert!(!options.protocols.udp && !options.protocols.tcp);
+            assert!(options.protocols.ws && options.protocols.wss);
+            assert_eq!(options.restricted_nat_retries, 0);
+            assert!(!options.upnp);
+        }
+        assert_eq!(options.dht_remote_max_records, 4096);
+    }
+
+    #[test]
+    fn test_wasm_rejects_udp_and_tcp() {
+        let websockets = NetworkProtocols {
+            udp: false,
+            tcp: false,
+            ws: true,
+            wss: true,
+        };
+        assert!(websockets.check(true).is_ok());
+        assert!(websockets.check(false).is_ok());
+
+        for protocols in [
+            NetworkProtocols {
+                udp: true,
+                ..websockets
+            },
+            NetworkProtocols {
+                tcp: true,
+                ..websockets
+            },
+        ] {
+            assert!(matches!(
+                protocols.check(true),
+                Err(VeilidDuplexError::InvalidConfig(_))
+            ));
+            assert!(protocols.check(false).is_ok());
+        }
+
+        let none = NetworkProtocols {
+            ws: false,
+            wss: false,
+            ..websockets
+        };
+        assert!(none.check(false).is_err());
+    }
+
+    #[test]
+    fn test_network_key_reaches_both_configs() {
+        let mut config = VeilidDuplexConfig::default();
+        let json = config.veilid_json(None, None);
+        assert!(json["network"]["network_key_password"].is_null());
+
+        config.network_key_password = Some("secret".to_string());
+        assert!(config.validate().is_ok());
+        let json = config.veilid_json(None, None);
+        assert_eq!(json["network"]["network_key_password"], "secret");
+
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let key_pair = veilid_core::Crypto::generate_keypair(CRYPTO_KIND_VLD0).unwrap();
+            let value = config_callback(
+                PathBuf::from("/tmp"),
+                key_pair,
+                &config,
+                "network.network_key_password".to_string(),
+            )
+            .unwrap();
+            assert_eq!(
+                *value.downcast::<Option<String>>().unwrap(),
+                Some("secret".to_string())
+            );
+        }
+
+        config.network_key_password = Some(String::new());
+        assert!(matches!(
+            config.validate(),
+            Err(VeilidDuplexError::InvalidConfig(_))
+        ));
+    }
+
+    fn tuned_config() -> VeilidDuplexConfig {
+        VeilidDuplexConfig {
+            route_hop_count: 2,
+            veilid_options: VeilidConfigOptions {
+                protocols: NetworkProtocols {
+                    udp: false,
+                    ..Default::default()
+                },
+                rpc_timeout_ms: 7_000,
+                rpc_max_timestamp_ahead_ms: None,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_veilid_json_matches_values() {
+        let config = tuned_config();
+        let key_pair = veilid_core::Crypto::generate_keypair(CRYPTO_KIND_VLD0).unwrap();
+        let dir = PathBuf::from("/tmp/veilid");
+
+        let json = config.veilid_json(Some(&dir), Some(key_pair));
+        for key in VEILID_CONFIG_KEYS {
+            let value = config
+                .veilid_value(Some(&dir), Some(key_pair), key)
+                .unwrap();
+            let pointer = format!("/{}", key.replace('.', "/"));
+            assert_eq!(json.pointer(&pointer), Some(&value.to_json()), "{}", key);
+        }
+        assert_eq!(json["network"]["protocol"]["udp"]["enabled"], false);
+        assert_eq!(json["network"]["rpc"]["timeout_ms"], 7_000);
+        assert!(json["network"]["rpc"]["max_timestamp_ahead_ms"].is_null());
+        assert_eq!(json["table_store"]["directory"], "/tmp/veilid/table");
+        assert!(config.veilid_value(None, None, "network.nope").is_none());
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn test_config_callback_reads_options() {
+        let config = tuned_config();
+        let key_pair = veilid_core::Crypto::generate_keypair(CRYPTO_KIND_VLD0).unwrap();
+        let read =
+            |key: &str| config_callback(PathBuf::from("/tmp"), key_pair, &config, key.to_string());
+
+        let udp = read("network.protocol.udp.enabled").unwrap();
+        assert!(!*udp.downcast::<bool>().unwrap());
+        let timeout = read("network.rpc.timeout_ms").unwrap();
+        assert_eq!(*timeout.downcast::<u32>().unwrap(), 7_000);
+        let ahead = read("network.rpc.max_timestamp_ahead_ms").unwrap();
+        assert_eq!(*ahead.downcast::<Option<u32>>().unwrap(), None);
+        assert!(read("network.nope").is_err());
+    }
+}
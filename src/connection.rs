@@ -0,0 +1,224 @@
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use flume::r#async::{RecvStream, SendSink};
+use flume::{bounded, Receiver, Sender};
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tracing::info;
+use veilid_core::tools::*;
+use veilid_core::*;
+
+use crate::error::VeilidDuplexError;
+use crate::socket::WriterState;
+use crate::veilid::{
+    AppLogic, AppMessage, DuplexSender, HandlerError, NetworkLoopHandle, VeilidDuplex,
+};
+
+// Messages buffered each way before sends or the peer's sends wait
+const CONNECTION_BUFFER: usize = 16;
+
+struct PeerInbox<T> {
+    remote: CryptoTyped<CryptoKey>,
+    messages: Sender<AppMessage<T>>,
+}
+
+impl<T> Clone for PeerInbox<T> {
+    fn clone(&self) -> Self {
+        Self {
+            remote: self.remote,
+            messages: self.messages.clone(),
+        }
+    }
+}
+
+impl<T> AppLogic<T> for PeerInbox<T>
+where
+    T: DeserializeOwned + Send,
+{
+    async fn on_message(&mut self, message: AppMessage<T>) -> Result<(), HandlerError> {
+        if message.dht_record != self.remote {
+            return Err(HandlerError::new("Not connected to this peer"));
+        }
+
+        self.messages
+            .send_async(message)
+            .await
+            .map_err(|_| HandlerError::new("Connection closed"))
+    }
+}
+
+async fn send_messages<T>(
+    sender: DuplexSender,
+    remote: CryptoTyped<CryptoKey>,
+    outbound: Receiver<AppMessage<T>>,
+    state: Arc<WriterState>,
+) where
+    T: Serialize + DeserializeOwned + Send + 'static,
+{
+    while let Ok(mut app_message) = outbound.recv_async().await {
+        app_message.dht_record = sender.our_dht_key;
+        if let Err(e) = sender.send_message(app_message, remote).await {
+            info!("Connection to {} broke: {}", remote, e);
+            state.failed(&e);
+            return;
+        }
+        state.sent();
+    }
+}
+
+/// Typed message channel to one peer over a `VeilidDuplex`. As a `Stream` it
+/// yields the peer's messages, already decoded and deduplicated; as a `Sink`
+/// it sends messages in order, each retried like `DuplexSender::send_message`.
+/// Messages are sent as coming from us whatever their `dht_record`, so ones
+/// read from the stream can be forwarded back as they are.
+pub struct PeerConnection<T: DeserializeOwned> {
+    remote: CryptoTyped<CryptoKey>,
+    inbound: RecvStream<'static, AppMessage<T>>,
+    outbound: SendSink<'static, AppMessage<T>>,
+    writer: Arc<WriterState>,
+    network_loop: NetworkLoopHandle,
+}
+
+/// Open a `PeerConnection` to `remote_dht_record`. Like `socket::connect`,
+/// it runs the duplex's network loop until dropped; messages from other peers
+/// are refused meanwhile.
+pub fn connect<T>(
+    duplex: &VeilidDuplex,
+    remote_dht_record: CryptoTyped<CryptoKey>,
+) -> Result<PeerConnection<T>, VeilidDuplexError>
+where
+    T: Serialize + DeserializeOwned + Send + Sync + Clone + 'static,
+{
+    if duplex.loop_running.load(Ordering::SeqCst) {
+        return Err(VeilidDuplexError::LoopAlreadyRunning);
+    }
+
+    let (messages, inbound) = bounded(CONNECTION_BUFFER);
+    let inbox = PeerInbox {
+        remote: remote_dht_record,
+        messages,
+    };
+    let network_loop = duplex.clone().spawn_network_loop::<T, _>(inbox);
+
+    let (outbound, outbound_receiver) = bounded(CONNECTION_BUFFER);
+    let writer = Arc::new(WriterState::default());
+    spawn_detached(send_messages(
+        duplex.sender(),
+        remote_dht_record,
+        outbound_receiver,
+        writer.clone(),
+    ));
+
+    Ok(PeerConnection {
+        remote: remote_dht_record,
+        inbound: inbound.into_stream(),
+        outbound: outbound.into_sink(),
+        writer,
+        network_loop,
+    })
+}
+
+impl<T: DeserializeOwned> PeerConnection<T> {
+    pub fn remote(&self) -> CryptoTyped<CryptoKey> {
+        self.remote
+    }
+}
+
+impl<T: DeserializeOwned> Stream for PeerConnection<T> {
+    type Item = AppMessage<T>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().inbound.poll_next_unpin(cx)
+    }
+}
+
+impl<T: DeserializeOwned> Sink<AppMessage<T>> for PeerConnection<T> {
+    type Error = VeilidDuplexError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        if let Some(e) = this.writer.error() {
+            return Poll::Ready(Err(e.into()));
+        }
+        ready!(this.outbound.poll_ready_unpin(cx)).map_err(|_| this.writer.broken())?;
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, app_message: AppMessage<T>) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        this.writer.queued();
+        this.outbound
+            .start_send_unpin(app_message)
+            .map_err(|_| this.writer.broken().into())
+    }
+
+    /// Ready once the peer acknowledged every message sent so far
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        ready!(this.outbound.poll_flush_unpin(cx)).map_err(|_| this.writer.broken())?;
+        this.writer.poll_flushed(cx).map_err(Into::into)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_flush(cx)
+    }
+}
+
+impl<T: DeserializeOwned> Drop for PeerConnection<T> {
+    fn drop(&mut self) {
+        self.network_loop.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Counter {
+        count: u64,
+    }
+
+    #[tokio::test]
+    async fn test_forward_echoes_messages() -> Result<(), VeilidDuplexError> {
+        let app = VeilidDuplex::new().await?;
+        let peer = VeilidDuplex::new().await?;
+        let mut connection = connect::<Counter>(&app, peer.our_dht_key)?;
+        let echo = connect::<Counter>(&peer, app.our_dht_key)?;
+
+        // Everything the peer reads goes straight back
+        let echoing = tokio::spawn(async move {
+            let (sink, stream) = echo.split();
+            stream.map(Ok).forward(sink).await
+        });
+
+        let messages = (0..5).map(|count| {
+            Ok(AppMessage {
+                data: Counter { count },
+                uuid: String::new(),
+                dht_record: app.our_dht_key,
+                reply_to: None,
+                timestamp: 0,
+                topic: None,
+            })
+        });
+        connection
+            .send_all(&mut futures_util::stream::iter(messages))
+            .await?;
+
+        let echoed: Vec<AppMessage<Counter>> = connection.by_ref().take(5).collect().await;
+        let counts: Vec<u64> = echoed.iter().map(|message| message.data.count).collect();
+        assert_eq!(counts, vec![0, 1, 2, 3, 4]);
+        assert!(echoed
+            .iter()
+            .all(|message| message.dht_record == peer.our_dht_key));
+
+        echoing.abort();
+        Ok(())
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Enough to cover redeliveries at high message rates without growing unbounded
+pub const DEDUP_CAPACITY: usize = 65536;
+/// Redeliveries arrive within seconds of the original, so older hashes can go
+pub const DEDUP_TTL: Duration = Duration::from_secs(60);
+
+/// Called with each hash evicted to make room, see `DedupCache::set_on_evict`
+pub type EvictionCallback = Arc<dyn Fn(u64) + Send + Sync>;
+
+/// Bounded set of recently received message hashes. Hashes expire after `ttl`,
+/// and once full the oldest hash is evicted to make room for a new one.
+/// Timestamps are veilid microsecond timestamps (see `get_timestamp`).
+#[derive(Clone)]
+pub struct DedupCache {
+    capacity: usize,
+    ttl: Option<Duration>,
+    order: VecDeque<(u64, u64)>,
+    seen: HashMap<u64, u64>,
+    on_evict: Option<EvictionCallback>,
+}
+
+impl fmt::Debug for DedupCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DedupCache")
+            .field("capacity", &self.capacity)
+            .field("ttl", &self.ttl)
+            .field("len", &self.order.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for DedupCache {
+    fn default() -> Self {
+        Self::new(DEDUP_CAPACITY, Some(DEDUP_TTL))
+    }
+}
+
+impl DedupCache {
+    pub fn new(capacity: usize, ttl: Option<Duration>) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            ttl,
+            order: VecDeque::new(),
+            seen: HashMap::new(),
+            on_evict: None,
+        }
+    }
+
+    /// Call `on_evict` with each hash pushed out by capacity before it
+    /// expired. Frequent evictions mean the window is too small to catch
+    /// redeliveries. Runs with the cache borrowed, so keep it quick.
+    pub fn set_on_evict(&mut self, on_evict: Option<EvictionCallback>) {
+        self.on_evict = on_evict;
+    }
+
+    /// Record `hash` seen at `now`, returning false if it was already seen and
+    /// hasn't expired. Expired hashes are pruned here.
+    pub fn insert(&mut self, hash: u64, now: u64) -> bool {
+        self.prune(now);
+        if self.seen.contains_key(&hash) {
+            return false;
+        }
+
+        self.seen.insert(hash, now);
+        self.order.push_back((hash, now));
+        self.evict_to(self.capacity);
+        true
+    }
+
+    pub fn contains(&self, hash: u64, now: u64) -> bool {
+        match self.seen.get(&hash) {
+            Some(inserted_at) => !self.is_expired(*inserted_at, now),
+            None => false,
+        }
+    }
+
+    pub fn remove(&mut self, hash: u64) {
+        if self.seen.remove(&hash).is_some() {
+            self.order.retain(|(h, _)| *h != hash);
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity.max(1);
+        self.evict_to(self.capacity);
+    }
+
+    /// `None` keeps hashes until they're evicted by capacity
+    pub fn set_ttl(&mut self, ttl: Option<Duration>) {
+        self.ttl = ttl;
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    /// Hashes from oldest to newest
+    pub fn hashes(&self) -> impl Iterator<Item = u64> + '_ {
+        self.order.iter().map(|(hash, _)| *hash)
+    }
+
+    fn is_expired(&self, inserted_at: u64, now: u64) -> bool {
+        match self.ttl {
+            Some(ttl) => now.saturating_sub(inserted_at) >= ttl.as_micros() as u64,
+            None => false,
+        }
+    }
+
+    // Hashes are queued in insertion order, so expired ones are at the front
+    fn prune(&mut self, now: u64) {
+        while let Some((hash, inserted_at)) = self.order.front().copied() {
+            if !self.is_expired(inserted_at, now) {
+                break;
+            }
+            self.order.pop_front();
+            self.seen.remove(&hash);
+        }
+    }
+
+    fn evict_to(&mut self, capacity: usize) {
+        while self.order.len() > capacity {
+            if let Some((oldest, _)) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+                if let Some(on_evict) = &self.on_evict {
+                    on_evict(oldest);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000;
+
+    #[test]
+    fn test_duplicates_are_detected() {
+        let mut cache = DedupCache::new(4, None);
+
+        assert!(cache.insert(1, 0));
+        assert!(!cache.insert(1, 0));
+        assert!(cache.contains(1, 0));
+    }
+
+    #[test]
+    fn test_oldest_is_evicted_past_capacity() {
+        let mut cache = DedupCache::new(3, None);
+        for hash in 0..4 {
+            assert!(cache.insert(hash, 0));
+        }
+
+        assert_eq!(cache.len(), 3);
+        assert!(!cache.contains(0, 0));
+        assert_eq!(cache.hashes().collect::<Vec<_>>(), vec![1, 2, 3]);
+
+        // An evicted hash counts as new again
+        assert!(cache.insert(0, 0));
+        assert!(!cache.contains(1, 0));
+    }
+
+    #[test]
+    fn test_on_evict_sees_capacity_evictions_only() {
+        let evicted = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut cache = DedupCache::new(2, Some(Duration::from_secs(10)));
+        let seen = evicted.clone();
+        cache.set_on_evict(Some(Arc::new(move |hash| seen.lock().unwrap().push(hash))));
+
+        cache.insert(1, 0);
+        cache.insert(2, 0);
+        cache.insert(3, 0);
+        // Expiry isn't eviction
+        cache.insert(4, 20 * SECOND);
+        cache.set_capacity(1);
+
+        assert_eq!(*evicted.lock().unwrap(), vec![1]);
+        assert_eq!(cache.hashes().collect::<Vec<_>>(), vec![4]);
+    }
+
+    #[test]
+    fn test_remove_forgets_hash() {
+        let mut cache = DedupCache::new(3, None);
+        cache.insert(1, 0);
+        cache.insert(2, 0);
+        cache.remove(1);
+
+        assert!(!cache.contains(1, 0));
+        assert_eq!(cache.hashes().collect::<Vec<_>>(), vec![2]);
+    }
+
+    #[test]
+    fn test_expired_hash_no_longer_suppresses_redelivery() {
+        let mut cache = DedupCache::new(16, Some(Duration::from_secs(60)));
+
+        assert!(cache.insert(1, 0));
+        assert!(!cache.insert(1, 59 * SECOND));
+        assert!(!cache.contains(1, 60 * SECOND));
+        assert!(cache.insert(1, 60 * SECOND));
+    }
+
+    #[test]
+    fn test_expired_hashes_are_pruned_on_insert() {
+        let mut cache = DedupCache::new(16, Some(Duration::from_secs(10)));
+        cache.insert(1, 0);
+        cache.insert(2, 5 * SECOND);
+        cache.insert(3, 12 * SECOND);
+
+        assert_eq!(cache.hashes().collect::<Vec<_>>(), vec![2, 3]);
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::codec::{decode_any, Codec, MessageCodec};
+use crate::error::VeilidDuplexError;
+use crate::veilid::{AppLogic, AppMessage, HandlerError};
+
+/// A message type with a tag naming it on the wire. Both peers must agree on
+/// the tag; it has to be unique within a `MessageRegistry`.
+pub trait MessageKind: Serialize + DeserializeOwned {
+    const KIND: &'static str;
+}
+
+/// `AppMessage` payload carrying any registered `MessageKind`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    pub kind: String,
+    pub body: Vec<u8>,
+}
+
+impl Envelope {
+    pub fn new<M: MessageKind>(message: &M) -> Result<Self, VeilidDuplexError> {
+        Ok(Self {
+            kind: M::KIND.to_string(),
+            body: MessageCodec::Bincode.encode(message)?,
+        })
+    }
+
+    /// Decode the body, if it holds an `M`
+    pub fn open<M: MessageKind>(&self) -> Result<M, VeilidDuplexError> {
+        if self.kind != M::KIND {
+            return Err(VeilidDuplexError::Codec(
+                format!("Expected {}, got {}", M::KIND, self.kind).into(),
+            ));
+        }
+
+        decode_any(&self.body)
+    }
+}
+
+type KindHandler = Arc<dyn Fn(&AppMessage<Envelope>) -> Result<(), HandlerError> + Send + Sync>;
+
+/// `AppLogic` over `Envelope` messages that decodes each one and hands it to
+/// the handler registered for its kind. Messages of an unregistered kind are
+/// rejected. Clones share registrations.
+#[derive(Clone, Default)]
+pub struct MessageRegistry {
+    handlers: Arc<Mutex<HashMap<&'static str, KindHandler>>>,
+}
+
+impl MessageRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handle messages of kind `M`, replacing any handler registered for it
+    pub fn register<M, F>(&self, handler: F)
+    where
+        M: MessageKind,
+        F: Fn(AppMessage<M>) -> Result<(), HandlerError> + Send + Sync + 'static,
+    {
+        let handler: KindHandler = Arc::new(move |message: &AppMessage<Envelope>| {
+            let data = message
+                .data
+                .open::<M>()
+                .map_err(|e| HandlerError::new(format!("Bad {} message: {}", M::KIND, e)))?;
+
+            handler(AppMessage {
+                data,
+                uuid: message.uuid.clone(),
+                dht_record: message.dht_record,
+                reply_to: message.reply_to.clone(),
+                timestamp: message.timestamp,
+                topic: message.topic.clone(),
+            })
+        });
+        self.handlers.lock().unwrap().insert(M::KIND, handler);
+    }
+
+    /// Returns false if nothing was registered for `M`
+    pub fn unregister<M: MessageKind>(&self) -> bool {
+        self.handlers.lock().unwrap().remove(M::KIND).is_some()
+    }
+
+    fn handler_for(&self, kind: &str) -> Option<KindHandler> {
+        self.handlers.lock().unwrap().get(kind).cloned()
+    }
+}
+
+impl AppLogic<Envelope> for MessageRegistry {
+    async fn on_message(&mut self, message: AppMessage<Envelope>) -> Result<(), HandlerError> {
+        let handler = self
+            .handler_for(&message.data.kind)
+            .ok_or_else(|| HandlerError::new(format!("No handler for {}", message.data.kind)))?;
+
+        handler(&message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::CRYPTO_KIND;
+    use veilid_core::*;
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Ping {
+        seq: u32,
+    }
+
+    impl MessageKind for Ping {
+        const KIND: &'static str = "ping";
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Chat {
+        text: String,
+    }
+
+    impl MessageKind for Chat {
+        const KIND: &'static str = "chat";
+    }
+
+    fn message(envelope: Envelope) -> AppMessage<Envelope> {
+        AppMessage {
+            data: envelope,
+            uuid: String::new(),
+            dht_record: CryptoTyped::new(CRYPTO_KIND, CryptoKey::new([1; 32])),
+            reply_to: None,
+            timestamp: 0,
+            topic: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_each_handler_fires_only_for_its_kind() {
+        let mut registry = MessageRegistry::new();
+        let pings = Arc::new(Mutex::new(Vec::new()));
+        let chats = Arc::new(Mutex::new(Vec::new()));
+
+        let sink = pings.clone();
+        registry.register(move |message: AppMessage<Ping>| {
+            sink.lock().unwrap().push(message.data);
+            Ok(())
+        });
+        let sink = chats.clone();
+        registry.register(move |message: AppMessage<Chat>| {
+            sink.lock().unwrap().push(message.data);
+            Ok(())
+        });
+
+        let ping = Ping { seq: 7 };
+        let chat = Chat {
+            text: "hi".to_string(),
+        };
+        registry
+            .on_message(message(Envelope::new(&ping).unwrap()))
+            .await
+            .unwrap();
+        registry
+            .on_message(message(Envelope::new(&chat).unwrap()))
+            .await
+            .unwrap();
+
+        assert_eq!(*pings.lock().unwrap(), vec![ping]);
+        assert_eq!(*chats.lock().unwrap(), vec![chat]);
+    }
+
+    #[tokio::test]
+    async fn test_unregistered_kind_is_rejected() {
+        let mut registry = MessageRegistry::new();
+        registry.register(|_: AppMessage<Ping>| Ok(()));
+
+        let chat = Envelope::new(&Chat {
+            text: "hi".to_string(),
+        })
+        .unwrap();
+        assert!(registry.on_message(message(chat)).await.is_err());
+
+        assert!(registry.unregister::<Ping>());
+        let ping = Envelope::new(&Ping { seq: 1 }).unwrap();
+        assert!(registry.on_message(message(ping)).await.is_err());
+    }
+
+    #[test]
+    fn test_envelope_checks_kind() {
+        let envelope = Envelope::new(&Ping { seq: 3 }).unwrap();
+        assert_eq!(envelope.open::<Ping>().unwrap(), Ping { seq: 3 });
+        assert!(envelope.open::<Chat>().is_err());
+    }
+}
@@ -0,0 +1,169 @@
+use std::io;
+
+use anyhow::{Error, Ok};
+use serde::{Deserialize, Serialize};
+
+use veilid_core::tools::*;
+use veilid_core::*;
+
+// Encrypted blobs start with a magic that can't begin a serialized or
+// compressed AppMessage
+const ENCRYPTION_MAGIC: &[u8; 4] = b"\0VDE";
+
+/// Cleartext part of an encrypted message. The receiver needs the sender to
+/// find the shared secret; the header is authenticated along with the body.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EncryptionHeader {
+    pub sender: CryptoTyped<CryptoKey>,
+    pub nonce: Nonce,
+}
+
+pub fn is_encrypted(blob: &[u8]) -> bool {
+    blob.starts_with(ENCRYPTION_MAGIC)
+}
+
+pub fn crypto_system(api: &VeilidAPI, kind: CryptoKind) -> Result<CryptoSystemVersion, Error> {
+    api.crypto()?.get(kind).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!("Unsupported crypto kind: {}", kind),
+        )
+        .into()
+    })
+}
+
+pub fn encrypt(
+    cs: &CryptoSystemVersion,
+    shared_secret: &SharedSecret,
+    sender: CryptoTyped<CryptoKey>,
+    blob: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let header = EncryptionHeader {
+        sender,
+        nonce: cs.random_nonce(),
+    };
+    let header_bytes = serde_json::to_vec(&header)?;
+    let ciphertext = cs.encrypt_aead(blob, &header.nonce, shared_secret, Some(&header_bytes))?;
+
+    let mut out = Vec::with_capacity(8 + header_bytes.len() + ciphertext.len());
+    out.extend_from_slice(ENCRYPTION_MAGIC);
+    out.extend_from_slice(&(header_bytes.len() as u32).to_be_bytes());
+    out.extend_from_slice(&header_bytes);
+    out.extend_from_slice(&ciphertext);
+    Ok(out)
+}
+
+fn split_encrypted(blob: &[u8]) -> Result<(EncryptionHeader, &[u8], &[u8]), Error> {
+    let malformed = || io::Error::new(io::ErrorKind::InvalidData, "Malformed encrypted message");
+
+    if !is_encrypted(blob) || blob.len() < 8 {
+        return Err(malformed().into());
+    }
+
+    let header_len = u32::from_be_bytes(blob[4..8].try_into()?) as usize;
+    let header_end = 8usize.checked_add(header_len).ok_or_else(malformed)?;
+    if blob.len() < header_end {
+        return Err(malformed().into());
+    }
+
+    let header_bytes = &blob[8..header_end];
+    let header = serde_json::from_slice(header_bytes)?;
+    Ok((header, header_bytes, &blob[header_end..]))
+}
+
+/// Sender named in the header of an encrypted message
+pub fn sender_of(blob: &[u8]) -> Result<CryptoTyped<CryptoKey>, Error> {
+    Ok(split_encrypted(blob)?.0.sender)
+}
+
+/// Decrypt a blob produced by `encrypt`. Fails if the ciphertext or header was
+/// tampered with or the wrong secret is used.
+pub fn decrypt(
+    cs: &CryptoSystemVersion,
+    shared_secret: &SharedSecret,
+    blob: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let (header, header_bytes, ciphertext) = split_encrypted(blob)?;
+    Ok(cs.decrypt_aead(ciphertext, &header.nonce, shared_secret, Some(header_bytes))?)
+}
+
+/// Shared secrets with peers, derived once per peer from our DHT record owner
+/// secret and the owner key published in their DHT record
+#[derive(Default)]
+pub struct PeerKeys {
+    shared_secrets: HashMap<CryptoKey, SharedSecret>,
+    owner_keys: HashMap<CryptoKey, PublicKey>,
+}
+
+impl PeerKeys {
+    /// Owner key of a peer's DHT record, which signs and receives its messages
+    pub async fn owner_key(
+        &mut self,
+        routing_context: &RoutingContext,
+        peer: CryptoTyped<CryptoKey>,
+    ) -> Result<PublicKey, Error> {
+        if let Some(owner_key) = self.owner_keys.get(&peer.value) {
+            return Ok(*owner_key);
+        }
+
+        let dht_desc = routing_context.open_dht_record(peer, None).await?;
+        let owner_key = *dht_desc.owner();
+        routing_context.close_dht_record(*dht_desc.key()).await?;
+        self.owner_keys.insert(peer.value, owner_key);
+
+        Ok(owner_key)
+    }
+
+    pub async fn shared_secret(
+        &mut self,
+        api: &VeilidAPI,
+        routing_context: &RoutingContext,
+        our_secret: &SecretKey,
+        peer: CryptoTyped<CryptoKey>,
+    ) -> Result<SharedSecret, Error> {
+        if let Some(shared_secret) = self.shared_secrets.get(&peer.value) {
+            return Ok(*shared_secret);
+        }
+
+        let their_key = self.owner_key(routing_context, peer).await?;
+        let shared_secret = crypto_system(api, peer.kind)?.cached_dh(&their_key, our_secret)?;
+        self.shared_secrets.insert(peer.value, shared_secret);
+
+        Ok(shared_secret)
+    }
+
+    /// Use `owner_key` for `peer` instead of looking it up on DHT
+    pub fn remember_owner(&mut self, peer: CryptoTyped<CryptoKey>, owner_key: PublicKey) {
+        self.owner_keys.insert(peer.value, owner_key);
+    }
+
+    pub fn forget(&mut self, peer: CryptoTyped<CryptoKey>) {
+        self.shared_secrets.remove(&peer.value);
+        self.owner_keys.remove(&peer.value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::CRYPTO_KIND;
+    use crate::veilid::VeilidDuplex;
+
+    #[tokio::test]
+    async fn test_tampered_ciphertext_fails_to_decrypt() -> Result<(), Error> {
+        let app = VeilidDuplex::new().await?;
+        let cs = crypto_system(&app.api, CRYPTO_KIND)?;
+        let shared_secret = cs.cached_dh(&app.dht_keypair.key, &app.dht_keypair.secret)?;
+
+        let blob = encrypt(&cs, &shared_secret, app.our_dht_key, b"hello")?;
+        assert_eq!(sender_of(&blob)?, app.our_dht_key);
+        assert_eq!(decrypt(&cs, &shared_secret, &blob)?, b"hello");
+
+        let mut tampered = blob.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(decrypt(&cs, &shared_secret, &tampered).is_err());
+
+        app.api.shutdown().await;
+        Ok(())
+    }
+}
@@ -0,0 +1,125 @@
+use std::io;
+use std::time::Duration;
+
+use thiserror::Error;
+
+use veilid_core::{CryptoKey, CryptoTyped, ValueSubkey, VeilidAPIError};
+
+/// Errors returned by the public API. Anything that doesn't fit a category
+/// ends up in `Other`, and the enum converts to `anyhow::Error` with `?`.
+#[derive(Debug, Error)]
+pub enum VeilidDuplexError {
+    #[error("Message of {size} bytes exceeds the limit of {limit} bytes")]
+    MessageTooLarge { size: usize, limit: usize },
+
+    #[error("Couldn't deliver message after {attempts} attempt(s)")]
+    SendExhausted { attempts: u16 },
+
+    #[error("Message rejected by peer: {0}")]
+    Rejected(String),
+
+    #[error("No reply to request {request_id} within {timeout:?}")]
+    RequestTimeout {
+        request_id: String,
+        timeout: Duration,
+    },
+
+    #[error("DHT value not found: {key}, subkey {subkey}")]
+    DhtValueNotFound {
+        key: CryptoTyped<CryptoKey>,
+        subkey: ValueSubkey,
+    },
+
+    #[error("Malformed route blob: {0}")]
+    MalformedRoute(String),
+
+    #[error("Unable to import route: {0}")]
+    RouteImport(#[source] VeilidAPIError),
+
+    #[error("Serialization failed: {0}")]
+    Serialization(#[from] serde_json::Error),
+
+    #[error("Unable to encode or decode message: {0}")]
+    Codec(Box<dyn std::error::Error + Send + Sync>),
+
+    #[error("State version {found} is newer than supported version {supported}")]
+    UnsupportedStateVersion { found: u32, supported: u32 },
+
+    #[error("Timed out after {timeout:?} waiting for {phase}")]
+    StartupTimeout {
+        phase: &'static str,
+        timeout: Duration,
+    },
+
+    #[error("Not attached and ready within {0:?}")]
+    NotReady(Duration),
+
+    #[error("Network loop is already running")]
+    LoopAlreadyRunning,
+
+    #[error("Peer behind the route to {0} couldn't prove it owns the record")]
+    IdentityMismatch(CryptoTyped<CryptoKey>),
+
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+
+    #[error(transparent)]
+    Veilid(#[from] VeilidAPIError),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+// Unwraps errors that went through anyhow, so they keep their variant
+impl From<anyhow::Error> for VeilidDuplexError {
+    fn from(error: anyhow::Error) -> Self {
+        match error.downcast::<VeilidDuplexError>() {
+            Ok(error) => error,
+            Err(error) => VeilidDuplexError::Other(error),
+        }
+    }
+}
+
+impl VeilidDuplexError {
+    /// Whether the same call may succeed if retried later. Size, encoding and
+    /// version errors, and rejections by the peer's handler, won't go away.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            VeilidDuplexError::SendExhausted { .. }
+            | VeilidDuplexError::RequestTimeout { .. }
+            | VeilidDuplexError::DhtValueNotFound { .. }
+            | VeilidDuplexError::RouteImport(_) => true,
+            VeilidDuplexError::Veilid(e) => matches!(
+                e,
+                VeilidAPIError::Timeout
+                    | VeilidAPIError::TryAgain { .. }
+                    | VeilidAPIError::NoConnection { .. }
+                    | VeilidAPIError::NotInitialized
+            ),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_anyhow_round_trip_keeps_variant() {
+        let error: anyhow::Error = VeilidDuplexError::SendExhausted { attempts: 3 }.into();
+        let error: VeilidDuplexError = error.into();
+        assert!(matches!(
+            error,
+            VeilidDuplexError::SendExhausted { attempts: 3 }
+        ));
+        assert!(error.is_transient());
+
+        let error: VeilidDuplexError = anyhow::anyhow!("something else").into();
+        assert!(matches!(error, VeilidDuplexError::Other(_)));
+        assert!(!error.is_transient());
+    }
+}
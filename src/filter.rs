@@ -0,0 +1,91 @@
+use std::collections::HashSet;
+
+use veilid_core::*;
+
+/// Whether senders on neither list get through
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FilterMode {
+    /// Everyone but blocked senders
+    #[default]
+    Open,
+    /// Only allowed senders
+    Closed,
+}
+
+/// Allowlist and blocklist of senders, keyed by their DHT record. A blocked
+/// sender stays blocked even if it is also allowed.
+#[derive(Debug, Clone, Default)]
+pub struct PeerFilter {
+    mode: FilterMode,
+    allowed: HashSet<CryptoTyped<CryptoKey>>,
+    blocked: HashSet<CryptoTyped<CryptoKey>>,
+}
+
+impl PeerFilter {
+    pub fn set_mode(&mut self, mode: FilterMode) {
+        self.mode = mode;
+    }
+
+    pub fn allow(&mut self, peer: CryptoTyped<CryptoKey>) {
+        self.allowed.insert(peer);
+    }
+
+    pub fn block(&mut self, peer: CryptoTyped<CryptoKey>) {
+        self.blocked.insert(peer);
+    }
+
+    /// Take a peer off both lists
+    pub fn forget(&mut self, peer: CryptoTyped<CryptoKey>) {
+        self.allowed.remove(&peer);
+        self.blocked.remove(&peer);
+    }
+
+    pub fn is_allowed(&self, peer: CryptoTyped<CryptoKey>) -> bool {
+        if self.blocked.contains(&peer) {
+            return false;
+        }
+
+        self.allowed.contains(&peer) || self.mode == FilterMode::Open
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::CRYPTO_KIND;
+
+    fn peer(n: u8) -> CryptoTyped<CryptoKey> {
+        CryptoTyped::new(CRYPTO_KIND, CryptoKey::new([n; 32]))
+    }
+
+    #[test]
+    fn test_open_filter_drops_only_blocked() {
+        let mut filter = PeerFilter::default();
+        assert!(filter.is_allowed(peer(1)));
+
+        filter.block(peer(1));
+        assert!(!filter.is_allowed(peer(1)));
+        assert!(filter.is_allowed(peer(2)));
+
+        // Blocking wins over allowing
+        filter.allow(peer(1));
+        assert!(!filter.is_allowed(peer(1)));
+
+        filter.forget(peer(1));
+        assert!(filter.is_allowed(peer(1)));
+    }
+
+    #[test]
+    fn test_closed_filter_lets_only_allowed_through() {
+        let mut filter = PeerFilter::default();
+        filter.set_mode(FilterMode::Closed);
+        assert!(!filter.is_allowed(peer(1)));
+
+        filter.allow(peer(1));
+        assert!(filter.is_allowed(peer(1)));
+        assert!(!filter.is_allowed(peer(2)));
+
+        filter.block(peer(1));
+        assert!(!filter.is_allowed(peer(1)));
+    }
+}
@@ -1,4 +1,27 @@
-mod config;
+pub mod cancel;
+pub mod chunking;
+pub mod clock;
+pub mod codec;
+pub mod compression;
+pub mod config;
+pub mod connection;
+pub mod dedup;
+pub mod dispatch;
+pub mod encryption;
+pub mod error;
+pub mod filter;
+pub mod ordering;
+pub mod outbox;
+pub mod permits;
+pub mod presence;
+pub mod rate_limit;
+pub mod signing;
+pub mod socket;
+pub mod stats;
+pub mod telemetry;
+pub mod topics;
+pub mod transfer;
+pub mod transport;
 pub mod utils;
 pub mod veilid;
 
@@ -0,0 +1,201 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use flume::{bounded, Receiver, Sender};
+
+use veilid_core::*;
+
+/// Which inbound messages are handed to `on_message` one at a time, in the
+/// order they arrived
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MessageOrdering {
+    /// Messages from the same sender; different senders run concurrently
+    #[default]
+    PerSender,
+    /// Every message, whoever sent it
+    Global,
+}
+
+impl MessageOrdering {
+    pub fn key_of(&self, sender: CryptoTyped<CryptoKey>) -> Option<CryptoTyped<CryptoKey>> {
+        match self {
+            MessageOrdering::PerSender => Some(sender),
+            MessageOrdering::Global => None,
+        }
+    }
+}
+
+type Waiting<K> = HashMap<K, VecDeque<(u64, Sender<()>)>>;
+
+/// FIFO queues of handlers, one per key. A handler takes a `Turn` when its
+/// message arrives and waits on it before running; dropping the turn lets
+/// the next handler for the key go.
+pub struct SerialQueues<K> {
+    waiting: Arc<Mutex<Waiting<K>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+pub struct Turn<K: Hash + Eq> {
+    key: K,
+    id: u64,
+    waiting: Arc<Mutex<Waiting<K>>>,
+    // Set until the turn has come
+    ready: Option<Receiver<()>>,
+}
+
+impl<K> Clone for SerialQueues<K> {
+    fn clone(&self) -> Self {
+        Self {
+            waiting: self.waiting.clone(),
+            next_id: self.next_id.clone(),
+        }
+    }
+}
+
+impl<K> Default for SerialQueues<K> {
+    fn default() -> Self {
+        Self {
+            waiting: Arc::new(Mutex::new(HashMap::new())),
+            next_id: Arc::new(AtomicU64::new(0)),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> SerialQueues<K> {
+    /// Take a place in `key`'s queue, behind every turn taken before
+    pub fn enqueue(&self, key: K) -> Turn<K> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+
+        let mut waiting = self.waiting.lock().unwrap();
+        let ready = match waiting.get_mut(&key) {
+            Some(queue) => {
+                let (sender, receiver) = bounded(1);
+                queue.push_back((id, sender));
+                Some(receiver)
+            }
+            None => {
+                waiting.insert(key.clone(), VecDeque::new());
+                None
+            }
+        };
+
+        Turn {
+            key,
+            id,
+            waiting: self.waiting.clone(),
+            ready,
+        }
+    }
+
+    /// Keys with a handler running or waiting
+    pub fn busy_keys(&self) -> usize {
+        self.waiting.lock().unwrap().len()
+    }
+}
+
+impl<K: Hash + Eq> Turn<K> {
+    /// Wait until every turn taken earlier for the key is dropped
+    pub async fn wait(&mut self) {
+        if let Some(ready) = &self.ready {
+            let _ = ready.recv_async().await;
+            self.ready = None;
+        }
+    }
+}
+
+impl<K: Hash + Eq> Drop for Turn<K> {
+    fn drop(&mut self) {
+        let mut waiting = self.waiting.lock().unwrap();
+
+        // Given up before its turn came: just leave the queue
+        if let Some(ready) = &self.ready {
+            if ready.try_recv().is_err() {
+                if let Some(queue) = waiting.get_mut(&self.key) {
+                    queue.retain(|(id, _)| *id != self.id);
+                }
+                return;
+            }
+        }
+
+        match waiting
+            .get_mut(&self.key)
+            .and_then(|queue| queue.pop_front())
+        {
+            Some((_, next)) => {
+                let _ = next.send(());
+            }
+            None => {
+                waiting.remove(&self.key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_same_key_runs_in_arrival_order() {
+        let queues = SerialQueues::default();
+        let observed = Arc::new(Mutex::new(Vec::new()));
+
+        let mut handlers = Vec::new();
+        for n in 0..5u64 {
+            let mut turn = queues.enqueue("peer");
+            let observed = observed.clone();
+            handlers.push(tokio::spawn(async move {
+                turn.wait().await;
+                // The first handler is slow, later ones must still wait for it
+                if n == 0 {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+                observed.lock().unwrap().push(n);
+            }));
+        }
+        for handler in handlers {
+            handler.await.unwrap();
+        }
+
+        assert_eq!(*observed.lock().unwrap(), vec![0, 1, 2, 3, 4]);
+        assert_eq!(queues.busy_keys(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_other_keys_run_concurrently() {
+        let queues = SerialQueues::default();
+
+        let mut slow = queues.enqueue("slow peer");
+        slow.wait().await;
+        let mut queued = queues.enqueue("slow peer");
+        let mut other = queues.enqueue("other peer");
+
+        let waited = tokio::time::timeout(Duration::from_millis(50), other.wait()).await;
+        assert!(waited.is_ok());
+        let waited = tokio::time::timeout(Duration::from_millis(50), queued.wait()).await;
+        assert!(waited.is_err());
+
+        drop(slow);
+        let waited = tokio::time::timeout(Duration::from_millis(50), queued.wait()).await;
+        assert!(waited.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_turn_leaves_the_queue() {
+        let queues = SerialQueues::default();
+
+        let mut first = queues.enqueue("peer");
+        first.wait().await;
+        let abandoned = queues.enqueue("peer");
+        let mut third = queues.enqueue("peer");
+        drop(abandoned);
+
+        drop(first);
+        let waited = tokio::time::timeout(Duration::from_millis(50), third.wait()).await;
+        assert!(waited.is_ok());
+    }
+}
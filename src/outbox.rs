@@ -0,0 +1,352 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+use tracing::info;
+use veilid_core::*;
+
+use crate::error::VeilidDuplexError;
+use crate::veilid::{AckStatus, DuplexSender};
+
+pub const OUTBOX_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+pub const OUTBOX_MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// A message waiting in an `Outbox`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct OutboxEntry {
+    pub uuid: String,
+    pub destination: CryptoTyped<CryptoKey>,
+    /// The message encoded as it goes out, before encryption
+    pub blob: Vec<u8>,
+    /// When the message was queued, in veilid microsecond timestamps
+    pub queued_at: u64,
+    /// Failed delivery attempts so far
+    pub attempts: u32,
+    /// Earliest time of the next attempt
+    pub next_attempt: u64,
+}
+
+/// Where an `Outbox` keeps its entries between restarts
+pub trait OutboxStore: Send + Sync {
+    fn load(&self) -> Result<Vec<OutboxEntry>, VeilidDuplexError>;
+
+    /// Add `entry`, or replace the one with its uuid
+    fn save(&self, entry: &OutboxEntry) -> Result<(), VeilidDuplexError>;
+
+    fn remove(&self, uuid: &str) -> Result<(), VeilidDuplexError>;
+}
+
+/// Keeps each entry as a JSON file in a directory
+#[derive(Debug, Clone)]
+pub struct FileOutboxStore {
+    dir: PathBuf,
+}
+
+impl FileOutboxStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self, VeilidDuplexError> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, uuid: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", uuid))
+    }
+}
+
+impl OutboxStore for FileOutboxStore {
+    fn load(&self) -> Result<Vec<OutboxEntry>, VeilidDuplexError> {
+        let mut entries = Vec::new();
+        for file in fs::read_dir(&self.dir)? {
+            let path = file?.path();
+            if path
+                .extension()
+                .map_or(true, |extension| extension != "json")
+            {
+                continue;
+            }
+
+            match serde_json::from_slice(&fs::read(&path)?) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => info!("Skipping unreadable outbox entry {:?}: {}", path, e),
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn save(&self, entry: &OutboxEntry) -> Result<(), VeilidDuplexError> {
+        // Written aside and renamed over, so a crash never leaves half an entry
+        let mut file = NamedTempFile::new_in(&self.dir)?;
+        file.write_all(&serde_json::to_vec(entry)?)?;
+        file.persist(self.path(&entry.uuid))
+            .map_err(|e| VeilidDuplexError::Io(e.error))?;
+        Ok(())
+    }
+
+    fn remove(&self, uuid: &str) -> Result<(), VeilidDuplexError> {
+        match fs::remove_file(self.path(uuid)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Keeps entries in memory only, so they don't survive a restart
+#[derive(Debug, Default)]
+pub struct MemoryOutboxStore {
+    entries: Mutex<BTreeMap<String, OutboxEntry>>,
+}
+
+impl OutboxStore for MemoryOutboxStore {
+    fn load(&self) -> Result<Vec<OutboxEntry>, VeilidDuplexError> {
+        Ok(self.entries.lock().unwrap().values().cloned().collect())
+    }
+
+    fn save(&self, entry: &OutboxEntry) -> Result<(), VeilidDuplexError> {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(entry.uuid.clone(), entry.clone());
+        Ok(())
+    }
+
+    fn remove(&self, uuid: &str) -> Result<(), VeilidDuplexError> {
+        self.entries.lock().unwrap().remove(uuid);
+        Ok(())
+    }
+}
+
+/// Store-and-forward queue behind `send_message`, see `VeilidDuplex::set_outbox`.
+/// Entries stay in the store until the peer acknowledges them, so whatever
+/// was queued before a restart is sent after it. Clones share the queue.
+#[derive(Clone)]
+pub struct Outbox {
+    store: Arc<dyn OutboxStore>,
+    entries: Arc<Mutex<VecDeque<OutboxEntry>>>,
+    draining: Arc<AtomicBool>,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl fmt::Debug for Outbox {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Outbox")
+            .field("queued", &self.len())
+            .finish_non_exhaustive()
+    }
+}
+
+// Lets the next drain start however this one ends
+struct DrainGuard(Arc<AtomicBool>);
+
+impl Drop for DrainGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Outbox {
+    /// Queue backed by `store`, starting with the entries already in it
+    pub fn open(store: impl OutboxStore + 'static) -> Result<Self, VeilidDuplexError> {
+        let mut entries = store.load()?;
+        entries.sort_by_key(|entry| entry.queued_at);
+        info!("Outbox opened with {} queued message(s)", entries.len());
+
+        Ok(Self {
+            store: Arc::new(store),
+            entries: Arc::new(Mutex::new(entries.into())),
+            draining: Arc::new(AtomicBool::new(false)),
+            initial_backoff: OUTBOX_INITIAL_BACKOFF,
+            max_backoff: OUTBOX_MAX_BACKOFF,
+        })
+    }
+
+    /// Wait between failed attempts at an entry, doubling from `initial` up
+    /// to `max`. Defaults to 1s up to 60s.
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Queued messages, oldest first
+    pub fn entries(&self) -> Vec<OutboxEntry> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub(crate) fn enqueue(
+        &self,
+        uuid: String,
+        destination: CryptoTyped<CryptoKey>,
+        blob: Vec<u8>,
+        now: u64,
+    ) -> Result<(), VeilidDuplexError> {
+        let entry = OutboxEntry {
+            uuid,
+            destination,
+            blob,
+            queued_at: now,
+            attempts: 0,
+            next_attempt: now,
+        };
+        self.store.save(&entry)?;
+        self.entries.lock().unwrap().push_back(entry);
+        Ok(())
+    }
+
+    /// Whether a drain at `now` would have something to send
+    pub fn has_due(&self, now: u64) -> bool {
+        !self.draining.load(Ordering::SeqCst)
+            && self
+                .entries
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|entry| entry.next_attempt <= now)
+    }
+
+    /// Try each entry that is due once, oldest first. Acknowledged entries are
+    /// removed, refused ones too after going to the sender's dead letters;
+    /// the rest back off. Returns how many were delivered. Does nothing while
+    /// another drain is running.
+    pub async fn drain(&self, sender: &DuplexSender) -> usize {
+        if self.draining.swap(true, Ordering::SeqCst) {
+            return 0;
+        }
+        let _guard = DrainGuard(self.draining.clone());
+
+        let now = sender.now();
+        let due: Vec<OutboxEntry> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|entry| entry.next_attempt <= now)
+            .cloned()
+            .collect();
+
+        let mut delivered = 0;
+        for mut entry in due {
+            let result = sender
+                .deliver_once(&entry.uuid, &entry.blob, entry.destination)
+                .await;
+            match result {
+                Ok(outcome) if outcome.status.is_accepted() => {
+                    delivered += 1;
+                    self.remove(&entry.uuid);
+                }
+                Ok(outcome) if outcome.status != AckStatus::Busy => {
+                    info!(
+                        "Peer refused queued message {}: {}",
+                        entry.uuid, outcome.status
+                    );
+                    let error = VeilidDuplexError::Rejected(outcome.status.to_string());
+                    sender.dead_letter(&entry.uuid, &entry.blob, entry.destination, &error);
+                    self.remove(&entry.uuid);
+                }
+                _ => {
+                    entry.attempts += 1;
+                    entry.next_attempt =
+                        sender.now() + self.backoff(entry.attempts).as_micros() as u64;
+                    if let Err(e) = self.store.save(&entry) {
+                        info!("Unable to update queued message {}: {}", entry.uuid, e);
+                    }
+                    if let Some(queued) = self
+                        .entries
+                        .lock()
+                        .unwrap()
+                        .iter_mut()
+                        .find(|queued| queued.uuid == entry.uuid)
+                    {
+                        *queued = entry;
+                    }
+                }
+            }
+        }
+
+        delivered
+    }
+
+    fn remove(&self, uuid: &str) {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|entry| entry.uuid != uuid);
+        if let Err(e) = self.store.remove(uuid) {
+            info!(
+                "Unable to remove delivered message {} from the outbox: {}",
+                uuid, e
+            );
+        }
+    }
+
+    fn backoff(&self, attempts: u32) -> Duration {
+        let doublings = attempts.saturating_sub(1).min(16);
+        (self.initial_backoff * 2u32.pow(doublings)).min(self.max_backoff)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::CRYPTO_KIND;
+
+    fn entry(uuid: &str, queued_at: u64) -> OutboxEntry {
+        OutboxEntry {
+            uuid: uuid.to_string(),
+            destination: CryptoTyped::new(CRYPTO_KIND, CryptoKey::new([1; 32])),
+            blob: vec![1, 2, 3],
+            queued_at,
+            attempts: 0,
+            next_attempt: queued_at,
+        }
+    }
+
+    #[test]
+    fn test_file_store_survives_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileOutboxStore::new(dir.path()).unwrap();
+        store.save(&entry("b", 2)).unwrap();
+        store.save(&entry("a", 1)).unwrap();
+        store.save(&entry("c", 3)).unwrap();
+        store.remove("c").unwrap();
+        store.remove("missing").unwrap();
+
+        let outbox = Outbox::open(FileOutboxStore::new(dir.path()).unwrap()).unwrap();
+        let uuids: Vec<String> = outbox
+            .entries()
+            .into_iter()
+            .map(|entry| entry.uuid)
+            .collect();
+        assert_eq!(uuids, vec!["a", "b"]);
+        assert!(outbox.has_due(5));
+        assert!(!outbox.has_due(0));
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_max() {
+        let outbox = Outbox::open(MemoryOutboxStore::default())
+            .unwrap()
+            .with_backoff(Duration::from_secs(1), Duration::from_secs(5));
+        assert_eq!(outbox.backoff(1), Duration::from_secs(1));
+        assert_eq!(outbox.backoff(3), Duration::from_secs(4));
+        assert_eq!(outbox.backoff(40), Duration::from_secs(5));
+    }
+}
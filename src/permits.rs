@@ -0,0 +1,159 @@
+use flume::{bounded, Receiver, Sender};
+
+/// Default cap on inbound messages handled at once
+pub const MAX_CONCURRENT_HANDLERS: usize = 64;
+
+/// Counting semaphore capping concurrent message handlers. Permits are tokens
+/// in a bounded channel, handed back when a `HandlerPermit` drops.
+#[derive(Clone)]
+pub struct HandlerPermits {
+    sender: Sender<()>,
+    receiver: Receiver<()>,
+}
+
+pub struct HandlerPermit {
+    sender: Sender<()>,
+}
+
+/// What an inbound message gets when every handler permit is taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HandlerOverflow {
+    /// Hold the network loop until a handler finishes
+    #[default]
+    Wait,
+    /// Reply `AckStatus::Busy` right away so the sender retries later
+    Reject,
+}
+
+impl HandlerPermits {
+    pub fn new(limit: usize) -> Self {
+        let limit = limit.max(1);
+        let (sender, receiver) = bounded(limit);
+        for _ in 0..limit {
+            let _ = sender.try_send(());
+        }
+
+        Self { sender, receiver }
+    }
+
+    /// Wait for a free permit
+    pub async fn acquire(&self) -> HandlerPermit {
+        // Can't fail, we hold a sender
+        let _ = self.receiver.recv_async().await;
+        HandlerPermit {
+            sender: self.sender.clone(),
+        }
+    }
+
+    /// Take a free permit without waiting
+    pub fn try_acquire(&self) -> Option<HandlerPermit> {
+        self.receiver.try_recv().ok().map(|_| HandlerPermit {
+            sender: self.sender.clone(),
+        })
+    }
+
+    pub fn available(&self) -> usize {
+        self.receiver.len()
+    }
+
+    /// Wait until every permit has been handed back
+    pub async fn wait_idle(&self) {
+        let limit = self.sender.capacity().unwrap_or(1);
+        let mut held = Vec::with_capacity(limit);
+        for _ in 0..limit {
+            held.push(self.acquire().await);
+        }
+    }
+}
+
+impl Default for HandlerPermits {
+    fn default() -> Self {
+        Self::new(MAX_CONCURRENT_HANDLERS)
+    }
+}
+
+impl Drop for HandlerPermit {
+    fn drop(&mut self) {
+        let _ = self.sender.try_send(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_slow_holder_blocks_only_past_the_limit() {
+        let permits = HandlerPermits::new(2);
+
+        let slow = permits.acquire().await;
+        // A second handler gets going while the first is still busy
+        let second = tokio::time::timeout(Duration::from_millis(100), permits.acquire()).await;
+        assert!(second.is_ok());
+        assert_eq!(permits.available(), 0);
+
+        let third = tokio::time::timeout(Duration::from_millis(100), permits.acquire()).await;
+        assert!(third.is_err());
+
+        drop(slow);
+        let third = tokio::time::timeout(Duration::from_millis(100), permits.acquire()).await;
+        assert!(third.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_wait_idle_waits_for_holders() {
+        let permits = HandlerPermits::new(2);
+        let held = permits.acquire().await;
+
+        let idle = tokio::time::timeout(Duration::from_millis(50), permits.wait_idle()).await;
+        assert!(idle.is_err());
+
+        drop(held);
+        let idle = tokio::time::timeout(Duration::from_millis(50), permits.wait_idle()).await;
+        assert!(idle.is_ok());
+        assert_eq!(permits.available(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_flood_runs_at_most_limit_at_once() {
+        let limit = 4;
+        let permits = HandlerPermits::new(limit);
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+
+        let mut handlers = Vec::new();
+        for _ in 0..limit + 16 {
+            let permit = permits.acquire().await;
+            let running = running.clone();
+            let peak = peak.clone();
+            handlers.push(tokio::spawn(async move {
+                let _permit = permit;
+                let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now_running, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+            }));
+        }
+        for handler in handlers {
+            handler.await.unwrap();
+        }
+
+        assert_eq!(peak.load(Ordering::SeqCst), limit);
+        assert_eq!(permits.available(), limit);
+    }
+
+    #[test]
+    fn test_try_acquire_fails_when_saturated() {
+        let permits = HandlerPermits::new(1);
+        let held = permits.try_acquire();
+        assert!(held.is_some());
+        assert!(permits.try_acquire().is_none());
+
+        drop(held);
+        assert!(permits.try_acquire().is_some());
+    }
+}
@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use flume::{unbounded, Receiver, Sender};
+use tracing::info;
+use veilid_core::*;
+
+use crate::veilid::{claim_if_due, DuplexSender};
+
+/// Called with each change of a tracked peer's status
+pub type PresenceCallback = Arc<dyn Fn(&PresenceChange) + Send + Sync>;
+
+/// How tracked peers are probed, see `VeilidDuplex::track_presence`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PresenceConfig {
+    /// Time between probing rounds. Each round looks every tracked peer up,
+    /// so keep it long with many peers to spare the DHT.
+    pub interval: Duration,
+    /// Ping peers over their route instead of only resolving it. A route that
+    /// resolves says little about whether its owner is running.
+    pub ping: bool,
+    /// How long a ping waits for the peer's answer
+    pub timeout: Duration,
+}
+
+impl Default for PresenceConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(60),
+            ping: true,
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PresenceStatus {
+    /// Not probed yet
+    #[default]
+    Unknown,
+    Online,
+    Offline,
+}
+
+/// What we know of a tracked peer's reachability
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Presence {
+    pub status: PresenceStatus,
+    /// Last time a probe reached the peer, in veilid microsecond timestamps
+    pub last_seen: Option<u64>,
+    /// Last time the peer was probed
+    pub checked_at: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PresenceChange {
+    pub peer: CryptoTyped<CryptoKey>,
+    pub previous: PresenceStatus,
+    pub presence: Presence,
+}
+
+// Lets the next round start however this one ends
+struct RoundGuard(Arc<AtomicBool>);
+
+impl Drop for RoundGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Online status of the peers we were asked to track, refreshed by probing
+/// rounds from the network loop. Clones share the statuses and subscribers.
+#[derive(Clone, Default)]
+pub struct PresenceTracker {
+    peers: Arc<Mutex<HashMap<CryptoKey, (CryptoTyped<CryptoKey>, Presence)>>>,
+    subscribers: Arc<Mutex<Vec<Sender<PresenceChange>>>>,
+    on_change: Option<PresenceCallback>,
+    config: PresenceConfig,
+    // Timestamp of the last round, shared so clones don't double up
+    last_round: Arc<AtomicU64>,
+    probing: Arc<AtomicBool>,
+}
+
+impl fmt::Debug for PresenceTracker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PresenceTracker")
+            .field("tracked", &self.tracked().len())
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PresenceTracker {
+    pub fn new(config: PresenceConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    pub fn config(&self) -> PresenceConfig {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: PresenceConfig) {
+        self.config = config;
+    }
+
+    pub fn set_on_change(&mut self, callback: impl Fn(&PresenceChange) + Send + Sync + 'static) {
+        self.on_change = Some(Arc::new(callback));
+    }
+
+    /// Start tracking `peer`, as `Unknown` until the next round. Returns
+    /// false if it was tracked already.
+    pub fn track(&self, peer: CryptoTyped<CryptoKey>) -> bool {
+        let mut peers = self.peers.lock().unwrap();
+        if peers.contains_key(&peer.value) {
+            return false;
+        }
+
+        peers.insert(peer.value, (peer, Presence::default()));
+        true
+    }
+
+    pub fn untrack(&self, peer: CryptoTyped<CryptoKey>) -> bool {
+        self.peers.lock().unwrap().remove(&peer.value).is_some()
+    }
+
+    pub fn tracked(&self) -> Vec<CryptoTyped<CryptoKey>> {
+        self.peers
+            .lock()
+            .unwrap()
+            .values()
+            .map(|(peer, _)| *peer)
+            .collect()
+    }
+
+    /// Status of `peer`, `Unknown` if it isn't tracked
+    pub fn status(&self, peer: CryptoTyped<CryptoKey>) -> Presence {
+        self.peers
+            .lock()
+            .unwrap()
+            .get(&peer.value)
+            .map(|(_, presence)| *presence)
+            .unwrap_or_default()
+    }
+
+    /// Changes of tracked peers' statuses from now on. Dropping the receiver
+    /// unsubscribes.
+    pub fn subscribe(&self) -> Receiver<PresenceChange> {
+        let (sender, receiver) = unbounded();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Record a probe of `peer` at `now`, telling subscribers if its status
+    /// changed. Untracked peers are ignored.
+    pub(crate) fn record(
+        &self,
+        peer: CryptoTyped<CryptoKey>,
+        online: bool,
+        now: u64,
+    ) -> Option<PresenceChange> {
+        let change = {
+            let mut peers = self.peers.lock().unwrap();
+            let (_, presence) = peers.get_mut(&peer.value)?;
+            let previous = presence.status;
+            presence.checked_at = Some(now);
+            presence.status = match online {
+                true => {
+                    presence.last_seen = Some(now);
+                    PresenceStatus::Online
+                }
+                false => PresenceStatus::Offline,
+            };
+            if presence.status == previous {
+                return None;
+            }
+
+            PresenceChange {
+                peer,
+                previous,
+                presence: *presence,
+            }
+        };
+
+        info!("{} is now {:?}", peer, change.presence.status);
+        if let Some(on_change) = &self.on_change {
+            on_change(&change);
+        }
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(change).is_ok());
+
+        Some(change)
+    }
+
+    /// Whether a round is due at `now`, claiming it if so
+    pub(crate) fn claim_round(&self, now: u64) -> bool {
+        if self.peers.lock().unwrap().is_empty() || self.probing.load(Ordering::SeqCst) {
+            return false;
+        }
+
+        claim_if_due(&self.last_round, self.config.interval, now)
+    }
+
+    /// Probe every tracked peer once, one after another. A peer that doesn't
+    /// answer has its cached route dropped, so the next round looks it up on
+    /// DHT again. Does nothing while another round is running.
+    pub async fn probe_all(&self, sender: &DuplexSender) {
+        if self.probing.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let _guard = RoundGuard(self.probing.clone());
+
+        for peer in self.tracked() {
+            let online = self.probe(sender, peer).await;
+            if !online {
+                sender.routes.lock().await.invalidate(peer);
+            }
+            self.record(peer, online, sender.now());
+        }
+    }
+
+    async fn probe(&self, sender: &DuplexSender, peer: CryptoTyped<CryptoKey>) -> bool {
+        if self.config.ping {
+            return sender.ping(peer, self.config.timeout).await.is_ok();
+        }
+
+        // Resolved afresh, so a peer that stopped publishing goes offline
+        let mut routes = sender.routes.lock().await;
+        routes.invalidate(peer);
+        routes
+            .get_route(
+                peer,
+                &*sender.transport,
+                sender.routing_context.clone(),
+                sender.now(),
+            )
+            .await
+            .is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::CRYPTO_KIND;
+
+    #[test]
+    fn test_record_reports_transitions_only() {
+        let peer = CryptoTyped::new(CRYPTO_KIND, CryptoKey::new([1; 32]));
+        let mut tracker = PresenceTracker::default();
+        let seen = Arc::new(AtomicU64::new(0));
+        let counted = seen.clone();
+        tracker.set_on_change(move |_| {
+            counted.fetch_add(1, Ordering::SeqCst);
+        });
+        let changes = tracker.subscribe();
+
+        assert!(tracker.record(peer, true, 1).is_none());
+        assert!(tracker.track(peer));
+        assert!(!tracker.track(peer));
+        assert_eq!(tracker.status(peer).status, PresenceStatus::Unknown);
+
+        let change = tracker.record(peer, true, 10).unwrap();
+        assert_eq!(change.previous, PresenceStatus::Unknown);
+        assert!(tracker.record(peer, true, 20).is_none());
+        tracker.record(peer, false, 30).unwrap();
+
+        let presence = tracker.status(peer);
+        assert_eq!(presence.status, PresenceStatus::Offline);
+        assert_eq!(presence.last_seen, Some(20));
+        assert_eq!(presence.checked_at, Some(30));
+        assert_eq!(seen.load(Ordering::SeqCst), 2);
+        assert_eq!(changes.drain().count(), 2);
+    }
+}
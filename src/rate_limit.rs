@@ -0,0 +1,254 @@
+use std::time::Duration;
+
+use veilid_core::tools::*;
+use veilid_core::*;
+
+/// Sustained rate and burst allowance for a token bucket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BandwidthLimit {
+    pub bytes_per_sec: u64,
+    pub burst_bytes: u64,
+}
+
+/// Sustained rate and burst allowance for inbound messages from one sender
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageRateLimit {
+    pub messages_per_sec: u64,
+    pub burst: u64,
+}
+
+/// Token bucket refilled continuously at `rate` tokens per second, holding at most `burst`.
+/// Timestamps are veilid microsecond timestamps (see `get_timestamp`).
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated_at: u64,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64, burst: u64, now: u64) -> Self {
+        Self {
+            rate: rate as f64,
+            burst: burst as f64,
+            tokens: burst as f64,
+            updated_at: now,
+        }
+    }
+
+    fn refill(&mut self, now: u64) {
+        let elapsed = now.saturating_sub(self.updated_at) as f64 / 1_000_000.0;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.updated_at = now.max(self.updated_at);
+    }
+
+    /// Take `amount` tokens and return how long to wait before using them. The
+    /// balance may go negative, so concurrent callers queue up behind each other
+    /// and requests larger than `burst` are delayed rather than refused.
+    pub fn reserve(&mut self, amount: u64, now: u64) -> Duration {
+        self.refill(now);
+        self.tokens -= amount as f64;
+        if self.tokens >= 0.0 || self.rate <= 0.0 {
+            return Duration::ZERO;
+        }
+
+        Duration::from_secs_f64(-self.tokens / self.rate)
+    }
+
+    /// Take `amount` tokens if the bucket holds them, never going negative
+    pub fn try_take(&mut self, amount: u64, now: u64) -> bool {
+        self.refill(now);
+        if self.tokens < amount as f64 {
+            return false;
+        }
+
+        self.tokens -= amount as f64;
+        true
+    }
+
+    /// Whether the bucket has refilled completely, making it no different from
+    /// a new one
+    pub fn is_full(&self, now: u64) -> bool {
+        let mut bucket = self.clone();
+        bucket.refill(now);
+        bucket.tokens >= bucket.burst
+    }
+
+    /// Whether `try_take` of `amount` would succeed, without taking anything
+    pub fn holds(&self, amount: u64, now: u64) -> bool {
+        let mut bucket = self.clone();
+        bucket.refill(now);
+        bucket.tokens >= amount as f64
+    }
+}
+
+/// Drops inbound messages from senders over their rate. Buckets of idle
+/// senders are forgotten once they refill.
+#[derive(Debug, Clone, Default)]
+pub struct InboundRateLimiter {
+    limit: Option<MessageRateLimit>,
+    peers: HashMap<CryptoKey, TokenBucket>,
+    last_prune: u64,
+}
+
+impl InboundRateLimiter {
+    pub fn set_limit(&mut self, limit: Option<MessageRateLimit>) {
+        self.limit = limit;
+        self.peers.clear();
+    }
+
+    /// Count a message from `peer`, returning false if it is over the limit
+    pub fn allow(&mut self, peer: CryptoTyped<CryptoKey>, now: u64) -> bool {
+        let Some(limit) = self.limit else {
+            return true;
+        };
+
+        if now.saturating_sub(self.last_prune) >= 1_000_000 {
+            self.peers.retain(|_, bucket| !bucket.is_full(now));
+            self.last_prune = now;
+        }
+
+        self.peers
+            .entry(peer.value)
+            .or_insert_with(|| TokenBucket::new(limit.messages_per_sec, limit.burst, now))
+            .try_take(1, now)
+    }
+
+    /// Whether a message from `peer` would be allowed, without counting it.
+    /// Lets a message be turned away before it costs any work.
+    pub fn would_allow(&self, peer: CryptoTyped<CryptoKey>, now: u64) -> bool {
+        let Some(limit) = self.limit else {
+            return true;
+        };
+
+        match self.peers.get(&peer.value) {
+            Some(bucket) => bucket.holds(1, now),
+            None => limit.burst >= 1,
+        }
+    }
+
+    /// Senders with a bucket still refilling
+    pub fn tracked_peers(&self) -> usize {
+        self.peers.len()
+    }
+}
+
+/// Paces outbound bytes globally and per peer
+#[derive(Debug, Clone, Default)]
+pub struct BandwidthLimiter {
+    global_limit: Option<BandwidthLimit>,
+    global: Option<TokenBucket>,
+    peer_limits: HashMap<CryptoKey, BandwidthLimit>,
+    peers: HashMap<CryptoKey, TokenBucket>,
+}
+
+impl BandwidthLimiter {
+    pub fn set_global_limit(&mut self, limit: Option<BandwidthLimit>) {
+        self.global_limit = limit;
+        self.global = None;
+    }
+
+    pub fn set_peer_limit(&mut self, peer: CryptoTyped<CryptoKey>, limit: Option<BandwidthLimit>) {
+        match limit {
+            Some(limit) => {
+                self.peer_limits.insert(peer.value, limit);
+            }
+            None => {
+                self.peer_limits.remove(&peer.value);
+            }
+        }
+        self.peers.remove(&peer.value);
+    }
+
+    pub fn global_limit(&self) -> Option<BandwidthLimit> {
+        self.global_limit
+    }
+
+    pub fn peer_limit(&self, peer: CryptoTyped<CryptoKey>) -> Option<BandwidthLimit> {
+        self.peer_limits.get(&peer.value).copied()
+    }
+
+    pub fn is_limited(&self, peer: CryptoTyped<CryptoKey>) -> bool {
+        self.global_limit.is_some() || self.peer_limits.contains_key(&peer.value)
+    }
+
+    /// Account for `bytes` sent to `peer`, returning the delay both the global and
+    /// the peer's bucket require before sending.
+    pub fn reserve(&mut self, peer: CryptoTyped<CryptoKey>, bytes: u64, now: u64) -> Duration {
+        let mut delay = Duration::ZERO;
+
+        if let Some(limit) = self.global_limit {
+            let bucket = self.global.get_or_insert_with(|| {
+                TokenBucket::new(limit.bytes_per_sec, limit.burst_bytes, now)
+            });
+            delay = delay.max(bucket.reserve(bytes, now));
+        }
+
+        if let Some(limit) = self.peer_limits.get(&peer.value) {
+            let bucket = self
+                .peers
+                .entry(peer.value)
+                .or_insert_with(|| TokenBucket::new(limit.bytes_per_sec, limit.burst_bytes, now));
+            delay = delay.max(bucket.reserve(bytes, now));
+        }
+
+        delay
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::CRYPTO_KIND;
+
+    #[test]
+    fn test_token_bucket_allows_burst_then_delays() {
+        let mut bucket = TokenBucket::new(1000, 2000, 0);
+
+        assert_eq!(bucket.reserve(2000, 0), Duration::ZERO);
+        assert_eq!(bucket.reserve(500, 0), Duration::from_millis(500));
+        // Refilled 1000 tokens after a second, still 500 short for another 1000
+        assert_eq!(bucket.reserve(1000, 1_000_000), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_inbound_limiter_drops_excess_and_forgets_idle_senders() {
+        let mut limiter = InboundRateLimiter::default();
+        let noisy = CryptoTyped::new(CRYPTO_KIND, CryptoKey::new([1; 32]));
+        let quiet = CryptoTyped::new(CRYPTO_KIND, CryptoKey::new([2; 32]));
+
+        // Off until a limit is set
+        assert!((0..100).all(|_| limiter.allow(noisy, 0)));
+
+        limiter.set_limit(Some(MessageRateLimit {
+            messages_per_sec: 10,
+            burst: 5,
+        }));
+        let allowed = (0..20).filter(|_| limiter.allow(noisy, 0)).count();
+        assert_eq!(allowed, 5);
+        // Asking doesn't count
+        assert!(!limiter.would_allow(noisy, 0));
+        assert!((0..10).all(|_| limiter.would_allow(quiet, 0)));
+        // Other senders have their own bucket
+        assert!(limiter.allow(quiet, 0));
+
+        // A tenth of a second refills one message
+        assert!(limiter.allow(noisy, 100_000));
+        assert!(!limiter.allow(noisy, 100_000));
+        assert_eq!(limiter.tracked_peers(), 2);
+
+        // Both buckets have refilled by now and are pruned
+        assert!(limiter.allow(noisy, 10_000_000));
+        assert_eq!(limiter.tracked_peers(), 1);
+    }
+
+    #[test]
+    fn test_token_bucket_caps_refill_at_burst() {
+        let mut bucket = TokenBucket::new(1000, 1000, 0);
+
+        assert_eq!(bucket.reserve(1000, 0), Duration::ZERO);
+        assert_eq!(bucket.reserve(1000, 60_000_000), Duration::ZERO);
+        assert_eq!(bucket.reserve(1000, 60_000_000), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,165 @@
+use std::io;
+
+use anyhow::{Error, Ok};
+
+use veilid_core::*;
+
+// Signed blobs start with a magic that can't begin a serialized or compressed
+// AppMessage, followed by the signature over the rest of the blob
+const SIGNATURE_MAGIC: &[u8; 4] = b"\0VDS";
+// Identity challenge of `DuplexSender::connect`: the magic and a random nonce,
+// answered by the peer's network loop with a signature
+const CHALLENGE_MAGIC: &[u8; 4] = b"\0VDI";
+const CHALLENGE_NONCE_LENGTH: usize = 32;
+
+pub fn is_signed(blob: &[u8]) -> bool {
+    blob.starts_with(SIGNATURE_MAGIC)
+}
+
+/// Prefix `blob` with a signature made with our DHT record owner keypair
+pub fn sign(cs: &CryptoSystemVersion, keypair: &KeyPair, blob: &[u8]) -> Result<Vec<u8>, Error> {
+    let signature = cs.sign(&keypair.key, &keypair.secret, blob)?;
+
+    let mut out = Vec::with_capacity(SIGNATURE_MAGIC.len() + SIGNATURE_LENGTH + blob.len());
+    out.extend_from_slice(SIGNATURE_MAGIC);
+    out.extend_from_slice(&signature.bytes);
+    out.extend_from_slice(blob);
+    Ok(out)
+}
+
+/// Split a blob produced by `sign` into the signature and the signed payload
+pub fn split_signed(blob: &[u8]) -> Result<(Signature, &[u8]), Error> {
+    let header_end = SIGNATURE_MAGIC.len() + SIGNATURE_LENGTH;
+    if !is_signed(blob) || blob.len() < header_end {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Malformed signed message").into());
+    }
+
+    let signature = Signature::try_from(&blob[SIGNATURE_MAGIC.len()..header_end])?;
+    Ok((signature, &blob[header_end..]))
+}
+
+/// Check `signature` over `payload` against the signer's public key. Fails if
+/// the payload was tampered with or signed by another key.
+pub fn verify(
+    cs: &CryptoSystemVersion,
+    key: &PublicKey,
+    payload: &[u8],
+    signature: &Signature,
+) -> Result<(), Error> {
+    cs.verify(key, payload, signature)?;
+    Ok(())
+}
+
+/// Challenge asking a peer to prove it holds its DHT record's owner key
+pub fn identity_challenge(nonce: [u8; CHALLENGE_NONCE_LENGTH]) -> Vec<u8> {
+    let mut challenge = CHALLENGE_MAGIC.to_vec();
+    challenge.extend_from_slice(&nonce);
+    challenge
+}
+
+pub fn is_identity_challenge(message: &[u8]) -> bool {
+    message.len() == CHALLENGE_MAGIC.len() + CHALLENGE_NONCE_LENGTH
+        && message.starts_with(CHALLENGE_MAGIC)
+}
+
+// Signed along with the challenge, so an answer for one record doesn't pass
+// for another
+fn challenge_payload(challenge: &[u8], dht_record: CryptoTyped<CryptoKey>) -> Vec<u8> {
+    let mut payload = challenge.to_vec();
+    payload.extend_from_slice(&dht_record.value.bytes);
+    payload
+}
+
+/// Answer `challenge` for `dht_record`, signing with its owner keypair
+pub fn answer_challenge(
+    cs: &CryptoSystemVersion,
+    keypair: &KeyPair,
+    dht_record: CryptoTyped<CryptoKey>,
+    challenge: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let payload = challenge_payload(challenge, dht_record);
+    let signature = cs.sign(&keypair.key, &keypair.secret, &payload)?;
+    Ok(signature.bytes.to_vec())
+}
+
+/// Check that `answer` to `challenge` was made by the holder of `key` for
+/// `dht_record`
+pub fn check_answer(
+    cs: &CryptoSystemVersion,
+    key: &PublicKey,
+    dht_record: CryptoTyped<CryptoKey>,
+    challenge: &[u8],
+    answer: &[u8],
+) -> Result<(), Error> {
+    let signature = Signature::try_from(answer)?;
+    verify(
+        cs,
+        key,
+        &challenge_payload(challenge, dht_record),
+        &signature,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::crypto_system;
+    use crate::utils::CRYPTO_KIND;
+    use crate::veilid::VeilidDuplex;
+
+    #[tokio::test]
+    async fn test_signature_checks_payload_and_key() -> Result<(), Error> {
+        let app = VeilidDuplex::new().await?;
+        let cs = crypto_system(&app.api, CRYPTO_KIND)?;
+
+        let blob = sign(&cs, &app.dht_keypair, b"hello")?;
+        assert!(is_signed(&blob));
+        let (signature, payload) = split_signed(&blob)?;
+        assert_eq!(payload, b"hello");
+        assert!(verify(&cs, &app.dht_keypair.key, payload, &signature).is_ok());
+
+        let mut tampered = blob.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        let (signature, payload) = split_signed(&tampered)?;
+        assert!(verify(&cs, &app.dht_keypair.key, payload, &signature).is_err());
+
+        // Signed by someone other than the claimed sender
+        let other = cs.generate_keypair();
+        let forged = sign(&cs, &other, b"hello")?;
+        let (signature, payload) = split_signed(&forged)?;
+        assert!(verify(&cs, &app.dht_keypair.key, payload, &signature).is_err());
+
+        app.api.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_challenge_answer_binds_key_and_record() -> Result<(), Error> {
+        let app = VeilidDuplex::new().await?;
+        let cs = crypto_system(&app.api, CRYPTO_KIND)?;
+        let challenge = identity_challenge([7; CHALLENGE_NONCE_LENGTH]);
+        assert!(is_identity_challenge(&challenge));
+        assert!(!is_identity_challenge(&challenge[1..]));
+
+        let record = app.our_dht_key;
+        let answer = answer_challenge(&cs, &app.dht_keypair, record, &challenge)?;
+        assert!(check_answer(&cs, &app.dht_keypair.key, record, &challenge, &answer).is_ok());
+
+        // Another nonce, another record or another key don't pass
+        let other_challenge = identity_challenge([8; CHALLENGE_NONCE_LENGTH]);
+        assert!(
+            check_answer(&cs, &app.dht_keypair.key, record, &other_challenge, &answer).is_err()
+        );
+        let other_record = CryptoTyped::new(CRYPTO_KIND, CryptoKey::new([9; 32]));
+        assert!(
+            check_answer(&cs, &app.dht_keypair.key, other_record, &challenge, &answer).is_err()
+        );
+        let other = cs.generate_keypair();
+        let forged = answer_challenge(&cs, &other, record, &challenge)?;
+        assert!(check_answer(&cs, &app.dht_keypair.key, record, &challenge, &forged).is_err());
+        assert!(check_answer(&cs, &app.dht_keypair.key, record, &challenge, b"ACK").is_err());
+
+        app.api.shutdown().await;
+        Ok(())
+    }
+}
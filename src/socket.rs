@@ -0,0 +1,399 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use async_std::sync::Mutex;
+use flume::r#async::{RecvStream, SendSink};
+use flume::{bounded, Receiver, Sender};
+use futures_util::task::AtomicWaker;
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::info;
+use veilid_core::tools::*;
+use veilid_core::*;
+
+use crate::error::VeilidDuplexError;
+use crate::veilid::{
+    AppLogic, AppMessage, DuplexSender, HandlerError, NetworkLoopHandle, VeilidDuplex,
+};
+
+/// Most bytes a single write puts in one message
+pub const SOCKET_FRAME_SIZE: usize = 32 * 1024;
+// Frames buffered each way before writes or the peer's sends wait
+const SOCKET_BUFFER: usize = 16;
+// Frames held for a gap in sequence numbers to fill before the peer is refused
+const MAX_EARLY_FRAMES: usize = 64;
+
+/// Message carrying a slice of a `DuplexSocket` byte stream
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SocketFrame {
+    pub seq: u64,
+    pub data: Vec<u8>,
+    /// The writer shut down, nothing follows
+    pub fin: bool,
+}
+
+enum Outbound {
+    Data(Vec<u8>),
+    Fin,
+}
+
+// Puts frames back in sequence order before handing them to the reader
+#[derive(Default)]
+struct Reorder {
+    next_seq: u64,
+    early: BTreeMap<u64, SocketFrame>,
+}
+
+impl Reorder {
+    fn push(&mut self, frame: SocketFrame) -> Result<Vec<SocketFrame>, HandlerError> {
+        if frame.seq < self.next_seq {
+            // A resend of a frame already read
+            return Ok(Vec::new());
+        }
+        if self.early.len() >= MAX_EARLY_FRAMES && !self.early.contains_key(&frame.seq) {
+            return Err(HandlerError::new("Too many frames out of order"));
+        }
+        self.early.insert(frame.seq, frame);
+
+        let mut ready = Vec::new();
+        while let Some(frame) = self.early.remove(&self.next_seq) {
+            self.next_seq += 1;
+            ready.push(frame);
+        }
+        Ok(ready)
+    }
+}
+
+#[derive(Clone)]
+struct SocketInbox {
+    remote: CryptoTyped<CryptoKey>,
+    reorder: Arc<Mutex<Reorder>>,
+    frames: Sender<SocketFrame>,
+}
+
+impl AppLogic<SocketFrame> for SocketInbox {
+    async fn on_message(&mut self, message: AppMessage<SocketFrame>) -> Result<(), HandlerError> {
+        if message.dht_record != self.remote {
+            return Err(HandlerError::new("Not connected to this peer"));
+        }
+
+        // Held while handing frames over, so concurrent handlers can't
+        // interleave them. Waiting on a full buffer holds back our reply, and
+        // so the peer's next write.
+        let mut reorder = self.reorder.lock().await;
+        for frame in reorder.push(message.data)? {
+            self.frames
+                .send_async(frame)
+                .await
+                .map_err(|_| HandlerError::new("Socket closed"))?;
+        }
+        Ok(())
+    }
+}
+
+// Shared by a connection handle and the task sending what it queues
+#[derive(Default)]
+pub(crate) struct WriterState {
+    // Messages queued and not yet acknowledged by the peer
+    pending: AtomicUsize,
+    flushed: AtomicWaker,
+    error: std::sync::Mutex<Option<String>>,
+}
+
+impl WriterState {
+    pub(crate) fn queued(&self) {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn sent(&self) {
+        self.pending.fetch_sub(1, Ordering::SeqCst);
+        self.flushed.wake();
+    }
+
+    /// The send task gave up, every later write fails with `e`
+    pub(crate) fn failed(&self, e: &VeilidDuplexError) {
+        *self.error.lock().unwrap() = Some(e.to_string());
+        self.flushed.wake();
+    }
+
+    /// Ready once everything queued was acknowledged
+    pub(crate) fn poll_flushed(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.flushed.register(cx.waker());
+        if let Some(e) = self.error() {
+            return Poll::Ready(Err(e));
+        }
+        match self.pending.load(Ordering::SeqCst) {
+            0 => Poll::Ready(Ok(())),
+            _ => Poll::Pending,
+        }
+    }
+
+    pub(crate) fn error(&self) -> Option<io::Error> {
+        self.error
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|e| io::Error::new(io::ErrorKind::BrokenPipe, e.clone()))
+    }
+
+    pub(crate) fn broken(&self) -> io::Error {
+        self.error()
+            .unwrap_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Socket writer stopped"))
+    }
+}
+
+async fn write_frames(
+    sender: DuplexSender,
+    remote: CryptoTyped<CryptoKey>,
+    outbound: Receiver<Outbound>,
+    state: Arc<WriterState>,
+) {
+    let mut seq = 0;
+    while let Ok(outbound) = outbound.recv_async().await {
+        let (data, fin) = match outbound {
+            Outbound::Data(data) => (data, false),
+            Outbound::Fin => (Vec::new(), true),
+        };
+        let app_message = AppMessage {
+            data: SocketFrame { seq, data, fin },
+            uuid: String::new(),
+            dht_record: sender.our_dht_key,
+            reply_to: None,
+            timestamp: 0,
+            topic: None,
+        };
+        seq += 1;
+
+        // One frame in flight at a time keeps them in order on the wire too
+        if let Err(e) = sender.send_message(app_message, remote).await {
+            info!("Socket to {} broke: {}", remote, e);
+            state.failed(&e);
+            return;
+        }
+        state.sent();
+    }
+}
+
+/// Byte stream to one peer over a `VeilidDuplex`, for code written against
+/// `AsyncRead` and `AsyncWrite`. Writes go out as `SocketFrame` messages of
+/// up to `SOCKET_FRAME_SIZE` bytes and are read back in order on the other
+/// side. Both peers have to `connect` to each other.
+pub struct DuplexSocket {
+    remote: CryptoTyped<CryptoKey>,
+    frames: RecvStream<'static, SocketFrame>,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    eof: bool,
+    outbound: SendSink<'static, Outbound>,
+    writer: Arc<WriterState>,
+    fin_sent: bool,
+    network_loop: NetworkLoopHandle,
+}
+
+/// Open a `DuplexSocket` to `remote_dht_record`. The socket runs the duplex's
+/// network loop, so the duplex can't run another one while it is open;
+/// dropping the socket stops the loop.
+pub fn connect(
+    duplex: &VeilidDuplex,
+    remote_dht_record: CryptoTyped<CryptoKey>,
+) -> Result<DuplexSocket, VeilidDuplexError> {
+    if duplex.loop_running.load(Ordering::SeqCst) {
+        return Err(VeilidDuplexError::LoopAlreadyRunning);
+    }
+
+    let (frames_sender, frames) = bounded(SOCKET_BUFFER);
+    let inbox = SocketInbox {
+        remote: remote_dht_record,
+        reorder: Arc::new(Mutex::new(Reorder::default())),
+        frames: frames_sender,
+    };
+    let network_loop = duplex.clone().spawn_network_loop::<SocketFrame, _>(inbox);
+
+    let (outbound, outbound_receiver) = bounded(SOCKET_BUFFER);
+    let writer = Arc::new(WriterState::default());
+    spawn_detached(write_frames(
+        duplex.sender(),
+        remote_dht_record,
+        outbound_receiver,
+        writer.clone(),
+    ));
+
+    Ok(DuplexSocket {
+        remote: remote_dht_record,
+        frames: frames.into_stream(),
+        read_buf: Vec::new(),
+        read_pos: 0,
+        eof: false,
+        outbound: outbound.into_sink(),
+        writer,
+        fin_sent: false,
+        network_loop,
+    })
+}
+
+impl DuplexSocket {
+    pub fn remote(&self) -> CryptoTyped<CryptoKey> {
+        self.remote
+    }
+
+    fn send(&mut self, cx: &mut Context<'_>, outbound: Outbound) -> Poll<io::Result<()>> {
+        if let Some(e) = self.writer.error() {
+            return Poll::Ready(Err(e));
+        }
+        ready!(self.outbound.poll_ready_unpin(cx)).map_err(|_| self.writer.broken())?;
+
+        self.writer.queued();
+        self.outbound
+            .start_send_unpin(outbound)
+            .map_err(|_| self.writer.broken())?;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for DuplexSocket {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.read_pos < this.read_buf.len() {
+                let n = buf.remaining().min(this.read_buf.len() - this.read_pos);
+                buf.put_slice(&this.read_buf[this.read_pos..this.read_pos + n]);
+                this.read_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            if this.eof {
+                return Poll::Ready(Ok(()));
+            }
+
+            match ready!(this.frames.poll_next_unpin(cx)) {
+                Some(frame) => {
+                    this.eof = frame.fin;
+                    this.read_buf = frame.data;
+                    this.read_pos = 0;
+                }
+                None => this.eof = true,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for DuplexSocket {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.fin_sent {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "Socket shut down",
+            )));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let n = buf.len().min(SOCKET_FRAME_SIZE);
+        ready!(this.send(cx, Outbound::Data(buf[..n].to_vec())))?;
+        Poll::Ready(Ok(n))
+    }
+
+    /// Ready once the peer acknowledged every frame written so far
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.writer.poll_flushed(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.fin_sent {
+            ready!(this.send(cx, Outbound::Fin))?;
+            this.fin_sent = true;
+        }
+        Pin::new(this).poll_flush(cx)
+    }
+}
+
+impl Drop for DuplexSocket {
+    fn drop(&mut self) {
+        self.network_loop.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn frame(seq: u64) -> SocketFrame {
+        SocketFrame {
+            seq,
+            data: vec![seq as u8],
+            fin: false,
+        }
+    }
+
+    #[test]
+    fn test_reorder_releases_frames_in_sequence() {
+        let mut reorder = Reorder::default();
+        assert!(reorder.push(frame(1)).unwrap().is_empty());
+        assert!(reorder.push(frame(2)).unwrap().is_empty());
+
+        let ready = reorder.push(frame(0)).unwrap();
+        let seqs: Vec<u64> = ready.iter().map(|frame| frame.seq).collect();
+        assert_eq!(seqs, vec![0, 1, 2]);
+
+        // Resent frames are dropped
+        assert!(reorder.push(frame(1)).unwrap().is_empty());
+        assert_eq!(reorder.push(frame(3)).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_reorder_caps_early_frames() {
+        let mut reorder = Reorder::default();
+        for seq in 1..=MAX_EARLY_FRAMES as u64 {
+            reorder.push(frame(seq)).unwrap();
+        }
+        assert!(reorder.push(frame(MAX_EARLY_FRAMES as u64 + 1)).is_err());
+        assert_eq!(reorder.push(frame(0)).unwrap().len(), MAX_EARLY_FRAMES + 1);
+    }
+
+    #[tokio::test]
+    async fn test_bytes_pipe_through_socket() -> Result<(), VeilidDuplexError> {
+        let app = VeilidDuplex::new().await?;
+        let peer = VeilidDuplex::new().await?;
+        let mut writer = connect(&app, peer.our_dht_key)?;
+        let mut reader = connect(&peer, app.our_dht_key)?;
+
+        let mut sent = vec![0; 300 * 1024 + 17];
+        rand::thread_rng().fill_bytes(&mut sent);
+
+        let writing = {
+            let sent = sent.clone();
+            tokio::spawn(async move {
+                writer.write_all(&sent).await?;
+                writer.shutdown().await?;
+                io::Result::Ok(writer)
+            })
+        };
+
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).await?;
+        let writer = writing.await.unwrap()?;
+
+        assert_eq!(received.len(), sent.len());
+        assert!(received == sent);
+        assert_eq!(writer.writer.pending.load(Ordering::SeqCst), 0);
+
+        Ok(())
+    }
+}
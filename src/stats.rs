@@ -0,0 +1,224 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How far back the throughput in `VeilidDuplexStats` looks
+pub const THROUGHPUT_WINDOW: Duration = Duration::from_secs(10);
+const THROUGHPUT_SLICES: u64 = 10;
+const SLICE_MICROS: u64 = THROUGHPUT_WINDOW.as_micros() as u64 / THROUGHPUT_SLICES;
+
+/// Counters shared by a `VeilidDuplex` and its clones
+#[derive(Debug, Default)]
+pub struct StatsCounters {
+    messages_sent: AtomicU64,
+    send_retries: AtomicU64,
+    sends_exhausted: AtomicU64,
+    messages_received: AtomicU64,
+    duplicates_dropped: AtomicU64,
+    timestamp_rejected: AtomicU64,
+    signature_rejected: AtomicU64,
+    blocked_dropped: AtomicU64,
+    rate_limited: AtomicU64,
+    dead_routes: AtomicU64,
+    dedup_evictions: AtomicU64,
+    sent_window: Mutex<ThroughputWindow>,
+    received_window: Mutex<ThroughputWindow>,
+}
+
+/// Average rate over the last `THROUGHPUT_WINDOW`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Throughput {
+    pub messages_per_sec: f64,
+    pub bytes_per_sec: f64,
+}
+
+// Messages and bytes per time slice, in a ring covering `THROUGHPUT_WINDOW`
+#[derive(Debug, Default)]
+struct ThroughputWindow {
+    // (slice the counts are for, messages, bytes)
+    slices: [(u64, u64, u64); THROUGHPUT_SLICES as usize],
+}
+
+impl ThroughputWindow {
+    fn record(&mut self, bytes: usize, now: u64) {
+        let slice = now / SLICE_MICROS;
+        let counts = &mut self.slices[(slice % THROUGHPUT_SLICES) as usize];
+        if counts.0 != slice {
+            *counts = (slice, 0, 0);
+        }
+        counts.1 += 1;
+        counts.2 += bytes as u64;
+    }
+
+    fn rate(&self, now: u64) -> Throughput {
+        let current = now / SLICE_MICROS;
+        let (messages, bytes) = self
+            .slices
+            .iter()
+            .filter(|(slice, _, _)| *slice <= current && current - slice < THROUGHPUT_SLICES)
+            .fold((0, 0), |(messages, bytes), (_, m, b)| {
+                (messages + m, bytes + b)
+            });
+        let window = THROUGHPUT_WINDOW.as_secs_f64();
+
+        Throughput {
+            messages_per_sec: messages as f64 / window,
+            bytes_per_sec: bytes as f64 / window,
+        }
+    }
+}
+
+/// Point-in-time copy of the counters
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct VeilidDuplexStats {
+    /// Messages a peer replied to, whether it accepted or rejected them
+    pub messages_sent: u64,
+    /// Failed send attempts that were retried or gave up
+    pub send_retries: u64,
+    /// Messages given up on after the last attempt
+    pub sends_exhausted: u64,
+    /// Inbound messages that passed the timestamp and duplicate checks
+    pub messages_received: u64,
+    pub duplicates_dropped: u64,
+    /// Inbound messages stamped outside the accepted timestamp window
+    pub timestamp_rejected: u64,
+    /// Inbound messages dropped for a missing or bad signature
+    pub signature_rejected: u64,
+    /// Inbound messages from senders the peer filter doesn't let through
+    pub blocked_dropped: u64,
+    /// Inbound messages dropped for being over their sender's rate limit
+    pub rate_limited: u64,
+    /// Cached remote routes dropped after being reported dead
+    pub dead_routes: u64,
+    /// Message hashes evicted from the full dedup window before they expired.
+    /// A high rate means redeliveries may get through, see
+    /// `VeilidDuplex::set_dedup_capacity`.
+    pub dedup_evictions: u64,
+    /// Messages peers replied to, recently
+    pub send_rate: Throughput,
+    /// Messages counted in `messages_received`, recently
+    pub receive_rate: Throughput,
+}
+
+impl StatsCounters {
+    pub(crate) fn record_sent(&self, bytes: usize, now: u64) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+        self.sent_window.lock().unwrap().record(bytes, now);
+    }
+
+    pub(crate) fn record_retry(&self) {
+        self.send_retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_exhausted(&self) {
+        self.sends_exhausted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_received(&self, bytes: usize, now: u64) {
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+        self.received_window.lock().unwrap().record(bytes, now);
+    }
+
+    pub(crate) fn record_duplicate(&self) {
+        self.duplicates_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_timestamp_rejected(&self) {
+        self.timestamp_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_signature_rejected(&self) {
+        self.signature_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_blocked(&self) {
+        self.blocked_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_dead_route(&self) {
+        self.dead_routes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_dedup_eviction(&self) {
+        self.dedup_evictions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counters as they are, with throughput as of `now`
+    pub fn snapshot(&self, now: u64) -> VeilidDuplexStats {
+        VeilidDuplexStats {
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            send_retries: self.send_retries.load(Ordering::Relaxed),
+            sends_exhausted: self.sends_exhausted.load(Ordering::Relaxed),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            duplicates_dropped: self.duplicates_dropped.load(Ordering::Relaxed),
+            timestamp_rejected: self.timestamp_rejected.load(Ordering::Relaxed),
+            signature_rejected: self.signature_rejected.load(Ordering::Relaxed),
+            blocked_dropped: self.blocked_dropped.load(Ordering::Relaxed),
+            rate_limited: self.rate_limited.load(Ordering::Relaxed),
+            dead_routes: self.dead_routes.load(Ordering::Relaxed),
+            dedup_evictions: self.dedup_evictions.load(Ordering::Relaxed),
+            send_rate: self.sent_window.lock().unwrap().rate(now),
+            receive_rate: self.received_window.lock().unwrap().rate(now),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_reflects_counters() {
+        let counters = StatsCounters::default();
+        assert_eq!(counters.snapshot(0), VeilidDuplexStats::default());
+
+        for _ in 0..3 {
+            counters.record_sent(10, 0);
+        }
+        counters.record_retry();
+        counters.record_received(10, 0);
+        counters.record_duplicate();
+        counters.record_dedup_eviction();
+
+        let stats = counters.snapshot(0);
+        assert_eq!(stats.messages_sent, 3);
+        assert_eq!(stats.send_retries, 1);
+        assert_eq!(stats.sends_exhausted, 0);
+        assert_eq!(stats.messages_received, 1);
+        assert_eq!(stats.duplicates_dropped, 1);
+        assert_eq!(stats.timestamp_rejected, 0);
+        assert_eq!(stats.signature_rejected, 0);
+        assert_eq!(stats.blocked_dropped, 0);
+        assert_eq!(stats.rate_limited, 0);
+        assert_eq!(stats.dead_routes, 0);
+        assert_eq!(stats.dedup_evictions, 1);
+    }
+
+    #[test]
+    fn test_throughput_covers_the_window_only() {
+        const SECOND: u64 = 1_000_000;
+        let counters = StatsCounters::default();
+        for second in 0..10 {
+            counters.record_sent(100, second * SECOND);
+        }
+        counters.record_received(50, 3 * SECOND);
+
+        let stats = counters.snapshot(9 * SECOND);
+        assert_eq!(stats.send_rate.messages_per_sec, 1.0);
+        assert_eq!(stats.send_rate.bytes_per_sec, 100.0);
+        assert_eq!(stats.receive_rate.bytes_per_sec, 5.0);
+
+        // The first five seconds have left the window
+        let later = counters.snapshot(14 * SECOND);
+        assert_eq!(later.send_rate.messages_per_sec, 0.5);
+        assert_eq!(later.receive_rate, Throughput::default());
+        assert_eq!(
+            counters.snapshot(30 * SECOND).send_rate,
+            Throughput::default()
+        );
+    }
+}
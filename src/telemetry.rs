@@ -0,0 +1,313 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use flume::{bounded, Receiver, Sender};
+use serde::{Deserialize, Serialize};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use veilid_core::tools::*;
+use veilid_core::*;
+
+use crate::error::VeilidDuplexError;
+use crate::rate_limit::TokenBucket;
+use crate::veilid::{claim_if_due, AckStatus, DuplexSender};
+
+// Telemetry batches start with a magic that can't begin a serialized
+// AppMessage. The collector's network loop hands them to its telemetry sink
+// instead of `on_message`.
+const TELEMETRY_MAGIC: &[u8; 4] = b"\0VDL";
+/// Longest event message forwarded; longer ones are cut short
+pub const MAX_TELEMETRY_MESSAGE: usize = 512;
+/// Most events in one batch, so a batch fits in a single app_call
+pub const MAX_TELEMETRY_BATCH: usize = 32;
+
+/// A tracing event as forwarded to a collector
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TelemetryEvent {
+    /// When it was recorded, in veilid microsecond timestamps
+    pub timestamp: u64,
+    pub level: String,
+    pub target: String,
+    pub message: String,
+}
+
+/// Events from one node, as the collector receives them. `node` is the DHT
+/// record the sender says it has; batches aren't signed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TelemetryBatch {
+    pub node: CryptoTyped<CryptoKey>,
+    pub events: Vec<TelemetryEvent>,
+    /// Events lost since the previous batch because the layer's buffer was full
+    pub dropped: u64,
+}
+
+/// `tracing_subscriber` layer capturing events for `VeilidDuplex::set_telemetry`,
+/// see `telemetry_layer`
+pub struct TelemetryLayer {
+    level: Level,
+    events: Sender<TelemetryEvent>,
+    dropped: Arc<AtomicU64>,
+}
+
+/// Events captured by a `TelemetryLayer`, waiting to be forwarded
+#[derive(Clone)]
+pub struct TelemetrySource {
+    events: Receiver<TelemetryEvent>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl fmt::Debug for TelemetrySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TelemetrySource")
+            .field("pending", &self.events.len())
+            .field("dropped", &self.dropped.load(Ordering::SeqCst))
+            .finish()
+    }
+}
+
+/// A layer keeping events at `level` or more severe, and the source to forward
+/// them from. Past `capacity` events waiting, new ones are dropped and counted.
+/// Forwarding logs at `info`, so a `level` of `WARN` or above keeps it from
+/// feeding on itself.
+pub fn telemetry_layer(level: Level, capacity: usize) -> (TelemetryLayer, TelemetrySource) {
+    let (events, receiver) = bounded(capacity);
+    let dropped = Arc::new(AtomicU64::new(0));
+    let layer = TelemetryLayer {
+        level,
+        events,
+        dropped: dropped.clone(),
+    };
+
+    (
+        layer,
+        TelemetrySource {
+            events: receiver,
+            dropped,
+        },
+    )
+}
+
+// Picks the formatted message out of an event's fields
+#[derive(Default)]
+struct MessageVisitor(String);
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{:?}", value);
+        }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for TelemetryLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        if *metadata.level() > self.level {
+            return;
+        }
+
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+        let mut message = visitor.0;
+        if message.len() > MAX_TELEMETRY_MESSAGE {
+            let mut end = MAX_TELEMETRY_MESSAGE;
+            while !message.is_char_boundary(end) {
+                end -= 1;
+            }
+            message.truncate(end);
+        }
+
+        let event = TelemetryEvent {
+            timestamp: get_timestamp(),
+            level: metadata.level().to_string(),
+            target: metadata.target().to_string(),
+            message,
+        };
+        if self.events.try_send(event).is_err() {
+            self.dropped.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Where and how fast a node forwards its events, see `VeilidDuplex::set_telemetry`
+#[derive(Debug, Clone)]
+pub struct TelemetryConfig {
+    /// DHT record of the collecting node
+    pub collector: CryptoTyped<CryptoKey>,
+    pub source: TelemetrySource,
+    /// Time between batches
+    pub interval: Duration,
+    /// Events forwarded per second on average. The rest wait in the source,
+    /// and once that is full get dropped.
+    pub events_per_sec: u64,
+}
+
+impl TelemetryConfig {
+    pub fn new(collector: CryptoTyped<CryptoKey>, source: TelemetrySource) -> Self {
+        Self {
+            collector,
+            source,
+            interval: Duration::from_secs(5),
+            events_per_sec: 10,
+        }
+    }
+}
+
+/// Sends captured events to the collector in rate-limited batches, from the
+/// network loop. Clones share the schedule and the rate limit.
+#[derive(Debug, Clone)]
+pub struct TelemetryForwarder {
+    config: TelemetryConfig,
+    budget: Arc<Mutex<TokenBucket>>,
+    last_batch: Arc<AtomicU64>,
+}
+
+impl TelemetryForwarder {
+    pub fn new(config: TelemetryConfig, now: u64) -> Self {
+        let budget = TokenBucket::new(config.events_per_sec, MAX_TELEMETRY_BATCH as u64, now);
+        Self {
+            config,
+            budget: Arc::new(Mutex::new(budget)),
+            last_batch: Arc::new(AtomicU64::new(now)),
+        }
+    }
+
+    pub fn config(&self) -> &TelemetryConfig {
+        &self.config
+    }
+
+    /// Whether a batch is due at `now`, claiming it if so
+    pub(crate) fn claim_batch(&self, now: u64) -> bool {
+        claim_if_due(&self.last_batch, self.config.interval, now)
+    }
+
+    /// Events the rate limit lets through at `now`, or `None` with nothing to
+    /// report
+    pub(crate) fn take_batch(
+        &self,
+        node: CryptoTyped<CryptoKey>,
+        now: u64,
+    ) -> Option<TelemetryBatch> {
+        let mut events = Vec::new();
+        {
+            let mut budget = self.budget.lock().unwrap();
+            while events.len() < MAX_TELEMETRY_BATCH && budget.holds(1, now) {
+                let Ok(event) = self.config.source.events.try_recv() else {
+                    break;
+                };
+                budget.try_take(1, now);
+                events.push(event);
+            }
+        }
+        let dropped = self.config.source.dropped.swap(0, Ordering::SeqCst);
+        if events.is_empty() && dropped == 0 {
+            return None;
+        }
+
+        Some(TelemetryBatch {
+            node,
+            events,
+            dropped,
+        })
+    }
+
+    /// Send `batch` to the collector over its route, once
+    pub async fn send(
+        &self,
+        sender: &DuplexSender,
+        batch: &TelemetryBatch,
+    ) -> Result<(), VeilidDuplexError> {
+        let target = sender
+            .routes
+            .lock()
+            .await
+            .get_route(
+                self.config.collector,
+                &*sender.transport,
+                sender.routing_context.clone(),
+                sender.now(),
+            )
+            .await?;
+
+        let reply = sender
+            .transport
+            .app_call(target, encode_batch(batch)?)
+            .await?;
+        match AckStatus::from_reply(&reply) {
+            AckStatus::Accepted => Ok(()),
+            status => Err(VeilidDuplexError::Rejected(status.to_string())),
+        }
+    }
+}
+
+pub(crate) fn encode_batch(batch: &TelemetryBatch) -> Result<Vec<u8>, VeilidDuplexError> {
+    let mut blob = TELEMETRY_MAGIC.to_vec();
+    blob.extend_from_slice(&serde_json::to_vec(batch)?);
+    Ok(blob)
+}
+
+pub(crate) fn is_telemetry(message: &[u8]) -> bool {
+    message.starts_with(TELEMETRY_MAGIC)
+}
+
+pub(crate) fn decode_batch(message: &[u8]) -> Result<TelemetryBatch, VeilidDuplexError> {
+    Ok(serde_json::from_slice(&message[TELEMETRY_MAGIC.len()..])?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::CRYPTO_KIND;
+    use tracing::{info, warn};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_layer_keeps_severe_events_and_counts_overflow() {
+        let (layer, source) = telemetry_layer(Level::WARN, 2);
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            info!("not forwarded");
+            warn!("disk at {}%", 93);
+            warn!("{}", "x".repeat(2 * MAX_TELEMETRY_MESSAGE));
+            warn!("no room left");
+        });
+
+        let events: Vec<_> = source.events.try_iter().collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].message, "disk at 93%");
+        assert_eq!(events[0].level, "WARN");
+        assert_eq!(events[1].message.len(), MAX_TELEMETRY_MESSAGE);
+        assert_eq!(source.dropped.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_batches_follow_the_rate_limit() {
+        let (layer, source) = telemetry_layer(Level::WARN, 100);
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            for n in 0..50 {
+                warn!("event {}", n);
+            }
+        });
+
+        let node = CryptoTyped::new(CRYPTO_KIND, CryptoKey::new([1; 32]));
+        let mut config = TelemetryConfig::new(node, source);
+        config.events_per_sec = 2;
+        let forwarder = TelemetryForwarder::new(config, 0);
+
+        // The burst first, then what a second of the rate refills
+        let batch = forwarder.take_batch(node, 0).unwrap();
+        assert_eq!(batch.events.len(), MAX_TELEMETRY_BATCH);
+        assert!(forwarder.take_batch(node, 0).is_none());
+        let batch = forwarder.take_batch(node, 1_000_000).unwrap();
+        assert_eq!(batch.events.len(), 2);
+        assert_eq!(batch.events[0].message, "event 32");
+
+        let blob = encode_batch(&batch).unwrap();
+        assert!(is_telemetry(&blob));
+        assert_eq!(decode_batch(&blob).unwrap(), batch);
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde::de::DeserializeOwned;
+use veilid_core::*;
+
+use crate::veilid::{AppLogic, AppMessage, HandlerError};
+
+/// Callback run for each message published to a subscribed topic
+pub type TopicCallback<T> = Arc<dyn Fn(&AppMessage<T>) -> Result<(), HandlerError> + Send + Sync>;
+
+/// Handle returned by `TopicRouter::subscribe`, used to unsubscribe
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(u64);
+
+type Subscribers<T> = HashMap<String, Vec<(SubscriptionId, TopicCallback<T>)>>;
+
+/// `AppLogic` that hands each message to the callbacks subscribed to its
+/// `topic`. Messages without a topic, or on a topic nobody subscribed to, go to
+/// `fallback`. Clones share subscriptions, so they can change while the network
+/// loop runs.
+pub struct TopicRouter<T: DeserializeOwned, U> {
+    subscribers: Arc<Mutex<Subscribers<T>>>,
+    next_id: Arc<AtomicU64>,
+    fallback: U,
+}
+
+impl<T: DeserializeOwned, U> TopicRouter<T, U> {
+    pub fn new(fallback: U) -> Self {
+        Self {
+            subscribers: Arc::new(Mutex::new(HashMap::new())),
+            next_id: Arc::new(AtomicU64::new(0)),
+            fallback,
+        }
+    }
+
+    pub fn subscribe<F>(&self, topic: impl Into<String>, callback: F) -> SubscriptionId
+    where
+        F: Fn(&AppMessage<T>) -> Result<(), HandlerError> + Send + Sync + 'static,
+    {
+        let id = SubscriptionId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        self.subscribers
+            .lock()
+            .unwrap()
+            .entry(topic.into())
+            .or_default()
+            .push((id, Arc::new(callback)));
+        id
+    }
+
+    /// Returns false if the subscription was already gone
+    pub fn unsubscribe(&self, id: SubscriptionId) -> bool {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let mut found = false;
+        subscribers.retain(|_, callbacks| {
+            let before = callbacks.len();
+            callbacks.retain(|(sub, _)| *sub != id);
+            found |= callbacks.len() != before;
+            !callbacks.is_empty()
+        });
+        found
+    }
+
+    pub fn topics(&self) -> Vec<String> {
+        self.subscribers.lock().unwrap().keys().cloned().collect()
+    }
+
+    // Copied out so callbacks run without the lock and may (un)subscribe
+    fn callbacks_for(&self, topic: Option<&str>) -> Vec<TopicCallback<T>> {
+        let Some(topic) = topic else {
+            return Vec::new();
+        };
+
+        self.subscribers
+            .lock()
+            .unwrap()
+            .get(topic)
+            .map(|callbacks| callbacks.iter().map(|(_, cb)| cb.clone()).collect())
+            .unwrap_or_default()
+    }
+}
+
+impl<T: DeserializeOwned, U: Clone> Clone for TopicRouter<T, U> {
+    fn clone(&self) -> Self {
+        Self {
+            subscribers: self.subscribers.clone(),
+            next_id: self.next_id.clone(),
+            fallback: self.fallback.clone(),
+        }
+    }
+}
+
+impl<T, U> AppLogic<T> for TopicRouter<T, U>
+where
+    T: DeserializeOwned + Send,
+    U: AppLogic<T> + Send,
+{
+    async fn on_message(&mut self, message: AppMessage<T>) -> Result<(), HandlerError> {
+        let callbacks = self.callbacks_for(message.topic.as_deref());
+        if callbacks.is_empty() {
+            return self.fallback.on_message(message).await;
+        }
+
+        // Every subscriber sees the message; the first failure is reported
+        let mut result = Ok(());
+        for callback in callbacks {
+            if let Err(e) = callback(&message) {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+
+    fn on_remote_route_dead(&mut self, dht_record: CryptoTyped<CryptoKey>) {
+        self.fallback.on_remote_route_dead(dht_record);
+    }
+
+    fn on_local_route_changed(&mut self, new_route: CryptoKey) {
+        self.fallback.on_local_route_changed(new_route);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::CRYPTO_KIND;
+
+    #[derive(Clone, Default)]
+    struct Fallback {
+        seen: Arc<Mutex<Vec<u32>>>,
+    }
+
+    impl AppLogic<u32> for Fallback {
+        async fn on_message(&mut self, message: AppMessage<u32>) -> Result<(), HandlerError> {
+            self.seen.lock().unwrap().push(message.data);
+            Ok(())
+        }
+    }
+
+    fn message(data: u32, topic: Option<&str>) -> AppMessage<u32> {
+        AppMessage {
+            data,
+            uuid: String::new(),
+            dht_record: CryptoTyped::new(CRYPTO_KIND, CryptoKey::new([1; 32])),
+            reply_to: None,
+            timestamp: 0,
+            topic: topic.map(str::to_string),
+        }
+    }
+
+    fn collector(
+        router: &TopicRouter<u32, Fallback>,
+        topic: &str,
+    ) -> (SubscriptionId, Arc<Mutex<Vec<u32>>>) {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        let id = router.subscribe(topic, move |message: &AppMessage<u32>| {
+            sink.lock().unwrap().push(message.data);
+            Ok(())
+        });
+        (id, seen)
+    }
+
+    #[tokio::test]
+    async fn test_publish_reaches_every_subscriber_of_the_topic() {
+        let mut router = TopicRouter::new(Fallback::default());
+        let (_, first) = collector(&router, "news");
+        let (_, second) = collector(&router, "news");
+        let (_, other) = collector(&router, "sports");
+
+        router.on_message(message(1, Some("news"))).await.unwrap();
+        router.on_message(message(2, Some("sports"))).await.unwrap();
+
+        assert_eq!(*first.lock().unwrap(), vec![1]);
+        assert_eq!(*second.lock().unwrap(), vec![1]);
+        assert_eq!(*other.lock().unwrap(), vec![2]);
+        assert!(router.fallback.seen.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unsubscribe_stops_delivery() {
+        let mut router = TopicRouter::new(Fallback::default());
+        let (id, seen) = collector(&router, "news");
+        let (_, kept) = collector(&router, "news");
+
+        assert!(router.unsubscribe(id));
+        assert!(!router.unsubscribe(id));
+        router.on_message(message(1, Some("news"))).await.unwrap();
+
+        assert!(seen.lock().unwrap().is_empty());
+        assert_eq!(*kept.lock().unwrap(), vec![1]);
+    }
+
+    #[tokio::test]
+    async fn test_unmatched_messages_fall_through() {
+        let fallback = Fallback::default();
+        let mut router = TopicRouter::new(fallback.clone());
+        let (id, _) = collector(&router, "news");
+
+        router.on_message(message(1, None)).await.unwrap();
+        router
+            .on_message(message(2, Some("weather")))
+            .await
+            .unwrap();
+        router.unsubscribe(id);
+        router.on_message(message(3, Some("news"))).await.unwrap();
+
+        assert_eq!(*fallback.seen.lock().unwrap(), vec![1, 2, 3]);
+        assert!(router.topics().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_subscriber_error_is_reported() {
+        let mut router = TopicRouter::new(Fallback::default());
+        router.subscribe("news", |_: &AppMessage<u32>| Err(HandlerError::new("nope")));
+        let (_, seen) = collector(&router, "news");
+
+        let result = router.on_message(message(1, Some("news"))).await;
+        assert_eq!(result.unwrap_err().reason, "nope");
+        assert_eq!(*seen.lock().unwrap(), vec![1]);
+    }
+}
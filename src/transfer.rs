@@ -0,0 +1,524 @@
+use std::collections::HashMap;
+use std::hash::Hasher;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_std::io::ReadExt;
+use fnv::FnvHasher;
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+use tracing::info;
+use uuid::Uuid;
+use veilid_core::*;
+
+use crate::config::RetryPolicy;
+use crate::error::VeilidDuplexError;
+use crate::utils::retry_with_backoff;
+use crate::veilid::{AppLogic, AppMessage, DuplexSender, HandlerError};
+
+/// File bytes per frame. The chunking layer splits frames that don't fit one
+/// app_call.
+pub const FILE_FRAME_SIZE: usize = 32 * 1024;
+
+/// Resends of a frame whose delivery gave up, e.g. because the peer's route
+/// died mid-transfer
+pub const FRAME_RETRY: RetryPolicy = RetryPolicy {
+    attempts: 5,
+    initial_backoff: Duration::from_secs(1),
+    max_backoff: Duration::from_secs(16),
+};
+
+/// One piece of a file sent with `DuplexSender::send_file`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileFrame {
+    pub transfer_id: String,
+    /// File name, without directories
+    pub name: String,
+    pub size: u64,
+    /// FNV-1a of the whole file, checked once every frame is in. It catches
+    /// corruption, not tampering; sign messages for that.
+    pub checksum: u64,
+    pub index: u32,
+    pub total: u32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferProgress {
+    pub transfer_id: String,
+    pub name: String,
+    pub bytes_done: u64,
+    pub total_bytes: u64,
+}
+
+impl TransferProgress {
+    pub fn is_complete(&self) -> bool {
+        self.bytes_done == self.total_bytes
+    }
+}
+
+/// A file that arrived whole and passed its checksum
+#[derive(Debug, Clone)]
+pub struct ReceivedFile {
+    pub transfer_id: String,
+    pub sender: CryptoTyped<CryptoKey>,
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+pub type ProgressCallback = Arc<dyn Fn(&TransferProgress) + Send + Sync>;
+pub type CompleteCallback = Arc<dyn Fn(&ReceivedFile) + Send + Sync>;
+
+fn frame_count(size: u64) -> Result<u32, VeilidDuplexError> {
+    let frames = size.div_ceil(FILE_FRAME_SIZE as u64).max(1);
+    u32::try_from(frames).map_err(|_| invalid_input(format!("File of {} bytes is too large", size)))
+}
+
+fn invalid_input(reason: String) -> VeilidDuplexError {
+    VeilidDuplexError::Io(io::Error::new(io::ErrorKind::InvalidInput, reason))
+}
+
+// Fills `buf` unless the file ends first
+async fn read_frame(file: &mut async_std::fs::File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = file.read(&mut buf[filled..]).await?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    Ok(filled)
+}
+
+async fn checksum_of(path: &Path) -> io::Result<u64> {
+    let mut file = async_std::fs::File::open(path).await?;
+    let mut hasher = FnvHasher::default();
+    let mut buf = vec![0; FILE_FRAME_SIZE];
+    loop {
+        let n = read_frame(&mut file, &mut buf).await?;
+        if n == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.write(&buf[..n]);
+    }
+}
+
+pub(crate) async fn send_file<F>(
+    sender: &DuplexSender,
+    path: &Path,
+    remote_dht_record: CryptoTyped<CryptoKey>,
+    progress: F,
+) -> Result<String, VeilidDuplexError>
+where
+    F: Fn(&TransferProgress),
+{
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| invalid_input(format!("No file name in {}", path.display())))?
+        .to_string();
+    let size = async_std::fs::metadata(path).await?.len();
+    let checksum = checksum_of(path).await?;
+    let total = frame_count(size)?;
+    let transfer_id = Uuid::new_v4().to_string();
+
+    let mut file = async_std::fs::File::open(path).await?;
+    let mut buf = vec![0; FILE_FRAME_SIZE];
+    let mut bytes_done = 0;
+    for index in 0..total {
+        let n = read_frame(&mut file, &mut buf).await?;
+        let frame = FileFrame {
+            transfer_id: transfer_id.clone(),
+            name: name.clone(),
+            size,
+            checksum,
+            index,
+            total,
+            data: buf[..n].to_vec(),
+        };
+
+        // Frames go one at a time, each acknowledged before the next, so a
+        // transfer cut off by a dead route carries on from this frame once
+        // the peer is reachable again. A refusal is final.
+        retry_with_backoff("Sending file frame", FRAME_RETRY, |_| {
+            let app_message = AppMessage {
+                data: frame.clone(),
+                uuid: String::new(),
+                dht_record: sender.our_dht_key,
+                reply_to: None,
+                timestamp: 0,
+                topic: None,
+            };
+            async move {
+                match sender.send_message(app_message, remote_dht_record).await {
+                    Ok(_) => Ok(Ok(())),
+                    Err(e @ VeilidDuplexError::Rejected(_)) => Ok(Err(e)),
+                    Err(e) => Err(e),
+                }
+            }
+        })
+        .await??;
+
+        bytes_done += n as u64;
+        progress(&TransferProgress {
+            transfer_id: transfer_id.clone(),
+            name: name.clone(),
+            bytes_done,
+            total_bytes: size,
+        });
+    }
+
+    info!("Sent {} ({} bytes) to {}", name, size, remote_dht_record);
+    Ok(transfer_id)
+}
+
+struct PartialFile {
+    sender: CryptoTyped<CryptoKey>,
+    name: String,
+    size: u64,
+    checksum: u64,
+    total: u32,
+    received: Vec<bool>,
+    bytes_done: u64,
+    temp: NamedTempFile,
+}
+
+impl PartialFile {
+    fn matches(&self, sender: CryptoTyped<CryptoKey>, frame: &FileFrame) -> bool {
+        self.sender == sender
+            && self.name == frame.name
+            && self.size == frame.size
+            && self.checksum == frame.checksum
+            && self.total == frame.total
+    }
+
+    fn is_complete(&self) -> bool {
+        self.received.iter().all(|received| *received)
+    }
+}
+
+/// Receiving side of `DuplexSender::send_file`. Frames are written to a temp
+/// file in `dir`, which is checked against the sender's checksum and renamed to
+/// the sent file name once every frame is in, replacing any file of that name.
+/// Frames that arrive twice, as happens when a sender resends after a lost
+/// reply, are ignored. Clones share transfers in progress.
+#[derive(Clone)]
+pub struct FileReceiver {
+    dir: PathBuf,
+    transfers: Arc<Mutex<HashMap<String, PartialFile>>>,
+    on_progress: Option<ProgressCallback>,
+    on_complete: Option<CompleteCallback>,
+}
+
+impl FileReceiver {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            transfers: Arc::new(Mutex::new(HashMap::new())),
+            on_progress: None,
+            on_complete: None,
+        }
+    }
+
+    pub fn on_progress(
+        mut self,
+        callback: impl Fn(&TransferProgress) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_progress = Some(Arc::new(callback));
+        self
+    }
+
+    pub fn on_complete(mut self, callback: impl Fn(&ReceivedFile) + Send + Sync + 'static) -> Self {
+        self.on_complete = Some(Arc::new(callback));
+        self
+    }
+
+    pub fn in_progress(&self) -> usize {
+        self.transfers.lock().unwrap().len()
+    }
+
+    /// Drop a transfer in progress along with its temp file
+    pub fn cancel(&self, transfer_id: &str) -> bool {
+        self.transfers.lock().unwrap().remove(transfer_id).is_some()
+    }
+
+    /// Store a frame. Returns the file once its last frame is in and it
+    /// passed its checksum.
+    pub fn receive(
+        &self,
+        sender: CryptoTyped<CryptoKey>,
+        frame: FileFrame,
+    ) -> Result<Option<ReceivedFile>, VeilidDuplexError> {
+        let (progress, finished) = {
+            let mut transfers = self.transfers.lock().unwrap();
+            if !transfers.contains_key(&frame.transfer_id) {
+                let partial = self.start(sender, &frame)?;
+                transfers.insert(frame.transfer_id.clone(), partial);
+            }
+            let partial = transfers.get_mut(&frame.transfer_id).unwrap();
+            if !partial.matches(sender, &frame) {
+                return Err(invalid_input(format!(
+                    "Frame doesn't match transfer {}",
+                    frame.transfer_id
+                )));
+            }
+
+            write_frame(partial, &frame)?;
+            let progress = TransferProgress {
+                transfer_id: frame.transfer_id.clone(),
+                name: partial.name.clone(),
+                bytes_done: partial.bytes_done,
+                total_bytes: partial.size,
+            };
+            let finished = match partial.is_complete() {
+                true => transfers.remove(&frame.transfer_id),
+                false => None,
+            };
+            (progress, finished)
+        };
+
+        if let Some(on_progress) = &self.on_progress {
+            on_progress(&progress);
+        }
+        let Some(partial) = finished else {
+            return Ok(None);
+        };
+
+        let received = self.finish(frame.transfer_id, partial)?;
+        if let Some(on_complete) = &self.on_complete {
+            on_complete(&received);
+        }
+        Ok(Some(received))
+    }
+
+    fn start(
+        &self,
+        sender: CryptoTyped<CryptoKey>,
+        frame: &FileFrame,
+    ) -> Result<PartialFile, VeilidDuplexError> {
+        // Only the last component, so a sender can't write outside `dir`
+        let name = Path::new(&frame.name)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .filter(|name| *name == frame.name)
+            .ok_or_else(|| invalid_input(format!("Bad file name {:?}", frame.name)))?;
+        if frame.total != frame_count(frame.size)? {
+            return Err(invalid_input(format!(
+                "{} frames can't hold {} bytes",
+                frame.total, frame.size
+            )));
+        }
+
+        info!("Receiving {} ({} bytes) from {}", name, frame.size, sender);
+        Ok(PartialFile {
+            sender,
+            name: name.to_string(),
+            size: frame.size,
+            checksum: frame.checksum,
+            total: frame.total,
+            received: vec![false; frame.total as usize],
+            bytes_done: 0,
+            temp: NamedTempFile::new_in(&self.dir)?,
+        })
+    }
+
+    fn finish(
+        &self,
+        transfer_id: String,
+        mut partial: PartialFile,
+    ) -> Result<ReceivedFile, VeilidDuplexError> {
+        partial.temp.flush()?;
+        let mut file = partial.temp.reopen()?;
+        let mut hasher = FnvHasher::default();
+        let mut buf = vec![0; FILE_FRAME_SIZE];
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.write(&buf[..n]);
+        }
+        // Dropping the temp file deletes it
+        if hasher.finish() != partial.checksum {
+            return Err(invalid_input(format!(
+                "Checksum mismatch for {}",
+                partial.name
+            )));
+        }
+
+        let path = self.dir.join(&partial.name);
+        partial.temp.persist(&path).map_err(|e| e.error)?;
+        info!("Received {} ({} bytes)", path.display(), partial.size);
+
+        Ok(ReceivedFile {
+            transfer_id,
+            sender: partial.sender,
+            path,
+            size: partial.size,
+        })
+    }
+}
+
+fn write_frame(partial: &mut PartialFile, frame: &FileFrame) -> Result<(), VeilidDuplexError> {
+    let index = frame.index as usize;
+    if index >= partial.received.len() {
+        return Err(invalid_input(format!(
+            "Frame {} of {}",
+            frame.index, frame.total
+        )));
+    }
+    let offset = index as u64 * FILE_FRAME_SIZE as u64;
+    let expected = (partial.size - offset).min(FILE_FRAME_SIZE as u64);
+    if frame.data.len() as u64 != expected {
+        return Err(invalid_input(format!(
+            "Frame {} has {} bytes, expected {}",
+            frame.index,
+            frame.data.len(),
+            expected
+        )));
+    }
+    if partial.received[index] {
+        return Ok(());
+    }
+
+    let file = partial.temp.as_file_mut();
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(&frame.data)?;
+    partial.received[index] = true;
+    partial.bytes_done += expected;
+    Ok(())
+}
+
+impl AppLogic<FileFrame> for FileReceiver {
+    async fn on_message(&mut self, message: AppMessage<FileFrame>) -> Result<(), HandlerError> {
+        self.receive(message.dht_record, message.data)
+            .map(|_| ())
+            .map_err(|e| HandlerError::new(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::CRYPTO_KIND;
+    use crate::veilid::VeilidDuplex;
+    use rand::RngCore;
+
+    fn peer() -> CryptoTyped<CryptoKey> {
+        CryptoTyped::new(CRYPTO_KIND, CryptoKey::new([1; 32]))
+    }
+
+    fn frames(name: &str, contents: &[u8]) -> Vec<FileFrame> {
+        let mut hasher = FnvHasher::default();
+        hasher.write(contents);
+        let checksum = hasher.finish();
+        let total = frame_count(contents.len() as u64).unwrap();
+
+        (0..total)
+            .map(|index| {
+                let start = index as usize * FILE_FRAME_SIZE;
+                let end = (start + FILE_FRAME_SIZE).min(contents.len());
+                FileFrame {
+                    transfer_id: "transfer".to_string(),
+                    name: name.to_string(),
+                    size: contents.len() as u64,
+                    checksum,
+                    index,
+                    total,
+                    data: contents[start..end].to_vec(),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_out_of_order_and_repeated_frames() {
+        let dir = tempfile::tempdir().unwrap();
+        let receiver = FileReceiver::new(dir.path());
+        let contents: Vec<u8> = (0..FILE_FRAME_SIZE * 3 + 10).map(|i| i as u8).collect();
+        let mut frames = frames("data.bin", &contents);
+        frames.reverse();
+        let repeated = frames[0].clone();
+
+        let last = frames.pop().unwrap();
+        for frame in frames {
+            assert!(receiver.receive(peer(), frame).unwrap().is_none());
+        }
+        assert!(receiver.receive(peer(), repeated).unwrap().is_none());
+        assert_eq!(receiver.in_progress(), 1);
+
+        let received = receiver.receive(peer(), last).unwrap().unwrap();
+        assert_eq!(std::fs::read(received.path).unwrap(), contents);
+        assert_eq!(receiver.in_progress(), 0);
+    }
+
+    #[test]
+    fn test_corrupted_file_is_discarded() {
+        let dir = tempfile::tempdir().unwrap();
+        let receiver = FileReceiver::new(dir.path());
+        let mut frames = frames("data.bin", &[7; 100]);
+        frames[0].data[3] = 8;
+
+        assert!(receiver.receive(peer(), frames.remove(0)).is_err());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_names_with_directories_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let receiver = FileReceiver::new(dir.path());
+        for name in ["../escape", "/etc/passwd", "sub/file", ".."] {
+            let frame = frames(name, b"data").remove(0);
+            assert!(receiver.receive(peer(), frame).is_err(), "{}", name);
+        }
+        assert_eq!(receiver.in_progress(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_send_file_between_instances() -> Result<(), VeilidDuplexError> {
+        let mut contents = vec![0; 3 * 1024 * 1024 + 123];
+        rand::thread_rng().fill_bytes(&mut contents);
+        let source_dir = tempfile::tempdir()?;
+        let source = source_dir.path().join("payload.bin");
+        std::fs::write(&source, &contents)?;
+        let target_dir = tempfile::tempdir()?;
+
+        let mut app = VeilidDuplex::new().await?;
+        app.set_codec(crate::codec::MessageCodec::Bincode);
+        let peer = VeilidDuplex::new().await?;
+        let completed = Arc::new(Mutex::new(Vec::new()));
+        let sink = completed.clone();
+        let receiver = FileReceiver::new(target_dir.path())
+            .on_complete(move |file: &ReceivedFile| sink.lock().unwrap().push(file.clone()));
+        let peer_loop = peer.spawn_network_loop::<FileFrame, _>(receiver);
+
+        let updates = Arc::new(Mutex::new(Vec::new()));
+        let progress = updates.clone();
+        app.send_file(
+            &source,
+            peer.our_dht_key,
+            move |update: &TransferProgress| progress.lock().unwrap().push(update.bytes_done),
+        )
+        .await?;
+
+        let completed = completed.lock().unwrap().clone();
+        assert_eq!(completed.len(), 1);
+        assert_eq!(completed[0].sender, app.our_dht_key);
+        let received = std::fs::read(&completed[0].path)?;
+        assert_eq!(
+            checksum_of(&completed[0].path).await?,
+            checksum_of(&source).await?
+        );
+        assert_eq!(received, contents);
+
+        let updates = updates.lock().unwrap();
+        assert!(updates.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(*updates.last().unwrap(), contents.len() as u64);
+
+        peer_loop.abort();
+        Ok(())
+    }
+}
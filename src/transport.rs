@@ -0,0 +1,534 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use flume::{bounded, Sender};
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use veilid_core::*;
+
+use crate::error::VeilidDuplexError;
+use crate::utils::{import_route_blob, read_route_blobs};
+
+/// How long a loopback `app_call` waits for the peer to reply, like Veilid's
+/// own app_call timeout
+pub const LOOPBACK_CALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Network operations `VeilidDuplex` sends and resolves routes through.
+/// `VeilidTransport` goes over Veilid; `LoopbackNetwork` connects instances in
+/// the same process, for tests.
+pub trait Transport: Send + Sync {
+    /// Send `message` to `target` and wait for its reply
+    fn app_call(
+        &self,
+        target: Target,
+        message: Vec<u8>,
+    ) -> BoxFuture<'static, VeilidAPIResult<Vec<u8>>>;
+
+    /// Reply to an `AppCall` that came in through this transport
+    fn app_call_reply(
+        &self,
+        call_id: OperationId,
+        message: Vec<u8>,
+    ) -> BoxFuture<'static, VeilidAPIResult<()>>;
+
+    /// Values of the subkeys of a DHT record from `subkey` on, skipping
+    /// subkeys with no value
+    fn get_dht_values(
+        &self,
+        key: CryptoTyped<CryptoKey>,
+        subkey: ValueSubkey,
+        force_refresh: bool,
+    ) -> BoxFuture<'static, Result<Vec<(ValueSubkey, Vec<u8>)>, VeilidDuplexError>>;
+
+    fn set_dht_value(
+        &self,
+        key: CryptoTyped<CryptoKey>,
+        subkey: ValueSubkey,
+        data: Vec<u8>,
+        writer: KeyPair,
+    ) -> BoxFuture<'static, Result<(), VeilidDuplexError>>;
+
+    /// Turn a route blob read from DHT into something to `app_call`
+    fn import_route(&self, blob: &[u8]) -> Result<(Target, CryptoKey), VeilidDuplexError>;
+}
+
+/// `Transport` over a Veilid node
+#[derive(Clone)]
+pub struct VeilidTransport {
+    pub api: VeilidAPI,
+    pub routing_context: RoutingContext,
+}
+
+impl Transport for VeilidTransport {
+    fn app_call(
+        &self,
+        target: Target,
+        message: Vec<u8>,
+    ) -> BoxFuture<'static, VeilidAPIResult<Vec<u8>>> {
+        let routing_context = self.routing_context.clone();
+        async move { routing_context.app_call(target, message).await }.boxed()
+    }
+
+    fn app_call_reply(
+        &self,
+        call_id: OperationId,
+        message: Vec<u8>,
+    ) -> BoxFuture<'static, VeilidAPIResult<()>> {
+        let api = self.api.clone();
+        async move { api.app_call_reply(call_id, message).await }.boxed()
+    }
+
+    fn get_dht_values(
+        &self,
+        key: CryptoTyped<CryptoKey>,
+        subkey: ValueSubkey,
+        force_refresh: bool,
+    ) -> BoxFuture<'static, Result<Vec<(ValueSubkey, Vec<u8>)>, VeilidDuplexError>> {
+        let routing_context = self.routing_context.clone();
+        async move {
+            let dht_desc = routing_context.open_dht_record(key, None).await?;
+            let blobs =
+                read_route_blobs(routing_context.clone(), dht_desc, subkey, force_refresh).await;
+            routing_context.close_dht_record(key).await?;
+            blobs
+        }
+        .boxed()
+    }
+
+    fn set_dht_value(
+        &self,
+        key: CryptoTyped<CryptoKey>,
+        subkey: ValueSubkey,
+        data: Vec<u8>,
+        writer: KeyPair,
+    ) -> BoxFuture<'static, Result<(), VeilidDuplexError>> {
+        let routing_context = self.routing_context.clone();
+        async move {
+            routing_context.open_dht_record(key, Some(writer)).await?;
+            let result = routing_context.set_dht_value(key, subkey, data, None).await;
+            routing_context.close_dht_record(key).await?;
+            result?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn import_route(&self, blob: &[u8]) -> Result<(Target, CryptoKey), VeilidDuplexError> {
+        import_route_blob(&self.api, blob)
+    }
+}
+
+/// Simulated quality of the link to a loopback endpoint. Random draws come
+/// from the network's seeded generator, so a test sees the same losses and
+/// latencies every run.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LinkConditions {
+    /// Added to every call
+    pub latency: Duration,
+    /// Up to this much more latency, drawn per call
+    pub jitter: Duration,
+    /// Chance, 0 to 1, that a call never reaches the endpoint
+    pub request_loss: f64,
+    /// Chance, 0 to 1, that the endpoint handles a call but its reply is
+    /// lost, so the sender retries something already delivered
+    pub reply_loss: f64,
+}
+
+impl LinkConditions {
+    pub fn with_latency(mut self, latency: Duration, jitter: Duration) -> Self {
+        self.latency = latency;
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn with_loss(mut self, request_loss: f64, reply_loss: f64) -> Self {
+        self.request_loss = request_loss;
+        self.reply_loss = reply_loss;
+        self
+    }
+
+    fn is_perfect(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+// What happens to one call, drawn up front
+struct CallFate {
+    delay: Duration,
+    request_lost: bool,
+    reply_lost: bool,
+}
+
+#[derive(Default)]
+struct LoopbackState {
+    // Update channel of each instance, by its fake route
+    endpoints: HashMap<CryptoKey, Sender<VeilidUpdate>>,
+    // Endpoints whose calls fail for now
+    offline: HashSet<CryptoKey>,
+    // Simulated link to an endpoint, if not a perfect one
+    conditions: HashMap<CryptoKey, LinkConditions>,
+    // Seeded on first use, see `LoopbackNetwork::with_seed`
+    rng: Option<StdRng>,
+    records: HashMap<CryptoKey, BTreeMap<ValueSubkey, Vec<u8>>>,
+    // app_calls waiting for a reply, by call id
+    calls: HashMap<u64, Sender<Vec<u8>>>,
+}
+
+/// In-process stand-in for the Veilid network: app_calls are handed straight
+/// to the receiving instance's update channel, and the DHT is a map. Clones
+/// share the same network. See `VeilidDuplex::in_memory_pair`.
+#[derive(Clone, Default)]
+pub struct LoopbackNetwork {
+    state: Arc<Mutex<LoopbackState>>,
+    next_call_id: Arc<AtomicU64>,
+}
+
+impl LoopbackNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A network whose simulated losses and jitter are drawn from `seed`.
+    /// `new` uses seed 0.
+    pub fn with_seed(seed: u64) -> Self {
+        let network = Self::default();
+        network.state.lock().unwrap().rng = Some(StdRng::seed_from_u64(seed));
+        network
+    }
+
+    /// Deliver app_calls to `route` as `VeilidUpdate::AppCall` on `updates`.
+    /// Returns the blob to publish for it.
+    pub fn add_endpoint(&self, route: CryptoKey, updates: Sender<VeilidUpdate>) -> Vec<u8> {
+        self.state.lock().unwrap().endpoints.insert(route, updates);
+        route.bytes.to_vec()
+    }
+
+    /// Calls to `route` fail from now on, as with a dead private route
+    pub fn remove_endpoint(&self, route: CryptoKey) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .endpoints
+            .remove(&route)
+            .is_some()
+    }
+
+    /// Make calls to `route` fail until it is back online, as with a peer
+    /// that lost connectivity. Its route still resolves.
+    pub fn set_online(&self, route: CryptoKey, online: bool) {
+        let mut state = self.state.lock().unwrap();
+        match online {
+            true => state.offline.remove(&route),
+            false => state.offline.insert(route),
+        };
+    }
+
+    /// Hold calls to `route` for `delay` before delivering them, as with a
+    /// slow route. `Duration::ZERO` removes the delay.
+    pub fn set_delay(&self, route: CryptoKey, delay: Duration) {
+        let conditions = self.conditions(route);
+        self.set_conditions(route, conditions.with_latency(delay, Duration::ZERO));
+    }
+
+    /// Simulate a slow or lossy link to `route`. `LinkConditions::default()`
+    /// makes it perfect again.
+    pub fn set_conditions(&self, route: CryptoKey, conditions: LinkConditions) {
+        let mut state = self.state.lock().unwrap();
+        match conditions.is_perfect() {
+            true => state.conditions.remove(&route),
+            false => state.conditions.insert(route, conditions),
+        };
+    }
+
+    pub fn conditions(&self, route: CryptoKey) -> LinkConditions {
+        self.state
+            .lock()
+            .unwrap()
+            .conditions
+            .get(&route)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+impl LoopbackState {
+    fn draw_fate(&mut self, route: CryptoKey) -> CallFate {
+        let Some(conditions) = self.conditions.get(&route).copied() else {
+            return CallFate {
+                delay: Duration::ZERO,
+                request_lost: false,
+                reply_lost: false,
+            };
+        };
+
+        let rng = self.rng.get_or_insert_with(|| StdRng::seed_from_u64(0));
+        let jitter = match conditions.jitter.is_zero() {
+            true => Duration::ZERO,
+            false => conditions.jitter.mul_f64(rng.gen::<f64>()),
+        };
+        CallFate {
+            delay: conditions.latency + jitter,
+            request_lost: rng.gen_bool(conditions.request_loss.clamp(0.0, 1.0)),
+            reply_lost: rng.gen_bool(conditions.reply_loss.clamp(0.0, 1.0)),
+        }
+    }
+}
+
+impl Transport for LoopbackNetwork {
+    fn app_call(
+        &self,
+        target: Target,
+        message: Vec<u8>,
+    ) -> BoxFuture<'static, VeilidAPIResult<Vec<u8>>> {
+        let route = match target {
+            Target::PrivateRoute(route) => route,
+            Target::NodeId(node_id) => node_id.value,
+        };
+        let call_id = self.next_call_id.fetch_add(1, Ordering::Relaxed);
+        let (reply_sender, reply) = bounded(1);
+        let state = self.state.clone();
+
+        async move {
+            let fate = state.lock().unwrap().draw_fate(route);
+            if !fate.delay.is_zero() {
+                async_std::task::sleep(fate.delay).await;
+            }
+            // Fails as a timed out call would, without waiting the timeout out
+            if fate.request_lost {
+                return Err(VeilidAPIError::Timeout);
+            }
+
+            let endpoint = {
+                let mut state = state.lock().unwrap();
+                let Some(endpoint) = state.endpoints.get(&route).cloned() else {
+                    return Err(VeilidAPIError::invalid_target(format!(
+                        "No route {}",
+                        route
+                    )));
+                };
+                if state.offline.contains(&route) {
+                    return Err(VeilidAPIError::invalid_target(format!(
+                        "Route {} is offline",
+                        route
+                    )));
+                }
+                state.calls.insert(call_id, reply_sender);
+                endpoint
+            };
+
+            let call = VeilidAppCall::new(None, Some(route), message, OperationId::new(call_id));
+            if endpoint
+                .send(VeilidUpdate::AppCall(Box::new(call)))
+                .is_err()
+            {
+                state.lock().unwrap().calls.remove(&call_id);
+                return Err(VeilidAPIError::invalid_target(format!(
+                    "Route {} is gone",
+                    route
+                )));
+            }
+
+            let reply = async_std::future::timeout(LOOPBACK_CALL_TIMEOUT, reply.recv_async()).await;
+            state.lock().unwrap().calls.remove(&call_id);
+            match reply {
+                Ok(Ok(_)) if fate.reply_lost => Err(VeilidAPIError::Timeout),
+                Ok(Ok(reply)) => Ok(reply),
+                _ => Err(VeilidAPIError::Timeout),
+            }
+        }
+        .boxed()
+    }
+
+    fn app_call_reply(
+        &self,
+        call_id: OperationId,
+        message: Vec<u8>,
+    ) -> BoxFuture<'static, VeilidAPIResult<()>> {
+        let caller = self.state.lock().unwrap().calls.remove(&call_id.as_u64());
+        async move {
+            match caller {
+                Some(caller) => {
+                    let _ = caller.send(message);
+                    Ok(())
+                }
+                None => Err(VeilidAPIError::generic(format!("No call {}", call_id))),
+            }
+        }
+        .boxed()
+    }
+
+    fn get_dht_values(
+        &self,
+        key: CryptoTyped<CryptoKey>,
+        subkey: ValueSubkey,
+        _force_refresh: bool,
+    ) -> BoxFuture<'static, Result<Vec<(ValueSubkey, Vec<u8>)>, VeilidDuplexError>> {
+        let values = self
+            .state
+            .lock()
+            .unwrap()
+            .records
+            .get(&key.value)
+            .map(|record| {
+                record
+                    .range(subkey..)
+                    .map(|(subkey, data)| (*subkey, data.clone()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        async move { Ok(values) }.boxed()
+    }
+
+    fn set_dht_value(
+        &self,
+        key: CryptoTyped<CryptoKey>,
+        subkey: ValueSubkey,
+        data: Vec<u8>,
+        _writer: KeyPair,
+    ) -> BoxFuture<'static, Result<(), VeilidDuplexError>> {
+        self.state
+            .lock()
+            .unwrap()
+            .records
+            .entry(key.value)
+            .or_default()
+            .insert(subkey, data);
+        async move { Ok(()) }.boxed()
+    }
+
+    fn import_route(&self, blob: &[u8]) -> Result<(Target, CryptoKey), VeilidDuplexError> {
+        let route = CryptoKey::try_from(blob)
+            .map_err(|e| VeilidDuplexError::MalformedRoute(e.to_string()))?;
+        if !self.state.lock().unwrap().endpoints.contains_key(&route) {
+            return Err(VeilidDuplexError::RouteImport(
+                VeilidAPIError::invalid_target(format!("No route {}", route)),
+            ));
+        }
+
+        Ok((Target::PrivateRoute(route), route))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::CRYPTO_KIND;
+    use flume::unbounded;
+
+    #[tokio::test]
+    async fn test_app_call_reaches_endpoint_and_gets_reply() {
+        let network = LoopbackNetwork::new();
+        let (updates, receiver) = unbounded();
+        let route = CryptoKey::new([3; 32]);
+        let blob = network.add_endpoint(route, updates);
+        let (target, imported) = network.import_route(&blob).unwrap();
+        assert_eq!(imported, route);
+
+        let answering = {
+            let network = network.clone();
+            tokio::spawn(async move {
+                let Ok(VeilidUpdate::AppCall(call)) = receiver.recv_async().await else {
+                    panic!("expected an app_call");
+                };
+                assert_eq!(call.message(), b"ping");
+                network.app_call_reply(call.id(), b"pong".to_vec()).await
+            })
+        };
+
+        let reply = network.app_call(target, b"ping".to_vec()).await.unwrap();
+        assert_eq!(reply, b"pong");
+        answering.await.unwrap().unwrap();
+    }
+
+    // Answers every call to `route` with "pong", counting them
+    fn answer_calls(network: &LoopbackNetwork, route: CryptoKey) -> (Vec<u8>, Arc<AtomicU64>) {
+        let (updates, receiver) = unbounded();
+        let blob = network.add_endpoint(route, updates);
+        let answered = Arc::new(AtomicU64::new(0));
+        let network = network.clone();
+        let counted = answered.clone();
+        tokio::spawn(async move {
+            while let Ok(VeilidUpdate::AppCall(call)) = receiver.recv_async().await {
+                counted.fetch_add(1, Ordering::SeqCst);
+                let _ = network.app_call_reply(call.id(), b"pong".to_vec()).await;
+            }
+        });
+        (blob, answered)
+    }
+
+    async fn call_outcomes(seed: u64, conditions: LinkConditions) -> (Vec<bool>, u64) {
+        let network = LoopbackNetwork::with_seed(seed);
+        let route = CryptoKey::new([9; 32]);
+        let (blob, answered) = answer_calls(&network, route);
+        network.set_conditions(route, conditions);
+        let (target, _) = network.import_route(&blob).unwrap();
+
+        let mut outcomes = Vec::new();
+        for _ in 0..50 {
+            let reply = network.app_call(target, b"ping".to_vec()).await;
+            outcomes.push(reply.is_ok());
+        }
+        (outcomes, answered.load(Ordering::SeqCst))
+    }
+
+    #[tokio::test]
+    async fn test_loss_is_repeatable_per_seed() {
+        let lossy = LinkConditions::default().with_loss(0.3, 0.3);
+        let (outcomes, answered) = call_outcomes(7, lossy).await;
+        assert_eq!(call_outcomes(7, lossy).await, (outcomes.clone(), answered));
+        assert!(outcomes.contains(&true));
+        assert!(outcomes.contains(&false));
+        // Lost replies were still answered
+        let delivered = outcomes.iter().filter(|ok| **ok).count() as u64;
+        assert!(answered > delivered);
+
+        let (outcomes, answered) = call_outcomes(7, lossy.with_loss(1.0, 0.0)).await;
+        assert!(!outcomes.contains(&true));
+        assert_eq!(answered, 0);
+        let (outcomes, answered) = call_outcomes(7, lossy.with_loss(0.0, 1.0)).await;
+        assert!(!outcomes.contains(&true));
+        assert_eq!(answered, 50);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_route_fails() {
+        let network = LoopbackNetwork::new();
+        let route = CryptoKey::new([4; 32]);
+        assert!(network.import_route(&route.bytes).is_err());
+        assert!(network
+            .app_call(Target::PrivateRoute(route), b"ping".to_vec())
+            .await
+            .is_err());
+
+        let (updates, _receiver) = unbounded();
+        network.add_endpoint(route, updates);
+        assert!(network.remove_endpoint(route));
+        assert!(network.import_route(&route.bytes).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dht_values_from_subkey_on() {
+        let network = LoopbackNetwork::new();
+        let key = CryptoTyped::new(CRYPTO_KIND, CryptoKey::new([5; 32]));
+        let writer = KeyPair::new(PublicKey::new([6; 32]), SecretKey::new([7; 32]));
+        for subkey in [0, 1, 3] {
+            network
+                .set_dht_value(key, subkey, vec![subkey as u8], writer)
+                .await
+                .unwrap();
+        }
+
+        let values = network.get_dht_values(key, 1, false).await.unwrap();
+        assert_eq!(values, vec![(1, vec![1]), (3, vec![3])]);
+        let other = CryptoTyped::new(CRYPTO_KIND, CryptoKey::new([8; 32]));
+        assert!(network
+            .get_dht_values(other, 0, false)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
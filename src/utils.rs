@@ -1,14 +1,15 @@
 use std::hash::Hasher;
-use std::io;
+use std::io::Write;
 
-#[cfg(not(target_arch = "wasm32"))]
 use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
 
-use anyhow::{Context, Error, Ok};
 use base64::engine::general_purpose;
 use base64::Engine;
 use fnv::FnvHasher;
-use tracing::info;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
 
 #[cfg(not(target_arch = "wasm32"))]
 use uuid::Uuid;
@@ -21,47 +22,329 @@ use veilid_core::{
 
 #[cfg(not(target_arch = "wasm32"))]
 use crate::config::config_callback;
+use crate::config::{RetryPolicy, StartupWait, StartupWaits, VeilidDuplexConfig};
+use crate::error::VeilidDuplexError;
+use crate::transport::{Transport, VeilidTransport};
 
 pub const CRYPTO_KIND: CryptoKind = CRYPTO_KIND_VLD0;
+/// Subkey of the service DHT record holding our route blob
+pub const ROUTE_SUBKEY: ValueSubkey = 0;
+
+/// Retries of a route lookup. A service's route often isn't found for a few
+/// seconds after it is published.
+pub const ROUTE_LOOKUP_RETRY: RetryPolicy = RetryPolicy {
+    attempts: 3,
+    initial_backoff: Duration::from_secs(1),
+    max_backoff: Duration::from_secs(4),
+};
 
+/// Look a service's route up on DHT and import it, retrying per `retry` while
+/// the value is missing or doesn't import. Attempts after the first always
+/// refresh from the network.
 pub async fn get_service_route_from_dht(
     api: VeilidAPI,
     routing_context: RoutingContext,
     service_key: CryptoTyped<CryptoKey>,
+    subkey: ValueSubkey,
     force_refresh: bool,
-) -> Result<(Target, CryptoKey), Error> {
-    info!("Looking up route on DHT: {}", service_key);
-    let service_key = service_key;
+    retry: RetryPolicy,
+) -> Result<(Target, CryptoKey), VeilidDuplexError> {
+    let transport = VeilidTransport {
+        api,
+        routing_context: routing_context.clone(),
+    };
     let dht_desc = routing_context.open_dht_record(service_key, None).await?;
+    let result = get_service_route_from_open_record(
+        &transport,
+        routing_context.clone(),
+        dht_desc,
+        subkey,
+        force_refresh,
+        retry,
+    )
+    .await;
+    routing_context.close_dht_record(service_key).await?;
+
+    result
+}
 
-    let dht_val = routing_context
-        .get_dht_value(*dht_desc.key(), 0, force_refresh)
-        .await?
-        .ok_or(io::Error::new(io::ErrorKind::Other, "DHT value not found"))?
-        .data()
-        .to_vec();
+/// `get_service_route_from_dht` for a record the caller opened and closes
+pub(crate) async fn get_service_route_from_open_record(
+    transport: &dyn Transport,
+    routing_context: RoutingContext,
+    dht_desc: DHTRecordDescriptor,
+    subkey: ValueSubkey,
+    force_refresh: bool,
+    retry: RetryPolicy,
+) -> Result<(Target, CryptoKey), VeilidDuplexError> {
+    let service_key = *dht_desc.key();
+    info!("Looking up route on DHT: {}", service_key);
+    let (target, their_route) = lookup_route(
+        service_key,
+        subkey,
+        force_refresh,
+        retry,
+        |force_refresh| {
+            read_route_blobs(
+                routing_context.clone(),
+                dht_desc.clone(),
+                subkey,
+                force_refresh,
+            )
+        },
+        |blob| transport.import_route(blob),
+    )
+    .await?;
+    info!("Looking up route on DHT, done: {:?}", their_route);
+
+    Ok((target, their_route))
+}
+
+/// `get_service_route_from_dht` through a `Transport`
+pub(crate) async fn get_service_route(
+    transport: &dyn Transport,
+    service_key: CryptoTyped<CryptoKey>,
+    subkey: ValueSubkey,
+    force_refresh: bool,
+    retry: RetryPolicy,
+) -> Result<(Target, CryptoKey), VeilidDuplexError> {
+    info!("Looking up route: {}", service_key);
+    lookup_route(
+        service_key,
+        subkey,
+        force_refresh,
+        retry,
+        |force_refresh| transport.get_dht_values(service_key, subkey, force_refresh),
+        |blob| transport.import_route(blob),
+    )
+    .await
+}
+
+/// DHT records of peers kept open between route lookups, so resolving a peer
+/// again doesn't open and close its record each time
+#[derive(Debug, Clone, Default)]
+pub struct OpenRecords {
+    records: HashMap<CryptoKey, DHTRecordDescriptor>,
+    // Records opened so far, for telling cache hits from misses
+    opened: u64,
+}
+
+impl OpenRecords {
+    pub(crate) async fn open(
+        &mut self,
+        routing_context: &RoutingContext,
+        service_key: CryptoTyped<CryptoKey>,
+    ) -> Result<DHTRecordDescriptor, VeilidDuplexError> {
+        if let Some(dht_desc) = self.records.get(&service_key.value) {
+            return Ok(dht_desc.clone());
+        }
+
+        let dht_desc = routing_context.open_dht_record(service_key, None).await?;
+        self.opened += 1;
+        self.records.insert(service_key.value, dht_desc.clone());
+
+        Ok(dht_desc)
+    }
+
+    /// Stop tracking a record that was closed elsewhere, e.g. along with a
+    /// watch on it
+    pub(crate) fn forget(&mut self, service_key: CryptoTyped<CryptoKey>) -> bool {
+        self.records.remove(&service_key.value).is_some()
+    }
+
+    pub(crate) async fn close(
+        &mut self,
+        routing_context: &RoutingContext,
+        service_key: CryptoTyped<CryptoKey>,
+    ) -> Result<(), VeilidDuplexError> {
+        if self.forget(service_key) {
+            routing_context.close_dht_record(service_key).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Close every record, carrying on past failures
+    pub(crate) async fn close_all(&mut self, routing_context: &RoutingContext) {
+        for (_, dht_desc) in self.records.drain() {
+            if let Err(e) = routing_context.close_dht_record(*dht_desc.key()).await {
+                info!("Unable to close DHT record {}: {}", dht_desc.key(), e);
+            }
+        }
+    }
+
+    pub fn opened(&self) -> u64 {
+        self.opened
+    }
+}
+
+async fn lookup_route<R, F, Fut>(
+    service_key: CryptoTyped<CryptoKey>,
+    subkey: ValueSubkey,
+    force_refresh: bool,
+    retry: RetryPolicy,
+    mut read_blobs: F,
+    import: impl Fn(&[u8]) -> Result<R, VeilidDuplexError>,
+) -> Result<R, VeilidDuplexError>
+where
+    F: FnMut(bool) -> Fut,
+    Fut: std::future::Future<Output = Result<Vec<(ValueSubkey, Vec<u8>)>, VeilidDuplexError>>,
+{
+    let import = &import;
+    retry_with_backoff("Looking up route", retry, |attempt| {
+        let blobs = read_blobs(force_refresh || attempt > 1);
+        async move {
+            import_first_route(blobs.await?, import).map_err(|e| {
+                let e = e.unwrap_or(VeilidDuplexError::DhtValueNotFound {
+                    key: service_key,
+                    subkey,
+                });
+                info!("No usable route in {}: {}", service_key, e);
+                e
+            })
+        }
+    })
+    .await
+}
+
+pub(crate) async fn read_route_blobs(
+    routing_context: RoutingContext,
+    dht_desc: DHTRecordDescriptor,
+    subkey: ValueSubkey,
+    force_refresh: bool,
+) -> Result<Vec<(ValueSubkey, Vec<u8>)>, VeilidDuplexError> {
+    let service_key = *dht_desc.key();
+
+    // Services publishing several routes put the extra ones in the subkeys
+    // after `subkey`, to be tried in order
+    let mut blobs = Vec::new();
+    for route_subkey in subkey..=dht_desc.schema().max_subkey() {
+        match routing_context
+            .get_dht_value(*dht_desc.key(), route_subkey, force_refresh)
+            .await
+        {
+            Result::Ok(Some(value)) => blobs.push((route_subkey, value.data().to_vec())),
+            Result::Ok(None) => (),
+            Err(e) => info!(
+                "Reading subkey {} of {} failed: {}",
+                route_subkey, service_key, e
+            ),
+        }
+    }
+
+    Ok(blobs)
+}
+
+/// Import the first route blob that works. Fails with the last import error,
+/// or `None` when there were no blobs.
+fn import_first_route<R>(
+    blobs: Vec<(ValueSubkey, Vec<u8>)>,
+    mut import: impl FnMut(&[u8]) -> Result<R, VeilidDuplexError>,
+) -> Result<R, Option<VeilidDuplexError>> {
+    let mut last_error = None;
+    for (subkey, blob) in blobs {
+        match import(&blob) {
+            Result::Ok(imported) => return Ok(imported),
+            Err(e) => {
+                info!(
+                    "Route in subkey {} ({} bytes) is unusable: {}",
+                    subkey,
+                    blob.len(),
+                    e
+                );
+                last_error = Some(e);
+            }
+        }
+    }
+
+    Err(last_error)
+}
+
+// Published instead of a route blob by services reachable by node id
+const NODE_ID_PREFIX: &[u8] = b"node:";
+
+/// Blob a service addressed by node id publishes in place of its route
+pub fn node_id_blob(node_id: CryptoTyped<CryptoKey>) -> Vec<u8> {
+    [NODE_ID_PREFIX, node_id.to_string().as_bytes()].concat()
+}
+
+/// Direct target for a blob from `node_id_blob`, `None` for other blobs
+fn node_id_target(blob: &[u8]) -> Option<Result<(Target, CryptoKey), VeilidDuplexError>> {
+    let node_id = blob.strip_prefix(NODE_ID_PREFIX)?;
+    let node_id = std::str::from_utf8(node_id)
+        .map_err(|e| e.to_string())
+        .and_then(|node_id| CryptoTyped::<CryptoKey>::from_str(node_id).map_err(|e| e.to_string()))
+        .map_err(VeilidDuplexError::MalformedRoute);
+
+    Some(node_id.map(|node_id| (Target::NodeId(node_id), node_id.value)))
+}
+
+/// Import a route blob as published to DHT: the base64 of an exported private
+/// route, or a node id from `node_id_blob`. DHT values come from any peer, so
+/// garbage is an error, not a panic.
+pub fn import_route_blob(
+    api: &VeilidAPI,
+    blob: &[u8],
+) -> Result<(Target, CryptoKey), VeilidDuplexError> {
+    if let Some(target) = node_id_target(blob) {
+        return target;
+    }
 
-    routing_context.close_dht_record(*dht_desc.key()).await?;
     let their_route_blob = general_purpose::STANDARD_NO_PAD
-        .decode(String::from_utf8(dht_val)?)
-        .unwrap();
-    let their_route = api.import_remote_private_route(their_route_blob.clone())?;
-    info!("Looking up route on DHT, done: {:?}", their_route);
+        .decode(blob)
+        .map_err(|e| VeilidDuplexError::MalformedRoute(e.to_string()))?;
+    let their_route = api
+        .import_remote_private_route(their_route_blob)
+        .map_err(VeilidDuplexError::RouteImport)?;
 
-    let target = veilid_core::Target::PrivateRoute(their_route);
+    Ok((veilid_core::Target::PrivateRoute(their_route), their_route))
+}
 
-    Ok((target, their_route))
+/// Watch a peer's route subkey for changes, reported as
+/// `VeilidUpdate::ValueChange`. The record stays open while watched. Returns
+/// when the watch expires, as a veilid timestamp.
+pub(crate) async fn watch_service_route(
+    routing_context: &RoutingContext,
+    service_key: CryptoTyped<CryptoKey>,
+    subkey: ValueSubkey,
+) -> Result<u64, VeilidDuplexError> {
+    info!("Watching route on DHT: {}", service_key);
+    routing_context.open_dht_record(service_key, None).await?;
+    let expiration = routing_context
+        .watch_dht_values(
+            service_key,
+            ValueSubkeyRangeSet::single(subkey),
+            Timestamp::default(),
+            u32::MAX,
+        )
+        .await?;
+
+    Ok(expiration.as_u64())
+}
+
+pub(crate) async fn unwatch_service_route(
+    routing_context: &RoutingContext,
+    service_key: CryptoTyped<CryptoKey>,
+    subkey: ValueSubkey,
+) -> Result<(), VeilidDuplexError> {
+    info!("No longer watching route on DHT: {}", service_key);
+    routing_context
+        .cancel_dht_watch(service_key, ValueSubkeyRangeSet::single(subkey))
+        .await?;
+    routing_context.close_dht_record(service_key).await?;
+
+    Ok(())
 }
 
-pub(crate) async fn create_private_route(api: VeilidAPI) -> Result<(CryptoKey, Vec<u8>), Error> {
+pub(crate) async fn create_private_route(
+    api: VeilidAPI,
+    crypto_kind: CryptoKind,
+    stability: Stability,
+    sequencing: Sequencing,
+) -> Result<(CryptoKey, Vec<u8>), VeilidDuplexError> {
     let (route_id, blob) = api
-        .new_custom_private_route(
-            &[CRYPTO_KIND],
-            veilid_core::Stability::Reliable,
-            veilid_core::Sequencing::PreferOrdered,
-        )
-        .await
-        .context("new_custom_private_route")?;
+        .new_custom_private_route(&[crypto_kind], stability, sequencing)
+        .await?;
 
     let blob = general_purpose::STANDARD_NO_PAD
         .encode(blob)
@@ -71,246 +354,280 @@ pub(crate) async fn create_private_route(api: VeilidAPI) -> Result<(CryptoKey, V
     Ok((route_id, blob))
 }
 
-pub(crate) async fn wait_for_attached(api: &VeilidAPI) -> Result<(), Error> {
-    info!("Awaiting attachment");
+/// Whether the node is attached well enough to route messages
+pub(crate) fn is_attached(state: AttachmentState) -> bool {
+    matches!(
+        state,
+        AttachedWeak | AttachedGood | AttachedStrong | FullyAttached | OverAttached
+    )
+}
+
+/// Whether messages can be sent and received: attached and reachable from the
+/// public internet
+pub(crate) fn is_ready(state: AttachmentState, public_internet_ready: bool) -> bool {
+    is_attached(state) && public_internet_ready
+}
+
+/// Poll `check` every `wait.poll_interval` until it holds, failing with
+/// `StartupTimeout` once `wait.timeout` passes
+pub(crate) async fn wait_until<F, Fut>(
+    phase: &'static str,
+    wait: StartupWait,
+    mut check: F,
+) -> Result<(), VeilidDuplexError>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<bool, VeilidDuplexError>>,
+{
+    info!("Awaiting {}", phase);
+    let started_at = get_timestamp();
     loop {
-        let state = api.get_state().await?;
-        match state.attachment.state {
-            AttachedWeak | AttachedGood | AttachedStrong | FullyAttached | OverAttached => {
-                info!("Awaiting attachment, done");
-                return Ok(());
+        if check().await? {
+            info!("Awaiting {}, done", phase);
+            return Ok(());
+        }
+        if let Some(timeout) = wait.timeout {
+            if get_timestamp().saturating_sub(started_at) >= timeout.as_micros() as u64 {
+                return Err(VeilidDuplexError::StartupTimeout { phase, timeout });
             }
-            _ => (),
         }
-        sleep(1000).await;
+        sleep(wait.poll_interval.as_millis() as u32).await;
     }
 }
 
-pub(crate) async fn wait_for_network_start(api: &VeilidAPI) -> Result<(), Error> {
-    info!("awaiting network initialization");
-    loop {
+pub(crate) async fn wait_for_attached(
+    api: &VeilidAPI,
+    wait: StartupWait,
+) -> Result<(), VeilidDuplexError> {
+    wait_until("attachment", wait, || async move {
+        Ok(is_attached(api.get_state().await?.attachment.state))
+    })
+    .await
+}
+
+pub(crate) async fn wait_for_network_start(
+    api: &VeilidAPI,
+    wait: StartupWait,
+) -> Result<(), VeilidDuplexError> {
+    wait_until("network initialization", wait, || async move {
         let vs = api.get_state().await?;
-        if vs.network.started && !vs.network.peers.is_empty() {
-            info!(
-                "Awaiting network initialization, done ({} peer(s))",
-                vs.network.peers.len()
-            );
-            return Ok(());
-        }
-        sleep(100).await;
-    }
+        Ok(vs.network.started && !vs.network.peers.is_empty())
+    })
+    .await
+}
+
+pub(crate) async fn wait_for_public_internet_ready(
+    api: &VeilidAPI,
+    wait: StartupWait,
+) -> Result<(), VeilidDuplexError> {
+    wait_until("'public_internet_ready'", wait, || async move {
+        Ok(api.get_state().await?.attachment.public_internet_ready)
+    })
+    .await
 }
 
-pub(crate) async fn wait_for_public_internet_ready(api: &VeilidAPI) -> Result<(), Error> {
-    info!("Awaiting 'public_internet_ready'");
+/// Run `step` until it succeeds or `retry.attempts` are used up, returning
+/// the last error. `run` gets the attempt number, starting at 1.
+pub(crate) async fn retry_with_backoff<R, F, Fut>(
+    step: &'static str,
+    retry: RetryPolicy,
+    mut run: F,
+) -> Result<R, VeilidDuplexError>
+where
+    F: FnMut(u16) -> Fut,
+    Fut: std::future::Future<Output = Result<R, VeilidDuplexError>>,
+{
+    let mut backoff = retry.initial_backoff;
+    let mut attempt = 1;
     loop {
-        let state = api.get_state().await?;
-        if state.attachment.public_internet_ready {
-            break;
+        info!("{} (attempt {}/{})", step, attempt, retry.attempts);
+        match run(attempt).await {
+            Ok(result) => return Ok(result),
+            Err(e) if attempt < retry.attempts => {
+                info!("{} failed: {}, retrying in {:?}", step, e, backoff);
+                sleep(backoff.as_millis() as u32).await;
+                backoff = (backoff * 2).min(retry.max_backoff);
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
         }
-        sleep(5000).await;
     }
+}
 
-    info!("Awaiting 'public_internet_ready', done");
-    Ok(())
+async fn wait_until_online(api: &VeilidAPI, waits: StartupWaits) -> Result<(), VeilidDuplexError> {
+    wait_for_network_start(api, waits.network_start).await?;
+    wait_for_attached(api, waits.attached).await?;
+    wait_for_public_internet_ready(api, waits.public_internet_ready).await
 }
 
 #[cfg(not(target_arch = "wasm32"))]
 pub(crate) async fn create_api_and_connect_with_keypair(
     update_callback: UpdateCallback,
     key_pair: KeyPair,
-) -> Result<VeilidAPI, Error> {
-    let id = Uuid::new_v4();
-    let veilid_storage_dir = tempfile::tempdir()?
-        .path()
-        .join(Path::new(&id.to_string()))
-        .to_path_buf();
+    config: VeilidDuplexConfig,
+) -> Result<VeilidAPI, VeilidDuplexError> {
+    let startup_waits = config.startup_waits;
+    let api = start_api_with_keypair(update_callback, key_pair, config).await?;
+
+    // Network
+    api.attach().await?;
+    wait_until_online(&api, startup_waits).await?;
+
+    Ok(api)
+}
+
+/// Start a Veilid core without attaching it to the network
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) async fn start_api_with_keypair(
+    update_callback: UpdateCallback,
+    key_pair: KeyPair,
+    config: VeilidDuplexConfig,
+) -> Result<VeilidAPI, VeilidDuplexError> {
+    let veilid_storage_dir = match config.persistent_storage_dir() {
+        Some(storage_dir) => {
+            std::fs::create_dir_all(&storage_dir)?;
+            storage_dir
+        }
+        None => {
+            let id = Uuid::new_v4();
+            tempfile::tempdir()?
+                .path()
+                .join(Path::new(&id.to_string()))
+                .to_path_buf()
+        }
+    };
 
     let config_callback = Arc::new(move |key| {
         config_callback(
             veilid_storage_dir.clone(),
-            CryptoTyped::new(CRYPTO_KIND, key_pair),
+            CryptoTyped::new(config.crypto_kind, key_pair),
+            &config,
             key,
         )
     });
 
-    let api = api_startup(update_callback, config_callback).await?;
-
-    // Network
-    api.attach().await?;
-    wait_for_network_start(&api).await?;
-    wait_for_attached(&api).await?;
-    wait_for_public_internet_ready(&api).await?;
-
-    Ok(api)
+    Ok(api_startup(update_callback, config_callback).await?)
 }
 
 #[cfg(target_arch = "wasm32")]
 pub(crate) async fn create_api_and_connect(
     update_callback: UpdateCallback,
-) -> Result<VeilidAPI, Error> {
-    let config = r#"
-    {
-        "program_name":"veilid_duplex",
-        "namespace":"",
-        "capabilities":{
-           "disable":[
-              
-           ]
-        },
-        "protected_store":{
-           "allow_insecure_fallback":true,
-           "always_use_insecure_storage":true,
-           "directory":"",
-           "delete":false,
-           "device_encryption_key_password":"none"
-        },
-        "table_store":{
-           "directory":"",
-           "delete":false
-        },
-        "block_store":{
-           "directory":"",
-           "delete":false
-        },
-        "network":{
-           "connection_initial_timeout_ms":2000,
-           "connection_inactivity_timeout_ms":60000,
-           "max_connections_per_ip4":32,
-           "max_connections_per_ip6_prefix":32,
-           "max_connections_per_ip6_prefix_size":56,
-           "max_connection_frequency_per_min":128,
-           "client_whitelist_timeout_ms":300000,
-           "reverse_connection_receipt_time_ms":5000,
-           "hole_punch_receipt_time_ms":5000,
-           "network_key_password":"",
-           "disable_capabilites":[
-              
-           ],
-           "routing_table":{
-              "node_id":[
-                 
-              ],
-              "node_id_secret":[
-                 
-              ],
-              "bootstrap":[
-                 "ws://bootstrap.veilid.net:5150/ws"
-              ],
-              "limit_over_attached":64,
-              "limit_fully_attached":32,
-              "limit_attached_strong":16,
-              "limit_attached_good":8,
-              "limit_attached_weak":4
-           },
-           "rpc":{
-              "concurrency":0,
-              "queue_size":1024,
-              "max_timestamp_behind_ms":10000,
-              "max_timestamp_ahead_ms":10000,
-              "timeout_ms":5000,
-              "max_route_hop_count":4,
-              "default_route_hop_count":1
-           },
-           "dht":{
-              "max_find_node_count":20,
-              "resolve_node_timeout_ms":10000,
-              "resolve_node_count":1,
-              "resolve_node_fanout":4,
-              "get_value_timeout_ms":10000,
-              "get_value_count":3,
-              "get_value_fanout":4,
-              "set_value_timeout_ms":10000,
-              "set_value_count":5,
-              "set_value_fanout":4,
-              "min_peer_count":20,
-              "min_peer_refresh_time_ms":60000,
-              "validate_dial_info_receipt_time_ms":2000,
-              "local_subkey_cache_size":128,
-              "local_max_subkey_cache_memory_mb":256,
-              "remote_subkey_cache_size":1024,
-              "remote_max_records":65536,
-              "remote_max_subkey_cache_memory_mb":256,
-              "remote_max_storage_space_mb":0
-           },
-           "upnp":true,
-           "detect_address_changes":true,
-           "restricted_nat_retries":0,
-           "tls":{
-              "certificate_path":"",
-              "private_key_path":"",
-              "connection_initial_timeout_ms":2000
-           },
-           "application":{
-              "https":{
-                 "enabled":false,
-                 "listen_address":":5150",
-                 "path":"app"
-              },
-              "http":{
-                 "enabled":false,
-                 "listen_address":":5150",
-                 "path":"app"
-              }
-           },
-           "protocol":{
-              "udp":{
-                 "enabled":false,
-                 "socket_pool_size":0,
-                 "listen_address":""
-              },
-              "tcp":{
-                 "connect":false,
-                 "listen":false,
-                 "max_connections":32,
-                 "listen_address":""
-              },
-              "ws":{
-                 "connect":true,
-                 "listen":true,
-                 "max_connections":16,
-                 "listen_address":":5150",
-                 "path":"ws"
-              },
-              "wss":{
-                 "connect":true,
-                 "listen":false,
-                 "max_connections":16,
-                 "listen_address":"",
-                 "path":"ws"
-              }
-           }
-        }
-     }
-    "#
-    .to_string();
+    config: VeilidDuplexConfig,
+) -> Result<VeilidAPI, VeilidDuplexError> {
+    let json_config = config.veilid_json(None, None);
 
-    let api = api_startup_json(update_callback, config.clone()).await?;
+    let api = api_startup_json(update_callback, json_config.to_string()).await?;
 
     // Network
     api.attach().await?;
-    wait_for_network_start(&api).await?;
-    wait_for_attached(&api).await?;
-    wait_for_public_internet_ready(&api).await?;
+    wait_until_online(&api, config.startup_waits).await?;
 
     Ok(api)
 }
 
+/// Read the node keypair from `path`, or generate one and save it there
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn load_or_create_keypair(
+    path: &Path,
+    crypto_kind: CryptoKind,
+) -> Result<KeyPair, VeilidDuplexError> {
+    if path.exists() {
+        info!("Loading node keypair from {}", path.display());
+        let keypair = std::fs::read_to_string(path)?;
+        return Ok(KeyPair::from_str(keypair.trim())?);
+    }
+
+    info!("Saving new node keypair to {}", path.display());
+    let keypair = veilid_core::Crypto::generate_keypair(crypto_kind)?.value;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(path)?
+        .write_all(keypair.to_string().as_bytes())?;
+
+    Ok(keypair)
+}
+
+/// Address of our service and the keys to update it. Reusing them keeps the
+/// address peers know us by stable across restarts.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ServiceKeys {
+    pub dht_key: CryptoTyped<CryptoKey>,
+    pub dht_owner_key: PublicKey,
+    pub dht_owner_secret_key: SecretKey,
+}
+
+impl ServiceKeys {
+    pub fn new(dht_key: CryptoTyped<CryptoKey>, dht_owner_keypair: KeyPair) -> Self {
+        Self {
+            dht_key,
+            dht_owner_key: dht_owner_keypair.key,
+            dht_owner_secret_key: dht_owner_keypair.secret,
+        }
+    }
+
+    pub fn dht_owner_keypair(&self) -> KeyPair {
+        KeyPair::new(self.dht_owner_key, self.dht_owner_secret_key)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, VeilidDuplexError> {
+        let keys = std::fs::read(path)?;
+        Ok(serde_json::from_slice(&keys)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), VeilidDuplexError> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        // Owner-only like the node keypair, and written aside then renamed
+        // so a crash can't leave the keys half written
+        let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(".tmp");
+        let temp_path = path.with_file_name(temp_name);
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&temp_path)?;
+        file.write_all(&serde_json::to_vec(self)?)?;
+        file.sync_all()?;
+        std::fs::rename(&temp_path, path)?;
+
+        Ok(())
+    }
+}
+
+/// Create a DHT record with enough subkeys to hold `subkey` and publish our route there
 pub(crate) async fn create_service_route_pin(
     rc: RoutingContext,
     route: Vec<u8>,
-) -> Result<(CryptoTyped<CryptoKey>, KeyPair), Error> {
-    let schema = DHTSchema::dflt(1)?;
+    subkey: ValueSubkey,
+    subkey_count: u16,
+    crypto_kind: CryptoKind,
+) -> Result<(CryptoTyped<CryptoKey>, KeyPair), VeilidDuplexError> {
+    let schema = DHTSchema::dflt(subkey_count)?;
 
-    let rec = rc.create_dht_record(schema, Some(CRYPTO_KIND)).await?;
+    let rec = rc.create_dht_record(schema, Some(crypto_kind)).await?;
 
     let dht_key = *rec.key();
     let owner = rec.owner();
-    let secret = rec.owner_secret().unwrap();
+    let secret = rec.owner_secret().ok_or_else(|| {
+        VeilidDuplexError::Other(anyhow::anyhow!(
+            "Created DHT record {} came without its owner secret",
+            dht_key
+        ))
+    })?;
     let keypair = KeyPair::new(*owner, *secret);
 
-    info!("Setting DHT Key: {}", dht_key);
-    rc.set_dht_value(*rec.key(), 0, route, None).await?;
+    info!("Setting DHT Key: {}, subkey {}", dht_key, subkey);
+    rc.set_dht_value(*rec.key(), subkey, route, None).await?;
     rc.close_dht_record(*rec.key()).await?;
 
     Ok((dht_key, keypair))
@@ -321,22 +638,324 @@ pub(crate) async fn update_service_route_pin(
     route: Vec<u8>,
     dht_key: CryptoTyped<CryptoKey>,
     dht_owner_keypair: KeyPair,
-) -> Result<(), Error> {
-    info!("Updating DHT Key: {} ", dht_key);
+    subkey: ValueSubkey,
+) -> Result<(), VeilidDuplexError> {
+    info!("Updating DHT Key: {}, subkey {}", dht_key, subkey);
     let rec = rc.open_dht_record(dht_key, Some(dht_owner_keypair)).await?;
 
-    rc.set_dht_value(*rec.key(), 0, route, None).await?;
+    rc.set_dht_value(*rec.key(), subkey, route, None).await?;
     rc.close_dht_record(*rec.key()).await?;
 
     Ok(())
 }
 
+/// Outcome of `set_dht_values`
+#[derive(Debug, Default)]
+pub struct SubkeyWriteReport {
+    pub written: Vec<ValueSubkey>,
+    pub failed: Vec<(ValueSubkey, VeilidAPIError)>,
+}
+
+impl SubkeyWriteReport {
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty()
+    }
+
+    /// Fail if the route subkey wasn't written. The others only hold backup
+    /// routes, so their failures are logged and failover goes without them.
+    pub fn check_route_pins(self) -> Result<(), VeilidDuplexError> {
+        let mut primary = None;
+        for (subkey, e) in self.failed {
+            match subkey {
+                ROUTE_SUBKEY => primary = Some(e),
+                _ => warn!("Backup route in subkey {} wasn't published: {}", subkey, e),
+            }
+        }
+
+        match primary {
+            Some(e) => Err(e.into()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Write several subkeys of a record we own with a single open/close. A failed
+/// subkey doesn't stop the remaining writes; check the report for which landed.
+pub async fn set_dht_values(
+    rc: RoutingContext,
+    dht_key: CryptoTyped<CryptoKey>,
+    dht_owner_keypair: KeyPair,
+    values: Vec<(ValueSubkey, Vec<u8>)>,
+) -> Result<SubkeyWriteReport, VeilidDuplexError> {
+    info!("Writing {} subkey(s) of DHT Key: {}", values.len(), dht_key);
+    let rec = rc.open_dht_record(dht_key, Some(dht_owner_keypair)).await?;
+
+    let mut report = SubkeyWriteReport::default();
+    for (subkey, data) in values {
+        match rc.set_dht_value(*rec.key(), subkey, data, None).await {
+            Result::Ok(_) => report.written.push(subkey),
+            Err(e) => {
+                info!("Writing subkey {} of {} failed: {}", subkey, dht_key, e);
+                report.failed.push((subkey, e));
+            }
+        }
+    }
+
+    rc.close_dht_record(*rec.key()).await?;
+
+    Ok(report)
+}
+
 pub(crate) fn calculate_hash(data: &[u8]) -> u64 {
     let mut hasher = FnvHasher::default();
     hasher.write(data);
     hasher.finish()
 }
 
-pub fn crypto_key_from_str(dht_key: String) -> Result<CryptoTyped<CryptoKey>, VeilidAPIError> {
-    CryptoTyped::<CryptoKey>::from_str(&dht_key)
+pub fn crypto_key_from_str(dht_key: String) -> Result<CryptoTyped<CryptoKey>, VeilidDuplexError> {
+    Ok(CryptoTyped::<CryptoKey>::from_str(&dht_key)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_falls_back_to_next_route_subkey() {
+        let import = |blob: &[u8]| match blob {
+            b"good" => Ok(blob.to_vec()),
+            _ => Err(VeilidDuplexError::MalformedRoute("bad".to_string())),
+        };
+
+        let blobs = vec![(0, b"stale".to_vec()), (1, b"good".to_vec())];
+        assert_eq!(import_first_route(blobs, import).unwrap(), b"good");
+
+        let blobs = vec![(0, b"stale".to_vec())];
+        assert!(matches!(
+            import_first_route(blobs, import),
+            Err(Some(VeilidDuplexError::MalformedRoute(_)))
+        ));
+        assert!(matches!(import_first_route(Vec::new(), import), Err(None)));
+    }
+
+    #[tokio::test]
+    async fn test_wait_times_out_when_never_ready() {
+        let wait = StartupWait {
+            timeout: Some(Duration::from_millis(50)),
+            poll_interval: Duration::from_millis(10),
+        };
+        let result = wait_until("attachment", wait, || async { Ok(false) }).await;
+        assert!(matches!(
+            result,
+            Err(VeilidDuplexError::StartupTimeout {
+                phase: "attachment",
+                ..
+            })
+        ));
+
+        let mut polls = 0;
+        let result = wait_until("attachment", wait, || {
+            polls += 1;
+            let ready = polls >= 3;
+            async move { Ok(ready) }
+        })
+        .await;
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_node_id_blob_gives_direct_target() {
+        let node_id = CryptoTyped::new(CRYPTO_KIND, CryptoKey::new([7; 32]));
+
+        let (target, route) = node_id_target(&node_id_blob(node_id)).unwrap().unwrap();
+        assert_eq!(target, Target::NodeId(node_id));
+        assert_eq!(route, node_id.value);
+
+        assert!(matches!(
+            node_id_target(b"node:not a key"),
+            Some(Err(VeilidDuplexError::MalformedRoute(_)))
+        ));
+        // Private route blobs are left to the route import
+        assert!(node_id_target(b"AAAA").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_lookup_retries_until_route_appears() {
+        let retry = RetryPolicy {
+            attempts: 3,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(2),
+        };
+        let service_key = CryptoTyped::new(CRYPTO_KIND, CryptoKey::new([1; 32]));
+        let import = |blob: &[u8]| match blob {
+            b"good" => Ok(blob.to_vec()),
+            _ => Err(VeilidDuplexError::MalformedRoute("stale".to_string())),
+        };
+
+        // Missing on the first poll, stale on the second, there on the third
+        let mut refreshes = Vec::new();
+        let route = lookup_route(
+            service_key,
+            ROUTE_SUBKEY,
+            false,
+            retry,
+            |force_refresh| {
+                refreshes.push(force_refresh);
+                let blobs = match refreshes.len() {
+                    1 => Vec::new(),
+                    2 => vec![(0, b"stale".to_vec())],
+                    _ => vec![(0, b"good".to_vec())],
+                };
+                async move { Ok(blobs) }
+            },
+            import,
+        )
+        .await;
+        assert_eq!(route.unwrap(), b"good");
+        assert_eq!(refreshes, vec![false, true, true]);
+
+        let result = lookup_route(
+            service_key,
+            ROUTE_SUBKEY,
+            true,
+            retry,
+            |_| async { Ok(Vec::new()) },
+            import,
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(VeilidDuplexError::DhtValueNotFound { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_startup_step_retries_until_it_succeeds() {
+        let retry = RetryPolicy {
+            attempts: 4,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(4),
+        };
+
+        let mut calls = 0;
+        let result = retry_with_backoff("Creating route", retry, |attempt| {
+            calls += 1;
+            let n = calls;
+            assert_eq!(attempt, n);
+            async move {
+                match n {
+                    1..=2 => Err(VeilidDuplexError::MalformedRoute("not yet".to_string())),
+                    _ => Ok(n),
+                }
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 3);
+
+        let mut calls = 0;
+        let result: Result<(), _> = retry_with_backoff("Creating route", retry, |_| {
+            calls += 1;
+            async { Err(VeilidDuplexError::MalformedRoute("never".to_string())) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 4);
+    }
+
+    #[test]
+    fn test_node_keypair_persists() -> Result<(), VeilidDuplexError> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("identity").join("node.key");
+
+        let first = load_or_create_keypair(&path, CRYPTO_KIND)?;
+        let second = load_or_create_keypair(&path, CRYPTO_KIND)?;
+        assert_eq!(first.key, second.key);
+        assert_eq!(first.secret, second.secret);
+
+        let other_dir = tempfile::tempdir()?;
+        let other = load_or_create_keypair(&other_dir.path().join("node.key"), CRYPTO_KIND)?;
+        assert_ne!(first.key, other.key);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_table_store_survives_restart() -> Result<(), VeilidDuplexError> {
+        let dir = tempfile::tempdir()?;
+        let config = VeilidDuplexConfig {
+            storage_dir: Some(dir.path().join("not").join("there").join("yet")),
+            ..Default::default()
+        };
+        let key_pair = veilid_core::Crypto::generate_keypair(CRYPTO_KIND)?.value;
+
+        let api = start_api_with_keypair(Arc::new(|_: VeilidUpdate| {}), key_pair, config.clone())
+            .await?;
+        let db = api.table_store()?.open("duplex_test", 1).await?;
+        db.store(0, b"greeting", b"hello").await?;
+        drop(db);
+        api.shutdown().await;
+
+        let api = start_api_with_keypair(Arc::new(|_: VeilidUpdate| {}), key_pair, config).await?;
+        let db = api.table_store()?.open("duplex_test", 1).await?;
+        assert_eq!(db.load(0, b"greeting").await?, Some(b"hello".to_vec()));
+        drop(db);
+        api.shutdown().await;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_only_a_failed_route_subkey_fails_the_pins() {
+        let report = SubkeyWriteReport {
+            written: vec![ROUTE_SUBKEY, 2],
+            failed: vec![(1, VeilidAPIError::Timeout)],
+        };
+        assert!(report.check_route_pins().is_ok());
+
+        let report = SubkeyWriteReport {
+            written: vec![1],
+            failed: vec![(ROUTE_SUBKEY, VeilidAPIError::Timeout)],
+        };
+        assert!(matches!(
+            report.check_route_pins(),
+            Err(VeilidDuplexError::Veilid(VeilidAPIError::Timeout))
+        ));
+    }
+
+    #[test]
+    fn test_service_keys_round_trip() -> Result<(), VeilidDuplexError> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("service.json");
+
+        let owner = veilid_core::Crypto::generate_keypair(CRYPTO_KIND)?.value;
+        let dht_key = CryptoTyped::new(CRYPTO_KIND, CryptoKey::new([7; 32]));
+        let keys = ServiceKeys::new(dht_key, owner);
+        keys.save(&path)?;
+
+        let loaded = ServiceKeys::load(&path)?;
+        assert_eq!(loaded, keys);
+        assert_eq!(loaded.dht_owner_keypair(), owner);
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_service_keys_are_owner_only() -> Result<(), VeilidDuplexError> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("service.json");
+        let owner = veilid_core::Crypto::generate_keypair(CRYPTO_KIND)?.value;
+        let dht_key = CryptoTyped::new(CRYPTO_KIND, CryptoKey::new([7; 32]));
+        ServiceKeys::new(dht_key, owner).save(&path)?;
+        // Saving again replaces the file rather than loosening it
+        ServiceKeys::new(dht_key, owner).save(&path)?;
+
+        let mode = std::fs::metadata(&path)?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(!dir.path().join("service.json.tmp").exists());
+
+        Ok(())
+    }
 }
@@ -1,23 +1,83 @@
-use std::collections::hash_map::Entry::Vacant;
-use std::io;
+use std::collections::VecDeque;
+use std::fmt;
+use std::path::{Path, PathBuf};
 
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::{Context, Error, Ok};
+use anyhow::Error;
 
 use async_std::sync::Mutex;
-use flume::{unbounded, Receiver, Sender};
+use flume::{bounded, unbounded, Receiver, Sender};
+use futures_util::stream::BoxStream;
+use futures_util::{Stream, StreamExt};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use tracing::info;
-use uuid::Uuid;
 
 use veilid_core::tools::*;
 use veilid_core::*;
 
+use crate::cancel::CancellationToken;
+use crate::chunking::{
+    split_into_frames, Chunk, Reassembler, CHUNK_PAYLOAD_SIZE, MAX_CHUNKS, MAX_FRAME_SIZE,
+};
+use crate::clock::{Clock, RandomUuids, SystemClock, UuidSource};
+use crate::codec::{decode_any, Codec, MessageCodec};
+use crate::compression::{compress, decompress, CompressionConfig};
+use crate::config::{
+    Addressing, NetworkProtocols, RetryPolicy, StartupWaits, VeilidConfigOptions,
+    VeilidDuplexConfig,
+};
+use crate::dedup::{DedupCache, EvictionCallback};
+use crate::encryption::{crypto_system, decrypt, encrypt, is_encrypted, sender_of, PeerKeys};
+use crate::error::VeilidDuplexError;
+use crate::filter::{FilterMode, PeerFilter};
+use crate::ordering::{MessageOrdering, SerialQueues, Turn};
+use crate::outbox::Outbox;
+use crate::permits::{HandlerOverflow, HandlerPermits, MAX_CONCURRENT_HANDLERS};
+use crate::presence::{Presence, PresenceChange, PresenceConfig, PresenceTracker};
+use crate::rate_limit::{BandwidthLimit, BandwidthLimiter, InboundRateLimiter, MessageRateLimit};
+use crate::signing::{
+    answer_challenge, check_answer, identity_challenge, is_identity_challenge, is_signed, sign,
+    split_signed, verify,
+};
+use crate::stats::{StatsCounters, VeilidDuplexStats};
+use crate::telemetry::{
+    decode_batch, is_telemetry, TelemetryBatch, TelemetryConfig, TelemetryForwarder,
+};
+use crate::transfer::{send_file, TransferProgress};
+use crate::transport::{LoopbackNetwork, Transport, VeilidTransport};
 use crate::utils::*;
 
 const SEND_ATTEMPTS: u16 = 1024;
+const SEND_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+const UNRESPONSIVE_THRESHOLD: u32 = 3;
+const STATE_VERSION: u32 = 1;
+/// Largest message the send side can produce
+pub const MAX_INBOUND_SIZE: usize = MAX_CHUNKS as usize * MAX_FRAME_SIZE;
+
+// Keepalive probe, answered by the receive path without reaching `on_message`.
+// Like the other magics it can't begin a serialized AppMessage.
+const KEEPALIVE_PING: &[u8; 4] = b"\0VDP";
+// Latency probe of `DuplexSender::ping`: the magic and a probe uuid, echoed
+// back as the reply
+const LATENCY_PING: &[u8; 4] = b"\0VDT";
+/// Unanswered app_calls kept while paused in `PauseMode::Hold`
+pub const MAX_HELD_CALLS: usize = 1024;
+/// Messages kept while paused in `PauseMode::Buffer`
+pub const MAX_PAUSED_MESSAGES: usize = 1024;
+// Messages `incoming` holds before the network loop waits for the consumer
+const INCOMING_BUFFER: usize = 64;
+// Pause of a background loop with no updates pending, so it doesn't spin
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(5);
+// How often watches on peers' route records are checked for renewal, and how
+// long before expiry they are renewed
+const WATCH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+const WATCH_RENEW_MARGIN: Duration = Duration::from_secs(60);
+
+pub type PeerCallback = Arc<dyn Fn(CryptoTyped<CryptoKey>) + Send + Sync>;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(bound = "T: Serialize + DeserializeOwned")]
@@ -25,301 +85,6365 @@ pub struct AppMessage<T: DeserializeOwned> {
     pub data: T,
     pub uuid: String,
     pub dht_record: CryptoTyped<CryptoKey>,
+    /// Uuid of the request this message answers, see `VeilidDuplex::send_request`
+    #[serde(default)]
+    pub reply_to: Option<String>,
+    /// When the message was first sent, in unix milliseconds. Zero for messages
+    /// from peers that don't set it.
+    #[serde(default)]
+    pub timestamp: u64,
+    /// Topic the message was published to, see `topics::TopicRouter`
+    #[serde(default)]
+    pub topic: Option<String>,
+}
+
+/// Returned by `AppLogic::on_message` when a message was delivered but couldn't
+/// be processed. The sender gets `AckStatus::Rejected` with the reason.
+#[derive(Debug, Clone)]
+pub struct HandlerError {
+    pub reason: String,
+}
+
+impl HandlerError {
+    pub fn new(reason: impl Into<String>) -> Self {
+        Self {
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+impl std::error::Error for HandlerError {}
+
+/// Status carried in the `app_call` reply
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum AckStatus {
+    Accepted,
+    /// Already received earlier; the peer has the message
+    Duplicate,
+    /// Over the peer's inbound size limit
+    TooLarge,
+    /// The peer couldn't decompress or decode the message
+    DeserializeFailed,
+    /// The peer's handler, or decryption, failed
+    Rejected(String),
+    /// The peer had no free handler; resending later may get through
+    Busy,
+}
+
+impl fmt::Display for AckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AckStatus::Accepted => write!(f, "Accepted"),
+            AckStatus::Duplicate => write!(f, "Duplicate"),
+            AckStatus::TooLarge => write!(f, "Message too large"),
+            AckStatus::DeserializeFailed => write!(f, "Malformed message"),
+            AckStatus::Rejected(reason) => write!(f, "{}", reason),
+            AckStatus::Busy => write!(f, "Peer busy"),
+        }
+    }
+}
+
+impl AckStatus {
+    /// Whether the peer has the message. Every other status but `Busy` is final
+    /// for this message: resending the same bytes gets the same answer.
+    pub fn is_accepted(&self) -> bool {
+        matches!(self, AckStatus::Accepted | AckStatus::Duplicate)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, VeilidDuplexError> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    /// Parse an `app_call` reply. Older peers reply with a bare `ACK`, which
+    /// (like any reply we can't parse) counts as `Accepted`.
+    pub fn from_reply(reply: &[u8]) -> Self {
+        serde_json::from_slice(reply).unwrap_or(AckStatus::Accepted)
+    }
+}
+
+/// How `AppMessage` turns into bytes on the wire
+#[derive(Debug, Clone, Default)]
+pub struct SendOptions {
+    pub codec: MessageCodec,
+    pub compression: CompressionConfig,
+    /// Encrypt messages to the recipient's DHT record owner key. Only applies to
+    /// `VeilidDuplex::send_message`, which knows our keys and the recipient.
+    pub encrypt: bool,
+    /// Sign messages with our DHT record owner key, so the recipient can check
+    /// they came from the `dht_record` they claim. Same scope as `encrypt`.
+    pub sign: bool,
+}
+
+/// What a delivered message got back from the peer
+#[derive(Debug, Clone)]
+pub struct SendOutcome {
+    /// Uuid the message was sent with, as the peer sees it in `AppMessage::uuid`
+    pub uuid: String,
+    /// Status parsed from the reply; replies from older peers read as `Accepted`
+    pub status: AckStatus,
+    /// Raw `app_call` reply bytes
+    pub reply: Vec<u8>,
+    /// Round trip of the attempt that got through, not counting earlier
+    /// failed attempts or the pauses between them
+    pub rtt: Duration,
+    /// Attempts used, including the successful one
+    pub attempts: u16,
+}
+
+/// Message that couldn't be delivered, see `VeilidDuplex::set_dead_letters`
+#[derive(Debug, Clone)]
+pub struct DeadLetter {
+    pub uuid: String,
+    pub destination: CryptoTyped<CryptoKey>,
+    /// Why the last attempt failed
+    pub error: String,
+    /// The message as it was sent, before encryption
+    pub blob: Vec<u8>,
+}
+
+impl DeadLetter {
+    /// The message that was sent, for persisting or resending
+    pub fn decode<T>(&self) -> Result<AppMessage<T>, VeilidDuplexError>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        let blob = match is_signed(&self.blob) {
+            true => split_signed(&self.blob)?.1,
+            false => &self.blob[..],
+        };
+        let raw_message = decompress(blob.to_vec(), MAX_INBOUND_SIZE)?;
+        decode_app_message(&raw_message)
+    }
 }
 
 pub trait AppLogic<T: DeserializeOwned> {
     fn on_message(
         &mut self,
         message: AppMessage<T>,
-    ) -> impl std::future::Future<Output = ()> + Send + Sized;
+    ) -> impl std::future::Future<Output = Result<(), HandlerError>> + Send + Sized;
+
+    /// Called when the cached route to a peer was reported dead and dropped.
+    /// The next send to the peer looks its route up on DHT again.
+    fn on_remote_route_dead(&mut self, _dht_record: CryptoTyped<CryptoKey>) {}
+
+    /// Called after our own route died and a new one was published to DHT
+    fn on_local_route_changed(&mut self, _new_route: CryptoKey) {}
+
+    /// Called when the node's attachment to the network changes, e.g. to
+    /// `Detached` when it goes offline
+    fn on_attachment_changed(&mut self, _state: AttachmentState) {}
+
+    /// Called when the node gains or loses public internet reachability
+    fn on_public_internet_ready_changed(&mut self, _ready: bool) {}
 }
 
-#[derive(Clone)]
-pub struct VeilidDuplexRoutes {
-    routes: HashMap<CryptoKey, (Target, CryptoKey)>,
+/// What happens to inbound messages while the duplex is paused.
+/// `Buffer` and `Drop` ACK messages, so senders don't retry them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PauseMode {
+    /// Keep messages and deliver them in arrival order on resume, up to
+    /// `MAX_PAUSED_MESSAGES`; past that they get `AckStatus::Busy`. Buffered
+    /// messages are lost on `shutdown` unless carried over with `export_state`.
+    #[default]
+    Buffer,
+    /// Discard messages received while paused
+    Drop,
+    /// Leave messages unanswered until resume, up to `MAX_HELD_CALLS`; past
+    /// that they get `AckStatus::Busy`. Senders whose app_call times out
+    /// meanwhile retry, and get `Duplicate` once the held copy is handled.
+    Hold,
 }
 
-impl VeilidDuplexRoutes {
-    pub async fn get_route(
-        &mut self,
-        remote_dht_record: CryptoTyped<CryptoKey>,
-        api: VeilidAPI,
-        routing_context: RoutingContext,
-    ) -> Result<Target, Error> {
-        if let Vacant(e) = self.routes.entry(remote_dht_record.value) {
-            let (target, route) = get_service_route_from_dht(
-                api.clone(),
-                routing_context.clone(),
-                remote_dht_record,
-                true,
-            )
-            .await?;
+/// Live state carried over from one instance to another by `export_state` and
+/// `import_state`. Routes are node-local, so peers are exported by DHT record and
+/// re-resolved on import.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DuplexState {
+    pub version: u32,
+    #[serde(default)]
+    pub received_message_hashes: Vec<u64>,
+    #[serde(default)]
+    pub peers: Vec<CryptoTyped<CryptoKey>>,
+    #[serde(default)]
+    pub paused_messages: Vec<Vec<u8>>,
+}
 
-            e.insert((target, route));
-        }
+/// Network attachment as last reported to the network loop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AttachmentStatus {
+    pub state: AttachmentState,
+    pub public_internet_ready: bool,
+}
 
-        Ok(self.routes.get(&remote_dht_record.value).unwrap().0)
+impl AttachmentStatus {
+    /// Attached and reachable from the public internet, so sends can go out
+    pub fn is_ready(&self) -> bool {
+        is_ready(self.state, self.public_internet_ready)
     }
 
-    fn remove_route_if_exists(&mut self, dead_route: CryptoKey) {
-        let key_to_remove: Option<CryptoKey> = self
-            .routes
-            .iter()
-            .filter(|(_, (_, route))| *route == dead_route)
-            .map(|(key, _)| *key)
-            .next();
-
-        if key_to_remove.is_none() {
-            return;
+    /// Record an attachment update and tell `app_logic` what changed
+    fn update<T, U>(
+        &mut self,
+        state: AttachmentState,
+        public_internet_ready: bool,
+        app_logic: &mut U,
+    ) where
+        T: DeserializeOwned,
+        U: AppLogic<T>,
+    {
+        if self.state != state {
+            info!("Attachment state changed: {:?} -> {:?}", self.state, state);
+            self.state = state;
+            app_logic.on_attachment_changed(state);
         }
 
-        self.routes.remove(&key_to_remove.unwrap());
+        if self.public_internet_ready != public_internet_ready {
+            info!("Public internet ready: {}", public_internet_ready);
+            self.public_internet_ready = public_internet_ready;
+            app_logic.on_public_internet_ready_changed(public_internet_ready);
+        }
     }
 }
 
-#[derive(Clone)]
-pub struct VeilidDuplex {
-    pub api: VeilidAPI,
-    pub routing_context: RoutingContext,
-    pub receiver: Receiver<VeilidUpdate>,
-    pub our_route: CryptoKey,
-    pub our_dht_key: CryptoTyped<CryptoKey>,
-    pub node_keypair: KeyPair,
-    pub dht_keypair: KeyPair,
-    pub routes: Arc<Mutex<VeilidDuplexRoutes>>,
-    // There can be multiple deliveries of the same message when the route is reported broken
-    // So far the easy fix is to log hashes of all received messages, and drop ones that were already received
-    pub received_message_hashes: Arc<Mutex<Vec<u64>>>,
+/// Periodic pings over cached routes, to catch routes that stopped working
+/// without veilid reporting them dead
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepaliveConfig {
+    pub interval: Duration,
+    /// Consecutive failed pings after which a route is evicted and looked up
+    /// on DHT again on the next send
+    pub failure_threshold: u32,
 }
 
-impl<T: DeserializeOwned + Serialize> AppMessage<T> {
-    pub async fn send(
-        &mut self,
-        routing_context: &RoutingContext,
-        target: Target,
-    ) -> Result<Vec<u8>, Error> {
-        self.set_uuid();
-        let app_message_blob = serde_json::to_vec(self).unwrap();
+/// Keepalive rounds per connection inactivity timeout, so a connection to a
+/// peer we talk to is used again well before veilid would close it
+const KEEPALIVES_PER_INACTIVITY_TIMEOUT: u32 = 2;
 
-        // Check if blob size > 32kb and fire an error
-        if app_message_blob.len() > 32 * 1024 {
-            return Err(io::Error::new(io::ErrorKind::Other, "Message size exceeds 32kb").into());
+impl KeepaliveConfig {
+    /// Ping often enough that connections over cached routes aren't torn down
+    /// for going idle `connection_inactivity_timeout`
+    pub fn for_inactivity_timeout(connection_inactivity_timeout: Duration) -> Self {
+        Self {
+            interval: connection_inactivity_timeout / KEEPALIVES_PER_INACTIVITY_TIMEOUT,
+            failure_threshold: 3,
         }
+    }
+}
 
-        info!(
-            "Sending message, origin_dht: {:?}, target: {:?}",
-            self.dht_record,
-            target.clone()
-        );
+/// Tied to the default `VeilidConfigOptions::connection_inactivity_timeout_ms`;
+/// see `VeilidDuplex::default_keepalive` for the one a node is configured with
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        let timeout_ms = VeilidConfigOptions::default().connection_inactivity_timeout_ms;
+        Self::for_inactivity_timeout(Duration::from_millis(timeout_ms as u64))
+    }
+}
 
-        routing_context
-            .app_call(target, app_message_blob)
-            .await
-            .context("app_call")
+/// Probing of the routes a peer publishes, to send over the one that answers
+/// fastest. Candidates are probed together when the route is looked up and
+/// again from keepalive rounds, in place of the keepalive ping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RouteProbing {
+    /// Routes probed per peer, in subkey order
+    pub max_candidates: usize,
+    /// How long a probe waits for its echo; slower routes lose
+    pub timeout: Duration,
+    /// Least time between probes of the same peer's candidates
+    pub interval: Duration,
+}
+
+impl Default for RouteProbing {
+    fn default() -> Self {
+        Self {
+            max_candidates: 4,
+            timeout: Duration::from_secs(2),
+            interval: Duration::from_secs(300),
+        }
     }
+}
+
+/// Backoff between attempts to re-attach after the node lost its attachment,
+/// doubling from `initial_backoff` up to `max_backoff`. After
+/// `failure_threshold` failed attempts in a row the circuit opens: no attempts
+/// for `open_for`, then a single trial. A failed trial opens the circuit again
+/// for twice as long, up to `max_open_for`, so a long outage doesn't keep
+/// hitting the bootstrap nodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub failure_threshold: u32,
+    pub open_for: Duration,
+    pub max_open_for: Duration,
+}
 
-    fn set_uuid(&mut self) {
-        self.uuid = format!("{}", Uuid::new_v4());
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            failure_threshold: 10,
+            open_for: Duration::from_secs(300),
+            max_open_for: Duration::from_secs(1800),
+        }
     }
 }
 
-impl VeilidDuplex {
-    async fn initialize(
-    ) -> Result<(VeilidAPI, RoutingContext, Receiver<VeilidUpdate>, KeyPair), Error> {
-        let (sender, receiver): (
-            Sender<veilid_core::VeilidUpdate>,
-            Receiver<veilid_core::VeilidUpdate>,
-        ) = unbounded();
+/// Whether reconnect attempts are being throttled, see `ReconnectPolicy`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CircuitState {
+    /// Attached, or retrying with backoff
+    #[default]
+    Closed,
+    /// Too many failed attempts; waiting before trying again
+    Open,
+    /// Making the trial attempt after a wait
+    HalfOpen,
+}
 
-        // Create VeilidCore setup
-        let update_callback = Arc::new(move |change: veilid_core::VeilidUpdate| {
-            if let Err(e) = sender.send(change) {
-                let change = e.into_inner();
-                info!("error sending veilid update callback: {:?}", change);
-            }
-        });
+/// How far an inbound message's send timestamp may be from our clock before
+/// the message is rejected. Retries keep the original timestamp, so
+/// `max_behind` should cover the sender's whole retry budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimestampWindow {
+    pub max_behind: Duration,
+    pub max_ahead: Duration,
+}
 
-        let node_keypair = veilid_core::Crypto::generate_keypair(CRYPTO_KIND)
-            .unwrap()
-            .value;
+impl Default for TimestampWindow {
+    fn default() -> Self {
+        Self {
+            max_behind: Duration::from_secs(300),
+            max_ahead: Duration::from_secs(10),
+        }
+    }
+}
 
-        #[cfg(target_arch = "wasm32")]
-        let api = create_api_and_connect(update_callback).await?;
-        #[cfg(not(target_arch = "wasm32"))]
-        let api = create_api_and_connect_with_keypair(update_callback, node_keypair).await?;
+// Recovery from a lost attachment: re-attach until attached again, then
+// publish a fresh route
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Reconnect {
+    attached: bool,
+    next_attempt: u64,
+    backoff: Duration,
+    failures: u32,
+    circuit: CircuitState,
+    open_for: Duration,
+}
 
-        let rc = api
-            .routing_context()?
-            .with_sequencing(Sequencing::PreferOrdered);
+impl Reconnect {
+    fn new(now: u64, policy: &ReconnectPolicy) -> Self {
+        Self {
+            attached: false,
+            next_attempt: now,
+            backoff: policy.initial_backoff,
+            failures: 0,
+            circuit: CircuitState::Closed,
+            open_for: policy.open_for,
+        }
+    }
 
-        Ok((api, rc, receiver, node_keypair))
+    fn is_due(&self, now: u64) -> bool {
+        now >= self.next_attempt
+    }
+
+    /// Let the next attempt go ahead, as the trial if the circuit was open
+    fn start_attempt(&mut self) {
+        if self.circuit == CircuitState::Open {
+            self.circuit = CircuitState::HalfOpen;
+        }
+    }
+
+    fn back_off(&mut self, now: u64, policy: &ReconnectPolicy) {
+        self.failures += 1;
+        let wait = match self.circuit {
+            CircuitState::HalfOpen => {
+                self.open_for = (self.open_for * 2).min(policy.max_open_for);
+                self.circuit = CircuitState::Open;
+                self.open_for
+            }
+            _ if self.failures >= policy.failure_threshold => {
+                self.circuit = CircuitState::Open;
+                self.open_for
+            }
+            _ => {
+                let wait = self.backoff;
+                self.backoff = (self.backoff * 2).min(policy.max_backoff);
+                wait
+            }
+        };
+        if self.circuit == CircuitState::Open {
+            info!("Reconnect circuit open, next attempt in {:?}", wait);
+        }
+        self.next_attempt = now + wait.as_micros() as u64;
     }
+}
+
+/// Snapshot of a peer we hold a route to
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub dht_record: CryptoTyped<CryptoKey>,
+    /// Time since the route was resolved from DHT
+    pub route_age: Duration,
+    /// Time since we last sent to or received from the peer
+    pub idle_time: Duration,
+    /// Whether the last interaction with the peer succeeded
+    pub alive: bool,
+    /// Consecutive sends the peer's route accepted but its app never replied to
+    pub unacked_sends: u32,
+}
 
-    pub async fn new() -> Result<Self, Error> {
-        let (api, routing_context, receiver, node_keypair) = Self::initialize().await?;
+#[derive(Clone)]
+struct RouteEntry {
+    dht_record: CryptoTyped<CryptoKey>,
+    target: Target,
+    route: CryptoKey,
+    created_at: u64,
+    last_activity: u64,
+    alive: bool,
+    unacked_sends: u32,
+    failed_pings: u32,
+    // Routes the peer publishes, when probing
+    candidates: Vec<(Target, CryptoKey)>,
+    probed_at: u64,
+}
 
-        let (our_route, our_route_blob) = create_private_route(api.clone()).await?;
-        info!("our route: {}", our_route);
-        let (our_dht_key, dht_keypair) =
-            create_service_route_pin(routing_context.clone(), our_route_blob.clone()).await?;
+#[derive(Clone)]
+pub struct VeilidDuplexRoutes {
+    routes: HashMap<CryptoKey, RouteEntry>,
+    // Subkey peers publish their route under
+    subkey: ValueSubkey,
+    // Routes older than this are resolved from DHT again
+    max_age: Option<Duration>,
+    // Peers whose route record we watch, with the watch's expiration
+    watched: HashMap<CryptoKey, (CryptoTyped<CryptoKey>, u64)>,
+    // Retries of a DHT lookup of a peer's route
+    lookup_retry: RetryPolicy,
+    // Peers' DHT records kept open between lookups, when enabled
+    open_records: Option<OpenRecords>,
+    probing: Option<RouteProbing>,
+}
 
-        let routes = Arc::new(Mutex::new(VeilidDuplexRoutes {
+impl VeilidDuplexRoutes {
+    pub fn new(subkey: ValueSubkey) -> Self {
+        Self {
             routes: HashMap::new(),
-        }));
-
-        let received_message_hashes = Arc::new(Mutex::new(Vec::<u64>::new()));
-
-        Ok(Self {
-            api,
-            routing_context,
-            receiver,
-            node_keypair,
-            dht_keypair,
-            our_route,
-            routes,
-            our_dht_key,
-            received_message_hashes,
-        })
+            subkey,
+            max_age: None,
+            watched: HashMap::new(),
+            lookup_retry: ROUTE_LOOKUP_RETRY,
+            open_records: None,
+            probing: None,
+        }
     }
 
-    pub async fn send_message<T: DeserializeOwned>(
-        &self,
-        mut app_message: AppMessage<T>,
+    pub async fn get_route(
+        &mut self,
         remote_dht_record: CryptoTyped<CryptoKey>,
-    ) -> Result<(), Error>
-    where
-        T: Serialize + DeserializeOwned + Send + 'static,
-    {
-        let routes = self.routes.clone();
-        for attempt_n in 0..SEND_ATTEMPTS {
-            let mut routes = routes.lock().await;
-            let target = routes
-                .get_route(
+        transport: &dyn Transport,
+        routing_context: RoutingContext,
+        now: u64,
+    ) -> Result<Target, VeilidDuplexError> {
+        if let Some(target) = self.cached_target(remote_dht_record, now) {
+            return Ok(target);
+        }
+
+        let (mut target, mut route) = match &mut self.open_records {
+            Some(open_records) => {
+                let dht_desc = open_records
+                    .open(&routing_context, remote_dht_record)
+                    .await?;
+                get_service_route_from_open_record(
+                    transport,
+                    routing_context.clone(),
+                    dht_desc,
+                    self.subkey,
+                    true,
+                    self.lookup_retry,
+                )
+                .await?
+            }
+            None => {
+                get_service_route(
+                    transport,
                     remote_dht_record,
-                    self.api.clone(),
-                    self.routing_context.clone(),
+                    self.subkey,
+                    true,
+                    self.lookup_retry,
                 )
-                .await?;
-
-            let result = app_message.send(&self.routing_context, target).await;
-            if result.is_ok() {
-                break;
-            } else if result.is_err() {
-                info!("Unable to send message, sleeping 500ms");
-                sleep(500).await;
-                continue;
+                .await?
             }
+        };
 
-            if attempt_n == (SEND_ATTEMPTS - 1) {
-                return Err(io::Error::new(io::ErrorKind::Other, "Couldn't send reply").into());
+        let mut candidates = Vec::new();
+        if let Some(probing) = self.probing {
+            candidates = self
+                .candidate_routes(remote_dht_record, transport, &routing_context, probing)
+                .await;
+            if let Some(fastest) = fastest_route(transport, &candidates, probing.timeout).await {
+                (target, route) = fastest;
             }
         }
-        Ok(())
-    }
 
-    pub async fn network_loop<T, U>(&mut self, app_logic: U) -> Result<(), Error>
-    where
-        T: Serialize + DeserializeOwned + Send + Sync + Clone + Sized + 'static,
-        U: AppLogic<T> + Clone + Send + 'static,
-    {
-        loop {
-            self.network_loop_cycle::<T, U>(app_logic.clone()).await?;
-        }
+        self.routes.insert(
+            remote_dht_record.value,
+            RouteEntry {
+                dht_record: remote_dht_record,
+                target,
+                route,
+                created_at: now,
+                last_activity: now,
+                alive: true,
+                unacked_sends: 0,
+                failed_pings: 0,
+                candidates,
+                probed_at: now,
+            },
+        );
+
+        Ok(target)
     }
 
-    pub async fn network_loop_cycle<T, U>(&mut self, app_logic: U) -> Result<(), Error>
-    where
-        T: Serialize + DeserializeOwned + Send + Sync + Clone + 'static,
-        U: AppLogic<T> + Clone + Send + 'static,
-    {
-        let reciever = self.receiver.clone();
-        let api = self.api.clone();
+    /// Routes the peer publishes that import, up to `max_candidates`
+    async fn candidate_routes(
+        &mut self,
+        remote_dht_record: CryptoTyped<CryptoKey>,
+        transport: &dyn Transport,
+        routing_context: &RoutingContext,
+        probing: RouteProbing,
+    ) -> Vec<(Target, CryptoKey)> {
+        // The record was just read by the lookup, so the local copy will do
+        let blobs = match &mut self.open_records {
+            Some(open_records) => match open_records.open(routing_context, remote_dht_record).await
+            {
+                Ok(dht_desc) => {
+                    read_route_blobs(routing_context.clone(), dht_desc, self.subkey, false).await
+                }
+                Err(e) => Err(e),
+            },
+            None => {
+                transport
+                    .get_dht_values(remote_dht_record, self.subkey, false)
+                    .await
+            }
+        };
 
-        if reciever.is_empty() {
-            return Ok(());
+        match blobs {
+            Ok(blobs) => blobs
+                .iter()
+                .filter_map(|(_, blob)| transport.import_route(blob).ok())
+                .take(probing.max_candidates)
+                .collect(),
+            Err(e) => {
+                info!(
+                    "Unable to read candidate routes of {}: {}",
+                    remote_dht_record, e
+                );
+                Vec::new()
+            }
         }
+    }
 
-        let res = reciever.recv()?;
-        let routes = self.routes.clone();
-        let received_message_hashes = self.received_message_hashes.clone();
-        let mut app_logic = app_logic.clone();
-
-        match res {
-            VeilidUpdate::AppCall(call) => {
-                info!("VeilidUpdate::AppMessage");
+    /// Candidates of a peer due to be probed again at `now`. The probe time is
+    /// claimed, so the caller is expected to probe them.
+    fn candidates_due(
+        &mut self,
+        dht_record: CryptoTyped<CryptoKey>,
+        now: u64,
+    ) -> Option<Vec<(Target, CryptoKey)>> {
+        let probing = self.probing?;
+        let entry = self.routes.get_mut(&dht_record.value)?;
+        if entry.candidates.len() < 2
+            || now.saturating_sub(entry.probed_at) < probing.interval.as_micros() as u64
+        {
+            return None;
+        }
 
-                spawn(async move {
-                    let raw_message = call.message();
-                    let message_hash = calculate_hash(raw_message);
+        entry.probed_at = now;
+        Some(entry.candidates.clone())
+    }
 
-                    let reply = api.app_call_reply(call.id(), b"ACK".to_vec()).await;
-                    if reply.is_err() {
-                        info!("Unable to send ACK");
-                        return;
-                    }
+    /// Send to a peer over `target` from now on, keeping the entry's age
+    fn prefer_route(
+        &mut self,
+        dht_record: CryptoTyped<CryptoKey>,
+        target: Target,
+        route: CryptoKey,
+    ) {
+        if let Some(entry) = self.routes.get_mut(&dht_record.value) {
+            if entry.route != route {
+                info!("Switching to a faster route to {}", dht_record);
+                entry.target = target;
+                entry.route = route;
+            }
+        }
+    }
 
-                    let app_message = serde_json::from_slice::<AppMessage<T>>(raw_message).unwrap();
+    /// Cached route to a peer, unless it is older than `max_age`. Stale
+    /// entries are dropped so the caller looks the route up again.
+    fn cached_target(&mut self, dht_record: CryptoTyped<CryptoKey>, now: u64) -> Option<Target> {
+        let entry = self.routes.get(&dht_record.value)?;
+        if let Some(max_age) = self.max_age {
+            if now.saturating_sub(entry.created_at) > max_age.as_micros() as u64 {
+                info!("Cached route to {} expired", dht_record);
+                self.routes.remove(&dht_record.value);
+                return None;
+            }
+        }
 
-                    {
-                        let mut received_message_hashes = received_message_hashes.lock().await;
-                        if received_message_hashes.contains(&message_hash) {
-                            info!("Message already received, skipping");
-                            return;
-                        }
+        Some(entry.target)
+    }
 
-                        received_message_hashes.push(message_hash);
-                    }
+    pub fn set_max_age(&mut self, max_age: Option<Duration>) {
+        self.max_age = max_age;
+    }
 
-                    app_logic.on_message(app_message).await;
-                })
-                .await;
-            }
-            VeilidUpdate::RouteChange(change) => {
-                info!("VeilidUpdate::RouteChange, {:?}", change);
+    pub fn set_lookup_retry(&mut self, lookup_retry: RetryPolicy) {
+        self.lookup_retry = lookup_retry;
+    }
 
-                let our_route_is_dead = change
-                    .dead_routes
-                    .iter()
-                    .filter(|r| **r == self.our_route)
-                    .count()
-                    > 0;
-                if our_route_is_dead {
-                    self.update_local_route().await?;
-                }
+    /// Probe a peer's published routes and send over the fastest. Off by
+    /// default, when the first route that imports is used.
+    pub fn set_probing(&mut self, probing: Option<RouteProbing>) {
+        self.probing = probing;
+    }
 
-                let mut routes = routes.lock().await;
-                for dead_route in change.dead_remote_routes {
-                    routes.remove_route_if_exists(dead_route);
-                }
+    /// Keep peers' DHT records open between lookups. Turning it off closes
+    /// the records held open.
+    pub async fn set_keep_records_open(&mut self, enabled: bool, routing_context: &RoutingContext) {
+        match (enabled, &mut self.open_records) {
+            (true, None) => self.open_records = Some(OpenRecords::default()),
+            (false, Some(open_records)) => {
+                open_records.close_all(routing_context).await;
+                self.open_records = None;
             }
             _ => (),
-        };
+        }
+    }
 
-        Ok(())
+    /// Close a peer's record if it is held open
+    pub async fn close_record(
+        &mut self,
+        dht_record: CryptoTyped<CryptoKey>,
+        routing_context: &RoutingContext,
+    ) -> Result<(), VeilidDuplexError> {
+        match &mut self.open_records {
+            Some(open_records) => open_records.close(routing_context, dht_record).await,
+            None => Ok(()),
+        }
     }
 
-    async fn update_local_route(&mut self) -> Result<(), Error> {
-        let (our_route, our_route_blob) = create_private_route(self.api.clone()).await?;
-        self.our_route = our_route;
-        update_service_route_pin(
-            self.routing_context.clone(),
-            our_route_blob,
-            self.our_dht_key,
-            self.dht_keypair,
-        )
-        .await?;
-        info!("DHT value for route {:} changed", self.our_route);
+    pub async fn close_records(&mut self, routing_context: &RoutingContext) {
+        if let Some(open_records) = &mut self.open_records {
+            open_records.close_all(routing_context).await;
+        }
+    }
 
-        Ok(())
+    /// Drop the cached route to a peer. Returns false if there was none.
+    pub fn invalidate(&mut self, dht_record: CryptoTyped<CryptoKey>) -> bool {
+        self.routes.remove(&dht_record.value).is_some()
+    }
+
+    pub fn clear(&mut self) {
+        self.routes.clear();
+    }
+
+    pub fn is_watched(&self, dht_record: CryptoTyped<CryptoKey>) -> bool {
+        self.watched.contains_key(&dht_record.value)
+    }
+
+    fn set_watch(&mut self, dht_record: CryptoTyped<CryptoKey>, expiration: u64) {
+        self.watched
+            .insert(dht_record.value, (dht_record, expiration));
+    }
+
+    fn remove_watch(&mut self, dht_record: CryptoTyped<CryptoKey>) -> bool {
+        self.watched.remove(&dht_record.value).is_some()
+    }
+
+    /// Watched peers whose watch expires within `margin`, or already has
+    fn watches_to_renew(&self, now: u64, margin: Duration) -> Vec<CryptoTyped<CryptoKey>> {
+        self.watched
+            .values()
+            .filter(|(_, expiration)| expiration.saturating_sub(now) < margin.as_micros() as u64)
+            .map(|(dht_record, _)| *dht_record)
+            .collect()
+    }
+
+    /// Cache a route a peer published in place of the one we had
+    fn replace_route(
+        &mut self,
+        dht_record: CryptoTyped<CryptoKey>,
+        target: Target,
+        route: CryptoKey,
+        now: u64,
+    ) {
+        let entry = self
+            .routes
+            .entry(dht_record.value)
+            .or_insert_with(|| RouteEntry {
+                dht_record,
+                target,
+                route,
+                created_at: now,
+                last_activity: now,
+                alive: true,
+                unacked_sends: 0,
+                failed_pings: 0,
+                candidates: Vec::new(),
+                probed_at: now,
+            });
+        entry.target = target;
+        entry.route = route;
+        entry.created_at = now;
+        entry.alive = true;
+        entry.unacked_sends = 0;
+        entry.failed_pings = 0;
+        // Probed again once the route is next looked up
+        entry.candidates.clear();
+    }
+
+    fn remove_route_if_exists(&mut self, dead_route: CryptoKey) -> Option<CryptoTyped<CryptoKey>> {
+        let key_to_remove: Option<CryptoKey> = self
+            .routes
+            .iter()
+            .filter(|(_, entry)| entry.route == dead_route)
+            .map(|(key, _)| *key)
+            .next();
+
+        self.routes
+            .remove(&key_to_remove?)
+            .map(|entry| entry.dht_record)
+    }
+
+    fn record_activity(&mut self, dht_record: CryptoTyped<CryptoKey>, alive: bool, now: u64) {
+        if let Some(entry) = self.routes.get_mut(&dht_record.value) {
+            entry.last_activity = now;
+            entry.alive = alive;
+            if alive {
+                entry.unacked_sends = 0;
+            }
+        }
+    }
+
+    /// Count a send that timed out waiting for the peer's reply. Returns true
+    /// when the peer has just reached `threshold` consecutive timeouts.
+    fn record_ack_timeout(&mut self, dht_record: CryptoTyped<CryptoKey>, threshold: u32) -> bool {
+        match self.routes.get_mut(&dht_record.value) {
+            Some(entry) => {
+                entry.unacked_sends += 1;
+                entry.unacked_sends == threshold
+            }
+            None => false,
+        }
+    }
+
+    fn targets(&self) -> Vec<(CryptoTyped<CryptoKey>, Target)> {
+        self.routes
+            .values()
+            .map(|entry| (entry.dht_record, entry.target))
+            .collect()
+    }
+
+    /// Record a keepalive ping. Returns true if the route reached
+    /// `failure_threshold` consecutive failures and was evicted.
+    fn record_ping(
+        &mut self,
+        dht_record: CryptoTyped<CryptoKey>,
+        ok: bool,
+        failure_threshold: u32,
+    ) -> bool {
+        let Some(entry) = self.routes.get_mut(&dht_record.value) else {
+            return false;
+        };
+
+        if ok {
+            entry.failed_pings = 0;
+            return false;
+        }
+
+        entry.failed_pings += 1;
+        if entry.failed_pings < failure_threshold {
+            return false;
+        }
+
+        self.routes.remove(&dht_record.value);
+        true
+    }
+
+    pub fn peers(&self, now: u64) -> Vec<PeerInfo> {
+        self.routes
+            .values()
+            .map(|entry| PeerInfo {
+                dht_record: entry.dht_record,
+                route_age: Duration::from_micros(now.saturating_sub(entry.created_at)),
+                idle_time: Duration::from_micros(now.saturating_sub(entry.last_activity)),
+                alive: entry.alive,
+                unacked_sends: entry.unacked_sends,
+            })
+            .collect()
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+#[derive(Clone)]
+pub struct VeilidDuplex {
+    pub api: VeilidAPI,
+    pub routing_context: RoutingContext,
+    // What app_calls and route lookups go through
+    pub transport: Arc<dyn Transport>,
+    pub receiver: Receiver<VeilidUpdate>,
+    pub our_route: CryptoKey,
+    // Blob our route was exported as, written to the DHT record
+    pub our_route_blob: Vec<u8>,
+    // Extra routes and their blobs, published in the subkeys after ROUTE_SUBKEY
+    pub backup_routes: Vec<(CryptoKey, Vec<u8>)>,
+    pub our_dht_key: CryptoTyped<CryptoKey>,
+    pub node_keypair: KeyPair,
+    pub dht_keypair: KeyPair,
+    pub routes: Arc<Mutex<VeilidDuplexRoutes>>,
+    // There can be multiple deliveries of the same message when the route is reported broken
+    // So far the easy fix is to log hashes of recently received message uuids, and drop ones that were already received
+    pub received_message_hashes: Arc<Mutex<DedupCache>>,
+    pub paused: Arc<AtomicBool>,
+    pub pause_mode: PauseMode,
+    pub paused_messages: Arc<Mutex<VecDeque<Vec<u8>>>>,
+    // App_calls not yet answered, in `PauseMode::Hold`
+    pub held_calls: Arc<Mutex<VecDeque<Box<VeilidAppCall>>>>,
+    // A route can keep working after the peer's app stopped replying, in which case
+    // app_call times out instead of failing to reach the route
+    pub unresponsive_threshold: u32,
+    pub on_peer_unresponsive: Option<PeerCallback>,
+    // Messages whose sends failed go here, when set
+    pub dead_letters: Option<Sender<DeadLetter>>,
+    // Queue `send_message` stores messages in for background delivery, when set
+    pub outbox: Option<Outbox>,
+    // Online status of the peers passed to `track_presence`
+    pub presence: PresenceTracker,
+    // Forwards our tracing events to a collector, when set
+    pub telemetry: Option<TelemetryForwarder>,
+    // Where telemetry from other nodes goes, when we collect it
+    pub telemetry_sink: Option<Sender<TelemetryBatch>>,
+    // Where message uuids and timestamps come from, replaceable in tests
+    pub uuid_source: Arc<dyn UuidSource>,
+    pub clock: Arc<dyn Clock>,
+    // Shared by clones, so a second network_loop on any clone is rejected
+    pub loop_running: Arc<AtomicBool>,
+    pub bandwidth: Arc<Mutex<BandwidthLimiter>>,
+    pub send_attempts: u16,
+    pub send_retry_interval: Duration,
+    pub reassembler: Arc<Mutex<Reassembler>>,
+    pub send_options: SendOptions,
+    pub peer_keys: Arc<Mutex<PeerKeys>>,
+    // Requests awaiting an answer, by request uuid, with the peer that has to
+    // give it
+    pub pending_requests: Arc<Mutex<HashMap<String, PendingRequest>>>,
+    pub attachment: Arc<Mutex<AttachmentStatus>>,
+    pub config: VeilidDuplexConfig,
+    pub counters: Arc<StatsCounters>,
+    pub throughput_log_interval: Option<Duration>,
+    pub last_throughput_log: Arc<AtomicU64>,
+    pub keepalive: Option<KeepaliveConfig>,
+    // Timestamp of the last keepalive round, shared so clones don't double up
+    pub last_keepalive: Arc<AtomicU64>,
+    // Rewrite our route to DHT this often so the record doesn't expire
+    pub pin_refresh_interval: Option<Duration>,
+    // Last time the route pin was written, by a refresh or a route change
+    pub last_pin_refresh: Arc<AtomicU64>,
+    // Last time watches on peers' route records were checked for renewal
+    pub last_watch_check: Arc<AtomicU64>,
+    pub handler_permits: HandlerPermits,
+    pub handler_overflow: HandlerOverflow,
+    // Run handlers one at a time per ordering key, off when None
+    pub message_ordering: Option<MessageOrdering>,
+    pub handler_queues: SerialQueues<Option<CryptoTyped<CryptoKey>>>,
+    pub max_inbound_size: usize,
+    // Reject inbound messages stamped outside this window, off when None
+    pub timestamp_window: Option<TimestampWindow>,
+    // Drop inbound messages that aren't signed by their claimed sender
+    pub require_signatures: bool,
+    // Senders whose messages are dropped before reaching `on_message`
+    pub peer_filter: Arc<Mutex<PeerFilter>>,
+    pub inbound_rate: Arc<Mutex<InboundRateLimiter>>,
+    pub reconnect_policy: ReconnectPolicy,
+    // Set while recovering from a lost attachment
+    reconnect: Option<Reconnect>,
+    // Circuit of `reconnect` as of the last cycle, for clones to read
+    pub reconnect_circuit: Arc<Mutex<CircuitState>>,
+    // Stops network loops for good, see `set_shutdown_token`
+    pub shutdown_token: CancellationToken,
+}
+
+// What `VeilidDuplex::assemble` builds an instance around
+struct CoreParts {
+    api: VeilidAPI,
+    routing_context: RoutingContext,
+    transport: Arc<dyn Transport>,
+    receiver: Receiver<VeilidUpdate>,
+    node_keypair: KeyPair,
+    dht_keypair: KeyPair,
+    our_route: CryptoKey,
+    our_route_blob: Vec<u8>,
+    backup_routes: Vec<(CryptoKey, Vec<u8>)>,
+    our_dht_key: CryptoTyped<CryptoKey>,
+    attachment: AttachmentStatus,
+}
+
+/// Sending half of a `VeilidDuplex`, see `VeilidDuplex::split`. Cheap to clone
+/// and shareable across tasks.
+#[derive(Clone)]
+pub struct DuplexSender {
+    pub api: VeilidAPI,
+    pub routing_context: RoutingContext,
+    pub transport: Arc<dyn Transport>,
+    pub routes: Arc<Mutex<VeilidDuplexRoutes>>,
+    pub our_dht_key: CryptoTyped<CryptoKey>,
+    dht_keypair: KeyPair,
+    send_options: SendOptions,
+    send_attempts: u16,
+    send_retry_interval: Duration,
+    unresponsive_threshold: u32,
+    on_peer_unresponsive: Option<PeerCallback>,
+    dead_letters: Option<Sender<DeadLetter>>,
+    outbox: Option<Outbox>,
+    uuid_source: Arc<dyn UuidSource>,
+    clock: Arc<dyn Clock>,
+    bandwidth: Arc<Mutex<BandwidthLimiter>>,
+    peer_keys: Arc<Mutex<PeerKeys>>,
+    pending_requests: Arc<Mutex<HashMap<String, PendingRequest>>>,
+    counters: Arc<StatsCounters>,
+}
+
+/// Receiving half of a `VeilidDuplex`. Owns the update receiver and runs the
+/// network loop.
+pub struct DuplexReceiver {
+    duplex: VeilidDuplex,
+}
+
+/// Configures and starts a `VeilidDuplex`. Node settings end up in
+/// `VeilidDuplexConfig`, which documents the veilid config key each maps to.
+#[derive(Debug, Clone)]
+pub struct VeilidDuplexBuilder {
+    config: VeilidDuplexConfig,
+    send_attempts: u16,
+    pin_refresh_interval: Option<Duration>,
+    keepalive: bool,
+    reconnect_policy: ReconnectPolicy,
+    shutdown_token: Option<CancellationToken>,
+    max_concurrent_handlers: usize,
+    dead_letters: Option<Sender<DeadLetter>>,
+    outbox: Option<Outbox>,
+}
+
+impl Default for VeilidDuplexBuilder {
+    fn default() -> Self {
+        Self::from(VeilidDuplexConfig::default())
+    }
+}
+
+impl From<VeilidDuplexConfig> for VeilidDuplexBuilder {
+    fn from(config: VeilidDuplexConfig) -> Self {
+        Self {
+            config,
+            send_attempts: SEND_ATTEMPTS,
+            pin_refresh_interval: None,
+            keepalive: false,
+            reconnect_policy: ReconnectPolicy::default(),
+            shutdown_token: None,
+            max_concurrent_handlers: MAX_CONCURRENT_HANDLERS,
+            dead_letters: None,
+            outbox: None,
+        }
+    }
+}
+
+impl VeilidDuplexBuilder {
+    /// Keep veilid's stores under `storage_dir` instead of a temporary directory
+    pub fn storage_dir(mut self, storage_dir: impl Into<PathBuf>) -> Self {
+        self.config.storage_dir = Some(storage_dir.into());
+        self
+    }
+
+    pub fn bootstrap(mut self, bootstrap: Vec<String>) -> Self {
+        self.config.bootstrap = bootstrap;
+        self
+    }
+
+    /// Join the private network secured by `network_key`
+    pub fn network_key(mut self, network_key: impl Into<String>) -> Self {
+        self.config.network_key_password = Some(network_key.into());
+        self
+    }
+
+    /// Whether messages must travel over ordered protocols (TCP, WebSockets).
+    /// `EnsureOrdered` suits chat-like apps that need messages in order, at the
+    /// cost of reaching fewer peers; `NoPreference` also uses UDP, which is
+    /// faster but can reorder messages, e.g. for game state where only the
+    /// latest update matters. Defaults to `PreferOrdered`.
+    pub fn sequencing(mut self, sequencing: Sequencing) -> Self {
+        self.config.sequencing = sequencing;
+        self
+    }
+
+    /// What our private route is built for. `Reliable` picks hops that stay
+    /// up, so the route survives longer; `LowLatency` picks fast hops, which
+    /// suits real-time traffic but means more route changes. Defaults to
+    /// `Reliable`.
+    pub fn stability(mut self, stability: Stability) -> Self {
+        self.config.stability = stability;
+        self
+    }
+
+    /// Publish `route_count` private routes instead of one, so peers have a
+    /// fallback when a route fails to import. At most `MAX_ROUTE_COUNT`.
+    pub fn route_count(mut self, route_count: u16) -> Self {
+        self.config.route_count = route_count;
+        self
+    }
+
+    /// Hops in our private route, from 1 for speed up to
+    /// `MAX_ROUTE_HOP_COUNT` for privacy. Defaults to 1.
+    pub fn route_hop_count(mut self, route_hop_count: u8) -> Self {
+        self.config.route_hop_count = route_hop_count;
+        self
+    }
+
+    /// Give up starting if any startup phase takes longer than `timeout`,
+    /// instead of waiting forever for the network
+    pub fn startup_timeout(mut self, timeout: Duration) -> Self {
+        self.config.startup_waits = self.config.startup_waits.with_timeout(timeout);
+        self
+    }
+
+    /// Polling intervals and timeouts of each startup phase
+    pub fn startup_waits(mut self, startup_waits: StartupWaits) -> Self {
+        self.config.startup_waits = startup_waits;
+        self
+    }
+
+    /// Publish our node id instead of a private route, so peers send to us
+    /// directly. See `Addressing::NodeId`.
+    pub fn addressing(mut self, addressing: Addressing) -> Self {
+        self.config.addressing = addressing;
+        self
+    }
+
+    /// Transports the node connects over. Defaults to UDP and TCP, or to
+    /// websockets on wasm, where UDP and TCP fail validation.
+    pub fn protocols(mut self, protocols: NetworkProtocols) -> Self {
+        self.config.veilid_options.protocols = protocols;
+        self
+    }
+
+    /// Retries of connecting through a restricted NAT,
+    /// `network.restricted_nat_retries`. Defaults to 3, or 0 on wasm.
+    pub fn restricted_nat_retries(mut self, retries: u32) -> Self {
+        self.config.veilid_options.restricted_nat_retries = retries;
+        self
+    }
+
+    /// Most records of other nodes kept in our share of the DHT,
+    /// `network.dht.remote_max_records`. Defaults to 4096.
+    pub fn dht_remote_max_records(mut self, max_records: u32) -> Self {
+        self.config.veilid_options.dht_remote_max_records = max_records;
+        self
+    }
+
+    /// Whether the node maps its port through UPnP, `network.upnp`. Servers
+    /// behind a managed firewall or in containers are better off without.
+    /// Defaults to true.
+    pub fn upnp(mut self, enabled: bool) -> Self {
+        self.config.veilid_options.upnp = enabled;
+        self
+    }
+
+    /// Whether the node watches for its public address changing,
+    /// `network.detect_address_changes`. Defaults to true.
+    pub fn detect_address_changes(mut self, enabled: bool) -> Self {
+        self.config.veilid_options.detect_address_changes = enabled;
+        self
+    }
+
+    /// Any other veilid setting, replacing the ones set by `protocols`,
+    /// `restricted_nat_retries`, `dht_remote_max_records`, `upnp` and
+    /// `detect_address_changes`
+    pub fn veilid_options(mut self, options: VeilidConfigOptions) -> Self {
+        self.config.veilid_options = options;
+        self
+    }
+
+    /// How often creating and publishing our route is retried during startup
+    pub fn startup_retry(mut self, startup_retry: RetryPolicy) -> Self {
+        self.config.startup_retry = startup_retry;
+        self
+    }
+
+    pub fn crypto_kind(mut self, crypto_kind: CryptoKind) -> Self {
+        self.config.crypto_kind = crypto_kind;
+        self
+    }
+
+    /// Keep the node keypair in `identity_path` so the node id survives
+    /// restarts. Unless `storage_dir` is set, veilid's stores move next to it.
+    pub fn persistent_identity_path(mut self, identity_path: impl Into<PathBuf>) -> Self {
+        self.config.identity_path = Some(identity_path.into());
+        self
+    }
+
+    /// Publish our route in an existing DHT record, keeping our address
+    pub fn service_keys(mut self, service_keys: ServiceKeys) -> Self {
+        self.config.service_keys = Some(service_keys);
+        self
+    }
+
+    /// Reuse the DHT record saved in `path`, creating and saving one on the
+    /// first start
+    pub fn service_keys_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.service_keys_path = Some(path.into());
+        self
+    }
+
+    /// See `VeilidDuplex::set_send_retry_policy`
+    pub fn send_attempts(mut self, send_attempts: u16) -> Self {
+        self.send_attempts = send_attempts;
+        self
+    }
+
+    /// See `VeilidDuplex::set_max_concurrent_handlers`
+    pub fn max_concurrent_handlers(mut self, limit: usize) -> Self {
+        self.max_concurrent_handlers = limit;
+        self
+    }
+
+    /// See `VeilidDuplex::set_pin_refresh_interval`
+    pub fn pin_refresh_interval(mut self, interval: Duration) -> Self {
+        self.pin_refresh_interval = Some(interval);
+        self
+    }
+
+    /// Ping cached routes with `VeilidDuplex::default_keepalive`, keeping
+    /// connections to peers open between messages
+    pub fn keepalive(mut self, enabled: bool) -> Self {
+        self.keepalive = enabled;
+        self
+    }
+
+    /// See `VeilidDuplex::set_reconnect_policy`
+    pub fn reconnect_policy(mut self, reconnect_policy: ReconnectPolicy) -> Self {
+        self.reconnect_policy = reconnect_policy;
+        self
+    }
+
+    /// See `VeilidDuplex::set_shutdown_token`
+    pub fn shutdown_token(mut self, shutdown_token: CancellationToken) -> Self {
+        self.shutdown_token = Some(shutdown_token);
+        self
+    }
+
+    /// See `VeilidDuplex::set_dead_letters`
+    pub fn dead_letters(mut self, dead_letters: Sender<DeadLetter>) -> Self {
+        self.dead_letters = Some(dead_letters);
+        self
+    }
+
+    /// See `VeilidDuplex::set_outbox`
+    pub fn outbox(mut self, outbox: Outbox) -> Self {
+        self.outbox = Some(outbox);
+        self
+    }
+
+    /// Start the node, attach to the network and publish our route
+    pub async fn build(self) -> Result<VeilidDuplex, VeilidDuplexError> {
+        let mut duplex = VeilidDuplex::start(self.config).await?;
+        duplex.send_attempts = self.send_attempts;
+        duplex.pin_refresh_interval = self.pin_refresh_interval;
+        if self.keepalive {
+            duplex.keepalive = Some(duplex.default_keepalive());
+        }
+        duplex.reconnect_policy = self.reconnect_policy;
+        if let Some(shutdown_token) = self.shutdown_token {
+            duplex.shutdown_token = shutdown_token;
+        }
+        duplex.set_max_concurrent_handlers(self.max_concurrent_handlers);
+        duplex.dead_letters = self.dead_letters;
+        duplex.outbox = self.outbox;
+        Ok(duplex)
+    }
+}
+
+// Clears the running flag however network_loop exits, including being dropped
+struct LoopGuard(Arc<AtomicBool>);
+
+impl Drop for LoopGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Network loop started by `VeilidDuplex::spawn_network_loop`. Dropping the
+/// handle leaves the loop running; call `abort` to stop it.
+pub struct NetworkLoopHandle {
+    cancel: CancellationToken,
+    result: Receiver<Result<(), VeilidDuplexError>>,
+}
+
+impl NetworkLoopHandle {
+    /// Stop the loop, as with `VeilidDuplex::network_loop_until`. `join`
+    /// returns once handlers already running have finished.
+    pub fn abort(&self) {
+        self.cancel.cancel();
+    }
+
+    /// Wait for the loop to end: `Ok` once aborted, or the error that stopped it
+    pub async fn join(self) -> Result<(), VeilidDuplexError> {
+        self.result
+            .recv_async()
+            .await
+            .map_err(|_| VeilidDuplexError::Other(anyhow::anyhow!("Network loop task vanished")))?
+    }
+}
+
+// Stream returned by `VeilidDuplex::incoming`, stopping its loop when dropped
+struct IncomingStream<T> {
+    messages: BoxStream<'static, AppMessage<T>>,
+    cancel: CancellationToken,
+}
+
+impl<T> Stream for IncomingStream<T> {
+    type Item = AppMessage<T>;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        self.messages.poll_next_unpin(cx)
+    }
+}
+
+impl<T> Drop for IncomingStream<T> {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+/// Where the answer to a request has to come from, and where it goes
+pub type PendingRequest = (CryptoTyped<CryptoKey>, Sender<Vec<u8>>);
+
+// Feeds messages into the stream returned by `VeilidDuplex::incoming`
+struct StreamLogic<T> {
+    sender: Sender<AppMessage<T>>,
+}
+
+impl<T> Clone for StreamLogic<T> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+impl<T> AppLogic<T> for StreamLogic<T>
+where
+    T: DeserializeOwned + Send,
+{
+    async fn on_message(&mut self, message: AppMessage<T>) -> Result<(), HandlerError> {
+        self.sender
+            .send_async(message)
+            .await
+            .map_err(|_| HandlerError::new("Message stream closed"))
+    }
+}
+
+impl<T: DeserializeOwned + Serialize> AppMessage<T> {
+    pub async fn send(
+        &mut self,
+        routing_context: &RoutingContext,
+        target: Target,
+    ) -> Result<Vec<u8>, VeilidDuplexError> {
+        self.send_with_options(routing_context, target, &SendOptions::default())
+            .await
+    }
+
+    pub async fn send_with_options(
+        &mut self,
+        routing_context: &RoutingContext,
+        target: Target,
+        options: &SendOptions,
+    ) -> Result<Vec<u8>, VeilidDuplexError> {
+        self.stamp(&RandomUuids, &SystemClock);
+        let app_message_blob = self.encode(options)?;
+
+        info!(
+            "Sending message, origin_dht: {:?}, target: {:?}",
+            self.dht_record,
+            target.clone()
+        );
+
+        send_blob(routing_context, target, app_message_blob).await
+    }
+
+    /// Serialize the message, compressing it if enabled
+    pub fn encode(&self, options: &SendOptions) -> Result<Vec<u8>, VeilidDuplexError> {
+        let app_message_blob = options.codec.encode(self)?;
+        Ok(compress(app_message_blob, &options.compression)?)
+    }
+
+    /// Send `data` back to whoever sent this message, as an answer to it: the
+    /// reply's `reply_to` is this message's uuid, so it completes a pending
+    /// `send_request`. Returns the reply's uuid.
+    pub async fn reply(&self, sender: &DuplexSender, data: T) -> Result<String, VeilidDuplexError>
+    where
+        T: Send + 'static,
+    {
+        let reply = AppMessage {
+            data,
+            uuid: String::new(),
+            dht_record: sender.our_dht_key,
+            reply_to: Some(self.uuid.clone()),
+            timestamp: 0,
+            topic: self.topic.clone(),
+        };
+
+        sender.send_message(reply, self.dht_record).await
+    }
+
+    /// Time since the message was sent, by `clock`, normally the receiving
+    /// node's `VeilidDuplex::clock`. Zero if the sender's clock is ahead or it
+    /// didn't set a timestamp.
+    pub fn age(&self, clock: &dyn Clock) -> Duration {
+        if self.timestamp == 0 {
+            return Duration::ZERO;
+        }
+
+        let now = clock.now() / 1000;
+        Duration::from_millis(now.saturating_sub(self.timestamp))
+    }
+
+    // Retries reuse the uuid and timestamp, so they read as the same message
+    pub(crate) fn stamp(&mut self, uuids: &dyn UuidSource, clock: &dyn Clock) {
+        self.uuid = format!("{}", uuids.next_uuid());
+        self.timestamp = clock.now() / 1000;
+    }
+}
+
+impl VeilidDuplex {
+    async fn initialize(
+        config: &VeilidDuplexConfig,
+    ) -> Result<(VeilidAPI, RoutingContext, Receiver<VeilidUpdate>, KeyPair), VeilidDuplexError>
+    {
+        let (sender, receiver): (
+            Sender<veilid_core::VeilidUpdate>,
+            Receiver<veilid_core::VeilidUpdate>,
+        ) = unbounded();
+
+        // Create VeilidCore setup
+        let update_callback = Arc::new(move |change: veilid_core::VeilidUpdate| {
+            if let Err(e) = sender.send(change) {
+                let change = e.into_inner();
+                info!("error sending veilid update callback: {:?}", change);
+            }
+        });
+
+        #[cfg(not(target_arch = "wasm32"))]
+        let node_keypair = match &config.identity_path {
+            Some(identity_path) => load_or_create_keypair(identity_path, config.crypto_kind)?,
+            None => veilid_core::Crypto::generate_keypair(config.crypto_kind)?.value,
+        };
+        #[cfg(target_arch = "wasm32")]
+        let node_keypair = veilid_core::Crypto::generate_keypair(config.crypto_kind)?.value;
+
+        #[cfg(target_arch = "wasm32")]
+        let api = create_api_and_connect(update_callback, config.clone()).await?;
+        #[cfg(not(target_arch = "wasm32"))]
+        let api =
+            create_api_and_connect_with_keypair(update_callback, node_keypair, config.clone())
+                .await?;
+
+        let rc = api.routing_context()?.with_sequencing(config.sequencing);
+
+        Ok((api, rc, receiver, node_keypair))
+    }
+
+    pub async fn new() -> Result<Self, VeilidDuplexError> {
+        VeilidDuplexBuilder::default().build().await
+    }
+
+    /// Start a node with custom network settings, e.g. to join a private
+    /// network with its own bootstrap nodes
+    pub async fn with_config(config: VeilidDuplexConfig) -> Result<Self, VeilidDuplexError> {
+        VeilidDuplexBuilder::from(config).build().await
+    }
+
+    pub fn builder() -> VeilidDuplexBuilder {
+        VeilidDuplexBuilder::default()
+    }
+
+    async fn start(config: VeilidDuplexConfig) -> Result<Self, VeilidDuplexError> {
+        config.validate()?;
+        let (api, routing_context, receiver, node_keypair) = Self::initialize(&config).await?;
+
+        let retry = config.startup_retry;
+        let new_route = |_: u16| {
+            create_private_route(
+                api.clone(),
+                config.crypto_kind,
+                config.stability,
+                config.sequencing,
+            )
+        };
+
+        let (our_route, our_route_blob) =
+            retry_with_backoff("Creating private route", retry, new_route).await?;
+        info!("our route: {}", our_route);
+        let published_blob = match config.addressing {
+            Addressing::PrivateRoute => our_route_blob.clone(),
+            Addressing::NodeId => {
+                node_id_blob(CryptoTyped::new(config.crypto_kind, node_keypair.key))
+            }
+        };
+        let service_keys = match (&config.service_keys, &config.service_keys_path) {
+            (Some(service_keys), _) => Some(service_keys.clone()),
+            (None, Some(path)) if path.exists() => Some(ServiceKeys::load(path)?),
+            _ => None,
+        };
+        let (our_dht_key, dht_keypair) = match service_keys {
+            Some(service_keys) => {
+                retry_with_backoff("Publishing route", retry, |_| {
+                    update_service_route_pin(
+                        routing_context.clone(),
+                        published_blob.clone(),
+                        service_keys.dht_key,
+                        service_keys.dht_owner_keypair(),
+                        ROUTE_SUBKEY,
+                    )
+                })
+                .await?;
+                (service_keys.dht_key, service_keys.dht_owner_keypair())
+            }
+            None => {
+                let (our_dht_key, dht_keypair) =
+                    retry_with_backoff("Publishing route", retry, |_| {
+                        create_service_route_pin(
+                            routing_context.clone(),
+                            published_blob.clone(),
+                            ROUTE_SUBKEY,
+                            ROUTE_SUBKEY as u16 + config.route_count,
+                            config.crypto_kind,
+                        )
+                    })
+                    .await?;
+                if let Some(path) = &config.service_keys_path {
+                    ServiceKeys::new(our_dht_key, dht_keypair).save(path)?;
+                }
+                (our_dht_key, dht_keypair)
+            }
+        };
+
+        let mut backup_routes = Vec::new();
+        for _ in 1..config.route_count {
+            backup_routes
+                .push(retry_with_backoff("Creating backup route", retry, new_route).await?);
+        }
+        if !backup_routes.is_empty() {
+            set_dht_values(
+                routing_context.clone(),
+                our_dht_key,
+                dht_keypair,
+                backup_pins(&backup_routes),
+            )
+            .await?
+            .check_route_pins()?;
+        }
+
+        let state = api.get_state().await?;
+        let attachment = AttachmentStatus {
+            state: state.attachment.state,
+            public_internet_ready: state.attachment.public_internet_ready,
+        };
+        let transport = Arc::new(VeilidTransport {
+            api: api.clone(),
+            routing_context: routing_context.clone(),
+        });
+
+        Ok(Self::assemble(
+            CoreParts {
+                api,
+                routing_context,
+                transport,
+                receiver,
+                node_keypair,
+                dht_keypair,
+                our_route,
+                our_route_blob,
+                backup_routes,
+                our_dht_key,
+                attachment,
+            },
+            config,
+        ))
+    }
+
+    /// Two instances connected through a `LoopbackNetwork` instead of Veilid,
+    /// for tests that shouldn't depend on the network
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn in_memory_pair() -> Result<(Self, Self), VeilidDuplexError> {
+        let network = LoopbackNetwork::new();
+        let app = Self::in_memory(&network).await?;
+        let peer = Self::in_memory(&network).await?;
+
+        // No DHT record owners to look up on the loopback
+        app.peer_keys
+            .lock()
+            .await
+            .remember_owner(peer.our_dht_key, peer.dht_keypair.key);
+        peer.peer_keys
+            .lock()
+            .await
+            .remember_owner(app.our_dht_key, app.dht_keypair.key);
+
+        Ok((app, peer))
+    }
+
+    /// Instance reachable by the others on `network`. Its Veilid core is
+    /// started for crypto but never attached; messages, acks and route lookups
+    /// all go through the loopback.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn in_memory(network: &LoopbackNetwork) -> Result<Self, VeilidDuplexError> {
+        let config = VeilidDuplexConfig::default();
+        let (updates, receiver) = unbounded();
+
+        // Only the loopback feeds `receiver`, so core updates are dropped
+        let node_keypair = Crypto::generate_keypair(config.crypto_kind)?.value;
+        let api =
+            start_api_with_keypair(Arc::new(|_: VeilidUpdate| {}), node_keypair, config.clone())
+                .await?;
+        let routing_context = api.routing_context()?.with_sequencing(config.sequencing);
+
+        let dht_keypair = Crypto::generate_keypair(config.crypto_kind)?.value;
+        let our_dht_key = CryptoTyped::new(config.crypto_kind, CryptoKey::new(rand::random()));
+        let our_route = CryptoKey::new(rand::random());
+        let our_route_blob = network.add_endpoint(our_route, updates);
+        network
+            .set_dht_value(
+                our_dht_key,
+                ROUTE_SUBKEY,
+                our_route_blob.clone(),
+                dht_keypair,
+            )
+            .await?;
+
+        Ok(Self::assemble(
+            CoreParts {
+                api,
+                routing_context,
+                transport: Arc::new(network.clone()),
+                receiver,
+                node_keypair,
+                dht_keypair,
+                our_route,
+                our_route_blob,
+                backup_routes: Vec::new(),
+                our_dht_key,
+                attachment: AttachmentStatus {
+                    state: AttachmentState::AttachedGood,
+                    public_internet_ready: true,
+                },
+            },
+            config,
+        ))
+    }
+
+    // Everything but the node and its network handles starts out at defaults
+    fn assemble(parts: CoreParts, config: VeilidDuplexConfig) -> Self {
+        let counters = Arc::new(StatsCounters::default());
+        let mut received_message_hashes = DedupCache::default();
+        received_message_hashes.set_on_evict(Some(count_evictions(counters.clone(), None)));
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        let now = clock.now();
+
+        Self {
+            api: parts.api,
+            routing_context: parts.routing_context,
+            transport: parts.transport,
+            receiver: parts.receiver,
+            node_keypair: parts.node_keypair,
+            dht_keypair: parts.dht_keypair,
+            our_route: parts.our_route,
+            our_route_blob: parts.our_route_blob,
+            backup_routes: parts.backup_routes,
+            routes: Arc::new(Mutex::new(VeilidDuplexRoutes::new(ROUTE_SUBKEY))),
+            our_dht_key: parts.our_dht_key,
+            received_message_hashes: Arc::new(Mutex::new(received_message_hashes)),
+            paused: Arc::new(AtomicBool::new(false)),
+            pause_mode: PauseMode::default(),
+            paused_messages: Arc::new(Mutex::new(VecDeque::new())),
+            held_calls: Arc::new(Mutex::new(VecDeque::new())),
+            unresponsive_threshold: UNRESPONSIVE_THRESHOLD,
+            on_peer_unresponsive: None,
+            dead_letters: None,
+            outbox: None,
+            presence: PresenceTracker::default(),
+            telemetry: None,
+            telemetry_sink: None,
+            uuid_source: Arc::new(RandomUuids),
+            clock,
+            loop_running: Arc::new(AtomicBool::new(false)),
+            bandwidth: Arc::new(Mutex::new(BandwidthLimiter::default())),
+            send_attempts: SEND_ATTEMPTS,
+            send_retry_interval: SEND_RETRY_INTERVAL,
+            reassembler: Arc::new(Mutex::new(Reassembler::default())),
+            send_options: SendOptions::default(),
+            peer_keys: Arc::new(Mutex::new(PeerKeys::default())),
+            pending_requests: Arc::new(Mutex::new(HashMap::new())),
+            attachment: Arc::new(Mutex::new(parts.attachment)),
+            config,
+            counters,
+            throughput_log_interval: None,
+            last_throughput_log: Arc::new(AtomicU64::new(now)),
+            keepalive: None,
+            last_keepalive: Arc::new(AtomicU64::new(now)),
+            pin_refresh_interval: None,
+            last_pin_refresh: Arc::new(AtomicU64::new(now)),
+            last_watch_check: Arc::new(AtomicU64::new(now)),
+            handler_permits: HandlerPermits::default(),
+            handler_overflow: HandlerOverflow::default(),
+            message_ordering: None,
+            handler_queues: SerialQueues::default(),
+            max_inbound_size: MAX_INBOUND_SIZE,
+            timestamp_window: None,
+            require_signatures: false,
+            peer_filter: Arc::new(Mutex::new(PeerFilter::default())),
+            inbound_rate: Arc::new(Mutex::new(InboundRateLimiter::default())),
+            reconnect_policy: ReconnectPolicy::default(),
+            reconnect: None,
+            reconnect_circuit: Arc::new(Mutex::new(CircuitState::Closed)),
+            shutdown_token: CancellationToken::new(),
+        }
+    }
+
+    /// Stop delivering messages to `on_message`. The node stays attached;
+    /// inbound messages are buffered, dropped or held according to
+    /// `pause_mode`.
+    pub fn pause(&self) {
+        info!("Pausing message processing");
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Resume delivery. Buffered and held messages are handled in arrival
+    /// order by the next cycles of the network loop.
+    pub fn resume(&self) {
+        info!("Resuming message processing");
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    pub fn set_pause_mode(&mut self, pause_mode: PauseMode) {
+        self.pause_mode = pause_mode;
+    }
+
+    /// How many times `send_message` tries before giving up, and how long it
+    /// waits between tries. Defaults to 1024 attempts 500ms apart.
+    pub fn set_send_retry_policy(&mut self, send_attempts: u16, send_retry_interval: Duration) {
+        self.send_attempts = send_attempts;
+        self.send_retry_interval = send_retry_interval;
+    }
+
+    /// Called once a peer's app has left `unresponsive_threshold` consecutive
+    /// sends unanswered while its route still accepted them.
+    pub fn set_on_peer_unresponsive(
+        &mut self,
+        callback: impl Fn(CryptoTyped<CryptoKey>) + Send + Sync + 'static,
+    ) {
+        self.on_peer_unresponsive = Some(Arc::new(callback));
+    }
+
+    /// Hand messages whose sends failed to `dead_letters`, with their
+    /// destination and the error, as well as returning the error. Sends
+    /// through `send_message_to_target` aren't covered.
+    pub fn set_dead_letters(&mut self, dead_letters: Option<Sender<DeadLetter>>) {
+        self.dead_letters = dead_letters;
+    }
+
+    /// Have `send_message` store messages in `outbox` and return once they are
+    /// stored, instead of waiting for delivery. The network loop sends them in
+    /// the background, retrying with backoff until the peer acknowledges them,
+    /// including messages left from before a restart. Other sends, like
+    /// `broadcast` or `send_request`, still go out directly.
+    pub fn set_outbox(&mut self, outbox: Option<Outbox>) {
+        self.outbox = outbox;
+    }
+
+    /// Probe `peer` from the network loop every `PresenceConfig::interval`,
+    /// keeping its online status for `peer_status`. Returns false if it was
+    /// tracked already.
+    pub fn track_presence(&self, peer: CryptoTyped<CryptoKey>) -> bool {
+        self.presence.track(peer)
+    }
+
+    pub fn untrack_presence(&self, peer: CryptoTyped<CryptoKey>) -> bool {
+        self.presence.untrack(peer)
+    }
+
+    /// Last known status of a tracked peer, `Unknown` for others
+    pub fn peer_status(&self, peer: CryptoTyped<CryptoKey>) -> Presence {
+        self.presence.status(peer)
+    }
+
+    /// See `PresenceTracker::subscribe`
+    pub fn presence_changes(&self) -> Receiver<PresenceChange> {
+        self.presence.subscribe()
+    }
+
+    pub fn set_presence_config(&mut self, config: PresenceConfig) {
+        self.presence.set_config(config);
+    }
+
+    /// Called whenever a tracked peer goes online or offline
+    pub fn set_on_presence_change(
+        &mut self,
+        callback: impl Fn(&PresenceChange) + Send + Sync + 'static,
+    ) {
+        self.presence.set_on_change(callback);
+    }
+
+    /// Forward the events captured by `config.source` to `config.collector`
+    /// from the network loop, in batches every `config.interval`. Batches go
+    /// past the collector's `on_message` to its `set_telemetry_sink`. Off by
+    /// default; `None` turns it off again.
+    pub fn set_telemetry(&mut self, config: Option<TelemetryConfig>) {
+        let now = self.clock.now();
+        self.telemetry = config.map(|config| TelemetryForwarder::new(config, now));
+    }
+
+    /// Collect telemetry batches other nodes send us into `sink`. Without one
+    /// they are refused. The sending node isn't verified.
+    pub fn set_telemetry_sink(&mut self, sink: Option<Sender<TelemetryBatch>>) {
+        self.telemetry_sink = sink;
+    }
+
+    /// Take message uuids from `uuid_source` instead of random v4 uuids
+    pub fn set_uuid_source(&mut self, uuid_source: impl UuidSource + 'static) {
+        self.uuid_source = Arc::new(uuid_source);
+    }
+
+    /// Read the time from `clock` when stamping outgoing messages and checking
+    /// inbound ones, instead of the system clock
+    pub fn set_clock(&mut self, clock: impl Clock + 'static) {
+        self.clock = Arc::new(clock);
+
+        // Periodic work is timed from now on the new clock
+        let now = self.clock.now();
+        for last in [
+            &self.last_throughput_log,
+            &self.last_keepalive,
+            &self.last_pin_refresh,
+            &self.last_watch_check,
+        ] {
+            last.store(now, Ordering::SeqCst);
+        }
+        if let Some(telemetry) = &mut self.telemetry {
+            *telemetry = TelemetryForwarder::new(telemetry.config().clone(), now);
+        }
+    }
+
+    /// Deflate outgoing messages of at least `threshold` serialized bytes. Peers
+    /// decompress based on a marker in the message, whatever their own setting.
+    pub fn set_compression(&mut self, enabled: bool, threshold: usize) {
+        self.send_options.compression = CompressionConfig { enabled, threshold };
+    }
+
+    /// Format outgoing messages are serialized in. Inbound messages are decoded
+    /// with whatever codec their sender used.
+    pub fn set_codec(&mut self, codec: MessageCodec) {
+        self.send_options.codec = codec;
+    }
+
+    /// Largest inbound message accepted, after reassembly and decompression.
+    /// Bigger ones are rejected before they are decoded.
+    pub fn set_max_inbound_size(&mut self, max_inbound_size: usize) {
+        self.max_inbound_size = max_inbound_size;
+    }
+
+    /// Reject inbound messages whose send timestamp is too far behind or
+    /// ahead of our clock; `None` turns the check off. Messages from peers
+    /// that don't stamp them are let through.
+    pub fn set_timestamp_window(&mut self, timestamp_window: Option<TimestampWindow>) {
+        self.timestamp_window = timestamp_window;
+    }
+
+    /// How many inbound messages may be in `on_message` at once. Handlers
+    /// already running keep their permits from the previous limit.
+    pub fn set_max_concurrent_handlers(&mut self, limit: usize) {
+        self.handler_permits = HandlerPermits::new(limit);
+    }
+
+    /// Whether inbound messages wait for a handler or are answered
+    /// `AckStatus::Busy` once `set_max_concurrent_handlers` is reached
+    pub fn set_handler_overflow(&mut self, handler_overflow: HandlerOverflow) {
+        self.handler_overflow = handler_overflow;
+    }
+
+    /// Hand messages sharing an ordering key to `on_message` one at a time, in
+    /// the order they arrived. `None` lets every handler run concurrently.
+    pub fn set_message_ordering(&mut self, message_ordering: Option<MessageOrdering>) {
+        self.message_ordering = message_ordering;
+    }
+
+    /// Encrypt outgoing messages end to end, keyed on our DHT record owner key and
+    /// the recipient's. Encrypted inbound messages are always decrypted.
+    pub fn set_encryption(&mut self, enabled: bool) {
+        self.send_options.encrypt = enabled;
+    }
+
+    /// Sign outgoing messages with our DHT record owner key. Signed inbound
+    /// messages are always verified.
+    pub fn set_signing(&mut self, enabled: bool) {
+        self.send_options.sign = enabled;
+    }
+
+    /// Drop inbound messages that aren't signed by the owner of the DHT record
+    /// they claim to come from
+    pub fn set_require_signatures(&mut self, required: bool) {
+        self.require_signatures = required;
+    }
+
+    /// How many recent message hashes are kept to drop redeliveries. A smaller
+    /// window saves memory but lets late duplicates through.
+    pub async fn set_dedup_capacity(&self, capacity: usize) {
+        self.received_message_hashes
+            .lock()
+            .await
+            .set_capacity(capacity);
+    }
+
+    /// Call `callback` with each message hash the full dedup window evicts
+    /// before it expired, on top of counting them in `stats`. Runs with the
+    /// window locked, so keep it quick.
+    pub async fn set_on_dedup_eviction(&self, callback: impl Fn(u64) + Send + Sync + 'static) {
+        let on_evict = count_evictions(self.counters.clone(), Some(Arc::new(callback)));
+        self.received_message_hashes
+            .lock()
+            .await
+            .set_on_evict(Some(on_evict));
+    }
+
+    /// How long a message hash is kept to drop redeliveries, `None` to keep
+    /// hashes until the capacity evicts them
+    pub async fn set_dedup_ttl(&self, ttl: Option<Duration>) {
+        self.received_message_hashes.lock().await.set_ttl(ttl);
+    }
+
+    /// Whether senders on neither the allowlist nor the blocklist get through
+    pub async fn set_peer_filter_mode(&self, mode: FilterMode) {
+        self.peer_filter.lock().await.set_mode(mode);
+    }
+
+    /// Let a sender through even when the filter is closed
+    pub async fn allow_peer(&self, peer: CryptoTyped<CryptoKey>) {
+        self.peer_filter.lock().await.allow(peer);
+    }
+
+    /// Drop a sender's messages. They are still ACKed, so the sender can't
+    /// tell it is blocked.
+    pub async fn block_peer(&self, peer: CryptoTyped<CryptoKey>) {
+        self.peer_filter.lock().await.block(peer);
+    }
+
+    /// Take a sender off the allowlist and the blocklist
+    pub async fn forget_peer(&self, peer: CryptoTyped<CryptoKey>) {
+        self.peer_filter.lock().await.forget(peer);
+    }
+
+    /// How long a resolved route is trusted before it is looked up on DHT again,
+    /// which recovers from route changes we weren't told about. `None` keeps
+    /// routes until they are reported dead.
+    pub async fn set_route_ttl(&self, ttl: Option<Duration>) {
+        self.routes.lock().await.set_max_age(ttl);
+    }
+
+    /// How often a peer's route is looked up again when it isn't on DHT yet or
+    /// doesn't import, before the send attempt fails
+    pub async fn set_route_lookup_retry(&self, retry: RetryPolicy) {
+        self.routes.lock().await.set_lookup_retry(retry);
+    }
+
+    /// See `VeilidDuplexRoutes::set_probing`. Re-probing rides on keepalive
+    /// rounds, so it needs `set_keepalive` too.
+    pub async fn set_route_probing(&self, probing: Option<RouteProbing>) {
+        self.routes.lock().await.set_probing(probing);
+    }
+
+    /// How long chunks of a large inbound message are kept while waiting for
+    /// the rest. Incomplete messages are dropped after this.
+    pub async fn set_reassembly_timeout(&self, timeout: Duration) {
+        self.reassembler.lock().await.set_timeout(timeout);
+    }
+
+    /// Cap outbound bytes across all peers. Sends over the limit are delayed, not dropped.
+    pub async fn set_bandwidth_limit(&self, limit: Option<BandwidthLimit>) {
+        self.bandwidth.lock().await.set_global_limit(limit);
+    }
+
+    /// Cap outbound bytes to one peer, on top of the global limit
+    pub async fn set_peer_bandwidth_limit(
+        &self,
+        remote_dht_record: CryptoTyped<CryptoKey>,
+        limit: Option<BandwidthLimit>,
+    ) {
+        self.bandwidth
+            .lock()
+            .await
+            .set_peer_limit(remote_dht_record, limit);
+    }
+
+    /// Cap inbound messages per sender. Messages over the limit are dropped and
+    /// answered `AckStatus::Busy` before being decrypted; resends of a message
+    /// already received don't count.
+    pub async fn set_inbound_rate_limit(&self, limit: Option<MessageRateLimit>) {
+        self.inbound_rate.lock().await.set_limit(limit);
+    }
+
+    /// Resolve and cache a peer's route ahead of the first send, so that send
+    /// doesn't pay for the DHT lookup and route import.
+    pub async fn warm_route(
+        &self,
+        remote_dht_record: CryptoTyped<CryptoKey>,
+    ) -> Result<(), VeilidDuplexError> {
+        self.routes
+            .lock()
+            .await
+            .get_route(
+                remote_dht_record,
+                &*self.transport,
+                self.routing_context.clone(),
+                self.clock.now(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn export_state(&self) -> Result<Vec<u8>, VeilidDuplexError> {
+        let state = DuplexState {
+            version: STATE_VERSION,
+            received_message_hashes: self.received_message_hashes.lock().await.hashes().collect(),
+            peers: self
+                .routes
+                .lock()
+                .await
+                .peers(self.clock.now())
+                .iter()
+                .map(|peer| peer.dht_record)
+                .collect(),
+            paused_messages: self.paused_messages.lock().await.iter().cloned().collect(),
+        };
+
+        Ok(serde_json::to_vec(&state)?)
+    }
+
+    /// Merge state exported by another instance into this one. States written by
+    /// older versions load with missing fields left empty; newer ones are refused.
+    /// Peers that can't be resolved are skipped.
+    pub async fn import_state(&self, state: &[u8]) -> Result<(), VeilidDuplexError> {
+        let state: DuplexState = serde_json::from_slice(state)?;
+        if state.version > STATE_VERSION {
+            return Err(VeilidDuplexError::UnsupportedStateVersion {
+                found: state.version,
+                supported: STATE_VERSION,
+            });
+        }
+
+        {
+            let mut received_message_hashes = self.received_message_hashes.lock().await;
+            let now = self.clock.now();
+            for hash in state.received_message_hashes {
+                received_message_hashes.insert(hash, now);
+            }
+        }
+
+        self.paused_messages
+            .lock()
+            .await
+            .extend(state.paused_messages);
+
+        for peer in state.peers {
+            if let Err(e) = self.warm_route(peer).await {
+                info!("Unable to restore route to {}: {}", peer, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn attachment(&self) -> AttachmentStatus {
+        *self.attachment.lock().await
+    }
+
+    /// Attachment as veilid reports it right now, rather than as last seen by
+    /// the network loop
+    pub async fn readiness(&self) -> Result<AttachmentStatus, VeilidDuplexError> {
+        let state = self.api.get_state().await?;
+        Ok(AttachmentStatus {
+            state: state.attachment.state,
+            public_internet_ready: state.attachment.public_internet_ready,
+        })
+    }
+
+    /// Whether the node is attached and reachable, see `readiness`
+    pub async fn is_ready(&self) -> bool {
+        self.readiness()
+            .await
+            .map(|status| status.is_ready())
+            .unwrap_or(false)
+    }
+
+    /// Wait for the node to be attached and reachable, e.g. after it went
+    /// offline. Fails with `NotReady` once `timeout` passes.
+    pub async fn await_ready(&self, timeout: Duration) -> Result<(), VeilidDuplexError> {
+        let deadline = get_timestamp() + timeout.as_micros() as u64;
+        loop {
+            if self.readiness().await?.is_ready() {
+                return Ok(());
+            }
+            if get_timestamp() >= deadline {
+                return Err(VeilidDuplexError::NotReady(timeout));
+            }
+            sleep(100).await;
+        }
+    }
+
+    /// Peers we currently hold a cached route to. The snapshot is taken under
+    /// a single lock of the route cache.
+    pub async fn active_peers(&self) -> Vec<PeerInfo> {
+        self.routes.lock().await.peers(self.clock.now())
+    }
+
+    /// Forget the cached route to a peer, so the next send looks it up on DHT.
+    /// Useful when the app knows the peer moved before veilid reports it.
+    pub async fn invalidate_route(&self, remote_dht_record: CryptoTyped<CryptoKey>) {
+        let mut routes = self.routes.lock().await;
+        if routes.invalidate(remote_dht_record) {
+            info!("Invalidated route to {}", remote_dht_record);
+        }
+        // A watched record stays open for the watch
+        if !routes.is_watched(remote_dht_record) {
+            if let Err(e) = routes
+                .close_record(remote_dht_record, &self.routing_context)
+                .await
+            {
+                info!("Unable to close DHT record {}: {}", remote_dht_record, e);
+            }
+        }
+    }
+
+    /// Forget all cached routes
+    pub async fn clear_routes(&self) {
+        let mut routes = self.routes.lock().await;
+        routes.clear();
+        routes.close_records(&self.routing_context).await;
+    }
+
+    /// Keep peers' DHT records open after looking their route up, instead of
+    /// opening and closing a record on every lookup. Records are closed when
+    /// their route is invalidated, and on `shutdown`.
+    pub async fn set_keep_dht_records_open(&self, enabled: bool) {
+        self.routes
+            .lock()
+            .await
+            .set_keep_records_open(enabled, &self.routing_context)
+            .await;
+    }
+
+    /// Stop this node's network loop for good once `shutdown_token` is
+    /// cancelled, e.g. by a parent app shutting its subsystems down together.
+    /// The loop drains like `network_loop_until`, letting running handlers
+    /// finish and ACK, then closes our DHT records and detaches; messages
+    /// buffered by `pause` aren't handled. It's the loop that acts on the
+    /// token, so with no loop running call `shutdown` instead. Clones made
+    /// afterwards share the token.
+    pub fn set_shutdown_token(&mut self, shutdown_token: CancellationToken) {
+        self.shutdown_token = shutdown_token;
+    }
+
+    /// Close the DHT records we hold open, then shut the node down
+    pub async fn shutdown(self) {
+        let buffered = self.paused_messages.lock().await.len();
+        if buffered > 0 {
+            info!(
+                "Shutting down with {} buffered messages unhandled",
+                buffered
+            );
+        }
+        self.routes
+            .lock()
+            .await
+            .close_records(&self.routing_context)
+            .await;
+        self.api.shutdown().await;
+    }
+
+    /// Watch a peer's DHT record, so a new route it publishes replaces the
+    /// cached one as soon as the network loop sees it, rather than after a
+    /// send fails. Watches are renewed before they expire.
+    pub async fn watch_route(
+        &self,
+        remote_dht_record: CryptoTyped<CryptoKey>,
+    ) -> Result<(), VeilidDuplexError> {
+        let subkey = self.routes.lock().await.subkey;
+        let expiration =
+            watch_service_route(&self.routing_context, remote_dht_record, subkey).await?;
+        self.routes
+            .lock()
+            .await
+            .set_watch(remote_dht_record, expiration);
+
+        Ok(())
+    }
+
+    /// Stop watching a peer's DHT record. Its cached route is kept.
+    pub async fn unwatch_route(
+        &self,
+        remote_dht_record: CryptoTyped<CryptoKey>,
+    ) -> Result<(), VeilidDuplexError> {
+        let subkey = {
+            let mut routes = self.routes.lock().await;
+            if !routes.remove_watch(remote_dht_record) {
+                return Ok(());
+            }
+            // Cancelling the watch closes the record
+            if let Some(open_records) = &mut routes.open_records {
+                open_records.forget(remote_dht_record);
+            }
+            routes.subkey
+        };
+
+        unwatch_service_route(&self.routing_context, remote_dht_record, subkey).await
+    }
+
+    fn spawn_watch_renewal_if_due(&self) {
+        if !claim_if_due(
+            &self.last_watch_check,
+            WATCH_CHECK_INTERVAL,
+            self.clock.now(),
+        ) {
+            return;
+        }
+
+        let duplex = self.clone();
+        spawn_detached(async move {
+            let due = duplex
+                .routes
+                .lock()
+                .await
+                .watches_to_renew(duplex.clock.now(), WATCH_RENEW_MARGIN);
+            for dht_record in due {
+                info!("Renewing watch on {}", dht_record);
+                if let Err(e) = duplex.watch_route(dht_record).await {
+                    info!("Unable to renew watch on {}: {}", dht_record, e);
+                }
+            }
+        });
+    }
+
+    async fn apply_value_change(&self, change: &VeilidValueChange) {
+        let mut routes = self.routes.lock().await;
+        if !routes.is_watched(change.key) || !change.subkeys.contains(routes.subkey) {
+            return;
+        }
+
+        if let Some(value) = &change.value {
+            match import_route_blob(&self.api, value.data()) {
+                Result::Ok((target, route)) => {
+                    info!("Route of {} changed to {}", change.key, route);
+                    routes.replace_route(change.key, target, route, self.clock.now());
+                }
+                Err(e) => info!("Ignoring route update from {}: {}", change.key, e),
+            }
+        }
+
+        // A count of zero means the watch ended; renew it on the next check
+        if change.count == 0 {
+            routes.set_watch(change.key, 0);
+        }
+    }
+
+    /// Ping cached routes in the background from `network_loop_cycle`, evicting
+    /// the ones that stop answering. Off by default.
+    pub fn set_keepalive(&mut self, keepalive: Option<KeepaliveConfig>) {
+        self.keepalive = keepalive;
+    }
+
+    /// Keepalive paced to this node's `connection_inactivity_timeout_ms`
+    pub fn default_keepalive(&self) -> KeepaliveConfig {
+        let timeout_ms = self.config.veilid_options.connection_inactivity_timeout_ms;
+        KeepaliveConfig::for_inactivity_timeout(Duration::from_millis(timeout_ms as u64))
+    }
+
+    /// Ping every cached route once. Peers whose candidate routes are due to
+    /// be probed get the probes instead, and the fastest route is kept. The
+    /// route cache is only locked between pings, so sends aren't held up while
+    /// a ping waits for its timeout.
+    pub async fn ping_routes(&self, keepalive: KeepaliveConfig) {
+        let (targets, probing) = {
+            let routes = self.routes.lock().await;
+            (routes.targets(), routes.probing)
+        };
+        for (dht_record, target) in targets {
+            let candidates = self
+                .routes
+                .lock()
+                .await
+                .candidates_due(dht_record, self.clock.now());
+            let ok = match (candidates, probing) {
+                (Some(candidates), Some(probing)) => {
+                    match fastest_route(&*self.transport, &candidates, probing.timeout).await {
+                        Some((target, route)) => {
+                            self.routes
+                                .lock()
+                                .await
+                                .prefer_route(dht_record, target, route);
+                            true
+                        }
+                        None => false,
+                    }
+                }
+                _ => self
+                    .transport
+                    .app_call(target, KEEPALIVE_PING.to_vec())
+                    .await
+                    .is_ok(),
+            };
+
+            let evicted =
+                self.routes
+                    .lock()
+                    .await
+                    .record_ping(dht_record, ok, keepalive.failure_threshold);
+            if evicted {
+                info!("Route to {} stopped answering keepalives", dht_record);
+                self.counters.record_dead_route();
+            }
+        }
+    }
+
+    fn spawn_keepalive_if_due(&self) {
+        let Some(keepalive) = self.keepalive else {
+            return;
+        };
+
+        if !claim_if_due(&self.last_keepalive, keepalive.interval, self.clock.now()) {
+            return;
+        }
+
+        let duplex = self.clone();
+        spawn_detached(async move {
+            duplex.ping_routes(keepalive).await;
+        });
+    }
+
+    fn spawn_presence_if_due(&self) {
+        if !self.presence.claim_round(self.clock.now()) {
+            return;
+        }
+
+        let presence = self.presence.clone();
+        let sender = self.sender();
+        spawn_detached(async move {
+            presence.probe_all(&sender).await;
+        });
+    }
+
+    fn spawn_telemetry_if_due(&self) {
+        let Some(telemetry) = &self.telemetry else {
+            return;
+        };
+
+        let now = self.clock.now();
+        if !telemetry.claim_batch(now) {
+            return;
+        }
+        let Some(batch) = telemetry.take_batch(self.our_dht_key, now) else {
+            return;
+        };
+
+        let telemetry = telemetry.clone();
+        let sender = self.sender();
+        spawn_detached(async move {
+            if let Err(e) = telemetry.send(&sender, &batch).await {
+                info!("Unable to forward telemetry: {}", e);
+            }
+        });
+    }
+
+    fn spawn_outbox_drain_if_due(&self) {
+        let Some(outbox) = &self.outbox else {
+            return;
+        };
+        if !outbox.has_due(self.clock.now()) {
+            return;
+        }
+
+        let outbox = outbox.clone();
+        let sender = self.sender();
+        spawn_detached(async move {
+            outbox.drain(&sender).await;
+        });
+    }
+
+    /// Rewrite our route to its DHT record every `interval`, from
+    /// `network_loop_cycle`, so the record stays fresh while the route lives.
+    /// Off by default.
+    pub fn set_pin_refresh_interval(&mut self, interval: Option<Duration>) {
+        self.pin_refresh_interval = interval;
+    }
+
+    fn spawn_pin_refresh_if_due(&self) {
+        let Some(interval) = self.pin_refresh_interval else {
+            return;
+        };
+
+        if !claim_if_due(&self.last_pin_refresh, interval, self.clock.now()) {
+            return;
+        }
+
+        let transport = self.transport.clone();
+        let mut pins = vec![(ROUTE_SUBKEY, self.published_blob())];
+        pins.extend(backup_pins(&self.backup_routes));
+        let our_dht_key = self.our_dht_key;
+        let dht_keypair = self.dht_keypair;
+        spawn_detached(async move {
+            info!("Refreshing route pin");
+            for (subkey, blob) in pins {
+                let written = transport
+                    .set_dht_value(our_dht_key, subkey, blob, dht_keypair)
+                    .await;
+                if let Err(e) = written {
+                    info!("Unable to refresh route pin in subkey {}: {}", subkey, e);
+                }
+            }
+        });
+    }
+
+    /// How quickly the network loop retries attaching after the node went
+    /// offline. Once attached again it republishes our route.
+    pub fn set_reconnect_policy(&mut self, reconnect_policy: ReconnectPolicy) {
+        self.reconnect_policy = reconnect_policy;
+    }
+
+    /// Whether reconnecting is being throttled, as of the network loop's last
+    /// cycle. `Closed` while attached.
+    pub async fn reconnect_state(&self) -> CircuitState {
+        *self.reconnect_circuit.lock().await
+    }
+
+    async fn publish_reconnect_state(&self) {
+        let circuit = self.reconnect.map(|reconnect| reconnect.circuit);
+        *self.reconnect_circuit.lock().await = circuit.unwrap_or_default();
+    }
+
+    /// Message counters since start, shared by all clones of this instance,
+    /// and throughput over the last `THROUGHPUT_WINDOW`
+    pub fn stats(&self) -> VeilidDuplexStats {
+        self.counters.snapshot(self.clock.now())
+    }
+
+    /// Log send and receive throughput every `interval` from the network
+    /// loop. Off by default.
+    pub fn set_throughput_log_interval(&mut self, interval: Option<Duration>) {
+        self.throughput_log_interval = interval;
+    }
+
+    fn log_throughput_if_due(&self) {
+        let Some(interval) = self.throughput_log_interval else {
+            return;
+        };
+        if !claim_if_due(&self.last_throughput_log, interval, self.clock.now()) {
+            return;
+        }
+
+        let stats = self.stats();
+        info!(
+            "Sending {:.1} msg/s, {:.0} B/s; receiving {:.1} msg/s, {:.0} B/s",
+            stats.send_rate.messages_per_sec,
+            stats.send_rate.bytes_per_sec,
+            stats.receive_rate.messages_per_sec,
+            stats.receive_rate.bytes_per_sec
+        );
+    }
+
+    /// Sending half of this instance, for tasks that send while another one
+    /// runs the network loop. Shares the route cache, counters and pending
+    /// requests; settings are copied as they are now.
+    pub fn sender(&self) -> DuplexSender {
+        DuplexSender {
+            api: self.api.clone(),
+            routing_context: self.routing_context.clone(),
+            transport: self.transport.clone(),
+            routes: self.routes.clone(),
+            our_dht_key: self.our_dht_key,
+            dht_keypair: self.dht_keypair,
+            send_options: self.send_options.clone(),
+            send_attempts: self.send_attempts,
+            send_retry_interval: self.send_retry_interval,
+            unresponsive_threshold: self.unresponsive_threshold,
+            on_peer_unresponsive: self.on_peer_unresponsive.clone(),
+            dead_letters: self.dead_letters.clone(),
+            outbox: self.outbox.clone(),
+            uuid_source: self.uuid_source.clone(),
+            clock: self.clock.clone(),
+            bandwidth: self.bandwidth.clone(),
+            peer_keys: self.peer_keys.clone(),
+            pending_requests: self.pending_requests.clone(),
+            counters: self.counters.clone(),
+        }
+    }
+
+    /// Split into a cloneable sender and the receiver that runs the network
+    /// loop. Configure the instance before splitting.
+    pub fn split(self) -> (DuplexSender, DuplexReceiver) {
+        (self.sender(), DuplexReceiver { duplex: self })
+    }
+
+    /// See `DuplexSender::send_message`
+    pub async fn send_message<T: DeserializeOwned>(
+        &self,
+        app_message: AppMessage<T>,
+        remote_dht_record: CryptoTyped<CryptoKey>,
+    ) -> Result<String, VeilidDuplexError>
+    where
+        T: Serialize + DeserializeOwned + Send + 'static,
+    {
+        self.sender()
+            .send_message(app_message, remote_dht_record)
+            .await
+    }
+
+    /// See `DuplexSender::send_message_with_reply`
+    pub async fn send_message_with_reply<T: DeserializeOwned>(
+        &self,
+        app_message: AppMessage<T>,
+        remote_dht_record: CryptoTyped<CryptoKey>,
+    ) -> Result<SendOutcome, VeilidDuplexError>
+    where
+        T: Serialize + DeserializeOwned + Send + 'static,
+    {
+        self.sender()
+            .send_message_with_reply(app_message, remote_dht_record)
+            .await
+    }
+
+    /// See `DuplexSender::broadcast`
+    pub async fn broadcast<T>(
+        &self,
+        app_message: AppMessage<T>,
+        recipients: &[CryptoTyped<CryptoKey>],
+    ) -> Result<Vec<Result<String, VeilidDuplexError>>, VeilidDuplexError>
+    where
+        T: Serialize + DeserializeOwned + Send + 'static,
+    {
+        self.sender().broadcast(app_message, recipients).await
+    }
+
+    /// See `DuplexSender::publish`
+    pub async fn publish<T>(
+        &self,
+        topic: impl Into<String>,
+        app_message: AppMessage<T>,
+        subscribers: &[CryptoTyped<CryptoKey>],
+    ) -> Result<Vec<Result<String, VeilidDuplexError>>, VeilidDuplexError>
+    where
+        T: Serialize + DeserializeOwned + Send + 'static,
+    {
+        self.sender().publish(topic, app_message, subscribers).await
+    }
+
+    /// See `DuplexSender::send_file`
+    pub async fn send_file<F>(
+        &self,
+        path: impl AsRef<Path>,
+        remote_dht_record: CryptoTyped<CryptoKey>,
+        progress: F,
+    ) -> Result<String, VeilidDuplexError>
+    where
+        F: Fn(&TransferProgress),
+    {
+        self.sender()
+            .send_file(path, remote_dht_record, progress)
+            .await
+    }
+
+    /// See `DuplexSender::send_message_to_target`
+    pub async fn send_message_to_target<T: DeserializeOwned>(
+        &self,
+        app_message: AppMessage<T>,
+        target: Target,
+    ) -> Result<(), VeilidDuplexError>
+    where
+        T: Serialize + DeserializeOwned + Send + 'static,
+    {
+        self.sender()
+            .send_message_to_target(app_message, target)
+            .await
+    }
+
+    /// What our DHT record points peers at, per `VeilidDuplexConfig::addressing`
+    fn published_blob(&self) -> Vec<u8> {
+        match self.config.addressing {
+            Addressing::PrivateRoute => self.our_route_blob.clone(),
+            Addressing::NodeId => node_id_blob(CryptoTyped::new(
+                self.config.crypto_kind,
+                self.node_keypair.key,
+            )),
+        }
+    }
+
+    /// Our current route as published to DHT, for handing to a peer out of
+    /// band. Goes stale when our route changes.
+    pub fn export_our_route_blob(&self) -> Vec<u8> {
+        self.our_route_blob.clone()
+    }
+
+    /// Import a route blob from `export_our_route_blob` for use with
+    /// `send_message_to_target`, without a DHT lookup
+    pub fn import_route_blob(&self, blob: Vec<u8>) -> Result<Target, VeilidDuplexError> {
+        let (target, _) = import_route_blob(&self.api, &blob)?;
+        Ok(target)
+    }
+
+    /// See `DuplexSender::ping`
+    pub async fn ping(
+        &self,
+        remote_dht_record: CryptoTyped<CryptoKey>,
+        timeout_after: Duration,
+    ) -> Result<Duration, VeilidDuplexError> {
+        self.sender().ping(remote_dht_record, timeout_after).await
+    }
+
+    /// See `DuplexSender::connect`
+    pub async fn connect(
+        &self,
+        remote_dht_record: CryptoTyped<CryptoKey>,
+        timeout_after: Duration,
+    ) -> Result<(), VeilidDuplexError> {
+        self.sender()
+            .connect(remote_dht_record, timeout_after)
+            .await
+    }
+
+    /// See `DuplexSender::send_request`
+    pub async fn send_request<T, R>(
+        &self,
+        app_message: AppMessage<T>,
+        remote_dht_record: CryptoTyped<CryptoKey>,
+        timeout_after: Duration,
+    ) -> Result<R, VeilidDuplexError>
+    where
+        T: Serialize + DeserializeOwned + Send + 'static,
+        R: Serialize + DeserializeOwned,
+    {
+        self.sender()
+            .send_request(app_message, remote_dht_record, timeout_after)
+            .await
+    }
+
+    /// Send `data` as a request, run the network loop until the answer is in
+    /// or `timeout_after` passes, then shut the node down either way. Messages
+    /// other than the answer are refused meanwhile. See `one_shot_request`.
+    pub async fn request_then_shutdown<T, R>(
+        self,
+        data: T,
+        remote_dht_record: CryptoTyped<CryptoKey>,
+        timeout_after: Duration,
+    ) -> Result<R, VeilidDuplexError>
+    where
+        T: Serialize + DeserializeOwned + Send + 'static,
+        R: Serialize + DeserializeOwned + Send + Sync + Clone + 'static,
+    {
+        let app_message = AppMessage {
+            data,
+            uuid: String::new(),
+            dht_record: self.our_dht_key,
+            reply_to: None,
+            timestamp: 0,
+            topic: None,
+        };
+        let network_loop = self.clone().spawn_network_loop::<R, _>(AnswersOnly);
+        let answer = self
+            .send_request(app_message, remote_dht_record, timeout_after)
+            .await;
+
+        network_loop.abort();
+        if let Err(e) = network_loop.join().await {
+            info!("Network loop failed: {}", e);
+        }
+        self.shutdown().await;
+        answer
+    }
+
+    /// Process updates until an error occurs. Only one loop may run per node:
+    /// a second call, on this instance or any clone of it, fails immediately.
+    pub async fn network_loop<T, U>(&mut self, app_logic: U) -> Result<(), VeilidDuplexError>
+    where
+        T: Serialize + DeserializeOwned + Send + Sync + Clone + Sized + 'static,
+        U: AppLogic<T> + Clone + Send + 'static,
+    {
+        self.network_loop_until(app_logic, CancellationToken::new())
+            .await
+    }
+
+    /// `network_loop` that returns `Ok` soon after `cancel` is cancelled.
+    /// Handlers already running finish and send their ACKs first; a message
+    /// still waiting for a free handler is answered `Busy`.
+    pub async fn network_loop_until<T, U>(
+        &mut self,
+        app_logic: U,
+        cancel: CancellationToken,
+    ) -> Result<(), VeilidDuplexError>
+    where
+        T: Serialize + DeserializeOwned + Send + Sync + Clone + Sized + 'static,
+        U: AppLogic<T> + Clone + Send + 'static,
+    {
+        let _guard = self.claim_loop()?;
+        self.run_network_loop::<T, U>(app_logic, &cancel).await
+    }
+
+    async fn run_network_loop<T, U>(
+        &mut self,
+        app_logic: U,
+        cancel: &CancellationToken,
+    ) -> Result<(), VeilidDuplexError>
+    where
+        T: Serialize + DeserializeOwned + Send + Sync + Clone + 'static,
+        U: AppLogic<T> + Clone + Send + 'static,
+    {
+        // The loop's own token stops just this loop; the shutdown token
+        // stops it as well
+        let cancel = &cancel.child_token();
+        self.shutdown_token.add_child(cancel.clone());
+
+        loop {
+            if self.receiver.is_empty() && !self.has_held_calls().await {
+                cancel
+                    .run_until(sleep(IDLE_POLL_INTERVAL.as_millis() as u32))
+                    .await;
+            }
+            if cancel.is_cancelled() {
+                break;
+            }
+            self.cycle::<T, U>(app_logic.clone(), cancel).await?;
+        }
+
+        self.handler_permits.wait_idle().await;
+        if self.shutdown_token.is_cancelled() {
+            self.close_and_detach().await;
+        }
+        Ok(())
+    }
+
+    async fn close_and_detach(&self) {
+        info!("Shutdown requested, closing records and detaching");
+        self.routes
+            .lock()
+            .await
+            .close_records(&self.routing_context)
+            .await;
+        if let Err(e) = self.api.detach().await {
+            info!("Unable to detach: {}", e);
+        }
+    }
+
+    /// Run the network loop in the background and yield inbound messages as a
+    /// stream instead of handing them to an `AppLogic`. Dedup, size checks and
+    /// ACKs work as in `network_loop`, which can't run at the same time. The
+    /// loop stops once the stream is dropped, or on the shutdown token.
+    pub fn incoming<T>(&self) -> Result<impl Stream<Item = AppMessage<T>>, VeilidDuplexError>
+    where
+        T: Serialize + DeserializeOwned + Send + Sync + Clone + 'static,
+    {
+        let guard = self.claim_loop()?;
+        let (sender, receiver) = bounded(INCOMING_BUFFER);
+        let app_logic = StreamLogic { sender };
+        let cancel = CancellationToken::new();
+
+        let mut duplex = self.clone();
+        let loop_cancel = cancel.clone();
+        spawn_detached(async move {
+            let _guard = guard;
+            if let Err(e) = duplex
+                .run_network_loop::<T, _>(app_logic, &loop_cancel)
+                .await
+            {
+                info!("Network loop stopped: {}", e);
+            }
+        });
+
+        Ok(IncomingStream {
+            messages: receiver.into_stream().boxed(),
+            cancel,
+        })
+    }
+
+    /// Run `network_loop` in a background task, for apps that can't block on it.
+    /// A loop already running on this node makes `join` fail with
+    /// `LoopAlreadyRunning`.
+    pub fn spawn_network_loop<T, U>(self, app_logic: U) -> NetworkLoopHandle
+    where
+        T: Serialize + DeserializeOwned + Send + Sync + Clone + 'static,
+        U: AppLogic<T> + Clone + Send + 'static,
+    {
+        let cancel = CancellationToken::new();
+        let (sender, result) = bounded(1);
+        let handle = NetworkLoopHandle {
+            cancel: cancel.clone(),
+            result,
+        };
+
+        let guard = match self.claim_loop() {
+            Result::Ok(guard) => guard,
+            Err(e) => {
+                let _ = sender.send(Err(e));
+                return handle;
+            }
+        };
+
+        let mut duplex = self;
+        spawn_detached(async move {
+            let _guard = guard;
+            let result = duplex.run_network_loop::<T, U>(app_logic, &cancel).await;
+            if let Err(e) = &result {
+                info!("Network loop stopped: {}", e);
+            }
+            let _ = sender.send(result);
+        });
+
+        handle
+    }
+
+    fn claim_loop(&self) -> Result<LoopGuard, VeilidDuplexError> {
+        if self
+            .loop_running
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return Err(VeilidDuplexError::LoopAlreadyRunning);
+        }
+
+        Ok(LoopGuard(self.loop_running.clone()))
+    }
+
+    pub async fn network_loop_cycle<T, U>(&mut self, app_logic: U) -> Result<(), VeilidDuplexError>
+    where
+        T: Serialize + DeserializeOwned + Send + Sync + Clone + 'static,
+        U: AppLogic<T> + Clone + Send + 'static,
+    {
+        self.cycle::<T, U>(app_logic, &CancellationToken::new())
+            .await
+    }
+
+    // One network loop cycle; `cancel` cuts short a wait for a free handler
+    async fn cycle<T, U>(
+        &mut self,
+        app_logic: U,
+        cancel: &CancellationToken,
+    ) -> Result<(), VeilidDuplexError>
+    where
+        T: Serialize + DeserializeOwned + Send + Sync + Clone + 'static,
+        U: AppLogic<T> + Clone + Send + 'static,
+    {
+        let reciever = self.receiver.clone();
+        let api = self.api.clone();
+        let transport = self.transport.clone();
+
+        if !self.is_paused() {
+            self.drain_paused_messages::<T, U>(app_logic.clone(), cancel)
+                .await;
+        }
+        self.spawn_keepalive_if_due();
+        self.spawn_pin_refresh_if_due();
+        self.spawn_watch_renewal_if_due();
+        self.spawn_outbox_drain_if_due();
+        self.spawn_presence_if_due();
+        self.spawn_telemetry_if_due();
+        self.log_throughput_if_due();
+        self.reconnect_if_due::<T, U>(&mut app_logic.clone()).await;
+        self.publish_reconnect_state().await;
+
+        let held_call = match self.is_paused() {
+            true => None,
+            false => self.held_calls.lock().await.pop_front(),
+        };
+        let res = match held_call {
+            Some(call) => VeilidUpdate::AppCall(call),
+            None if reciever.is_empty() => return Ok(()),
+            None => reciever.recv().map_err(Error::from)?,
+        };
+        let routes = self.routes.clone();
+        let received_message_hashes = self.received_message_hashes.clone();
+        let mut app_logic = app_logic.clone();
+        let paused = self.paused.clone();
+        let pause_mode = self.pause_mode;
+        let paused_messages = self.paused_messages.clone();
+        let reassembler = self.reassembler.clone();
+        let routing_context = self.routing_context.clone();
+        let peer_keys = self.peer_keys.clone();
+        let our_secret = self.dht_keypair.secret;
+        let pending_requests = self.pending_requests.clone();
+        let counters = self.counters.clone();
+        let max_inbound_size = self.max_inbound_size;
+        let timestamp_window = self.timestamp_window;
+        let require_signatures = self.require_signatures;
+        let peer_filter = self.peer_filter.clone();
+        let inbound_rate = self.inbound_rate.clone();
+        let message_ordering = self.message_ordering;
+        let handler_queues = self.handler_queues.clone();
+        let clock = self.clock.clone();
+
+        match res {
+            VeilidUpdate::AppCall(call) => {
+                info!("VeilidUpdate::AppMessage");
+
+                // Answered straight from the loop, so the measured latency
+                // doesn't include waiting for a handler
+                if is_latency_ping(call.message()) {
+                    let pong = call.message().to_vec();
+                    if transport.app_call_reply(call.id(), pong).await.is_err() {
+                        info!("Unable to answer ping");
+                    }
+                    return Ok(());
+                }
+
+                // Telemetry from another node, for the sink rather than the app
+                if is_telemetry(call.message()) {
+                    let status = match (&self.telemetry_sink, decode_batch(call.message())) {
+                        (None, _) => AckStatus::Rejected("Not collecting telemetry".to_string()),
+                        (Some(_), Err(_)) => AckStatus::DeserializeFailed,
+                        (Some(sink), Result::Ok(batch)) => {
+                            if sink.try_send(batch).is_err() {
+                                info!("Telemetry sink full or closed, dropping batch");
+                            }
+                            AckStatus::Accepted
+                        }
+                    };
+                    reply_to_call(&*transport, call.id(), status).await;
+                    return Ok(());
+                }
+
+                // A peer's `connect` checking that we hold our record's owner key
+                if is_identity_challenge(call.message()) {
+                    let answer = crypto_system(&api, self.our_dht_key.kind).and_then(|cs| {
+                        answer_challenge(&cs, &self.dht_keypair, self.our_dht_key, call.message())
+                    });
+                    match answer {
+                        Result::Ok(answer) => {
+                            if transport.app_call_reply(call.id(), answer).await.is_err() {
+                                info!("Unable to answer identity challenge");
+                            }
+                        }
+                        Err(e) => info!("Unable to answer identity challenge: {}", e),
+                    }
+                    return Ok(());
+                }
+
+                // Answered before taking a handler, so busy handlers or a hold
+                // don't make a live node look dead and lose it peers' routes
+                if call.message() == KEEPALIVE_PING {
+                    reply_to_call(&*transport, call.id(), AckStatus::Accepted).await;
+                    return Ok(());
+                }
+
+                if self.is_paused() && self.pause_mode == PauseMode::Hold {
+                    let mut held_calls = self.held_calls.lock().await;
+                    if held_calls.len() < MAX_HELD_CALLS {
+                        info!("Message processing paused, holding message");
+                        held_calls.push_back(call);
+                    } else {
+                        drop(held_calls);
+                        info!("Message processing paused and hold full, replying busy");
+                        reply_to_call(&*transport, call.id(), AckStatus::Busy).await;
+                    }
+                    return Ok(());
+                }
+
+                // Handlers run detached so a slow one doesn't hold up the next
+                // update; past the permit limit the loop waits for a free one
+                // or turns the message away
+                let permit = match self.handler_overflow {
+                    HandlerOverflow::Wait => {
+                        match cancel.run_until(self.handler_permits.acquire()).await {
+                            Some(permit) => permit,
+                            None => {
+                                info!("Shutting down, replying busy");
+                                reply_to_call(&*transport, call.id(), AckStatus::Busy).await;
+                                return Ok(());
+                            }
+                        }
+                    }
+                    HandlerOverflow::Reject => match self.handler_permits.try_acquire() {
+                        Some(permit) => permit,
+                        None => {
+                            info!("No free handler, replying busy");
+                            reply_to_call(&*transport, call.id(), AckStatus::Busy).await;
+                            return Ok(());
+                        }
+                    },
+                };
+
+                // Reassembled and decoded here rather than in the handler
+                // task, so the sender is known and its ordering turn taken in
+                // the order messages arrived. Only decryption has to wait.
+                let reassembled =
+                    reassemble(&reassembler, call.message(), max_inbound_size, clock.now()).await;
+                let raw_message = match reassembled {
+                    Result::Ok(Some(raw_message)) => raw_message,
+                    Result::Ok(None) => {
+                        reply_to_call(&*transport, call.id(), AckStatus::Accepted).await;
+                        return Ok(());
+                    }
+                    Err(status) => {
+                        reply_to_call(&*transport, call.id(), status).await;
+                        return Ok(());
+                    }
+                };
+                let inbound = match is_encrypted(&raw_message) {
+                    true => Inbound::Encrypted(raw_message),
+                    false => match open_message::<T>(raw_message, max_inbound_size, &counters) {
+                        Result::Ok(opened) => Inbound::Opened(opened),
+                        Err(status) => {
+                            reply_to_call(&*transport, call.id(), status).await;
+                            return Ok(());
+                        }
+                    },
+                };
+                // Filtered and rate limited on the claimed sender before any
+                // decryption or signature check, so a flood costs no crypto
+                if let Some(sender) = inbound.claimed_sender() {
+                    if !peer_filter.lock().await.is_allowed(sender) {
+                        info!("Dropping message from filtered peer {}", sender);
+                        counters.record_blocked();
+                        reply_to_call(&*transport, call.id(), AckStatus::Accepted).await;
+                        return Ok(());
+                    }
+                    if !inbound_rate.lock().await.would_allow(sender, clock.now()) {
+                        info!("Dropping message over {}'s rate limit", sender);
+                        counters.record_rate_limited();
+                        reply_to_call(&*transport, call.id(), AckStatus::Busy).await;
+                        return Ok(());
+                    }
+                }
+                let turn = match (message_ordering, inbound.claimed_sender()) {
+                    (Some(ordering), Some(sender)) => {
+                        Some(handler_queues.enqueue(ordering.key_of(sender)))
+                    }
+                    _ => None,
+                };
+
+                spawn_detached(async move {
+                    let _permit = permit;
+                    let (signed, raw_message, app_message) = match inbound {
+                        Inbound::Opened(opened) => opened,
+                        Inbound::Encrypted(raw_message) => {
+                            let decrypted: Result<Vec<u8>, Error> = async {
+                                let sender = sender_of(&raw_message)?;
+                                let shared_secret = peer_keys
+                                    .lock()
+                                    .await
+                                    .shared_secret(&api, &routing_context, &our_secret, sender)
+                                    .await?;
+                                let cs = crypto_system(&api, sender.kind)?;
+                                decrypt(&cs, &shared_secret, &raw_message)
+                            }
+                            .await;
+
+                            let opened = match decrypted {
+                                Result::Ok(raw_message) => {
+                                    open_message::<T>(raw_message, max_inbound_size, &counters)
+                                }
+                                Err(e) => {
+                                    info!("Unable to decrypt message: {}", e);
+                                    Err(AckStatus::Rejected("Unable to decrypt".to_string()))
+                                }
+                            };
+                            match opened {
+                                Result::Ok(opened) => opened,
+                                Err(status) => {
+                                    reply_to_call(&*transport, call.id(), status).await;
+                                    return;
+                                }
+                            }
+                        }
+                    };
+
+                    let signature_ok = match signed {
+                        Some((signature, payload)) => {
+                            let verified: Result<(), Error> = async {
+                                let sender = app_message.dht_record;
+                                let owner_key = peer_keys
+                                    .lock()
+                                    .await
+                                    .owner_key(&routing_context, sender)
+                                    .await?;
+                                let cs = crypto_system(&api, sender.kind)?;
+                                verify(&cs, &owner_key, &payload, &signature)
+                            }
+                            .await;
+                            if let Err(e) = &verified {
+                                info!("Bad signature from {}: {}", app_message.dht_record, e);
+                            }
+                            verified.is_ok()
+                        }
+                        None => !require_signatures,
+                    };
+                    if !signature_ok {
+                        counters.record_signature_rejected();
+                        let status = AckStatus::Rejected("Bad or missing signature".to_string());
+                        reply_to_call(&*transport, call.id(), status).await;
+                        return;
+                    }
+
+                    // Checked again on the verified sender, in case it isn't
+                    // the one the message claimed up front
+                    if !peer_filter.lock().await.is_allowed(app_message.dht_record) {
+                        info!(
+                            "Dropping message from filtered peer {}",
+                            app_message.dht_record
+                        );
+                        counters.record_blocked();
+                        reply_to_call(&*transport, call.id(), AckStatus::Accepted).await;
+                        return;
+                    }
+                    let message_hash = dedup_key(&app_message, &raw_message);
+
+                    if let Some(window) = timestamp_window {
+                        let now = clock.now() / 1000;
+                        if let Err(status) = check_timestamp(app_message.timestamp, now, window) {
+                            counters.record_timestamp_rejected();
+                            reply_to_call(&*transport, call.id(), status).await;
+                            return;
+                        }
+                    }
+
+                    let is_duplicate = !received_message_hashes
+                        .lock()
+                        .await
+                        .insert(message_hash, clock.now());
+
+                    if is_duplicate {
+                        info!("Message already received, skipping");
+                        counters.record_duplicate();
+                        reply_to_call(&*transport, call.id(), AckStatus::Duplicate).await;
+                        return;
+                    }
+                    // Resends of a message don't use up the sender's budget
+                    let within_rate = inbound_rate
+                        .lock()
+                        .await
+                        .allow(app_message.dht_record, clock.now());
+                    if !within_rate {
+                        info!(
+                            "Dropping message over {}'s rate limit",
+                            app_message.dht_record
+                        );
+                        counters.record_rate_limited();
+                        received_message_hashes.lock().await.remove(message_hash);
+                        reply_to_call(&*transport, call.id(), AckStatus::Busy).await;
+                        return;
+                    }
+                    // Only fresh messages count, so replays don't keep a route alive
+                    counters.record_received(raw_message.len(), clock.now());
+                    routes
+                        .lock()
+                        .await
+                        .record_activity(app_message.dht_record, true, clock.now());
+
+                    if let Some(request_id) = &app_message.reply_to {
+                        let reply_sender = {
+                            let mut pending_requests = pending_requests.lock().await;
+                            match pending_requests.get(request_id) {
+                                Some((from, _)) if *from == app_message.dht_record => {
+                                    pending_requests
+                                        .remove(request_id)
+                                        .map(|(_, sender)| sender)
+                                }
+                                Some(_) => {
+                                    info!(
+                                        "Ignoring answer to request {} from {}, it went elsewhere",
+                                        request_id, app_message.dht_record
+                                    );
+                                    None
+                                }
+                                None => None,
+                            }
+                        };
+                        if let Some(reply_sender) = reply_sender {
+                            info!("Received reply to request {}", request_id);
+                            let _ = reply_sender.send(raw_message);
+                            reply_to_call(&*transport, call.id(), AckStatus::Accepted).await;
+                            return;
+                        }
+                    }
+
+                    if paused.load(Ordering::SeqCst) {
+                        let status = match pause_mode {
+                            // Paused after the loop let it through; its hash is
+                            // recorded, so buffer it rather than have the sender
+                            // retry, unless the buffer is full
+                            PauseMode::Buffer | PauseMode::Hold => {
+                                let mut buffered = paused_messages.lock().await;
+                                if buffered.len() < MAX_PAUSED_MESSAGES {
+                                    info!("Message processing paused, buffering message");
+                                    buffered.push_back(raw_message);
+                                    AckStatus::Accepted
+                                } else {
+                                    drop(buffered);
+                                    info!(
+                                        "Message processing paused and buffer full, replying busy"
+                                    );
+                                    received_message_hashes.lock().await.remove(message_hash);
+                                    AckStatus::Busy
+                                }
+                            }
+                            PauseMode::Drop => {
+                                info!("Message processing paused, dropping message");
+                                AckStatus::Accepted
+                            }
+                        };
+                        reply_to_call(&*transport, call.id(), status).await;
+                        return;
+                    }
+
+                    let status = run_handler(
+                        &mut app_logic,
+                        app_message,
+                        turn,
+                        &received_message_hashes,
+                        message_hash,
+                    )
+                    .await;
+                    reply_to_call(&*transport, call.id(), status).await;
+                });
+            }
+            VeilidUpdate::RouteChange(change) => {
+                info!("VeilidUpdate::RouteChange, {:?}", change);
+
+                let our_route_is_dead = change
+                    .dead_routes
+                    .iter()
+                    .filter(|r| **r == self.our_route)
+                    .count()
+                    > 0;
+                if our_route_is_dead {
+                    self.update_local_route().await?;
+                    app_logic.on_local_route_changed(self.our_route);
+                }
+                self.replace_dead_backup_routes(&change.dead_routes).await;
+
+                remove_dead_remote_routes(
+                    &mut *routes.lock().await,
+                    &change.dead_remote_routes,
+                    &mut app_logic,
+                    &self.counters,
+                );
+            }
+            VeilidUpdate::Attachment(attachment) => {
+                self.attachment.lock().await.update(
+                    attachment.state,
+                    attachment.public_internet_ready,
+                    &mut app_logic,
+                );
+                track_attachment(
+                    &mut self.reconnect,
+                    attachment.state,
+                    self.clock.now(),
+                    &self.reconnect_policy,
+                );
+                self.publish_reconnect_state().await;
+            }
+            VeilidUpdate::ValueChange(change) => {
+                self.apply_value_change(&change).await;
+            }
+            _ => (),
+        };
+
+        Ok(())
+    }
+
+    async fn has_held_calls(&self) -> bool {
+        !self.is_paused() && !self.held_calls.lock().await.is_empty()
+    }
+
+    /// Hand messages buffered while paused to handlers in the order they
+    /// arrived, taking permits and ordering turns like live messages do.
+    /// They were ACKed when buffered, so a failed handler is only logged.
+    async fn drain_paused_messages<T, U>(&self, app_logic: U, cancel: &CancellationToken)
+    where
+        T: Serialize + DeserializeOwned + Send + Sync + Clone + 'static,
+        U: AppLogic<T> + Clone + Send + 'static,
+    {
+        while !self.is_paused() {
+            let raw_message = self.paused_messages.lock().await.pop_front();
+            let Some(raw_message) = raw_message else {
+                return;
+            };
+
+            let app_message = match decode_app_message::<T>(&raw_message) {
+                Result::Ok(app_message) => app_message,
+                Err(e) => {
+                    info!("Dropping malformed buffered message: {:#}", e);
+                    continue;
+                }
+            };
+            // Always waits: the sender was told it's accepted, so Busy is no answer
+            let Some(permit) = cancel.run_until(self.handler_permits.acquire()).await else {
+                // Kept for export_state
+                self.paused_messages.lock().await.push_front(raw_message);
+                return;
+            };
+            let turn = self.message_ordering.map(|ordering| {
+                self.handler_queues
+                    .enqueue(ordering.key_of(app_message.dht_record))
+            });
+            let message_hash = dedup_key(&app_message, &raw_message);
+            let received_message_hashes = self.received_message_hashes.clone();
+            let mut app_logic = app_logic.clone();
+
+            spawn_detached(async move {
+                let _permit = permit;
+                run_handler(
+                    &mut app_logic,
+                    app_message,
+                    turn,
+                    &received_message_hashes,
+                    message_hash,
+                )
+                .await;
+            });
+        }
+    }
+
+    async fn reconnect_if_due<T, U>(&mut self, app_logic: &mut U)
+    where
+        T: DeserializeOwned,
+        U: AppLogic<T>,
+    {
+        let now = self.clock.now();
+        let Some(mut reconnect) = self.reconnect.filter(|reconnect| reconnect.is_due(now)) else {
+            return;
+        };
+        reconnect.start_attempt();
+
+        if !reconnect.attached {
+            info!("Re-attaching to the network");
+            if let Err(e) = self.api.attach().await {
+                info!("Unable to attach: {}", e);
+            }
+            reconnect.back_off(now, &self.reconnect_policy);
+            self.reconnect = Some(reconnect);
+            return;
+        }
+
+        match self.update_local_route().await {
+            Result::Ok(()) => {
+                info!("Reconnected, route republished");
+                self.reconnect = None;
+                app_logic.on_local_route_changed(self.our_route);
+            }
+            Err(e) => {
+                info!("Unable to republish route: {}", e);
+                reconnect.back_off(now, &self.reconnect_policy);
+                self.reconnect = Some(reconnect);
+            }
+        }
+    }
+
+    // A dead backup route only costs peers a fallback, so failing to replace
+    // it isn't fatal to the loop
+    async fn replace_dead_backup_routes(&mut self, dead_routes: &[CryptoKey]) {
+        for (index, subkey) in (0..self.backup_routes.len()).zip(backup_subkeys()) {
+            if !dead_routes.contains(&self.backup_routes[index].0) {
+                continue;
+            }
+
+            let replaced: Result<(), VeilidDuplexError> = async {
+                let (route, blob) = create_private_route(
+                    self.api.clone(),
+                    self.config.crypto_kind,
+                    self.config.stability,
+                    self.config.sequencing,
+                )
+                .await?;
+                update_service_route_pin(
+                    self.routing_context.clone(),
+                    blob.clone(),
+                    self.our_dht_key,
+                    self.dht_keypair,
+                    subkey,
+                )
+                .await?;
+                info!("Backup route in subkey {} replaced by {}", subkey, route);
+                self.backup_routes[index] = (route, blob);
+                Ok(())
+            }
+            .await;
+            if let Err(e) = replaced {
+                info!("Unable to replace backup route in subkey {}: {}", subkey, e);
+            }
+        }
+    }
+
+    async fn update_local_route(&mut self) -> Result<(), VeilidDuplexError> {
+        let (our_route, our_route_blob) = create_private_route(
+            self.api.clone(),
+            self.config.crypto_kind,
+            self.config.stability,
+            self.config.sequencing,
+        )
+        .await?;
+        self.our_route = our_route;
+        self.our_route_blob = our_route_blob;
+        update_service_route_pin(
+            self.routing_context.clone(),
+            self.published_blob(),
+            self.our_dht_key,
+            self.dht_keypair,
+            ROUTE_SUBKEY,
+        )
+        .await?;
+        // The pin was just written, no need for a refresh soon
+        self.last_pin_refresh
+            .store(self.clock.now(), Ordering::SeqCst);
+        info!("DHT value for route {:} changed", self.our_route);
+
+        Ok(())
+    }
+}
+
+impl DuplexSender {
+    /// Send a message, retrying until the peer replies, and return the uuid it
+    /// was sent with. With an outbox set, returns once the message is queued,
+    /// see `VeilidDuplex::set_outbox`. A status other than
+    /// `Accepted` or `Duplicate` means the peer got the message but refused it;
+    /// that is returned as an error without retrying.
+    pub async fn send_message<T: DeserializeOwned>(
+        &self,
+        mut app_message: AppMessage<T>,
+        remote_dht_record: CryptoTyped<CryptoKey>,
+    ) -> Result<String, VeilidDuplexError>
+    where
+        T: Serialize + DeserializeOwned + Send + 'static,
+    {
+        if let Some(outbox) = &self.outbox {
+            app_message.stamp(&*self.uuid_source, &*self.clock);
+            let blob = self.encode_message(&app_message)?;
+            outbox.enqueue(
+                app_message.uuid.clone(),
+                remote_dht_record,
+                blob,
+                self.clock.now(),
+            )?;
+            return Ok(app_message.uuid);
+        }
+
+        let outcome = self
+            .send_message_with_reply(app_message, remote_dht_record)
+            .await?;
+
+        if !outcome.status.is_accepted() {
+            return Err(VeilidDuplexError::Rejected(outcome.status.to_string()));
+        }
+
+        Ok(outcome.uuid)
+    }
+
+    /// Like `send_message`, but hands back the peer's reply instead of turning a
+    /// refusal into an error. Fails only when every attempt failed.
+    pub async fn send_message_with_reply<T: DeserializeOwned>(
+        &self,
+        mut app_message: AppMessage<T>,
+        remote_dht_record: CryptoTyped<CryptoKey>,
+    ) -> Result<SendOutcome, VeilidDuplexError>
+    where
+        T: Serialize + DeserializeOwned + Send + 'static,
+    {
+        app_message.stamp(&*self.uuid_source, &*self.clock);
+        self.deliver(&app_message, remote_dht_record).await
+    }
+
+    /// Send one message to several peers at once. It is encoded once and the
+    /// sends run concurrently, each with its own retries; a peer that fails
+    /// doesn't hold up or abort the others. Returns the message's uuid per
+    /// recipient, in the order of `recipients`, or an error if the message
+    /// couldn't be encoded at all.
+    pub async fn broadcast<T>(
+        &self,
+        mut app_message: AppMessage<T>,
+        recipients: &[CryptoTyped<CryptoKey>],
+    ) -> Result<Vec<Result<String, VeilidDuplexError>>, VeilidDuplexError>
+    where
+        T: Serialize + DeserializeOwned + Send + 'static,
+    {
+        app_message.stamp(&*self.uuid_source, &*self.clock);
+        let blob = self.encode_message(&app_message)?;
+
+        let sends = recipients.iter().map(|recipient| async {
+            let outcome = self
+                .deliver_blob(&app_message.uuid, &blob, *recipient)
+                .await?;
+            if !outcome.status.is_accepted() {
+                return Err(VeilidDuplexError::Rejected(outcome.status.to_string()));
+            }
+
+            Ok(outcome.uuid)
+        });
+
+        Ok(futures_util::future::join_all(sends).await)
+    }
+
+    /// Broadcast a message tagged with `topic` to the peers subscribed to it.
+    /// Receivers dispatch it with a `topics::TopicRouter`.
+    pub async fn publish<T>(
+        &self,
+        topic: impl Into<String>,
+        mut app_message: AppMessage<T>,
+        subscribers: &[CryptoTyped<CryptoKey>],
+    ) -> Result<Vec<Result<String, VeilidDuplexError>>, VeilidDuplexError>
+    where
+        T: Serialize + DeserializeOwned + Send + 'static,
+    {
+        app_message.topic = Some(topic.into());
+        self.broadcast(app_message, subscribers).await
+    }
+
+    /// Stream a file to a peer running a `transfer::FileReceiver` as its
+    /// `AppLogic<FileFrame>`, calling `progress` after each acknowledged frame.
+    /// The peer checks the whole file against a checksum before keeping it.
+    /// Returns the transfer id.
+    pub async fn send_file<F>(
+        &self,
+        path: impl AsRef<Path>,
+        remote_dht_record: CryptoTyped<CryptoKey>,
+        progress: F,
+    ) -> Result<String, VeilidDuplexError>
+    where
+        F: Fn(&TransferProgress),
+    {
+        send_file(self, path.as_ref(), remote_dht_record, progress).await
+    }
+
+    /// Resolve the peer's route and check that whoever answers on it holds
+    /// the owner key of `remote_dht_record`, by having it sign a random
+    /// challenge. The key is looked up from the DHT record, unless one was
+    /// pinned with `PeerKeys::remember_owner`. On `IdentityMismatch` the route
+    /// is dropped from the cache, so it isn't used for sends.
+    pub async fn connect(
+        &self,
+        remote_dht_record: CryptoTyped<CryptoKey>,
+        timeout_after: Duration,
+    ) -> Result<(), VeilidDuplexError> {
+        let target = self
+            .routes
+            .lock()
+            .await
+            .get_route(
+                remote_dht_record,
+                &*self.transport,
+                self.routing_context.clone(),
+                self.clock.now(),
+            )
+            .await?;
+        let owner_key = self
+            .peer_keys
+            .lock()
+            .await
+            .owner_key(&self.routing_context, remote_dht_record)
+            .await?;
+
+        let challenge = identity_challenge(rand::random());
+        let answer = timeout(
+            timeout_after.as_millis() as u32,
+            self.transport.app_call(target, challenge.clone()),
+        )
+        .await
+        .map_err(|_| VeilidDuplexError::RequestTimeout {
+            request_id: "identity challenge".to_string(),
+            timeout: timeout_after,
+        })??;
+
+        let cs = crypto_system(&self.api, remote_dht_record.kind)?;
+        if let Err(e) = check_answer(&cs, &owner_key, remote_dht_record, &challenge, &answer) {
+            info!("Identity check of {} failed: {}", remote_dht_record, e);
+            self.routes.lock().await.invalidate(remote_dht_record);
+            return Err(VeilidDuplexError::IdentityMismatch(remote_dht_record));
+        }
+
+        Ok(())
+    }
+
+    /// Round trip of a probe to the peer and back over its cached route. The
+    /// peer's network loop answers it without involving `on_message`. A single
+    /// attempt, failing with `RequestTimeout` past `timeout`.
+    pub async fn ping(
+        &self,
+        remote_dht_record: CryptoTyped<CryptoKey>,
+        timeout_after: Duration,
+    ) -> Result<Duration, VeilidDuplexError> {
+        let target = self
+            .routes
+            .lock()
+            .await
+            .get_route(
+                remote_dht_record,
+                &*self.transport,
+                self.routing_context.clone(),
+                self.clock.now(),
+            )
+            .await?;
+
+        let result = probe_latency(
+            &*self.transport,
+            target,
+            *self.uuid_source.next_uuid().as_bytes(),
+            timeout_after,
+        )
+        .await;
+        if !matches!(result, Err(VeilidDuplexError::RequestTimeout { .. })) {
+            self.routes.lock().await.record_activity(
+                remote_dht_record,
+                result.is_ok(),
+                self.clock.now(),
+            );
+        }
+
+        result
+    }
+
+    /// Send a request and wait for the peer's answer: a message whose `reply_to`
+    /// is this request's uuid. The whole exchange, including send retries, has to
+    /// finish within `timeout`.
+    pub async fn send_request<T, R>(
+        &self,
+        mut app_message: AppMessage<T>,
+        remote_dht_record: CryptoTyped<CryptoKey>,
+        timeout_after: Duration,
+    ) -> Result<R, VeilidDuplexError>
+    where
+        T: Serialize + DeserializeOwned + Send + 'static,
+        R: Serialize + DeserializeOwned,
+    {
+        app_message.stamp(&*self.uuid_source, &*self.clock);
+        let request_id = app_message.uuid.clone();
+
+        let (reply_sender, reply_receiver) = bounded(1);
+        self.pending_requests
+            .lock()
+            .await
+            .insert(request_id.clone(), (remote_dht_record, reply_sender));
+
+        let result = timeout(timeout_after.as_millis() as u32, async {
+            let outcome = self.deliver(&app_message, remote_dht_record).await?;
+            if !outcome.status.is_accepted() {
+                return Err(VeilidDuplexError::Rejected(outcome.status.to_string()));
+            }
+
+            Ok(reply_receiver.recv_async().await.map_err(Error::from)?)
+        })
+        .await;
+
+        self.pending_requests.lock().await.remove(&request_id);
+
+        let raw_reply = match result {
+            Result::Ok(raw_reply) => raw_reply?,
+            Err(_) => {
+                return Err(VeilidDuplexError::RequestTimeout {
+                    request_id,
+                    timeout: timeout_after,
+                })
+            }
+        };
+
+        Ok(decode_app_message::<R>(&raw_reply)?.data)
+    }
+
+    /// Send to a route imported with `VeilidDuplex::import_route_blob`, retrying
+    /// like `send_message`. Signed when `set_signing` is on. Encryption,
+    /// bandwidth limits and route tracking are keyed on the peer's DHT record,
+    /// so they don't apply.
+    pub async fn send_message_to_target<T: DeserializeOwned>(
+        &self,
+        mut app_message: AppMessage<T>,
+        target: Target,
+    ) -> Result<(), VeilidDuplexError>
+    where
+        T: Serialize + DeserializeOwned + Send + 'static,
+    {
+        app_message.stamp(&*self.uuid_source, &*self.clock);
+        let blob = self.encode_message(&app_message)?;
+
+        for _ in 0..self.send_attempts {
+            match send_frames(&*self.transport, target, blob.clone()).await {
+                Result::Ok(reply) => {
+                    self.counters.record_sent(blob.len(), self.clock.now());
+                    let status = AckStatus::from_reply(&reply);
+                    if !status.is_accepted() {
+                        return Err(VeilidDuplexError::Rejected(status.to_string()));
+                    }
+                    return Ok(());
+                }
+                Err(e) => {
+                    self.counters.record_retry();
+                    info!(
+                        "Unable to send message: {}, sleeping {:?}",
+                        e, self.send_retry_interval
+                    );
+                    sleep(self.send_retry_interval.as_millis() as u32).await;
+                }
+            }
+        }
+
+        self.counters.record_exhausted();
+        Err(VeilidDuplexError::SendExhausted {
+            attempts: self.send_attempts,
+        })
+    }
+
+    // Sends with the message's current uuid, so every retry carries the same one
+    async fn deliver<T>(
+        &self,
+        app_message: &AppMessage<T>,
+        remote_dht_record: CryptoTyped<CryptoKey>,
+    ) -> Result<SendOutcome, VeilidDuplexError>
+    where
+        T: Serialize + DeserializeOwned + Send + 'static,
+    {
+        let blob = self.encode_message(app_message)?;
+        self.deliver_blob(&app_message.uuid, &blob, remote_dht_record)
+            .await
+    }
+
+    // Encoded and signed, ready for per-recipient encryption
+    fn encode_message<T>(&self, app_message: &AppMessage<T>) -> Result<Vec<u8>, VeilidDuplexError>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        let blob = app_message.encode(&self.send_options)?;
+        if !self.send_options.sign {
+            return Ok(blob);
+        }
+
+        let cs = crypto_system(&self.api, self.our_dht_key.kind)?;
+        Ok(sign(&cs, &self.dht_keypair, &blob)?)
+    }
+
+    async fn deliver_blob(
+        &self,
+        uuid: &str,
+        blob: &[u8],
+        remote_dht_record: CryptoTyped<CryptoKey>,
+    ) -> Result<SendOutcome, VeilidDuplexError> {
+        let result = self
+            .try_deliver_blob(uuid, blob, remote_dht_record, self.send_attempts)
+            .await;
+        if let Err(e) = &result {
+            self.dead_letter(uuid, blob, remote_dht_record, e);
+        }
+
+        result
+    }
+
+    // A single attempt, for the outbox to schedule retries itself
+    pub(crate) async fn deliver_once(
+        &self,
+        uuid: &str,
+        blob: &[u8],
+        remote_dht_record: CryptoTyped<CryptoKey>,
+    ) -> Result<SendOutcome, VeilidDuplexError> {
+        self.try_deliver_blob(uuid, blob, remote_dht_record, 1)
+            .await
+    }
+
+    pub(crate) fn dead_letter(
+        &self,
+        uuid: &str,
+        blob: &[u8],
+        remote_dht_record: CryptoTyped<CryptoKey>,
+        error: &VeilidDuplexError,
+    ) {
+        let Some(dead_letters) = &self.dead_letters else {
+            return;
+        };
+
+        let dead_letter = DeadLetter {
+            uuid: uuid.to_string(),
+            destination: remote_dht_record,
+            error: error.to_string(),
+            blob: blob.to_vec(),
+        };
+        if dead_letters.send(dead_letter).is_err() {
+            info!("Dead letter receiver is gone, dropping message {}", uuid);
+        }
+    }
+
+    pub(crate) fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
+    async fn try_deliver_blob(
+        &self,
+        uuid: &str,
+        blob: &[u8],
+        remote_dht_record: CryptoTyped<CryptoKey>,
+        attempts: u16,
+    ) -> Result<SendOutcome, VeilidDuplexError> {
+        for attempt_n in 0..attempts {
+            self.pace_send(blob.len() as u64, remote_dht_record).await;
+
+            // The cache is only locked to look the route up and to record the
+            // outcome, so sends to other peers aren't held up by this one
+            let target = self
+                .routes
+                .lock()
+                .await
+                .get_route(
+                    remote_dht_record,
+                    &*self.transport,
+                    self.routing_context.clone(),
+                    self.clock.now(),
+                )
+                .await?;
+
+            let blob = match self.send_options.encrypt {
+                true => self.encrypt_for(remote_dht_record, blob).await?,
+                false => blob.to_vec(),
+            };
+
+            let sent_at = get_timestamp();
+            let sent_bytes = blob.len();
+            let result = send_frames(&*self.transport, target, blob).await;
+            let became_unresponsive = {
+                let mut routes = self.routes.lock().await;
+                routes.record_activity(remote_dht_record, result.is_ok(), self.clock.now());
+                matches!(&result, Err(e) if is_ack_timeout(e))
+                    && routes.record_ack_timeout(remote_dht_record, self.unresponsive_threshold)
+            };
+            match result {
+                Result::Ok(reply) => {
+                    let status = AckStatus::from_reply(&reply);
+                    if status == AckStatus::Busy && attempt_n + 1 < attempts {
+                        self.counters.record_retry();
+                        info!("Peer busy, sleeping {:?}", self.send_retry_interval);
+                        sleep(self.send_retry_interval.as_millis() as u32).await;
+                        continue;
+                    }
+
+                    self.counters.record_sent(sent_bytes, self.clock.now());
+                    return Ok(SendOutcome {
+                        uuid: uuid.to_string(),
+                        status,
+                        reply,
+                        rtt: Duration::from_micros(get_timestamp().saturating_sub(sent_at)),
+                        attempts: attempt_n + 1,
+                    });
+                }
+                Err(_) => {
+                    self.counters.record_retry();
+                    if became_unresponsive {
+                        info!("Peer {} stopped replying to messages", remote_dht_record);
+                        if let Some(on_peer_unresponsive) = &self.on_peer_unresponsive {
+                            on_peer_unresponsive(remote_dht_record);
+                        }
+                    }
+
+                    info!(
+                        "Unable to send message, sleeping {:?}",
+                        self.send_retry_interval
+                    );
+                    sleep(self.send_retry_interval.as_millis() as u32).await;
+                }
+            }
+        }
+
+        self.counters.record_exhausted();
+        Err(VeilidDuplexError::SendExhausted { attempts })
+    }
+
+    async fn encrypt_for(
+        &self,
+        remote_dht_record: CryptoTyped<CryptoKey>,
+        blob: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let shared_secret = self
+            .peer_keys
+            .lock()
+            .await
+            .shared_secret(
+                &self.api,
+                &self.routing_context,
+                &self.dht_keypair.secret,
+                remote_dht_record,
+            )
+            .await?;
+        let cs = crypto_system(&self.api, remote_dht_record.kind)?;
+
+        encrypt(&cs, &shared_secret, self.our_dht_key, blob)
+    }
+
+    async fn pace_send(&self, size: u64, remote_dht_record: CryptoTyped<CryptoKey>) {
+        let delay = {
+            let mut bandwidth = self.bandwidth.lock().await;
+            if !bandwidth.is_limited(remote_dht_record) {
+                return;
+            }
+
+            bandwidth.reserve(remote_dht_record, size, self.clock.now())
+        };
+
+        if !delay.is_zero() {
+            info!("Bandwidth limit reached, delaying send by {:?}", delay);
+            sleep(delay.as_millis() as u32).await;
+        }
+    }
+}
+
+impl DuplexReceiver {
+    /// See `VeilidDuplex::network_loop`
+    pub async fn network_loop<T, U>(&mut self, app_logic: U) -> Result<(), VeilidDuplexError>
+    where
+        T: Serialize + DeserializeOwned + Send + Sync + Clone + Sized + 'static,
+        U: AppLogic<T> + Clone + Send + 'static,
+    {
+        self.duplex.network_loop(app_logic).await
+    }
+
+    /// See `VeilidDuplex::network_loop_until`
+    pub async fn network_loop_until<T, U>(
+        &mut self,
+        app_logic: U,
+        cancel: CancellationToken,
+    ) -> Result<(), VeilidDuplexError>
+    where
+        T: Serialize + DeserializeOwned + Send + Sync + Clone + Sized + 'static,
+        U: AppLogic<T> + Clone + Send + 'static,
+    {
+        self.duplex.network_loop_until(app_logic, cancel).await
+    }
+
+    pub async fn network_loop_cycle<T, U>(&mut self, app_logic: U) -> Result<(), VeilidDuplexError>
+    where
+        T: Serialize + DeserializeOwned + Send + Sync + Clone + 'static,
+        U: AppLogic<T> + Clone + Send + 'static,
+    {
+        self.duplex.network_loop_cycle(app_logic).await
+    }
+
+    /// See `VeilidDuplex::spawn_network_loop`
+    pub fn spawn_network_loop<T, U>(self, app_logic: U) -> NetworkLoopHandle
+    where
+        T: Serialize + DeserializeOwned + Send + Sync + Clone + 'static,
+        U: AppLogic<T> + Clone + Send + 'static,
+    {
+        self.duplex.spawn_network_loop(app_logic)
+    }
+
+    /// See `VeilidDuplex::incoming`
+    pub fn incoming<T>(&self) -> Result<impl Stream<Item = AppMessage<T>>, VeilidDuplexError>
+    where
+        T: Serialize + DeserializeOwned + Send + Sync + Clone + 'static,
+    {
+        self.duplex.incoming()
+    }
+}
+
+// Subkeys of our DHT record holding backup routes, in order
+fn backup_subkeys() -> impl Iterator<Item = ValueSubkey> {
+    ROUTE_SUBKEY + 1..
+}
+
+fn backup_pins(backup_routes: &[(CryptoKey, Vec<u8>)]) -> Vec<(ValueSubkey, Vec<u8>)> {
+    backup_subkeys()
+        .zip(backup_routes.iter().map(|(_, blob)| blob.clone()))
+        .collect()
+}
+
+/// Whether `interval` has passed since `last`, in which case `last` is set to
+/// `now`. Only one of several callers racing on the same timestamp wins.
+pub(crate) fn claim_if_due(last: &AtomicU64, interval: Duration, now: u64) -> bool {
+    let previous = last.load(Ordering::SeqCst);
+    if now.saturating_sub(previous) < interval.as_micros() as u64 {
+        return false;
+    }
+
+    last.compare_exchange(previous, now, Ordering::SeqCst, Ordering::SeqCst)
+        .is_ok()
+}
+
+/// Start recovering when the node detaches, and move on to republishing our
+/// route once it is attached again
+fn track_attachment(
+    reconnect: &mut Option<Reconnect>,
+    state: AttachmentState,
+    now: u64,
+    policy: &ReconnectPolicy,
+) {
+    let detached = matches!(
+        state,
+        AttachmentState::Detached | AttachmentState::Detaching
+    );
+    match reconnect {
+        None if detached => {
+            info!("Lost attachment, reconnecting");
+            *reconnect = Some(Reconnect::new(now, policy));
+        }
+        // Attaching again is the success that closes the circuit
+        Some(pending) if is_attached(state) && !pending.attached => {
+            *pending = Reconnect {
+                attached: true,
+                ..Reconnect::new(now, policy)
+            };
+        }
+        Some(pending) if detached && pending.attached => {
+            *pending = Reconnect::new(now, policy);
+        }
+        _ => (),
+    }
+}
+
+fn remove_dead_remote_routes<T, U>(
+    routes: &mut VeilidDuplexRoutes,
+    dead_routes: &[CryptoKey],
+    app_logic: &mut U,
+    counters: &StatsCounters,
+) where
+    T: DeserializeOwned,
+    U: AppLogic<T>,
+{
+    for dead_route in dead_routes {
+        if let Some(dht_record) = routes.remove_route_if_exists(*dead_route) {
+            info!("Route to {} is dead", dht_record);
+            counters.record_dead_route();
+            app_logic.on_remote_route_dead(dht_record);
+        }
+    }
+}
+
+// Counts dedup evictions in the stats before handing them to `callback`
+fn count_evictions(
+    counters: Arc<StatsCounters>,
+    callback: Option<EvictionCallback>,
+) -> EvictionCallback {
+    Arc::new(move |hash| {
+        counters.record_dedup_eviction();
+        if let Some(callback) = &callback {
+            callback(hash);
+        }
+    })
+}
+
+/// Hand a message to `on_message` once its ordering turn comes. A failed
+/// handler forgets the message, so a resend gets processed again.
+async fn run_handler<T, U>(
+    app_logic: &mut U,
+    app_message: AppMessage<T>,
+    mut turn: Option<Turn<Option<CryptoTyped<CryptoKey>>>>,
+    received_message_hashes: &Mutex<DedupCache>,
+    message_hash: u64,
+) -> AckStatus
+where
+    T: DeserializeOwned,
+    U: AppLogic<T>,
+{
+    if let Some(turn) = &mut turn {
+        turn.wait().await;
+    }
+    match app_logic.on_message(app_message).await {
+        Result::Ok(()) => AckStatus::Accepted,
+        Err(e) => {
+            info!("Message handler failed: {}", e);
+            received_message_hashes.lock().await.remove(message_hash);
+            AckStatus::Rejected(e.reason)
+        }
+    }
+}
+
+/// What redeliveries of a message have in common: its sender and uuid,
+/// whatever codec or envelope it was resent with. Uuids are only unique per
+/// sender. Messages without one fall back to their bytes.
+fn dedup_key<T: DeserializeOwned>(app_message: &AppMessage<T>, raw_message: &[u8]) -> u64 {
+    if app_message.uuid.is_empty() {
+        return calculate_hash(raw_message);
+    }
+
+    let mut key = app_message.dht_record.value.bytes.to_vec();
+    key.extend_from_slice(app_message.uuid.as_bytes());
+    calculate_hash(&key)
+}
+
+/// Signature and signed payload if the message was signed, the message as
+/// decompressed, and the message decoded
+type OpenedMessage<T> = (Option<(Signature, Vec<u8>)>, Vec<u8>, AppMessage<T>);
+
+/// A complete inbound message, opened as far as it can be without awaiting
+enum Inbound<T: DeserializeOwned> {
+    Encrypted(Vec<u8>),
+    Opened(OpenedMessage<T>),
+}
+
+impl<T: DeserializeOwned> Inbound<T> {
+    /// Sender the message claims to be from, not verified yet
+    fn claimed_sender(&self) -> Option<CryptoTyped<CryptoKey>> {
+        match self {
+            Inbound::Encrypted(raw_message) => sender_of(raw_message).ok(),
+            Inbound::Opened((_, _, app_message)) => Some(app_message.dht_record),
+        }
+    }
+}
+
+/// Add an inbound frame to the message it belongs to. `None` until the last
+/// chunk of a chunked message is in.
+async fn reassemble(
+    reassembler: &Mutex<Reassembler>,
+    frame: &[u8],
+    max_inbound_size: usize,
+    now: u64,
+) -> Result<Option<Vec<u8>>, AckStatus> {
+    let raw_message = match Chunk::from_bytes(frame) {
+        Some(chunk) => {
+            let announced_size = chunk.total as usize * CHUNK_PAYLOAD_SIZE;
+            check_inbound_size(announced_size, max_inbound_size)?;
+
+            let mut reassembler = reassembler.lock().await;
+            reassembler.prune(now);
+            match reassembler.insert(chunk, now) {
+                Some(raw_message) => raw_message,
+                None => return Ok(None),
+            }
+        }
+        None => frame.to_vec(),
+    };
+    check_inbound_size(raw_message.len(), max_inbound_size)?;
+
+    Ok(Some(raw_message))
+}
+
+/// Split off the signature of a decrypted message, if any, and decode it
+fn open_message<T>(
+    raw_message: Vec<u8>,
+    max_inbound_size: usize,
+    counters: &StatsCounters,
+) -> Result<OpenedMessage<T>, AckStatus>
+where
+    T: Serialize + DeserializeOwned,
+{
+    let (signed, raw_message) = if is_signed(&raw_message) {
+        match split_signed(&raw_message) {
+            Result::Ok((signature, payload)) => {
+                let payload = payload.to_vec();
+                (Some((signature, payload.clone())), payload)
+            }
+            Err(e) => {
+                info!("Dropping malformed signed message: {}", e);
+                counters.record_signature_rejected();
+                return Err(AckStatus::DeserializeFailed);
+            }
+        }
+    } else {
+        (None, raw_message)
+    };
+    let (raw_message, app_message) = unpack_message::<T>(raw_message, max_inbound_size)?;
+
+    Ok((signed, raw_message, app_message))
+}
+
+fn check_inbound_size(size: usize, max_size: usize) -> Result<(), AckStatus> {
+    if size > max_size {
+        info!("Dropping message of {} bytes, limit is {}", size, max_size);
+        return Err(AckStatus::TooLarge);
+    }
+
+    Ok(())
+}
+
+/// Check a message's send timestamp (unix ms) against `now_ms`. Unstamped
+/// messages pass.
+fn check_timestamp(timestamp: u64, now_ms: u64, window: TimestampWindow) -> Result<(), AckStatus> {
+    if timestamp == 0 {
+        return Ok(());
+    }
+
+    let behind = now_ms.saturating_sub(timestamp);
+    let ahead = timestamp.saturating_sub(now_ms);
+    if behind > window.max_behind.as_millis() as u64 || ahead > window.max_ahead.as_millis() as u64
+    {
+        info!(
+            "Dropping message stamped {} ms behind, {} ms ahead of our clock",
+            behind, ahead
+        );
+        return Err(AckStatus::Rejected(
+            "Timestamp outside accepted window".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Decompress and decode a received, decrypted message. Any peer can send
+/// arbitrary bytes to our route, so failures come back as the status to reply with.
+fn unpack_message<T>(
+    raw_message: Vec<u8>,
+    max_size: usize,
+) -> Result<(Vec<u8>, AppMessage<T>), AckStatus>
+where
+    T: Serialize + DeserializeOwned,
+{
+    check_inbound_size(raw_message.len(), max_size)?;
+    let raw_message = decompress(raw_message, max_size).map_err(|e| {
+        info!("Unable to decompress message: {}", e);
+        match e.downcast_ref::<VeilidDuplexError>() {
+            Some(VeilidDuplexError::MessageTooLarge { .. }) => AckStatus::TooLarge,
+            _ => AckStatus::DeserializeFailed,
+        }
+    })?;
+
+    let app_message = decode_app_message::<T>(&raw_message).map_err(|e| {
+        info!("Dropping malformed message: {:#}", e);
+        AckStatus::DeserializeFailed
+    })?;
+
+    Ok((raw_message, app_message))
+}
+
+fn decode_app_message<T>(raw_message: &[u8]) -> Result<AppMessage<T>, VeilidDuplexError>
+where
+    T: Serialize + DeserializeOwned,
+{
+    decode_any::<AppMessage<T>>(raw_message)
+}
+
+/// Start a node with the default config, ask `remote_dht_record` one question
+/// and shut the node down again, for CLI tools and other callers that don't
+/// keep a connection. Startup takes as long as for any node; nothing is kept
+/// afterwards. For other settings, build a `VeilidDuplex` and call
+/// `request_then_shutdown` on it.
+pub async fn one_shot_request<T, R>(
+    remote_dht_record: CryptoTyped<CryptoKey>,
+    data: T,
+    timeout_after: Duration,
+) -> Result<R, VeilidDuplexError>
+where
+    T: Serialize + DeserializeOwned + Send + 'static,
+    R: Serialize + DeserializeOwned + Send + Sync + Clone + 'static,
+{
+    VeilidDuplex::new()
+        .await?
+        .request_then_shutdown(data, remote_dht_record, timeout_after)
+        .await
+}
+
+// Refuses everything reaching `on_message`; answers to our requests are
+// taken before that
+#[derive(Clone)]
+struct AnswersOnly;
+
+impl<T: DeserializeOwned + Send> AppLogic<T> for AnswersOnly {
+    async fn on_message(&mut self, _message: AppMessage<T>) -> Result<(), HandlerError> {
+        Err(HandlerError::new("Only expecting an answer"))
+    }
+}
+
+/// Send an encoded message. Blobs over 32kb go out as several app_calls; the
+/// reply to the last one reflects the handling of the whole message.
+pub async fn send_blob(
+    routing_context: &RoutingContext,
+    target: Target,
+    blob: Vec<u8>,
+) -> Result<Vec<u8>, VeilidDuplexError> {
+    let mut reply = Vec::new();
+    for frame in split_into_frames(blob)? {
+        reply = routing_context.app_call(target, frame).await?;
+    }
+
+    Ok(reply)
+}
+
+// `send_blob` through a `Transport`
+async fn send_frames(
+    transport: &dyn Transport,
+    target: Target,
+    blob: Vec<u8>,
+) -> Result<Vec<u8>, VeilidDuplexError> {
+    let mut reply = Vec::new();
+    for frame in split_into_frames(blob)? {
+        reply = transport.app_call(target, frame).await?;
+    }
+
+    Ok(reply)
+}
+
+/// Round trip of a latency probe over `target`, failing with `RequestTimeout`
+/// past `timeout_after`
+async fn probe_latency(
+    transport: &dyn Transport,
+    target: Target,
+    probe_id: [u8; 16],
+    timeout_after: Duration,
+) -> Result<Duration, VeilidDuplexError> {
+    let mut probe = LATENCY_PING.to_vec();
+    probe.extend_from_slice(&probe_id);
+
+    let sent_at = get_timestamp();
+    let reply = timeout(
+        timeout_after.as_millis() as u32,
+        transport.app_call(target, probe.clone()),
+    )
+    .await
+    .map_err(|_| VeilidDuplexError::RequestTimeout {
+        request_id: uuid::Uuid::from_bytes(probe_id).to_string(),
+        timeout: timeout_after,
+    })??;
+    let rtt = Duration::from_micros(get_timestamp().saturating_sub(sent_at));
+
+    if reply != probe {
+        return Err(VeilidDuplexError::Rejected(
+            "Ping answered with something else".to_string(),
+        ));
+    }
+
+    Ok(rtt)
+}
+
+/// The candidate answering a probe fastest. All are probed at once, so this
+/// takes at most `timeout_after`; `None` if none answered.
+async fn fastest_route(
+    transport: &dyn Transport,
+    candidates: &[(Target, CryptoKey)],
+    timeout_after: Duration,
+) -> Option<(Target, CryptoKey)> {
+    let probes = candidates.iter().map(|&(target, route)| async move {
+        let rtt = probe_latency(transport, target, rand::random(), timeout_after).await;
+        rtt.ok().map(|rtt| (rtt, target, route))
+    });
+
+    futures_util::future::join_all(probes)
+        .await
+        .into_iter()
+        .flatten()
+        .min_by_key(|(rtt, _, _)| *rtt)
+        .map(|(_, target, route)| (target, route))
+}
+
+fn is_latency_ping(message: &[u8]) -> bool {
+    message.len() == LATENCY_PING.len() + 16 && message.starts_with(LATENCY_PING)
+}
+
+fn is_ack_timeout(e: &VeilidDuplexError) -> bool {
+    matches!(e, VeilidDuplexError::Veilid(VeilidAPIError::Timeout))
+}
+
+async fn reply_to_call(transport: &dyn Transport, call_id: OperationId, status: AckStatus) {
+    let reply = match status.to_bytes() {
+        Result::Ok(reply) => reply,
+        Err(e) => {
+            info!("Unable to encode ACK: {}", e);
+            return;
+        }
+    };
+    if transport.app_call_reply(call_id, reply).await.is_err() {
+        info!("Unable to send ACK");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{ManualClock, SequentialUuids};
+    use crate::config::ConfigValue;
+    use crate::outbox::FileOutboxStore;
+    use crate::presence::PresenceStatus;
+    use crate::transport::LinkConditions;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_ack_status_from_reply() {
+        assert_eq!(AckStatus::from_reply(b"ACK"), AckStatus::Accepted);
+        assert_eq!(
+            AckStatus::from_reply(&AckStatus::Accepted.to_bytes().unwrap()),
+            AckStatus::Accepted
+        );
+
+        let rejected = AckStatus::Rejected("no handler".to_string());
+        assert_eq!(
+            AckStatus::from_reply(&rejected.to_bytes().unwrap()),
+            rejected
+        );
+        assert!(!rejected.is_accepted());
+
+        let duplicate = AckStatus::from_reply(&AckStatus::Duplicate.to_bytes().unwrap());
+        assert_eq!(duplicate, AckStatus::Duplicate);
+        assert!(duplicate.is_accepted());
+        assert!(!AckStatus::TooLarge.is_accepted());
+        assert!(!AckStatus::Busy.is_accepted());
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    struct Counter {
+        count: u64,
+    }
+
+    #[test]
+    fn test_timestamp_and_age() {
+        let mut app_message = AppMessage {
+            data: Counter { count: 1 },
+            uuid: String::new(),
+            dht_record: CryptoTyped::new(CRYPTO_KIND, CryptoKey::new([1; 32])),
+            reply_to: None,
+            timestamp: 0,
+            topic: None,
+        };
+        app_message.stamp(&RandomUuids, &SystemClock);
+        assert!(app_message.timestamp > 0);
+
+        let blob = app_message.encode(&SendOptions::default()).unwrap();
+        let received = decode_app_message::<Counter>(&blob).unwrap();
+        assert_eq!(received.timestamp, app_message.timestamp);
+        assert!(received.age(&SystemClock) < Duration::from_secs(1));
+
+        // Messages from peers without the field still decode
+        let legacy = br#"{"data":{"count":1},"uuid":"u","dht_record":"VLD0:AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE"}"#;
+        let legacy = decode_app_message::<Counter>(legacy).unwrap();
+        assert_eq!(legacy.timestamp, 0);
+        assert_eq!(legacy.age(&SystemClock), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_injected_sources_make_stamping_reproducible() {
+        let encode_stamped = || {
+            let mut app_message = AppMessage {
+                data: Counter { count: 1 },
+                uuid: String::new(),
+                dht_record: CryptoTyped::new(CRYPTO_KIND, CryptoKey::new([1; 32])),
+                reply_to: None,
+                timestamp: 0,
+                topic: None,
+            };
+            app_message.stamp(&SequentialUuids::new(), &ManualClock::new(5_000_000));
+            (
+                app_message.uuid.clone(),
+                app_message.timestamp,
+                app_message.encode(&SendOptions::default()).unwrap(),
+            )
+        };
+
+        let (uuid, timestamp, blob) = encode_stamped();
+        assert_eq!(uuid, Uuid::from_u128(1).to_string());
+        assert_eq!(timestamp, 5_000);
+        assert_eq!(encode_stamped().2, blob);
+    }
+
+    #[tokio::test]
+    async fn test_sender_uses_injected_uuids() -> Result<(), VeilidDuplexError> {
+        let (mut app, mut peer) = VeilidDuplex::in_memory_pair().await?;
+        app.set_uuid_source(SequentialUuids::new());
+        app.set_clock(ManualClock::new(get_timestamp()));
+
+        let app_logic = CountingLogic::default();
+        let sender = app.sender();
+        for count in 0..2 {
+            let app_message = AppMessage {
+                data: Counter { count },
+                uuid: String::new(),
+                dht_record: app.our_dht_key,
+                reply_to: None,
+                timestamp: 0,
+                topic: None,
+            };
+            tokio::select! {
+                result = sender.send_message(app_message, peer.our_dht_key) => { result?; }
+                result = peer.network_loop::<Counter, _>(app_logic.clone()) => {
+                    panic!("network loop stopped: {:?}", result);
+                }
+            };
+        }
+        let expected: Vec<String> = (1..=2).map(|n| Uuid::from_u128(n).to_string()).collect();
+        assert_eq!(*app_logic.uuids.lock().unwrap(), expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_timestamp_window() {
+        let window = TimestampWindow {
+            max_behind: Duration::from_secs(60),
+            max_ahead: Duration::from_secs(5),
+        };
+        let now = 1_700_000_000_000;
+
+        assert!(check_timestamp(now, now, window).is_ok());
+        assert!(check_timestamp(now - 59_000, now, window).is_ok());
+        assert!(check_timestamp(now + 4_000, now, window).is_ok());
+
+        // Replayed long after it was sent
+        assert!(matches!(
+            check_timestamp(now - 61_000, now, window),
+            Err(AckStatus::Rejected(_))
+        ));
+        // Sender's clock far ahead of ours
+        assert!(matches!(
+            check_timestamp(now + 6_000, now, window),
+            Err(AckStatus::Rejected(_))
+        ));
+
+        // Peers that don't stamp messages aren't cut off
+        assert!(check_timestamp(0, now, window).is_ok());
+    }
+
+    #[test]
+    fn test_garbage_message_is_rejected_without_panic() {
+        for garbage in [
+            &b""[..],
+            b"\xff\xfe\x00garbage",
+            b"{\"data\":",
+            b"{\"data\":{\"count\":\"nope\"},\"uuid\":\"\"}",
+        ] {
+            assert!(decode_app_message::<Counter>(garbage).is_err());
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct RecordingLogic {
+        dead_peers: Arc<std::sync::Mutex<Vec<CryptoTyped<CryptoKey>>>>,
+        attachment_states: Arc<std::sync::Mutex<Vec<AttachmentState>>>,
+        internet_ready: Arc<std::sync::Mutex<Vec<bool>>>,
+        local_routes: Arc<std::sync::Mutex<Vec<CryptoKey>>>,
+    }
+
+    impl AppLogic<Counter> for RecordingLogic {
+        async fn on_message(&mut self, _message: AppMessage<Counter>) -> Result<(), HandlerError> {
+            Ok(())
+        }
+
+        fn on_remote_route_dead(&mut self, dht_record: CryptoTyped<CryptoKey>) {
+            self.dead_peers.lock().unwrap().push(dht_record);
+        }
+
+        fn on_attachment_changed(&mut self, state: AttachmentState) {
+            self.attachment_states.lock().unwrap().push(state);
+        }
+
+        fn on_local_route_changed(&mut self, new_route: CryptoKey) {
+            self.local_routes.lock().unwrap().push(new_route);
+        }
+
+        fn on_public_internet_ready_changed(&mut self, ready: bool) {
+            self.internet_ready.lock().unwrap().push(ready);
+        }
+    }
+
+    #[test]
+    fn test_dead_remote_route_fires_hook() {
+        let peer = CryptoTyped::new(CRYPTO_KIND, CryptoKey::new([1; 32]));
+        let route = CryptoKey::new([2; 32]);
+        let mut routes = VeilidDuplexRoutes::new(ROUTE_SUBKEY);
+        routes
+            .routes
+            .insert(peer.value, route_entry(peer, route, 0));
+
+        let mut app_logic = RecordingLogic::default();
+        let counters = StatsCounters::default();
+        let unknown_route = CryptoKey::new([3; 32]);
+        remove_dead_remote_routes::<Counter, _>(
+            &mut routes,
+            &[unknown_route, route],
+            &mut app_logic,
+            &counters,
+        );
+
+        assert_eq!(*app_logic.dead_peers.lock().unwrap(), vec![peer]);
+        assert_eq!(counters.snapshot(0).dead_routes, 1);
+        assert!(routes.peers(get_timestamp()).is_empty());
+    }
+
+    fn route_entry(
+        dht_record: CryptoTyped<CryptoKey>,
+        route: CryptoKey,
+        created_at: u64,
+    ) -> RouteEntry {
+        RouteEntry {
+            dht_record,
+            target: Target::PrivateRoute(route),
+            route,
+            created_at,
+            last_activity: created_at,
+            alive: true,
+            unacked_sends: 0,
+            failed_pings: 0,
+            candidates: Vec::new(),
+            probed_at: created_at,
+        }
+    }
+
+    #[test]
+    fn test_invalidated_route_is_resolved_again() {
+        let peer = CryptoTyped::new(CRYPTO_KIND, CryptoKey::new([1; 32]));
+        let other = CryptoTyped::new(CRYPTO_KIND, CryptoKey::new([4; 32]));
+        let route = CryptoKey::new([2; 32]);
+        let mut routes = VeilidDuplexRoutes::new(ROUTE_SUBKEY);
+        routes
+            .routes
+            .insert(peer.value, route_entry(peer, route, 0));
+        routes
+            .routes
+            .insert(other.value, route_entry(other, route, 0));
+
+        assert!(routes.invalidate(peer));
+        assert!(!routes.invalidate(peer));
+        // A miss is what sends get_route back to DHT
+        assert!(routes.cached_target(peer, 0).is_none());
+        assert!(routes.cached_target(other, 0).is_some());
+
+        routes.clear();
+        assert!(routes.cached_target(other, 0).is_none());
+    }
+
+    #[test]
+    fn test_stale_route_is_a_cache_miss() {
+        let peer = CryptoTyped::new(CRYPTO_KIND, CryptoKey::new([1; 32]));
+        let route = CryptoKey::new([2; 32]);
+        let mut routes = VeilidDuplexRoutes::new(ROUTE_SUBKEY);
+        routes
+            .routes
+            .insert(peer.value, route_entry(peer, route, 1_000_000));
+
+        // Without a TTL routes never expire
+        assert!(routes.cached_target(peer, u64::MAX).is_some());
+
+        routes.set_max_age(Some(Duration::from_secs(60)));
+        assert!(routes.cached_target(peer, 31_000_000).is_some());
+        assert!(routes.cached_target(peer, 62_000_000).is_none());
+        // The stale entry is gone, so get_route goes back to DHT
+        assert!(routes.targets().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_follows_inactivity_timeout() -> Result<(), VeilidDuplexError> {
+        assert_eq!(KeepaliveConfig::default().interval, Duration::from_secs(30));
+        let keepalive = KeepaliveConfig::for_inactivity_timeout(Duration::from_secs(10));
+        assert_eq!(keepalive.interval, Duration::from_secs(5));
+
+        let (mut app, _) = VeilidDuplex::in_memory_pair().await?;
+        app.config.veilid_options.connection_inactivity_timeout_ms = 20_000;
+        assert_eq!(app.default_keepalive().interval, Duration::from_secs(10));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_keepalives_evict_route() {
+        let peer = CryptoTyped::new(CRYPTO_KIND, CryptoKey::new([1; 32]));
+        let route = CryptoKey::new([2; 32]);
+        let mut routes = VeilidDuplexRoutes::new(ROUTE_SUBKEY);
+        routes
+            .routes
+            .insert(peer.value, route_entry(peer, route, 0));
+
+        assert!(!routes.record_ping(peer, false, 3));
+        assert!(!routes.record_ping(peer, false, 3));
+        // A successful ping resets the count
+        assert!(!routes.record_ping(peer, true, 3));
+        assert!(!routes.record_ping(peer, false, 3));
+        assert!(!routes.record_ping(peer, false, 3));
+        assert!(routes.record_ping(peer, false, 3));
+        assert!(routes.targets().is_empty());
+        assert!(!routes.record_ping(peer, false, 3));
+    }
+
+    #[test]
+    fn test_attachment_hooks_fire_on_transitions_only() {
+        let mut status = AttachmentStatus {
+            state: AttachmentState::AttachedGood,
+            public_internet_ready: true,
+        };
+        let mut app_logic = RecordingLogic::default();
+
+        for (state, ready) in [
+            (AttachmentState::AttachedGood, true),
+            (AttachmentState::AttachedWeak, true),
+            (AttachmentState::Detached, false),
+            (AttachmentState::Detached, false),
+            (AttachmentState::Attaching, false),
+        ] {
+            status.update::<Counter, _>(state, ready, &mut app_logic);
+        }
+
+        assert_eq!(
+            *app_logic.attachment_states.lock().unwrap(),
+            vec![
+                AttachmentState::AttachedWeak,
+                AttachmentState::Detached,
+                AttachmentState::Attaching
+            ]
+        );
+        assert_eq!(*app_logic.internet_ready.lock().unwrap(), vec![false]);
+    }
+
+    #[test]
+    fn test_reconnect_backs_off_until_attached() {
+        let policy = ReconnectPolicy {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(3),
+            ..Default::default()
+        };
+        let mut reconnect = None;
+
+        track_attachment(&mut reconnect, AttachmentState::Attaching, 0, &policy);
+        assert!(reconnect.is_none());
+        track_attachment(&mut reconnect, AttachmentState::Detached, 0, &policy);
+        let mut pending = reconnect.unwrap();
+        assert!(pending.is_due(0));
+
+        let mut waits = Vec::new();
+        let mut now = 0;
+        for _ in 0..4 {
+            pending.back_off(now, &policy);
+            waits.push(pending.next_attempt - now);
+            now = pending.next_attempt;
+        }
+        assert_eq!(waits, vec![1_000_000, 2_000_000, 3_000_000, 3_000_000]);
+
+        reconnect = Some(pending);
+        track_attachment(&mut reconnect, AttachmentState::AttachedWeak, now, &policy);
+        let pending = reconnect.unwrap();
+        assert!(pending.attached);
+        assert!(pending.is_due(now));
+        assert_eq!(pending.backoff, policy.initial_backoff);
+    }
+
+    #[test]
+    fn test_reconnect_circuit_opens_and_resets() {
+        const SECOND: u64 = 1_000_000;
+        let policy = ReconnectPolicy {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(1),
+            failure_threshold: 2,
+            open_for: Duration::from_secs(10),
+            max_open_for: Duration::from_secs(15),
+        };
+        let mut reconnect = None;
+        track_attachment(&mut reconnect, AttachmentState::Detached, 0, &policy);
+        let mut pending = reconnect.unwrap();
+
+        pending.back_off(0, &policy);
+        assert_eq!(pending.circuit, CircuitState::Closed);
+        pending.back_off(SECOND, &policy);
+        assert_eq!(pending.circuit, CircuitState::Open);
+        assert!(!pending.is_due(10 * SECOND));
+        assert!(pending.is_due(11 * SECOND));
+
+        // A failed trial waits longer, up to the cap
+        pending.start_attempt();
+        assert_eq!(pending.circuit, CircuitState::HalfOpen);
+        pending.back_off(11 * SECOND, &policy);
+        assert_eq!(pending.circuit, CircuitState::Open);
+        assert_eq!(pending.next_attempt, 26 * SECOND);
+
+        pending.start_attempt();
+        reconnect = Some(pending);
+        track_attachment(
+            &mut reconnect,
+            AttachmentState::AttachedGood,
+            26 * SECOND,
+            &policy,
+        );
+        let pending = reconnect.unwrap();
+        assert_eq!(pending.circuit, CircuitState::Closed);
+        assert_eq!(pending.failures, 0);
+    }
+
+    #[tokio::test]
+    async fn test_pin_refresh_runs_on_schedule() -> Result<(), VeilidDuplexError> {
+        let network = LoopbackNetwork::new();
+        let mut app = VeilidDuplex::in_memory(&network).await?;
+        // Well behind the wall clock, which the schedule mustn't mix in
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        app.set_clock(clock.clone());
+        app.set_pin_refresh_interval(Some(Duration::from_secs(60)));
+
+        // Overwritten behind our back, so a refresh shows as the blob coming back
+        let (our_dht_key, dht_keypair) = (app.our_dht_key, app.dht_keypair);
+        let blob = app.published_blob();
+        let pinned = || async {
+            let values = network
+                .get_dht_values(our_dht_key, ROUTE_SUBKEY, false)
+                .await
+                .unwrap();
+            values[0].1.clone()
+        };
+        let stale = b"stale".to_vec();
+        network
+            .set_dht_value(our_dht_key, ROUTE_SUBKEY, stale.clone(), dht_keypair)
+            .await?;
+
+        clock.advance(Duration::from_secs(59));
+        app.network_loop_cycle::<Counter, _>(CountingLogic::default())
+            .await?;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(pinned().await, stale);
+
+        clock.advance(Duration::from_secs(1));
+        app.network_loop_cycle::<Counter, _>(CountingLogic::default())
+            .await?;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while pinned().await != blob {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("route pin wasn't refreshed");
+
+        // Claimed for this interval, so the next cycle leaves it alone
+        network
+            .set_dht_value(our_dht_key, ROUTE_SUBKEY, stale.clone(), dht_keypair)
+            .await?;
+        app.network_loop_cycle::<Counter, _>(CountingLogic::default())
+            .await?;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(pinned().await, stale);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_watches_renew_before_expiry() {
+        let peer = CryptoTyped::new(CRYPTO_KIND, CryptoKey::new([1; 32]));
+        let other = CryptoTyped::new(CRYPTO_KIND, CryptoKey::new([3; 32]));
+        let mut routes = VeilidDuplexRoutes::new(ROUTE_SUBKEY);
+        routes.set_watch(peer, 120_000_000);
+        routes.set_watch(other, 0);
+
+        let margin = Duration::from_secs(60);
+        assert_eq!(routes.watches_to_renew(0, margin), vec![other]);
+        let mut due = routes.watches_to_renew(61_000_000, margin);
+        due.sort_by_key(|dht_record| dht_record.value);
+        assert_eq!(due, vec![peer, other]);
+
+        assert!(routes.remove_watch(other));
+        assert!(!routes.is_watched(other));
+        assert!(routes.watches_to_renew(0, margin).is_empty());
+    }
+
+    #[test]
+    fn test_builder_fills_config() {
+        let builder = VeilidDuplex::builder()
+            .bootstrap(vec!["bootstrap.example.org".to_string()])
+            .network_key("secret")
+            .storage_dir("/var/lib/duplex")
+            .sequencing(Sequencing::EnsureOrdered)
+            .stability(Stability::LowLatency)
+            .send_attempts(5)
+            .pin_refresh_interval(Duration::from_secs(300))
+            .max_concurrent_handlers(8)
+            .startup_timeout(Duration::from_secs(30))
+            .addressing(Addressing::NodeId)
+            .restricted_nat_retries(1)
+            .dht_remote_max_records(1024);
+
+        assert_eq!(builder.config.bootstrap, vec!["bootstrap.example.org"]);
+        assert_eq!(
+            builder.config.network_key_password.as_deref(),
+            Some("secret")
+        );
+        assert_eq!(
+            builder.config.storage_dir,
+            Some(PathBuf::from("/var/lib/duplex"))
+        );
+        assert_eq!(builder.config.sequencing, Sequencing::EnsureOrdered);
+        assert_eq!(builder.config.stability, Stability::LowLatency);
+        assert_eq!(builder.config.crypto_kind, CRYPTO_KIND);
+        assert_eq!(builder.send_attempts, 5);
+        assert_eq!(builder.pin_refresh_interval, Some(Duration::from_secs(300)));
+        assert_eq!(builder.max_concurrent_handlers, 8);
+        assert_eq!(builder.config.addressing, Addressing::NodeId);
+        let waits = builder.config.startup_waits;
+        assert_eq!(waits.attached.timeout, Some(Duration::from_secs(30)));
+        assert_eq!(waits.attached.poll_interval, Duration::from_millis(1000));
+        let options = &builder.config.veilid_options;
+        assert_eq!(options.restricted_nat_retries, 1);
+        assert_eq!(options.dht_remote_max_records, 1024);
+    }
+
+    #[test]
+    fn test_builder_turns_off_upnp_and_address_detection() {
+        let defaults = VeilidDuplex::builder().config.veilid_json(None, None);
+        assert_eq!(defaults["network"]["detect_address_changes"], true);
+        #[cfg(not(target_arch = "wasm32"))]
+        assert_eq!(defaults["network"]["upnp"], true);
+
+        let builder = VeilidDuplex::builder()
+            .upnp(false)
+            .detect_address_changes(false);
+        let json = builder.config.veilid_json(None, None);
+        assert_eq!(json["network"]["upnp"], false);
+        assert_eq!(json["network"]["detect_address_changes"], false);
+        let upnp = builder.config.veilid_value(None, None, "network.upnp");
+        assert!(matches!(upnp, Some(ConfigValue::Bool(false))));
+    }
+
+    #[test]
+    fn test_oversized_message_is_rejected() {
+        let app_message = AppMessage {
+            data: Counter { count: 1 },
+            uuid: String::new(),
+            dht_record: CryptoTyped::new(CRYPTO_KIND, CryptoKey::new([1; 32])),
+            reply_to: None,
+            timestamp: 0,
+            topic: None,
+        };
+        let raw_message = serde_json::to_vec(&app_message).unwrap();
+        assert!(unpack_message::<Counter>(raw_message.clone(), raw_message.len()).is_ok());
+
+        let result = unpack_message::<Counter>(raw_message.clone(), raw_message.len() - 1);
+        assert_eq!(result.err(), Some(AckStatus::TooLarge));
+
+        // Compressed input is held to the limit once inflated
+        let options = SendOptions {
+            compression: CompressionConfig {
+                enabled: true,
+                threshold: 0,
+            },
+            ..Default::default()
+        };
+        let padded = AppMessage {
+            data: "a".repeat(64 * 1024),
+            uuid: String::new(),
+            dht_record: app_message.dht_record,
+            reply_to: None,
+            timestamp: 0,
+            topic: None,
+        };
+        let compressed = padded.encode(&options).unwrap();
+        assert!(compressed.len() < 32 * 1024);
+        let result = unpack_message::<String>(compressed, 32 * 1024);
+        assert_eq!(result.err(), Some(AckStatus::TooLarge));
+
+        let result = unpack_message::<Counter>(b"{\"data\":".to_vec(), MAX_INBOUND_SIZE);
+        assert_eq!(result.err(), Some(AckStatus::DeserializeFailed));
+    }
+
+    #[test]
+    fn test_dedup_keys_on_uuid() {
+        let app_message = AppMessage {
+            data: Counter { count: 1 },
+            uuid: "c0ffee".to_string(),
+            dht_record: CryptoTyped::new(CRYPTO_KIND, CryptoKey::new([1; 32])),
+            reply_to: None,
+            timestamp: 0,
+            topic: None,
+        };
+        let json = MessageCodec::Json.encode(&app_message).unwrap();
+        let bincode = MessageCodec::Bincode.encode(&app_message).unwrap();
+        assert_ne!(json, bincode);
+
+        let (json, first) = unpack_message::<Counter>(json, MAX_INBOUND_SIZE).unwrap();
+        let (bincode, resent) = unpack_message::<Counter>(bincode, MAX_INBOUND_SIZE).unwrap();
+        let mut cache = DedupCache::default();
+        assert!(cache.insert(dedup_key(&first, &json), 0));
+        assert!(!cache.insert(dedup_key(&resent, &bincode), 0));
+
+        let other = AppMessage {
+            uuid: "decaf".to_string(),
+            ..app_message.clone()
+        };
+        let other_blob = MessageCodec::Json.encode(&other).unwrap();
+        assert!(cache.insert(dedup_key(&other, &other_blob), 0));
+
+        // Same uuid from someone else
+        let other_sender = AppMessage {
+            dht_record: CryptoTyped::new(CRYPTO_KIND, CryptoKey::new([2; 32])),
+            ..app_message.clone()
+        };
+        let other_sender_blob = MessageCodec::Json.encode(&other_sender).unwrap();
+        assert!(cache.insert(dedup_key(&other_sender, &other_sender_blob), 0));
+    }
+
+    #[tokio::test]
+    async fn test_stream_logic_feeds_stream() {
+        let (sender, receiver) = bounded(1);
+        let mut app_logic = StreamLogic { sender };
+        let mut stream = receiver.into_stream();
+
+        let app_message = AppMessage {
+            data: Counter { count: 7 },
+            uuid: "uuid".to_string(),
+            dht_record: CryptoTyped::new(CRYPTO_KIND, CryptoKey::new([1; 32])),
+            reply_to: None,
+            timestamp: 0,
+            topic: None,
+        };
+        app_logic.on_message(app_message).await.unwrap();
+        assert_eq!(stream.next().await.unwrap().data.count, 7);
+
+        drop(stream);
+        assert!(app_logic.sender.is_disconnected());
+        let app_message = AppMessage {
+            data: Counter { count: 8 },
+            uuid: "uuid".to_string(),
+            dht_record: CryptoTyped::new(CRYPTO_KIND, CryptoKey::new([1; 32])),
+            reply_to: None,
+            timestamp: 0,
+            topic: None,
+        };
+        assert!(app_logic.on_message(app_message).await.is_err());
+    }
+
+    #[test]
+    fn test_duplex_state_defaults_missing_fields() {
+        let state: DuplexState = serde_json::from_str(r#"{"version":1}"#).unwrap();
+        assert_eq!(state.version, 1);
+        assert!(state.received_message_hashes.is_empty());
+        assert!(state.peers.is_empty());
+        assert!(state.paused_messages.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_fresh_route_pin_resolves() -> Result<(), VeilidDuplexError> {
+        let app = VeilidDuplex::new().await?;
+        let peer = VeilidDuplex::new().await?;
+
+        // A new record, read back before any update_service_route_pin
+        let (dht_key, _) = create_service_route_pin(
+            app.routing_context.clone(),
+            app.export_our_route_blob(),
+            ROUTE_SUBKEY,
+            ROUTE_SUBKEY as u16 + 1,
+            CRYPTO_KIND,
+        )
+        .await?;
+        let (_, route) = get_service_route_from_dht(
+            peer.api.clone(),
+            peer.routing_context.clone(),
+            dht_key,
+            ROUTE_SUBKEY,
+            true,
+            ROUTE_LOOKUP_RETRY,
+        )
+        .await?;
+        assert_eq!(route, app.our_route);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_garbage_route_pin_is_an_error() -> Result<(), VeilidDuplexError> {
+        let app = VeilidDuplex::new().await?;
+        let peer = VeilidDuplex::new().await?;
+        let retry = RetryPolicy {
+            attempts: 1,
+            ..Default::default()
+        };
+
+        // Not base64
+        let (dht_key, _) = create_service_route_pin(
+            app.routing_context.clone(),
+            b"not a route!".to_vec(),
+            ROUTE_SUBKEY,
+            ROUTE_SUBKEY as u16 + 1,
+            CRYPTO_KIND,
+        )
+        .await?;
+        let result = get_service_route_from_dht(
+            peer.api.clone(),
+            peer.routing_context.clone(),
+            dht_key,
+            ROUTE_SUBKEY,
+            true,
+            retry,
+        )
+        .await;
+        assert!(matches!(result, Err(VeilidDuplexError::MalformedRoute(_))));
+
+        // Base64 of something that isn't a route
+        let (dht_key, _) = create_service_route_pin(
+            app.routing_context.clone(),
+            b"Z2FyYmFnZQ".to_vec(),
+            ROUTE_SUBKEY,
+            ROUTE_SUBKEY as u16 + 1,
+            CRYPTO_KIND,
+        )
+        .await?;
+        let result = get_service_route_from_dht(
+            peer.api.clone(),
+            peer.routing_context.clone(),
+            dht_key,
+            ROUTE_SUBKEY,
+            true,
+            retry,
+        )
+        .await;
+        assert!(matches!(result, Err(VeilidDuplexError::RouteImport(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_route_resolves_then_caches() -> Result<(), VeilidDuplexError> {
+        let app = VeilidDuplex::new().await?;
+        let peer = VeilidDuplex::new().await?;
+        let mut routes = VeilidDuplexRoutes::new(ROUTE_SUBKEY);
+
+        assert!(routes
+            .cached_target(peer.our_dht_key, get_timestamp())
+            .is_none());
+        let resolved = routes
+            .get_route(
+                peer.our_dht_key,
+                &*app.transport,
+                app.routing_context.clone(),
+                get_timestamp(),
+            )
+            .await?;
+        assert_eq!(
+            resolved,
+            Target::PrivateRoute(routes.routes[&peer.our_dht_key.value].route)
+        );
+
+        let cached = routes
+            .get_route(
+                peer.our_dht_key,
+                &*app.transport,
+                app.routing_context.clone(),
+                get_timestamp(),
+            )
+            .await?;
+        assert_eq!(cached, resolved);
+        assert_eq!(routes.peers(get_timestamp()).len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_open_record_is_reused() -> Result<(), VeilidDuplexError> {
+        let app = VeilidDuplex::new().await?;
+        let peer = VeilidDuplex::new().await?;
+        let mut routes = VeilidDuplexRoutes::new(ROUTE_SUBKEY);
+        routes
+            .set_keep_records_open(true, &app.routing_context)
+            .await;
+
+        for _ in 0..2 {
+            routes
+                .get_route(
+                    peer.our_dht_key,
+                    &*app.transport,
+                    app.routing_context.clone(),
+                    get_timestamp(),
+                )
+                .await?;
+            assert!(routes.invalidate(peer.our_dht_key));
+        }
+        assert_eq!(routes.open_records.as_ref().unwrap().opened(), 1);
+
+        routes.close_records(&app.routing_context).await;
+        app.shutdown().await;
+        peer.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_routes_unlocked_during_send() -> Result<(), VeilidDuplexError> {
+        let mut sender = VeilidDuplex::new().await?;
+        let receiver = VeilidDuplex::new().await?;
+        let target = receiver.our_dht_key;
+        sender.set_send_retry_policy(1, Duration::from_millis(0));
+        sender.warm_route(target).await?;
+
+        // Nobody runs the receiver's network loop, so the send waits until
+        // app_call times out
+        let in_flight = {
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                let app_message = AppMessage {
+                    data: Counter { count: 0 },
+                    uuid: String::new(),
+                    dht_record: sender.our_dht_key,
+                    reply_to: None,
+                    timestamp: 0,
+                    topic: None,
+                };
+                sender.send_message_with_reply(app_message, target).await
+            })
+        };
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let routes = tokio::time::timeout(Duration::from_millis(100), sender.routes.lock()).await;
+        assert!(routes.is_ok(), "route cache is locked during a send");
+        drop(routes);
+
+        let _ = in_flight.await;
+        drop(receiver);
+
+        Ok(())
+    }
+
+    #[derive(Clone, Default)]
+    struct CountingLogic {
+        received: Arc<AtomicU64>,
+        uuids: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    impl AppLogic<Counter> for CountingLogic {
+        async fn on_message(&mut self, message: AppMessage<Counter>) -> Result<(), HandlerError> {
+            self.received.fetch_add(1, Ordering::SeqCst);
+            self.uuids.lock().unwrap().push(message.uuid);
+            Ok(())
+        }
+    }
+
+    #[derive(Clone)]
+    struct EchoLogic {
+        sender: DuplexSender,
+    }
+
+    impl AppLogic<Counter> for EchoLogic {
+        async fn on_message(&mut self, message: AppMessage<Counter>) -> Result<(), HandlerError> {
+            let data = Counter {
+                count: message.data.count + 1,
+            };
+            message
+                .reply(&self.sender, data)
+                .await
+                .map_err(|e| HandlerError::new(e.to_string()))?;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reply_answers_request() -> Result<(), VeilidDuplexError> {
+        let app = VeilidDuplex::new().await?;
+        let (peer_sender, mut peer_receiver) = VeilidDuplex::new().await?.split();
+        let mut app_loop = app.clone();
+
+        let app_message = AppMessage {
+            data: Counter { count: 41 },
+            uuid: String::new(),
+            dht_record: app.our_dht_key,
+            reply_to: None,
+            timestamp: 0,
+            topic: None,
+        };
+        let echo = EchoLogic {
+            sender: peer_sender.clone(),
+        };
+        let answer: Counter = tokio::select! {
+            result = app.send_request(app_message, peer_sender.our_dht_key, Duration::from_secs(60)) => result?,
+            result = app_loop.network_loop::<Counter, _>(CountingLogic::default()) => {
+                panic!("network loop stopped: {:?}", result);
+            }
+            result = peer_receiver.network_loop::<Counter, _>(echo) => {
+                panic!("peer network loop stopped: {:?}", result);
+            }
+        };
+        assert_eq!(answer.count, 42);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_message_returns_uuid() -> Result<(), VeilidDuplexError> {
+        let app = VeilidDuplex::new().await?;
+        let mut peer = VeilidDuplex::new().await?;
+        let app_message = AppMessage {
+            data: Counter { count: 1 },
+            uuid: String::new(),
+            dht_record: app.our_dht_key,
+            reply_to: None,
+            timestamp: 0,
+            topic: None,
+        };
+
+        let app_logic = CountingLogic::default();
+        let uuid = tokio::select! {
+            result = app.send_message(app_message, peer.our_dht_key) => result?,
+            result = peer.network_loop::<Counter, _>(app_logic.clone()) => {
+                panic!("network loop stopped: {:?}", result);
+            }
+        };
+        assert!(!uuid.is_empty());
+        assert_eq!(*app_logic.uuids.lock().unwrap(), vec![uuid]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_survives_a_failing_peer() -> Result<(), VeilidDuplexError> {
+        let mut app = VeilidDuplex::new().await?;
+        app.set_send_retry_policy(1, Duration::from_millis(100));
+        app.set_route_lookup_retry(RetryPolicy {
+            attempts: 1,
+            ..Default::default()
+        })
+        .await;
+
+        let first = VeilidDuplex::new().await?;
+        let second = VeilidDuplex::new().await?;
+        // Never published, so its route lookup fails
+        let missing = CryptoTyped::new(CRYPTO_KIND, CryptoKey::new([9; 32]));
+        let recipients = [first.our_dht_key, missing, second.our_dht_key];
+
+        let first_logic = CountingLogic::default();
+        let second_logic = CountingLogic::default();
+        let first_loop = first.spawn_network_loop::<Counter, _>(first_logic.clone());
+        let second_loop = second.spawn_network_loop::<Counter, _>(second_logic.clone());
+
+        let app_message = AppMessage {
+            data: Counter { count: 1 },
+            uuid: String::new(),
+            dht_record: app.our_dht_key,
+            reply_to: None,
+            timestamp: 0,
+            topic: None,
+        };
+        let results = app.broadcast(app_message, &recipients).await?;
+
+        assert_eq!(results.len(), 3);
+        assert!(results[0].is_ok());
+        assert!(results[1].is_err());
+        assert!(results[2].is_ok());
+        assert_eq!(first_logic.received.load(Ordering::SeqCst), 1);
+        assert_eq!(second_logic.received.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            first_logic.uuids.lock().unwrap()[0],
+            *results[0].as_ref().unwrap()
+        );
+
+        first_loop.abort();
+        second_loop.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cloned_senders_send_concurrently() -> Result<(), VeilidDuplexError> {
+        let (sender, _) = VeilidDuplex::new().await?.split();
+        let (peer, mut receiver) = VeilidDuplex::new().await?.split();
+        let target = peer.our_dht_key;
+
+        let sends = (0..3).map(|count| {
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                let app_message = AppMessage {
+                    data: Counter { count },
+                    uuid: String::new(),
+                    dht_record: sender.our_dht_key,
+                    reply_to: None,
+                    timestamp: 0,
+                    topic: None,
+                };
+                sender.send_message(app_message, target).await
+            })
+        });
+
+        let app_logic = CountingLogic::default();
+        tokio::select! {
+            results = futures_util::future::join_all(sends) => {
+                for result in results {
+                    result.unwrap()?;
+                }
+            }
+            result = receiver.network_loop::<Counter, _>(app_logic.clone()) => {
+                panic!("network loop stopped: {:?}", result);
+            }
+        }
+        assert_eq!(app_logic.received.load(Ordering::SeqCst), 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_spawned_network_loop_aborts() -> Result<(), VeilidDuplexError> {
+        let app = VeilidDuplex::new().await?;
+        let running = app.loop_running.clone();
+        let other = app.clone();
+
+        let handle = app.spawn_network_loop::<Counter, _>(CountingLogic::default());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(running.load(Ordering::SeqCst));
+
+        let second = other.spawn_network_loop::<Counter, _>(CountingLogic::default());
+        assert!(matches!(
+            second.join().await,
+            Err(VeilidDuplexError::LoopAlreadyRunning)
+        ));
+        assert!(running.load(Ordering::SeqCst));
+
+        handle.abort();
+        tokio::time::timeout(Duration::from_secs(5), handle.join())
+            .await
+            .expect("loop didn't stop after abort")?;
+        assert!(!running.load(Ordering::SeqCst));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_pair_delivers() -> Result<(), VeilidDuplexError> {
+        let (app, mut peer) = VeilidDuplex::in_memory_pair().await?;
+        let app_message = AppMessage {
+            data: Counter { count: 1 },
+            uuid: String::new(),
+            dht_record: app.our_dht_key,
+            reply_to: None,
+            timestamp: 0,
+            topic: None,
+        };
+
+        let app_logic = CountingLogic::default();
+        let uuid = tokio::select! {
+            result = app.send_message(app_message, peer.our_dht_key) => result?,
+            result = peer.network_loop::<Counter, _>(app_logic.clone()) => {
+                panic!("network loop stopped: {:?}", result);
+            }
+        };
+        assert_eq!(*app_logic.uuids.lock().unwrap(), vec![uuid]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_same_uuid_from_two_senders_is_delivered_twice() -> Result<(), VeilidDuplexError> {
+        let network = LoopbackNetwork::new();
+        let mut receiver = VeilidDuplex::in_memory(&network).await?;
+        let mut senders = Vec::new();
+        for _ in 0..2 {
+            let mut sender = VeilidDuplex::in_memory(&network).await?;
+            // Both start counting at 1, so their first uuids collide
+            sender.set_uuid_source(SequentialUuids::new());
+            sender
+                .peer_keys
+                .lock()
+                .await
+                .remember_owner(receiver.our_dht_key, receiver.dht_keypair.key);
+            receiver
+                .peer_keys
+                .lock()
+                .await
+                .remember_owner(sender.our_dht_key, sender.dht_keypair.key);
+            senders.push(sender);
+        }
+
+        let app_logic = CountingLogic::default();
+        let remote = receiver.our_dht_key;
+        let uuids = tokio::select! {
+            result = async {
+                let mut uuids = Vec::new();
+                for sender in &senders {
+                    let app_message = AppMessage {
+                        data: Counter { count: 1 },
+                        uuid: String::new(),
+                        dht_record: sender.our_dht_key,
+                        reply_to: None,
+                        timestamp: 0,
+                        topic: None,
+                    };
+                    uuids.push(sender.send_message(app_message, remote).await?);
+                }
+                Ok::<_, VeilidDuplexError>(uuids)
+            } => result?,
+            result = receiver.network_loop::<Counter, _>(app_logic.clone()) => {
+                panic!("network loop stopped: {:?}", result);
+            }
+        };
+        assert_eq!(uuids[0], uuids[1]);
+        assert_eq!(app_logic.received.load(Ordering::SeqCst), 2);
+
+        Ok(())
+    }
+
+    #[derive(Clone, Default)]
+    struct SlowFirstLogic {
+        seen: Arc<std::sync::Mutex<Vec<u64>>>,
+    }
+
+    impl AppLogic<Counter> for SlowFirstLogic {
+        async fn on_message(&mut self, message: AppMessage<Counter>) -> Result<(), HandlerError> {
+            if message.data.count == 0 {
+                sleep(300).await;
+            }
+            self.seen.lock().unwrap().push(message.data.count);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_ordering_keeps_arrival_order() -> Result<(), VeilidDuplexError> {
+        let (app, mut peer) = VeilidDuplex::in_memory_pair().await?;
+        peer.set_message_ordering(Some(MessageOrdering::PerSender));
+        let target = peer.our_dht_key;
+        let app_logic = SlowFirstLogic::default();
+        let handle = peer.spawn_network_loop::<Counter, _>(app_logic.clone());
+
+        // Staggered so they arrive in order, while the first is still handled
+        let sends = (0..4).map(|count| {
+            let app = app.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(20 * count)).await;
+                let app_message = AppMessage {
+                    data: Counter { count },
+                    uuid: String::new(),
+                    dht_record: app.our_dht_key,
+                    reply_to: None,
+                    timestamp: 0,
+                    topic: None,
+                };
+                app.send_message(app_message, target).await
+            })
+        });
+        for result in futures_util::future::join_all(sends).await {
+            result.unwrap()?;
+        }
+        assert_eq!(*app_logic.seen.lock().unwrap(), vec![0, 1, 2, 3]);
+
+        handle.abort();
+        Ok(())
+    }
+
+    #[derive(Clone)]
+    struct BytesLogic {
+        received: Sender<Vec<u8>>,
+    }
+
+    impl AppLogic<Vec<u8>> for BytesLogic {
+        async fn on_message(&mut self, message: AppMessage<Vec<u8>>) -> Result<(), HandlerError> {
+            self.received
+                .send(message.data)
+                .map_err(|_| HandlerError::new("Test is over"))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_chunked_message_arrives_whole() -> Result<(), VeilidDuplexError> {
+        let (app, mut peer) = VeilidDuplex::in_memory_pair().await?;
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let app_message = AppMessage {
+            data: data.clone(),
+            uuid: String::new(),
+            dht_record: app.our_dht_key,
+            reply_to: None,
+            timestamp: 0,
+            topic: None,
+        };
+
+        let (received, receiver) = unbounded();
+        tokio::select! {
+            result = app.send_message(app_message, peer.our_dht_key) => { result?; }
+            result = peer.network_loop::<Vec<u8>, _>(BytesLogic { received }) => {
+                panic!("network loop stopped: {:?}", result);
+            }
+        };
+        assert_eq!(receiver.try_recv().unwrap(), data);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_request_gets_answer() -> Result<(), VeilidDuplexError> {
+        let (app, peer) = VeilidDuplex::in_memory_pair().await?;
+        let (peer_sender, mut peer_receiver) = peer.split();
+        let mut app_loop = app.clone();
+
+        let app_message = AppMessage {
+            data: Counter { count: 41 },
+            uuid: String::new(),
+            dht_record: app.our_dht_key,
+            reply_to: None,
+            timestamp: 0,
+            topic: None,
+        };
+        let echo = EchoLogic {
+            sender: peer_sender.clone(),
+        };
+        let answer: Counter = tokio::select! {
+            result = app.send_request(app_message, peer_sender.our_dht_key, Duration::from_secs(10)) => result?,
+            result = app_loop.network_loop::<Counter, _>(CountingLogic::default()) => {
+                panic!("network loop stopped: {:?}", result);
+            }
+            result = peer_receiver.network_loop::<Counter, _>(echo) => {
+                panic!("peer network loop stopped: {:?}", result);
+            }
+        };
+        assert_eq!(answer.count, 42);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_answer_from_another_peer_is_not_the_reply() -> Result<(), VeilidDuplexError> {
+        let network = LoopbackNetwork::new();
+        let mut app = VeilidDuplex::in_memory(&network).await?;
+        app.set_uuid_source(SequentialUuids::new());
+        let mut peer = VeilidDuplex::in_memory(&network).await?;
+        let intruder = VeilidDuplex::in_memory(&network).await?;
+        for other in [&peer, &intruder] {
+            app.peer_keys
+                .lock()
+                .await
+                .remember_owner(other.our_dht_key, other.dht_keypair.key);
+            other
+                .peer_keys
+                .lock()
+                .await
+                .remember_owner(app.our_dht_key, app.dht_keypair.key);
+        }
+        let mut app_loop = app.clone();
+
+        let request = AppMessage {
+            data: Counter { count: 41 },
+            uuid: String::new(),
+            dht_record: app.our_dht_key,
+            reply_to: None,
+            timestamp: 0,
+            topic: None,
+        };
+        // The first uuid `app` stamps, so the request id is known up front
+        let forged = AppMessage {
+            data: Counter { count: 666 },
+            uuid: String::new(),
+            dht_record: intruder.our_dht_key,
+            reply_to: Some(Uuid::from_u128(1).to_string()),
+            timestamp: 0,
+            topic: None,
+        };
+        let app_logic = CountingLogic::default();
+        let result = tokio::select! {
+            result = app.send_request::<Counter, Counter>(request, peer.our_dht_key, Duration::from_secs(2)) => result,
+            result = async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                intruder.send_message(forged, app.our_dht_key).await?;
+                std::future::pending::<Result<(), VeilidDuplexError>>().await
+            } => panic!("intruder stopped: {:?}", result),
+            result = app_loop.network_loop::<Counter, _>(app_logic.clone()) => {
+                panic!("network loop stopped: {:?}", result);
+            }
+            // Takes the request but never answers
+            result = peer.network_loop::<Counter, _>(CountingLogic::default()) => {
+                panic!("peer network loop stopped: {:?}", result);
+            }
+        };
+        assert!(matches!(
+            result,
+            Err(VeilidDuplexError::RequestTimeout { .. })
+        ));
+        // Handled as an ordinary message instead
+        assert_eq!(app_logic.received.load(Ordering::SeqCst), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_token_stops_loop() -> Result<(), VeilidDuplexError> {
+        let (_, mut peer) = VeilidDuplex::in_memory_pair().await?;
+        let shutdown = CancellationToken::new();
+        peer.set_shutdown_token(shutdown.clone());
+        let running = peer.loop_running.clone();
+
+        let network_loop = peer.spawn_network_loop::<Counter, _>(CountingLogic::default());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(running.load(Ordering::SeqCst));
+
+        shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(5), network_loop.join())
+            .await
+            .expect("loop didn't stop on shutdown")?;
+        assert!(!running.load(Ordering::SeqCst));
+
+        Ok(())
+    }
+
+    // Counts how often the node asks for the time, i.e. roughly how many
+    // cycles its loop runs
+    struct CountingClock(Arc<AtomicU64>);
+
+    impl Clock for CountingClock {
+        fn now(&self) -> u64 {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            get_timestamp()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_incoming_waits_when_idle() -> Result<(), VeilidDuplexError> {
+        let (mut app, _peer) = VeilidDuplex::in_memory_pair().await?;
+        let reads = Arc::new(AtomicU64::new(0));
+        app.set_clock(CountingClock(reads.clone()));
+        let running = app.loop_running.clone();
+
+        let stream = app.incoming::<Counter>()?;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        // 200ms of 5ms idle waits is 40 cycles; a spinning loop does thousands
+        let idle_reads = reads.load(Ordering::SeqCst);
+        assert!(idle_reads < 1_000, "{idle_reads} clock reads while idle");
+
+        drop(stream);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while running.load(Ordering::SeqCst) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("loop didn't stop with the stream");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_request_then_shutdown_returns_answer() -> Result<(), VeilidDuplexError> {
+        let (app, peer) = VeilidDuplex::in_memory_pair().await?;
+        let (peer_sender, peer_receiver) = peer.split();
+        let remote = peer_sender.our_dht_key;
+        let peer_loop = peer_receiver.spawn_network_loop::<Counter, _>(EchoLogic {
+            sender: peer_sender,
+        });
+        let running = app.loop_running.clone();
+
+        let answer: Counter = app
+            .request_then_shutdown(Counter { count: 41 }, remote, Duration::from_secs(10))
+            .await?;
+        assert_eq!(answer.count, 42);
+        assert!(!running.load(Ordering::SeqCst));
+
+        peer_loop.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_network_loop_returns() -> Result<(), VeilidDuplexError> {
+        let (_, mut peer) = VeilidDuplex::in_memory_pair().await?;
+        let running = peer.loop_running.clone();
+        let cancel = CancellationToken::new();
+
+        let canceller = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            canceller.cancel();
+        });
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            peer.network_loop_until::<Counter, _>(CountingLogic::default(), cancel),
+        )
+        .await
+        .expect("loop didn't stop after cancel")?;
+        assert!(!running.load(Ordering::SeqCst));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_hold_leaves_messages_unanswered_until_resume() -> Result<(), VeilidDuplexError> {
+        let (app, mut peer) = VeilidDuplex::in_memory_pair().await?;
+        peer.set_pause_mode(PauseMode::Hold);
+        peer.pause();
+        let held_calls = peer.held_calls.clone();
+        let controls = peer.clone();
+
+        let app_logic = CountingLogic::default();
+        let network_loop = peer.spawn_network_loop::<Counter, _>(app_logic.clone());
+        let app_message = AppMessage {
+            data: Counter { count: 1 },
+            uuid: String::new(),
+            dht_record: app.our_dht_key,
+            reply_to: None,
+            timestamp: 0,
+            topic: None,
+        };
+        let sender = app.sender();
+        let remote = controls.our_dht_key;
+        let sending = tokio::spawn(async move { sender.send_message(app_message, remote).await });
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(app_logic.received.load(Ordering::SeqCst), 0);
+        assert_eq!(held_calls.lock().await.len(), 1);
+        assert!(!sending.is_finished());
+
+        controls.resume();
+        let uuid = tokio::time::timeout(Duration::from_secs(5), sending)
+            .await
+            .expect("held message wasn't answered after resume")
+            .unwrap()?;
+        assert_eq!(*app_logic.uuids.lock().unwrap(), vec![uuid]);
+
+        network_loop.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_buffered_messages_run_in_order_on_resume() -> Result<(), VeilidDuplexError> {
+        let (app, mut peer) = VeilidDuplex::in_memory_pair().await?;
+        peer.set_pause_mode(PauseMode::Buffer);
+        peer.set_message_ordering(Some(MessageOrdering::PerSender));
+        peer.pause();
+        let controls = peer.clone();
+
+        let app_logic = SlowFirstLogic::default();
+        let network_loop = peer.spawn_network_loop::<Counter, _>(app_logic.clone());
+        for count in 0..3 {
+            let app_message = AppMessage {
+                data: Counter { count },
+                uuid: String::new(),
+                dht_record: app.our_dht_key,
+                reply_to: None,
+                timestamp: 0,
+                topic: None,
+            };
+            // ACKed while paused
+            app.send_message(app_message, controls.our_dht_key).await?;
+        }
+        assert!(app_logic.seen.lock().unwrap().is_empty());
+        assert_eq!(controls.paused_messages.lock().await.len(), 3);
+
+        controls.resume();
+        tokio::time::sleep(Duration::from_millis(800)).await;
+        // The slow first handler doesn't let the others overtake it
+        assert_eq!(*app_logic.seen.lock().unwrap(), vec![0, 1, 2]);
+        assert!(controls.paused_messages.lock().await.is_empty());
+
+        network_loop.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connect_checks_the_peers_identity() -> Result<(), VeilidDuplexError> {
+        let (app, peer) = VeilidDuplex::in_memory_pair().await?;
+        let remote = peer.our_dht_key;
+        let network_loop = peer.spawn_network_loop::<Counter, _>(CountingLogic::default());
+
+        app.connect(remote, Duration::from_secs(5)).await?;
+
+        // Expecting another key, as when the route was swapped for an impostor's
+        let cs = crypto_system(&app.api, CRYPTO_KIND)?;
+        app.peer_keys
+            .lock()
+            .await
+            .remember_owner(remote, cs.generate_keypair().key);
+        let result = app.connect(remote, Duration::from_secs(5)).await;
+        assert!(matches!(
+            result,
+            Err(VeilidDuplexError::IdentityMismatch(record)) if record == remote
+        ));
+        assert!(app.active_peers().await.is_empty());
+
+        network_loop.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_telemetry_reaches_collector_sink() -> Result<(), VeilidDuplexError> {
+        use crate::telemetry::telemetry_layer;
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let (mut app, mut collector) = VeilidDuplex::in_memory_pair().await?;
+        let (sink, batches) = unbounded();
+        collector.set_telemetry_sink(Some(sink));
+        let collector_key = collector.our_dht_key;
+        let collector_loop = collector.spawn_network_loop::<Counter, _>(CountingLogic::default());
+
+        let (layer, source) = telemetry_layer(tracing::Level::WARN, 16);
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::warn!("queue backing up");
+        });
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        app.set_clock(clock.clone());
+        app.set_telemetry(Some(TelemetryConfig::new(collector_key, source)));
+
+        // Nothing goes out before the interval
+        app.network_loop_cycle::<Counter, _>(CountingLogic::default())
+            .await?;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(batches.is_empty());
+
+        clock.advance(Duration::from_secs(5));
+        app.network_loop_cycle::<Counter, _>(CountingLogic::default())
+            .await?;
+        let batch = tokio::time::timeout(Duration::from_secs(5), batches.recv_async())
+            .await
+            .expect("no telemetry batch arrived")
+            .unwrap();
+        assert_eq!(batch.node, app.our_dht_key);
+        assert_eq!(batch.events.len(), 1);
+        assert_eq!(batch.events[0].message, "queue backing up");
+
+        collector_loop.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_is_answered_with_no_free_handler() -> Result<(), VeilidDuplexError> {
+        let (app, mut peer) = VeilidDuplex::in_memory_pair().await?;
+        peer.set_max_concurrent_handlers(1);
+        peer.set_handler_overflow(HandlerOverflow::Reject);
+        let _taken = peer.handler_permits.try_acquire().unwrap();
+        let remote = peer.our_dht_key;
+        let network_loop = peer.spawn_network_loop::<Counter, _>(CountingLogic::default());
+
+        let target = app
+            .routes
+            .lock()
+            .await
+            .get_route(
+                remote,
+                &*app.transport,
+                app.routing_context.clone(),
+                get_timestamp(),
+            )
+            .await?;
+        let reply = app
+            .transport
+            .app_call(target, KEEPALIVE_PING.to_vec())
+            .await?;
+        assert_eq!(AckStatus::from_reply(&reply), AckStatus::Accepted);
+
+        network_loop.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resends_dont_use_up_rate_limit() -> Result<(), VeilidDuplexError> {
+        let (mut app, peer) = VeilidDuplex::in_memory_pair().await?;
+        app.set_send_retry_policy(1, Duration::from_millis(10));
+        peer.set_inbound_rate_limit(Some(MessageRateLimit {
+            messages_per_sec: 1,
+            burst: 2,
+        }))
+        .await;
+        let remote = peer.our_dht_key;
+        let network_loop = peer.spawn_network_loop::<Counter, _>(CountingLogic::default());
+
+        let app_message = |count| AppMessage {
+            data: Counter { count },
+            uuid: String::new(),
+            dht_record: app.our_dht_key,
+            reply_to: None,
+            timestamp: 0,
+            topic: None,
+        };
+        let sender = app.sender();
+        let first = app_message(1);
+        let outcome = sender.deliver(&first, remote).await?;
+        assert_eq!(outcome.status, AckStatus::Accepted);
+        let outcome = sender.deliver(&first, remote).await?;
+        assert_eq!(outcome.status, AckStatus::Duplicate);
+        // The resend left the second message its share of the budget
+        let outcome = sender.deliver(&app_message(2), remote).await?;
+        assert_eq!(outcome.status, AckStatus::Accepted);
+        let outcome = sender.deliver(&app_message(3), remote).await?;
+        assert_eq!(outcome.status, AckStatus::Busy);
+
+        network_loop.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_full_pause_buffer_replies_busy() -> Result<(), VeilidDuplexError> {
+        let (mut app, mut peer) = VeilidDuplex::in_memory_pair().await?;
+        app.set_send_retry_policy(1, Duration::from_millis(10));
+        peer.set_pause_mode(PauseMode::Buffer);
+        peer.pause();
+        let controls = peer.clone();
+        controls
+            .paused_messages
+            .lock()
+            .await
+            .extend(std::iter::repeat(Vec::new()).take(MAX_PAUSED_MESSAGES));
+
+        let network_loop = peer.spawn_network_loop::<Counter, _>(CountingLogic::default());
+        let app_message = AppMessage {
+            data: Counter { count: 1 },
+            uuid: String::new(),
+            dht_record: app.our_dht_key,
+            reply_to: None,
+            timestamp: 0,
+            topic: None,
+        };
+        let sender = app.sender();
+        let outcome = sender.deliver(&app_message, controls.our_dht_key).await?;
+        assert_eq!(outcome.status, AckStatus::Busy);
+        assert_eq!(
+            controls.paused_messages.lock().await.len(),
+            MAX_PAUSED_MESSAGES
+        );
+
+        // Not remembered as received, so it's taken once there's room
+        controls.paused_messages.lock().await.pop_front();
+        let outcome = sender.deliver(&app_message, controls.our_dht_key).await?;
+        assert_eq!(outcome.status, AckStatus::Accepted);
+        assert_eq!(
+            controls.paused_messages.lock().await.len(),
+            MAX_PAUSED_MESSAGES
+        );
+
+        network_loop.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_retries_and_dedup_over_lossy_link() -> Result<(), VeilidDuplexError> {
+        let network = LoopbackNetwork::with_seed(3);
+        let mut app = VeilidDuplex::in_memory(&network).await?;
+        let peer = VeilidDuplex::in_memory(&network).await?;
+        app.set_send_retry_policy(50, Duration::from_millis(10));
+        network.set_conditions(
+            peer.our_route,
+            LinkConditions::default()
+                .with_latency(Duration::from_millis(2), Duration::from_millis(5))
+                .with_loss(0.2, 0.4),
+        );
+
+        let app_logic = CountingLogic::default();
+        let remote = peer.our_dht_key;
+        let stats = peer.clone();
+        let peer_loop = peer.spawn_network_loop::<Counter, _>(app_logic.clone());
+        let mut uuids = Vec::new();
+        for count in 0..20 {
+            let app_message = AppMessage {
+                data: Counter { count },
+                uuid: String::new(),
+                dht_record: app.our_dht_key,
+                reply_to: None,
+                timestamp: 0,
+                topic: None,
+            };
+            uuids.push(app.send_message(app_message, remote).await?);
+        }
+
+        // Every message handled once, though lost replies made some arrive twice
+        assert_eq!(*app_logic.uuids.lock().unwrap(), uuids);
+        assert!(app.stats().send_retries > 0);
+        assert!(stats.stats().duplicates_dropped > 0);
+
+        peer_loop.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_exhausted_send_lands_in_dead_letters() -> Result<(), VeilidDuplexError> {
+        let network = LoopbackNetwork::new();
+        let mut app = VeilidDuplex::in_memory(&network).await?;
+        let peer = VeilidDuplex::in_memory(&network).await?;
+        let (dead_letters, dead_letter_receiver) = unbounded();
+        app.set_dead_letters(Some(dead_letters));
+        app.set_send_retry_policy(2, Duration::from_millis(10));
+
+        // The route is cached, then the peer goes away
+        app.warm_route(peer.our_dht_key).await?;
+        network.remove_endpoint(peer.our_route);
+
+        let app_message = AppMessage {
+            data: Counter { count: 7 },
+            uuid: String::new(),
+            dht_record: app.our_dht_key,
+            reply_to: None,
+            timestamp: 0,
+            topic: None,
+        };
+        let result = app.send_message(app_message, peer.our_dht_key).await;
+        assert!(matches!(
+            result,
+            Err(VeilidDuplexError::SendExhausted { attempts: 2 })
+        ));
+
+        let dead_letter = dead_letter_receiver.try_recv().unwrap();
+        assert_eq!(dead_letter.destination, peer.our_dht_key);
+        assert_eq!(
+            dead_letter.error,
+            VeilidDuplexError::SendExhausted { attempts: 2 }.to_string()
+        );
+        let message = dead_letter.decode::<Counter>()?;
+        assert_eq!(message.data.count, 7);
+        assert_eq!(message.uuid, dead_letter.uuid);
+        assert!(dead_letter_receiver.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_outbox_delivers_after_restart() -> Result<(), VeilidDuplexError> {
+        let dir = tempfile::tempdir()?;
+        let open_outbox = || -> Result<Outbox, VeilidDuplexError> {
+            Ok(Outbox::open(FileOutboxStore::new(dir.path())?)?
+                .with_backoff(Duration::from_millis(10), Duration::from_millis(50)))
+        };
+        let network = LoopbackNetwork::new();
+        let peer = VeilidDuplex::in_memory(&network).await?;
+        let mut app = VeilidDuplex::in_memory(&network).await?;
+        app.set_outbox(Some(open_outbox()?));
+
+        // The peer is offline: the message is queued and a drain keeps it
+        network.set_online(peer.our_route, false);
+        let app_message = AppMessage {
+            data: Counter { count: 3 },
+            uuid: String::new(),
+            dht_record: app.our_dht_key,
+            reply_to: None,
+            timestamp: 0,
+            topic: None,
+        };
+        let uuid = app.send_message(app_message, peer.our_dht_key).await?;
+        let outbox = app.outbox.clone().unwrap();
+        assert_eq!(outbox.drain(&app.sender()).await, 0);
+        assert_eq!(outbox.entries()[0].attempts, 1);
+        drop(app);
+
+        // Restart with the queue left on disk, then the peer comes back
+        let mut app = VeilidDuplex::in_memory(&network).await?;
+        app.set_outbox(Some(open_outbox()?));
+        assert_eq!(app.outbox.as_ref().unwrap().len(), 1);
+        network.set_online(peer.our_route, true);
+
+        let app_logic = CountingLogic::default();
+        let peer_loop = peer.spawn_network_loop::<Counter, _>(app_logic.clone());
+        let app_loop = app
+            .clone()
+            .spawn_network_loop::<Counter, _>(CountingLogic::default());
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while app_logic.received.load(Ordering::SeqCst) == 0 {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("queued message wasn't delivered");
+        assert_eq!(*app_logic.uuids.lock().unwrap(), vec![uuid]);
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(app.outbox.as_ref().unwrap().is_empty());
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 0);
+
+        peer_loop.abort();
+        app_loop.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_measures_round_trip() -> Result<(), VeilidDuplexError> {
+        let (app, peer) = VeilidDuplex::in_memory_pair().await?;
+        let remote = peer.our_dht_key;
+        let app_logic = CountingLogic::default();
+        let peer_loop = peer.spawn_network_loop::<Counter, _>(app_logic.clone());
+
+        let rtt = app.ping(remote, Duration::from_secs(5)).await?;
+        assert!(rtt < Duration::from_secs(5));
+        assert_eq!(app_logic.received.load(Ordering::SeqCst), 0);
+
+        // Without the peer's loop nothing answers
+        peer_loop.abort();
+        peer_loop.join().await?;
+        assert!(matches!(
+            app.ping(remote, Duration::from_millis(200)).await,
+            Err(VeilidDuplexError::RequestTimeout { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_probing_picks_faster_route() -> Result<(), VeilidDuplexError> {
+        let network = LoopbackNetwork::new();
+        let app = VeilidDuplex::in_memory(&network).await?;
+        let peer = VeilidDuplex::in_memory(&network).await?;
+        let remote = peer.our_dht_key;
+        let slow_route = peer.our_route;
+        let peer_loop = peer
+            .clone()
+            .spawn_network_loop::<Counter, _>(CountingLogic::default());
+
+        // A backup route in the next subkey, echoed by a stand-in for the peer
+        let (updates, calls) = unbounded();
+        let fast_route = CryptoKey::new(rand::random());
+        let blob = network.add_endpoint(fast_route, updates);
+        network
+            .set_dht_value(remote, ROUTE_SUBKEY + 1, blob, peer.dht_keypair)
+            .await?;
+        let echo = {
+            let network = network.clone();
+            tokio::spawn(async move {
+                while let Ok(VeilidUpdate::AppCall(call)) = calls.recv_async().await {
+                    let _ = network
+                        .app_call_reply(call.id(), call.message().to_vec())
+                        .await;
+                }
+            })
+        };
+
+        network.set_delay(slow_route, Duration::from_millis(200));
+        let probing = RouteProbing {
+            interval: Duration::ZERO,
+            ..Default::default()
+        };
+        app.set_route_probing(Some(probing)).await;
+        let target = app
+            .routes
+            .lock()
+            .await
+            .get_route(
+                remote,
+                &*app.transport,
+                app.routing_context.clone(),
+                get_timestamp(),
+            )
+            .await?;
+        assert_eq!(target, Target::PrivateRoute(fast_route));
+
+        // The next keepalive round probes again and follows the change
+        network.set_delay(slow_route, Duration::ZERO);
+        network.set_delay(fast_route, Duration::from_millis(200));
+        app.ping_routes(KeepaliveConfig::default()).await;
+        let target = app
+            .routes
+            .lock()
+            .await
+            .cached_target(remote, get_timestamp());
+        assert_eq!(target, Some(Target::PrivateRoute(slow_route)));
+
+        echo.abort();
+        peer_loop.abort();
+        Ok(())
+    }
+
+    async fn next_change(changes: &Receiver<PresenceChange>) -> PresenceChange {
+        tokio::time::timeout(Duration::from_secs(5), changes.recv_async())
+            .await
+            .expect("presence didn't change")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_presence_follows_reachability() -> Result<(), VeilidDuplexError> {
+        let network = LoopbackNetwork::new();
+        let mut app = VeilidDuplex::in_memory(&network).await?;
+        let peer = VeilidDuplex::in_memory(&network).await?;
+        let remote = peer.our_dht_key;
+        app.set_presence_config(PresenceConfig {
+            interval: Duration::from_millis(50),
+            timeout: Duration::from_millis(500),
+            ..Default::default()
+        });
+        let changes = app.presence_changes();
+        assert!(app.track_presence(remote));
+        assert_eq!(app.peer_status(remote).status, PresenceStatus::Unknown);
+
+        let peer_loop = peer
+            .clone()
+            .spawn_network_loop::<Counter, _>(CountingLogic::default());
+        let app_loop = app
+            .clone()
+            .spawn_network_loop::<Counter, _>(CountingLogic::default());
+        let change = next_change(&changes).await;
+        assert_eq!(change.previous, PresenceStatus::Unknown);
+        assert_eq!(change.presence.status, PresenceStatus::Online);
+
+        network.set_online(peer.our_route, false);
+        let change = next_change(&changes).await;
+        assert_eq!(change.presence.status, PresenceStatus::Offline);
+        let presence = app.peer_status(remote);
+        assert!(presence.last_seen < presence.checked_at);
+
+        network.set_online(peer.our_route, true);
+        assert_eq!(
+            next_change(&changes).await.presence.status,
+            PresenceStatus::Online
+        );
+
+        peer_loop.abort();
+        app_loop.abort();
+        Ok(())
+    }
+
+    #[test]
+    fn test_latency_ping_shape() {
+        let mut probe = LATENCY_PING.to_vec();
+        assert!(!is_latency_ping(&probe));
+        probe.extend_from_slice(&[7; 16]);
+        assert!(is_latency_ping(&probe));
+        assert!(!is_latency_ping(KEEPALIVE_PING));
+    }
+
+    fn attachment_update(state: AttachmentState) -> VeilidUpdate {
+        VeilidUpdate::Attachment(Box::new(VeilidStateAttachment {
+            state,
+            public_internet_ready: is_attached(state),
+            local_network_ready: is_attached(state),
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_reattach_republishes_route() -> Result<(), VeilidDuplexError> {
+        let mut app = VeilidDuplex::new().await?;
+        let peer = VeilidDuplex::new().await?;
+        app.set_reconnect_policy(ReconnectPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+            ..Default::default()
+        });
+        let (inject, receiver) = unbounded();
+        app.receiver = receiver;
+        let old_route = app.our_route;
+        let app_logic = RecordingLogic::default();
+
+        inject
+            .send(attachment_update(AttachmentState::Detached))
+            .unwrap();
+        app.network_loop_cycle::<Counter, _>(app_logic.clone())
+            .await?;
+        assert!(app.reconnect.is_some());
+
+        inject
+            .send(attachment_update(AttachmentState::AttachedGood))
+            .unwrap();
+        for _ in 0..50 {
+            app.network_loop_cycle::<Counter, _>(app_logic.clone())
+                .await?;
+            if app.reconnect.is_none() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(app.reconnect.is_none());
+        assert_eq!(app.reconnect_state().await, CircuitState::Closed);
+        assert_ne!(app.our_route, old_route);
+        assert_eq!(*app_logic.local_routes.lock().unwrap(), vec![app.our_route]);
+
+        let (_, pinned_route) = get_service_route_from_dht(
+            peer.api.clone(),
+            peer.routing_context.clone(),
+            app.our_dht_key,
+            ROUTE_SUBKEY,
+            true,
+            ROUTE_LOOKUP_RETRY,
+        )
+        .await?;
+        assert_eq!(pinned_route, app.our_route);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_route_blob_round_trip() -> Result<(), VeilidDuplexError> {
+        let mut app = VeilidDuplex::new().await?;
+        let peer = VeilidDuplex::new().await?;
+
+        let target = peer.import_route_blob(app.export_our_route_blob())?;
+        assert_eq!(target, Target::PrivateRoute(app.our_route));
+        assert!(peer.import_route_blob(b"not a route".to_vec()).is_err());
+
+        let app_message = AppMessage {
+            data: Counter { count: 1 },
+            uuid: String::new(),
+            dht_record: peer.our_dht_key,
+            reply_to: None,
+            timestamp: 0,
+            topic: None,
+        };
+        let app_logic = CountingLogic::default();
+        tokio::select! {
+            result = peer.send_message_to_target(app_message, target) => result?,
+            result = app.network_loop::<Counter, _>(app_logic.clone()) => {
+                panic!("network loop stopped: {:?}", result);
+            }
+        }
+        assert_eq!(app_logic.received.load(Ordering::SeqCst), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_signed_send_to_target_passes_required_signatures() -> Result<(), VeilidDuplexError>
+    {
+        let mut app = VeilidDuplex::new().await?;
+        app.set_require_signatures(true);
+        let mut peer = VeilidDuplex::new().await?;
+        peer.set_signing(true);
+
+        let target = peer.import_route_blob(app.export_our_route_blob())?;
+        let app_message = AppMessage {
+            data: Counter { count: 1 },
+            uuid: String::new(),
+            dht_record: peer.our_dht_key,
+            reply_to: None,
+            timestamp: 0,
+            topic: None,
+        };
+        let app_logic = CountingLogic::default();
+        tokio::select! {
+            result = peer.send_message_to_target(app_message, target) => result?,
+            result = app.network_loop::<Counter, _>(app_logic.clone()) => {
+                panic!("network loop stopped: {:?}", result);
+            }
+        }
+        assert_eq!(app_logic.received.load(Ordering::SeqCst), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_value_change_refreshes_cached_route() -> Result<(), VeilidDuplexError> {
+        let mut app = VeilidDuplex::new().await?;
+        let mut peer = VeilidDuplex::new().await?;
+        app.warm_route(peer.our_dht_key).await?;
+        app.watch_route(peer.our_dht_key).await?;
+        let (inject, receiver) = unbounded();
+        app.receiver = receiver;
+
+        let old_route = peer.our_route;
+        peer.update_local_route().await?;
+        assert_ne!(peer.our_route, old_route);
+
+        let value = ValueData::new(peer.export_our_route_blob(), peer.dht_keypair.key)?;
+        inject
+            .send(VeilidUpdate::ValueChange(Box::new(VeilidValueChange {
+                key: peer.our_dht_key,
+                subkeys: ValueSubkeyRangeSet::single(ROUTE_SUBKEY),
+                count: 1,
+                value: Some(value),
+            })))
+            .unwrap();
+        app.network_loop_cycle::<Counter, _>(CountingLogic::default())
+            .await?;
+
+        let routes = app.routes.lock().await;
+        assert_eq!(routes.routes[&peer.our_dht_key.value].route, peer.our_route);
+        assert!(routes.is_watched(peer.our_dht_key));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_explicit_crypto_kind() -> Result<(), VeilidDuplexError> {
+        let app = VeilidDuplex::builder()
+            .crypto_kind(CRYPTO_KIND_VLD0)
+            .build()
+            .await?;
+
+        assert_eq!(app.config.crypto_kind, CRYPTO_KIND_VLD0);
+        assert_eq!(app.our_dht_key.kind, CRYPTO_KIND_VLD0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ready_after_new() -> Result<(), VeilidDuplexError> {
+        let app = VeilidDuplex::new().await?;
+
+        assert!(app.is_ready().await);
+        assert!(app.readiness().await?.is_ready());
+        app.await_ready(Duration::from_millis(10)).await?;
+
+        Ok(())
+    }
 
     #[tokio::test]
-    async fn test_dht_test_update() -> Result<(), Error> {
+    async fn test_dht_test_update() -> Result<(), VeilidDuplexError> {
         eprintln!("test_dht_test_update");
         let mut app = VeilidDuplex::new().await?;
 